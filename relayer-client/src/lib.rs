@@ -0,0 +1,293 @@
+//! Typed client for the relayer HTTP API.
+
+use std::time::Duration;
+
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+pub use crate::types::*;
+
+pub mod types;
+
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Transaction rejected: {0:?}")]
+    Validation(Vec<ValidationError>),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Not found")]
+    NotFound,
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Relayer is paused")]
+    Paused,
+    #[error("Server error ({status}): {message}")]
+    Server { status: StatusCode, message: String },
+    #[error("Job failed")]
+    JobFailed,
+    #[error("Job cancelled")]
+    JobCancelled,
+    #[error("Job expired")]
+    JobExpired,
+    #[error("Timed out")]
+    Timeout,
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Retries of requests that failed with a 5xx status or a connection error.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Doubled after every retry.
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayerClient {
+    client: reqwest::Client,
+    base_url: String,
+    retry: RetryPolicy,
+    /// Sent as a bearer token with every request.
+    token: Option<String>,
+}
+
+impl RelayerClient {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            retry: RetryPolicy::default(),
+            token: None,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// An API key, or the admin token for [`RelayerClient::jobs`].
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_owned());
+        self
+    }
+
+    pub async fn version(&self) -> Result<VersionResponse> {
+        self.request(|| self.builder(Method::GET, "/version")).await
+    }
+
+    pub async fn info(&self) -> Result<InfoResponse> {
+        self.request(|| self.builder(Method::GET, "/info")).await
+    }
+
+    /// Submissions are retried with the same idempotency key, so a transaction is never queued
+    /// twice.
+    pub async fn submit_transaction<P: Serialize>(&self, tx: &TxDataRequest<P>) -> Result<JobId> {
+        let idempotency_key = Uuid::new_v4().to_string();
+        let res: CreateTransactionResponse = self
+            .request(|| {
+                self.builder(Method::POST, "/transactions")
+                    .header(IDEMPOTENCY_KEY_HEADER, &idempotency_key)
+                    .json(tx)
+            })
+            .await?;
+
+        Ok(res.job_id)
+    }
+
+    /// Checks whether the relayer would accept the transaction, without submitting it. Returns the
+    /// pool index the transaction would get if submitted now.
+    pub async fn validate_transaction<P: Serialize>(&self, tx: &TxDataRequest<P>) -> Result<u64> {
+        let res: ValidateTransactionResponse = self
+            .request(|| {
+                self.builder(Method::POST, "/transactions/validate")
+                    .json(tx)
+            })
+            .await?;
+
+        Ok(res.estimated_index)
+    }
+
+    pub async fn job_status(&self, job_id: JobId) -> Result<JobStatus> {
+        let res: JobStatusResponse = self
+            .request(|| self.builder(Method::GET, &format!("/job/{job_id}")))
+            .await?;
+
+        Ok(res.state)
+    }
+
+    /// Status of the job that created the transaction at pool index `index`, e.g. to recover
+    /// after losing the job id.
+    pub async fn job_status_by_index(&self, index: u64) -> Result<JobStatus> {
+        let res: JobStatusResponse = self
+            .request(|| self.builder(Method::GET, &format!("/jobByIndex/{index}")))
+            .await?;
+
+        Ok(res.state)
+    }
+
+    /// Recent jobs, newest first, optionally only the ones with `status`. Jobs created at or after
+    /// `before` (unix timestamp in seconds) are skipped. Requires an API key or the admin token,
+    /// see [`RelayerClient::with_token`].
+    pub async fn jobs(
+        &self,
+        status: Option<JobStatus>,
+        before: Option<u64>,
+        limit: u64,
+    ) -> Result<Vec<JobSummary>> {
+        self.request(|| {
+            let builder = self
+                .builder(Method::GET, "/jobs")
+                .query(&[("limit", limit)]);
+            let builder = match status {
+                Some(status) => builder.query(&[("status", status)]),
+                None => builder,
+            };
+            match before {
+                Some(before) => builder.query(&[("before", before)]),
+                None => builder,
+            }
+        })
+        .await
+    }
+
+    /// Returns raw transaction records starting at pool index `offset`.
+    pub async fn transactions(&self, offset: u64, limit: u64) -> Result<Vec<Vec<u8>>> {
+        let res: Vec<Hex> = self
+            .request(|| {
+                self.builder(Method::GET, "/transactions")
+                    .query(&[("offset", offset), ("limit", limit)])
+            })
+            .await?;
+
+        Ok(res.into_iter().map(|tx| tx.0).collect())
+    }
+
+    /// Returns the output ciphertext prefixes of the transactions starting at pool index `offset`,
+    /// for finding the outputs of a wallet without fetching all transactions.
+    pub async fn ciphertexts(&self, offset: u64, limit: u64) -> Result<Vec<CiphertextPrefix>> {
+        self.request(|| {
+            self.builder(Method::GET, "/ciphertexts")
+                .query(&[("offset", offset), ("limit", limit)])
+        })
+        .await
+    }
+
+    /// Every root the relayer has had at pool index `index`, including the ones that were rolled
+    /// back.
+    pub async fn roots(&self, index: u64) -> Result<RootsResponse> {
+        self.request(|| self.builder(Method::GET, &format!("/roots/{index}")))
+            .await
+    }
+
+    /// Polls the job status until the job is completed.
+    pub async fn wait_for_job(&self, job_id: JobId, timeout: Duration) -> Result<()> {
+        let poll = async {
+            loop {
+                match self.job_status(job_id).await? {
+                    JobStatus::Completed => return Ok(()),
+                    JobStatus::Failed => return Err(Error::JobFailed),
+                    JobStatus::Cancelled => return Err(Error::JobCancelled),
+                    JobStatus::Expired => return Err(Error::JobExpired),
+                    JobStatus::Pending | JobStatus::InProgress => {
+                        tokio::time::sleep(JOB_POLL_INTERVAL).await;
+                    }
+                }
+            }
+        };
+
+        tokio::time::timeout(timeout, poll)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    fn builder(&self, method: Method, path: &str) -> RequestBuilder {
+        let builder = self
+            .client
+            .request(method, format!("{}{path}", self.base_url));
+        match &self.token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        }
+    }
+
+    async fn request<T, F>(&self, build: F) -> Result<T>
+    where
+        T: DeserializeOwned,
+        F: Fn() -> RequestBuilder,
+    {
+        let mut backoff = self.retry.initial_backoff;
+        let mut retries = 0;
+
+        loop {
+            let res = match build().send().await {
+                Ok(res) => res,
+                Err(err) if retries < self.retry.max_retries && is_retryable(&err) => {
+                    retries += 1;
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            let status = res.status();
+            if status.is_success() {
+                return Ok(res.json().await?);
+            }
+
+            let body: ErrorResponse = res.json().await.unwrap_or_default();
+            let err = to_error(status, body);
+
+            // A paused relayer is not a transient failure.
+            let retryable = status.is_server_error() && !matches!(err, Error::Paused);
+            if retryable && retries < self.retry.max_retries {
+                retries += 1;
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                continue;
+            }
+
+            return Err(err);
+        }
+    }
+}
+
+fn is_retryable(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout()
+}
+
+fn to_error(status: StatusCode, body: ErrorResponse) -> Error {
+    match status {
+        StatusCode::NOT_FOUND => Error::NotFound,
+        StatusCode::UNAUTHORIZED => Error::Unauthorized,
+        StatusCode::CONFLICT => Error::Conflict(body.error),
+        StatusCode::BAD_REQUEST if !body.errors.is_empty() => Error::Validation(body.errors),
+        StatusCode::BAD_REQUEST => Error::BadRequest(body.error),
+        StatusCode::SERVICE_UNAVAILABLE if body.code.as_deref() == Some("RELAYER_PAUSED") => {
+            Error::Paused
+        }
+        status => Error::Server {
+            status,
+            message: body.error,
+        },
+    }
+}
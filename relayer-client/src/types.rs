@@ -0,0 +1,214 @@
+//! Request and response types of the relayer HTTP API, shared with the server.
+
+use serde::{Deserialize, Serialize};
+pub use zeropool_tx::TxType;
+
+pub type JobId = u64;
+
+/// `P` is the proof with its public inputs. Its shape depends on the proving system the relayer
+/// is built with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TxDataRequest<P> {
+    pub tx_type: TxType,
+    pub proof: P,
+    #[serde(with = "hex")]
+    pub memo: Vec<u8>,
+    /// Deposit signature of the depositor, on EVM followed by the depositor's address.
+    #[serde(with = "hex", default)]
+    pub extra_data: Vec<u8>,
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateTransactionResponse {
+    pub job_id: JobId,
+}
+
+/// Response of the dry run `POST /transactions/validate`. Rejected transactions get the usual
+/// validation error response instead.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidateTransactionResponse {
+    pub valid: bool,
+    /// Pool index the transaction would get if it was submitted now.
+    pub estimated_index: u64,
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    InProgress,
+    Completed,
+    Failed,
+    /// Cancelled by a rollback before its transaction was sent.
+    Cancelled,
+    /// Dropped before its transaction was sent, because the transfer index of the transaction
+    /// fell too far behind the pool index.
+    Expired,
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatusResponse {
+    pub state: JobStatus,
+    /// Account that sent the transaction, if the relayer uses several.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+}
+
+/// A job in `GET /jobs`.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobSummary {
+    pub id: JobId,
+    pub status: JobStatus,
+    /// Unix timestamp in seconds.
+    pub created_at: u64,
+    /// Hash of the sent transaction, in the encoding of the backend.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<String>,
+    /// Commit index the transaction was prepared for, i.e. its pool index divided by 128.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_index: Option<u64>,
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InfoResponse {
+    pub backend: String,
+    pub chain_id: String,
+    /// Pool id that proofs must be made for.
+    #[serde(default)]
+    pub pool_id: u64,
+    pub api_version: String,
+    /// Merkle root of the mined transactions, a field element in decimal.
+    pub root: String,
+    /// Merkle root including the queued transactions, a field element in decimal.
+    pub optimistic_root: String,
+    /// Pool index after the last mined transaction, in decimal.
+    pub pool_index: String,
+    /// Pool index after the last queued transaction, in decimal.
+    pub optimistic_index: String,
+    pub paused: bool,
+    pub sending_paused: bool,
+    pub pending_jobs: Option<u64>,
+    pub in_progress_jobs: Option<u64>,
+    pub failed_jobs_last24h: Option<u64>,
+    pub mined_transactions: u64,
+    pub optimistic_transactions: Option<u64>,
+    /// Read-only replicas that serve `/transactions` and `/info`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildInfo>,
+    /// Incremented on every rollback of the optimistic state, e.g. after a reorg or a failed
+    /// transaction. A change means that optimistic roots seen before might have been replaced, see
+    /// `GET /roots/{index}`.
+    #[serde(default)]
+    pub supersede_counter: u64,
+}
+
+/// Version and build options of a relayer binary.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_hash: String,
+    /// Enabled cargo features, e.g. the proving system and the backends.
+    pub features: Vec<String>,
+    /// Unix timestamp in seconds.
+    pub build_timestamp: u64,
+}
+
+/// Which relayer binary is running and how it's configured, see `GET /version`.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionResponse {
+    /// Crate version.
+    pub version: String,
+    /// Commit the binary was built from, `unknown` if it wasn't available at build time.
+    pub git_hash: String,
+    /// Enabled cargo features, e.g. the proving system and the backends.
+    pub features: Vec<String>,
+    /// Unix timestamp in seconds.
+    pub build_timestamp: u64,
+    /// `groth16` or `plonk`.
+    pub proving_system: String,
+    /// Backend the relayer is running with, e.g. `evm`.
+    pub backend: String,
+}
+
+/// Roots the relayer has had at a pool index, see `GET /roots/{index}`.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RootsResponse {
+    pub index: u64,
+    /// Current supersede counter, as in `/info`.
+    pub supersede_counter: u64,
+    /// Oldest first. Roots that were rolled back are kept with `canonical: false`.
+    pub roots: Vec<RootLogEntry>,
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RootLogEntry {
+    /// Field element in decimal.
+    pub root: String,
+    /// Supersede counter at the time the root was recorded.
+    pub supersede_counter: u64,
+    /// Whether the root is still part of the relayer's history.
+    pub canonical: bool,
+}
+
+/// Start of an output ciphertext, see `GET /ciphertexts`.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CiphertextPrefix {
+    /// Commitment index of the output.
+    pub index: u64,
+    /// Ephemeral key the output is encrypted with.
+    pub prefix: Hex,
+}
+
+/// Hex-encoded binary data.
+#[cfg_attr(
+    feature = "openapi",
+    derive(utoipa::ToSchema),
+    schema(value_type = String, format = "hex")
+)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hex(#[serde(with = "hex")] pub Vec<u8>);
+
+/// Body of all error responses.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Individual errors of a rejected transaction.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ValidationError>,
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidationError {
+    pub error: String,
+    pub code: String,
+}
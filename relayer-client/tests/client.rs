@@ -0,0 +1,262 @@
+//! Runs the client against an in-process stub that mimics the relayer API.
+
+use std::{
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use zeropool_relayer_client::{
+    Error, Hex, InfoResponse, JobStatus, RelayerClient, RetryPolicy, TxDataRequest, TxType,
+};
+
+#[derive(Default)]
+struct Stub {
+    submissions: AtomicU64,
+    idempotency_keys: Mutex<Vec<String>>,
+    job_polls: AtomicU64,
+}
+
+async fn info() -> Json<InfoResponse> {
+    Json(InfoResponse {
+        backend: "mock".to_owned(),
+        chain_id: "mock".to_owned(),
+        pool_id: 0,
+        api_version: "3".to_owned(),
+        root: "0".to_owned(),
+        optimistic_root: "0".to_owned(),
+        pool_index: "0".to_owned(),
+        optimistic_index: "128".to_owned(),
+        paused: false,
+        sending_paused: false,
+        pending_jobs: Some(1),
+        in_progress_jobs: Some(0),
+        failed_jobs_last24h: None,
+        mined_transactions: 0,
+        optimistic_transactions: Some(1),
+        replicas: vec![],
+        pool_address: None,
+        build: None,
+        supersede_counter: 0,
+    })
+}
+
+/// Fails the first submission with a 500, rejects memos starting with 0xff.
+async fn create_transaction(
+    State(stub): State<Arc<Stub>>,
+    headers: HeaderMap,
+    Json(tx): Json<TxDataRequest<serde_json::Value>>,
+) -> Response {
+    let key = headers["Idempotency-Key"].to_str().unwrap().to_owned();
+    stub.idempotency_keys.lock().unwrap().push(key);
+
+    if tx.memo.first() == Some(&0xff) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Validation error",
+                "errors": [{ "error": "Fee too low", "code": "FeeTooLow" }],
+            })),
+        )
+            .into_response();
+    }
+
+    if stub.submissions.fetch_add(1, Ordering::SeqCst) == 0 {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": "Redis is down" })),
+        )
+            .into_response();
+    }
+
+    Json(json!({ "jobId": 7 })).into_response()
+}
+
+async fn validate_transaction(Json(tx): Json<TxDataRequest<serde_json::Value>>) -> Response {
+    if tx.memo.first() == Some(&0xff) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Validation error",
+                "errors": [
+                    { "error": "Fee too low", "code": "fee_too_low" },
+                    { "error": "Invalid values", "code": "invalid_values" },
+                ],
+            })),
+        )
+            .into_response();
+    }
+
+    Json(json!({ "valid": true, "estimatedIndex": 256 })).into_response()
+}
+
+async fn job(State(stub): State<Arc<Stub>>, Path(id): Path<u64>) -> Response {
+    let state = match id {
+        7 if stub.job_polls.fetch_add(1, Ordering::SeqCst) < 2 => JobStatus::InProgress,
+        7 => JobStatus::Completed,
+        8 => JobStatus::Failed,
+        9 => JobStatus::Pending,
+        10 => JobStatus::Expired,
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    Json(json!({ "state": state })).into_response()
+}
+
+#[derive(Deserialize)]
+struct Pagination {
+    offset: u64,
+    limit: u64,
+}
+
+async fn transactions(Query(pagination): Query<Pagination>) -> Json<Vec<Hex>> {
+    Json(
+        (pagination.offset..pagination.offset + pagination.limit)
+            .map(|i| Hex(vec![i as u8]))
+            .collect(),
+    )
+}
+
+async fn paused() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({ "error": "Relayer is paused", "code": "RELAYER_PAUSED" })),
+    )
+        .into_response()
+}
+
+fn start_stub() -> (String, Arc<Stub>) {
+    let stub = Arc::new(Stub::default());
+    let router = Router::new()
+        .route("/info", get(info))
+        .route("/transactions", get(transactions).post(create_transaction))
+        .route("/transactions/validate", post(validate_transaction))
+        .route("/job/:id", get(job))
+        .with_state(stub.clone());
+
+    (serve(router), stub)
+}
+
+/// Serves the router on a random local port, returns its URL.
+fn serve(router: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service()),
+    );
+
+    format!("http://{addr}")
+}
+
+fn client(url: &str) -> RelayerClient {
+    RelayerClient::new(url).with_retry_policy(RetryPolicy {
+        max_retries: 2,
+        initial_backoff: Duration::from_millis(1),
+    })
+}
+
+fn tx(memo: Vec<u8>) -> TxDataRequest<serde_json::Value> {
+    TxDataRequest {
+        tx_type: TxType::Transfer,
+        proof: json!({ "proof": {}, "inputs": [] }),
+        memo,
+        extra_data: vec![],
+    }
+}
+
+#[tokio::test]
+async fn test_client() {
+    let (url, stub) = start_stub();
+    let client = client(&url);
+
+    let info = client.info().await.unwrap();
+    assert_eq!(info.backend, "mock");
+    assert_eq!(info.pending_jobs, Some(1));
+
+    // The first attempt fails with a 500 and is retried with the same idempotency key.
+    let job_id = client.submit_transaction(&tx(vec![0; 8])).await.unwrap();
+    assert_eq!(job_id, 7);
+    let keys = stub.idempotency_keys.lock().unwrap().clone();
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys[0], keys[1]);
+
+    let err = client
+        .submit_transaction(&tx(vec![0xff; 8]))
+        .await
+        .unwrap_err();
+    match err {
+        Error::Validation(errors) => assert_eq!(errors[0].code, "FeeTooLow"),
+        err => panic!("Unexpected error: {err}"),
+    }
+
+    assert_eq!(client.job_status(9).await.unwrap(), JobStatus::Pending);
+    assert!(matches!(client.job_status(100).await, Err(Error::NotFound)));
+
+    client
+        .wait_for_job(7, Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.wait_for_job(8, Duration::from_secs(5)).await,
+        Err(Error::JobFailed)
+    ));
+    assert!(matches!(
+        client.wait_for_job(10, Duration::from_secs(5)).await,
+        Err(Error::JobExpired)
+    ));
+    assert!(matches!(
+        client.wait_for_job(9, Duration::from_millis(100)).await,
+        Err(Error::Timeout)
+    ));
+
+    let txs = client.transactions(3, 2).await.unwrap();
+    assert_eq!(txs, vec![vec![3], vec![4]]);
+}
+
+#[tokio::test]
+async fn test_client_validate_transaction() {
+    let (url, stub) = start_stub();
+    let client = client(&url);
+
+    assert_eq!(
+        client.validate_transaction(&tx(vec![0; 8])).await.unwrap(),
+        256
+    );
+
+    match client.validate_transaction(&tx(vec![0xff; 8])).await {
+        Err(Error::Validation(errors)) => {
+            let codes: Vec<_> = errors.iter().map(|err| err.code.as_str()).collect();
+            assert_eq!(codes, ["fee_too_low", "invalid_values"]);
+        }
+        res => panic!("Unexpected result: {res:?}"),
+    }
+
+    // Nothing is submitted
+    assert!(stub.idempotency_keys.lock().unwrap().is_empty());
+    assert_eq!(stub.submissions.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_client_paused() {
+    let url = serve(Router::new().route("/transactions", post(paused)));
+
+    let err = client(&url)
+        .submit_transaction(&tx(vec![0; 8]))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Paused));
+}
@@ -0,0 +1,143 @@
+//! Optional API key authentication for private deployments.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::config::Secret;
+
+pub struct ApiKeys {
+    /// Keys are compared by their hashes, so that the comparison doesn't depend on their lengths.
+    hashes: Vec<[u8; 32]>,
+}
+
+impl ApiKeys {
+    /// Returns `None` if `keys` is empty, i.e. authentication is disabled.
+    pub fn new(keys: &[Secret]) -> Option<Self> {
+        if keys.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            hashes: keys.iter().map(|key| hash(&key.0)).collect(),
+        })
+    }
+
+    /// Takes constant time for a given number of keys.
+    pub fn check(&self, key: &str) -> bool {
+        let key = hash(key);
+
+        self.hashes.iter().fold(false, |found, expected| {
+            found | constant_time_eq(expected, &key)
+        })
+    }
+
+    pub(crate) fn check_headers(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map_or(false, |key| self.check(key))
+    }
+}
+
+fn hash(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Compares a bearer token with the expected one in constant time, like [`ApiKeys::check`].
+pub(crate) fn token_matches(expected: &Secret, token: &str) -> bool {
+    constant_time_eq(&hash(&expected.0), &hash(token))
+}
+
+pub async fn middleware<B>(
+    State(keys): State<Arc<ApiKeys>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if keys.check_headers(req.headers()) {
+        return next.run(req).await;
+    }
+
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({
+            "error": "Missing or invalid API key",
+            "code": "UNAUTHORIZED",
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::post, Router};
+
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn test_check() {
+        assert!(ApiKeys::new(&[]).is_none());
+
+        let keys =
+            ApiKeys::new(&[Secret("first".to_owned()), Secret("second".to_owned())]).unwrap();
+        assert!(keys.check("first"));
+        assert!(keys.check("second"));
+        assert!(!keys.check("third"));
+        assert!(!keys.check("firs"));
+        assert!(!keys.check(""));
+
+        let token = Secret("admin".to_owned());
+        assert!(token_matches(&token, "admin"));
+        assert!(!token_matches(&token, "admi"));
+        assert!(!token_matches(&token, ""));
+    }
+
+    #[tokio::test]
+    async fn test_api_key_middleware() {
+        let keys = Arc::new(ApiKeys::new(&[Secret("secret".to_owned())]).unwrap());
+        let router = Router::new()
+            .route("/transactions", post(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(keys, super::middleware));
+
+        let addr = test_utils::serve(router);
+
+        let client = reqwest::Client::new();
+        let send = |authorization: Option<&'static str>| {
+            let mut req = client.post(format!("http://{addr}/transactions"));
+            if let Some(authorization) = authorization {
+                req = req.header(AUTHORIZATION, authorization);
+            }
+            req.send()
+        };
+
+        // Missing key
+        let res = send(None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(body["code"], "UNAUTHORIZED");
+
+        // Wrong key or scheme
+        let res = send(Some("Bearer wrong")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = send(Some("secret")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // Correct key
+        let res = send(Some("Bearer secret")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
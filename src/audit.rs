@@ -0,0 +1,389 @@
+//! Append-only audit log of the operations that mutate the relayer state, written as JSON lines
+//! by a dedicated task so that request handling never waits for the disk.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+use crate::{
+    config::RuntimeSettings,
+    job_queue::{unix_timestamp, JobId},
+};
+
+/// Entries recorded while the writer is this far behind are dropped.
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_path")]
+    pub path: PathBuf,
+    /// The log is rotated once it would grow past this size. Only one rotated file is kept, with
+    /// a `.1` suffix.
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            path: default_path(),
+            max_size: default_max_size(),
+            fsync: FsyncPolicy::default(),
+        }
+    }
+}
+
+fn default_path() -> PathBuf {
+    PathBuf::from("audit.jsonl")
+}
+
+fn default_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// Every entry is synced to disk before the next one is written.
+    #[default]
+    Always,
+    /// Entries are left to the OS, a crash of the machine can lose the latest ones.
+    Never,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    TransactionAccepted {
+        job_id: JobId,
+        request_id: Uuid,
+        /// Pool index the transaction is expected at.
+        index: u64,
+    },
+    TransactionSent {
+        job_id: JobId,
+        index: u64,
+        tx_hash: String,
+    },
+    /// Local transactions starting at pool index `to` were removed. `job_id` is the failed job
+    /// that caused the rollback, if any.
+    Rollback {
+        job_id: Option<JobId>,
+        to: u64,
+        removed: u64,
+        cause: String,
+    },
+    JobExpired {
+        job_id: JobId,
+        index: u64,
+        cause: String,
+    },
+    Reinitialized {
+        relayer_index: u64,
+        pool_index: u64,
+    },
+    Paused {
+        sending: bool,
+    },
+    Resumed,
+    Compacted,
+    FailedJobRetried {
+        failed_job_id: JobId,
+        job_id: JobId,
+    },
+    SignerDisabled {
+        address: String,
+    },
+    /// A standby took over from its primary, see `peer_sync`.
+    Promoted {
+        resubmitted: u64,
+        dropped: u64,
+    },
+    /// `RuntimeSettings` were changed, `source` is `api` or `sighup`.
+    SettingsChanged {
+        settings: RuntimeSettings,
+        source: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix timestamp in seconds.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+enum Message {
+    Entry(AuditEntry),
+    Flush(oneshot::Sender<()>),
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    sender: mpsc::Sender<Message>,
+}
+
+impl AuditLog {
+    /// Opens the log and starts the writer task.
+    pub fn open(config: &Config) -> Result<Self> {
+        let mut writer = Writer::open(config)?;
+        let (sender, mut receiver) = mpsc::channel(CHANNEL_CAPACITY);
+
+        tokio::task::spawn_blocking(move || {
+            while let Some(message) = receiver.blocking_recv() {
+                match message {
+                    Message::Entry(entry) => {
+                        if let Err(err) = writer.write(&entry) {
+                            tracing::error!("Failed to write audit entry {entry:?}: {err:#}");
+                        }
+                    }
+                    Message::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            path: config.path.clone(),
+            sender,
+        })
+    }
+
+    /// Queues the event for writing. Never blocks: if the writer can't keep up, the event is
+    /// dropped and logged instead.
+    pub fn record(&self, event: AuditEvent) {
+        let entry = AuditEntry {
+            timestamp: unix_timestamp(),
+            event,
+        };
+
+        if let Err(err) = self.sender.try_send(Message::Entry(entry)) {
+            if let Message::Entry(entry) = err.into_inner() {
+                tracing::error!("Audit log is unavailable, dropping entry {entry:?}");
+            }
+        }
+    }
+
+    /// Waits until all previously recorded entries are written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    /// Returns up to `limit` entries recorded at or after `since` (unix timestamp), oldest first.
+    /// Entries that were rotated out twice are no longer available.
+    pub fn read(&self, since: u64, limit: usize) -> Result<Vec<AuditEntry>> {
+        let mut entries = Vec::new();
+        for path in [rotated_path(&self.path), self.path.clone()] {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            for line in BufReader::new(file).lines() {
+                // Lines torn by a crash are skipped.
+                let Ok(entry) = serde_json::from_str::<AuditEntry>(&line?) else {
+                    continue;
+                };
+
+                if entry.timestamp >= since {
+                    entries.push(entry);
+                    if entries.len() >= limit {
+                        return Ok(entries);
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".1");
+    PathBuf::from(path)
+}
+
+struct Writer {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    fsync: FsyncPolicy,
+}
+
+impl Writer {
+    fn open(config: &Config) -> Result<Self> {
+        let mut file = open_append(&config.path)?;
+        let mut size = file.metadata()?.len();
+
+        // Terminate a line torn by a crash, so that the next entry starts on its own line.
+        if size > 0 {
+            let mut last = [0];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+                size += 1;
+            }
+        }
+
+        Ok(Self {
+            path: config.path.clone(),
+            file,
+            size,
+            max_size: config.max_size,
+            fsync: config.fsync,
+        })
+    }
+
+    fn write(&mut self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+
+        if self.fsync == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        std::fs::rename(&self.path, rotated_path(&self.path))?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path, max_size: u64) -> Config {
+        Config {
+            path: dir.join("audit.jsonl"),
+            max_size,
+            fsync: FsyncPolicy::Always,
+        }
+    }
+
+    fn sent(index: u64) -> AuditEvent {
+        AuditEvent::TransactionSent {
+            job_id: index,
+            index,
+            tx_hash: format!("0x{index:02x}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::open(&config(dir.path(), default_max_size())).unwrap();
+
+        for index in 0..100 {
+            log.record(sent(index));
+        }
+        log.record(AuditEvent::Paused { sending: true });
+        log.flush().await;
+
+        let entries = log.read(0, usize::MAX).unwrap();
+        let events = entries.into_iter().map(|e| e.event).collect::<Vec<_>>();
+        let mut expected = (0..100).map(sent).collect::<Vec<_>>();
+        expected.push(AuditEvent::Paused { sending: true });
+        assert_eq!(events, expected);
+
+        assert_eq!(log.read(0, 3).unwrap().len(), 3);
+        assert!(log.read(u64::MAX, 10).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), default_max_size());
+
+        let log = AuditLog::open(&config).unwrap();
+        log.record(sent(0));
+        log.record(sent(1));
+        log.flush().await;
+        drop(log);
+
+        // The process died in the middle of writing an entry.
+        let mut file = open_append(&config.path).unwrap();
+        file.write_all(br#"{"timestamp":1,"event":"transac"#)
+            .unwrap();
+        drop(file);
+
+        let log = AuditLog::open(&config).unwrap();
+        log.record(sent(2));
+        log.flush().await;
+
+        let events = log
+            .read(0, usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.event)
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![sent(0), sent(1), sent(2)]);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let line_size = serde_json::to_vec(&AuditEntry {
+            timestamp: unix_timestamp(),
+            event: sent(0),
+        })
+        .unwrap()
+        .len() as u64
+            + 1;
+        let config = config(dir.path(), 3 * line_size);
+        let log = AuditLog::open(&config).unwrap();
+
+        for index in 0..8 {
+            log.record(sent(index));
+        }
+        log.flush().await;
+
+        // Two full files were rotated, only the latest one is kept.
+        assert!(rotated_path(&config.path).exists());
+        let events = log
+            .read(0, usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.event)
+            .collect::<Vec<_>>();
+        assert_eq!(events, (3..8).map(sent).collect::<Vec<_>>());
+    }
+}
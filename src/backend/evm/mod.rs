@@ -2,41 +2,166 @@ use std::str::FromStr;
 
 use anyhow::Result;
 use axum::async_trait;
-use libzeropool_rs::libzeropool::fawkes_crypto;
+use libzeropool_rs::libzeropool::fawkes_crypto::{
+    self,
+    ff_uint::{PrimeField, Uint},
+};
 use secp256k1::SecretKey;
 use serde::Deserialize;
 use web3::{
     contract::{Contract, Options},
     transports::Http,
-    types::{TransactionParameters, U256},
+    types::{Address, BlockId, BlockNumber, TransactionParameters, H256, U256, U64},
     Web3,
 };
-use zeropool_tx::TxData;
+use zeropool_tx::{TxData, TxType};
 
+use self::{
+    relay::{PrivateRelay, Submission, SubmissionModeKind},
+    signers::Signers,
+};
 use crate::{
-    backend::{BlockchainBackend, TxCalldata, TxHash},
+    backend::{
+        http_client,
+        util::{self, retry},
+        BlockchainBackend, HashEncoding, SendError, SentTx, TxCalldata, TxConfirmation, TxHash,
+    },
+    config::Secret,
+    job_queue::RetryPolicy,
+    monitoring,
     tx::{ParsedTxData, TxValidationError},
     Fr, Proof,
 };
 
+mod nonce;
+mod relay;
+mod signature;
+mod signers;
+
+/// Withdrawal memo layout: fee (8 bytes), native amount (8 bytes), receiver address (20 bytes).
+const WITHDRAW_ADDRESS_OFFSET: usize = 16;
+const ADDRESS_LENGTH: usize = 20;
+const EIP1559_TX_TYPE: u64 = 2;
+pub(crate) const HASH_ENCODING: HashEncoding = HashEncoding::Hex { size: 32 };
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvmTxType {
+    #[default]
+    Legacy,
+    Eip1559,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub rpc_url: String,
     pub pool_address: String,
     pub token_address: String,
-    pub sk: String,
+    /// Single signing key, used if `sks` is empty.
+    #[serde(default)]
+    pub sk: Option<Secret>,
+    /// Comma-separated signing keys, used in round-robin order.
+    #[serde(default)]
+    pub sks: Vec<Secret>,
+    /// Signing keys with a lower native balance (in wei) are skipped.
+    #[serde(default)]
+    pub min_signer_balance: u64,
+    #[serde(default)]
+    pub tx_type: EvmTxType,
+    /// Tip for EIP-1559 transactions, in wei.
+    #[serde(default = "default_max_priority_fee_per_gas")]
+    pub max_priority_fee_per_gas: u64,
+    /// Timeout of RPC requests, 30 seconds by default.
+    #[serde(default = "crate::backend::default_rpc_timeout_secs")]
+    pub rpc_timeout_secs: u64,
+    /// `public` sends transactions to the public mempool, `private` to `relay_url`.
+    #[serde(default)]
+    pub submission_mode: SubmissionModeKind,
+    /// Private relay endpoint, required in the private submission mode.
+    #[serde(default)]
+    pub relay_url: Option<String>,
+    #[serde(default)]
+    pub relay_auth_key: Option<Secret>,
+    /// JSON-RPC method of the relay.
+    #[serde(default = "default_relay_method")]
+    pub relay_method: String,
+    /// Send transactions to the public mempool after `relay_max_failures` consecutive relay
+    /// failures.
+    #[serde(default)]
+    pub relay_fallback_to_public: bool,
+    #[serde(default = "default_relay_max_failures")]
+    pub relay_max_failures: u32,
+}
+
+fn default_max_priority_fee_per_gas() -> u64 {
+    1_500_000_000 // 1.5 gwei
+}
+
+fn default_relay_method() -> String {
+    "eth_sendPrivateTransaction".to_owned()
+}
+
+fn default_relay_max_failures() -> u32 {
+    3
 }
 
 pub struct EvmBackend {
     web3: Web3<Http>,
     contract: Contract<Http>,
     token: Contract<Http>,
-    sk: SecretKey,
+    signers: Signers,
+    tx_type: EvmTxType,
+    max_priority_fee_per_gas: U256,
+    submission: Submission,
+    retry: RetryPolicy,
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Eip1559Fees {
+    max_fee_per_gas: U256,
+    max_priority_fee_per_gas: U256,
+}
+
+impl Eip1559Fees {
+    /// Leaves room for the base fee to double before the transaction is included.
+    fn new(base_fee: U256, max_priority_fee_per_gas: U256) -> Self {
+        Self {
+            max_fee_per_gas: base_fee * 2 + max_priority_fee_per_gas,
+            max_priority_fee_per_gas,
+        }
+    }
+}
+
+/// Builds a legacy transaction if `fees` is `None`.
+fn tx_parameters(
+    to: Address,
+    data: Vec<u8>,
+    nonce: u64,
+    fees: Option<Eip1559Fees>,
+) -> TransactionParameters {
+    let params = TransactionParameters {
+        nonce: Some(nonce.into()),
+        to: Some(to),
+        data: data.into(),
+        ..Default::default()
+    };
+
+    match fees {
+        Some(fees) => TransactionParameters {
+            transaction_type: Some(U64::from(EIP1559_TX_TYPE)),
+            max_fee_per_gas: Some(fees.max_fee_per_gas),
+            max_priority_fee_per_gas: Some(fees.max_priority_fee_per_gas),
+            ..params
+        },
+        None => params,
+    }
 }
 
 impl EvmBackend {
-    pub fn new(config: Config) -> Result<Self> {
-        let transport = Http::new(&config.rpc_url)?;
+    pub fn new(config: Config, retry: RetryPolicy, dry_run: bool) -> Result<Self> {
+        let http = http_client(config.rpc_timeout_secs)?;
+        let transport = Http::with_client(http.clone(), config.rpc_url.parse()?);
         let web3 = Web3::new(transport.clone());
         let contract = Contract::from_json(
             web3.eth(),
@@ -49,15 +174,61 @@ impl EvmBackend {
             include_bytes!("token.json"),
         )?;
 
-        let sk = SecretKey::from_str(&config.sk)?;
+        let sks = match (config.sks.is_empty(), &config.sk) {
+            (false, _) => config.sks.iter().collect(),
+            (true, Some(sk)) => vec![sk],
+            (true, None) => anyhow::bail!("Either EVM_SK or EVM_SKS must be set"),
+        };
+        let sks = sks
+            .into_iter()
+            .map(|sk| SecretKey::from_str(&sk.0))
+            .collect::<Result<_, _>>()?;
+
+        let submission = match config.submission_mode {
+            SubmissionModeKind::Public => Submission::Public,
+            SubmissionModeKind::Private => {
+                let url = config.relay_url.ok_or_else(|| {
+                    anyhow::anyhow!("EVM_RELAY_URL must be set in the private submission mode")
+                })?;
+                Submission::Private(PrivateRelay::new(
+                    http,
+                    url,
+                    config.relay_auth_key,
+                    config.relay_method,
+                    config
+                        .relay_fallback_to_public
+                        .then_some(config.relay_max_failures),
+                ))
+            }
+        };
 
         Ok(Self {
             web3,
             contract,
-            sk,
+            signers: Signers::new(sks, config.min_signer_balance.into()),
             token,
+            tx_type: config.tx_type,
+            max_priority_fee_per_gas: config.max_priority_fee_per_gas.into(),
+            submission,
+            retry,
+            dry_run,
         })
     }
+
+    async fn latest_base_fee(&self) -> Result<U256, SendError> {
+        let block = self
+            .web3
+            .eth()
+            .block(BlockId::Number(BlockNumber::Latest))
+            .await
+            .map_err(send_error)?;
+
+        block
+            .and_then(|block| block.base_fee_per_gas)
+            .ok_or_else(|| {
+                anyhow::anyhow!("Latest block has no base fee, is EIP-1559 supported?").into()
+            })
+    }
 }
 
 #[async_trait]
@@ -66,78 +237,351 @@ impl BlockchainBackend for EvmBackend {
         "evm"
     }
 
-    async fn fetch_latest_transactions(&self) -> Result<Vec<TxCalldata>> {
+    async fn chain_id(&self) -> Result<String> {
+        Ok(self.web3.eth().chain_id().await?.to_string())
+    }
+
+    async fn fetch_latest_transactions(&self, _from: u64, _limit: u64) -> Result<Vec<TxCalldata>> {
         Ok(vec![])
     }
 
-    async fn validate_tx(&self, _tx: &ParsedTxData) -> Vec<TxValidationError> {
-        // let address = recover(&tx.signature, &tx.hash).unwrap();
-        // let balance = self
-        //     .token
-        //     .query("balanceOf", tx.sender, None, Options::default(), None);
-        // TODO: Check the balance of the sender for deposits.
-        vec![]
+    async fn validate_tx(&self, tx: &ParsedTxData) -> Vec<TxValidationError> {
+        let mut errors = Vec::new();
+
+        if matches!(tx.tx_type, TxType::Deposit) {
+            let nullifier = tx.nullifier.0.to_uint().to_big_endian();
+            match signature::verify_depositor(&nullifier, &tx.extra_data) {
+                // TODO: Check the balance of the depositor.
+                Some(depositor) => tracing::debug!("Deposit from {depositor:?}"),
+                None => errors.push(TxValidationError::InvalidSignature),
+            }
+        }
+
+        errors
     }
 
     /// Sign and send a transaction to the blockchain.
-    async fn send_tx(&self, tx: TxData<Fr, Proof>) -> Result<TxHash> {
+    async fn send_tx(&self, tx: TxData<Fr, Proof>) -> Result<SentTx, SendError> {
         let mut calldata = Vec::new();
-        zeropool_tx::evm::write(&tx, &mut calldata)?;
+        zeropool_tx::evm::write(&tx, &mut calldata).map_err(anyhow::Error::from)?;
+
+        if self.dry_run {
+            return Ok(util::dry_run(self.name(), &calldata));
+        }
 
-        let tx_object = TransactionParameters {
-            to: Some(self.contract.address()),
-            data: calldata.into(),
-            ..Default::default()
+        let fees = match self.tx_type {
+            EvmTxType::Legacy => None,
+            EvmTxType::Eip1559 => Some(Eip1559Fees::new(
+                self.latest_base_fee().await?,
+                self.max_priority_fee_per_gas,
+            )),
         };
+        let signer = self
+            .signers
+            .select(|address| async move {
+                let balance = self
+                    .web3
+                    .eth()
+                    .balance(address, None)
+                    .await
+                    .map_err(send_error)?;
+                monitoring::set_signer_balance(format!("{address:?}"), balance.low_u128() as f64);
+                Ok(balance)
+            })
+            .await?
+            .ok_or_else(|| {
+                SendError::Retryable(anyhow::anyhow!("No enabled signer with enough balance"))
+            })?;
 
-        let signed = self
-            .web3
-            .accounts()
-            .sign_transaction(tx_object, &self.sk)
+        let nonce = signer
+            .nonces
+            .next(|| async {
+                let count = self
+                    .web3
+                    .eth()
+                    .transaction_count(signer.address, Some(BlockNumber::Pending))
+                    .await
+                    .map_err(send_error)?;
+                Ok(count.as_u64())
+            })
             .await?;
+        let tx_object = tx_parameters(self.contract.address(), calldata, nonce, fees);
 
         // TODO: Calculate gas
-        let result = self
+        let result = async {
+            let signed = self
+                .web3
+                .accounts()
+                .sign_transaction(tx_object, &signer.sk)
+                .await
+                .map_err(send_error)?;
+
+            // The hash is computed locally, relays don't necessarily return it.
+            self.submission
+                .send(&signed.raw_transaction.0, || async {
+                    self.web3
+                        .eth()
+                        .send_raw_transaction(signed.raw_transaction.clone())
+                        .await
+                        .map(drop)
+                        .map_err(send_error)
+                })
+                .await?;
+
+            Ok::<_, SendError>(signed.transaction_hash)
+        }
+        .await;
+
+        match result {
+            Ok(hash) => Ok(SentTx {
+                hash: hash.to_fixed_bytes().to_vec(),
+                sender: Some(format!("{:?}", signer.address)),
+            }),
+            Err(err) => {
+                // The nonce might not have been used, or might be out of sync with the node.
+                // Either way, fetch it again for the next transaction.
+                signer.nonces.reset().await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn get_tx_status(&self, hash: &TxHash) -> Result<TxConfirmation> {
+        if hash.len() != H256::len_bytes() {
+            anyhow::bail!("Invalid transaction hash length: {}", hash.len());
+        }
+
+        let receipt = self
             .web3
             .eth()
-            .send_raw_transaction(signed.raw_transaction)
+            .transaction_receipt(H256::from_slice(hash))
             .await?;
 
-        Ok(result.to_fixed_bytes().to_vec())
+        // Receipts of pre-Byzantium transactions have no status.
+        Ok(match receipt {
+            Some(receipt) if receipt.block_number.is_none() => TxConfirmation::Pending,
+            Some(receipt) if receipt.status == Some(U64::zero()) => TxConfirmation::Failed,
+            Some(_) => TxConfirmation::Confirmed,
+            None => TxConfirmation::Pending,
+        })
     }
 
     async fn get_pool_index(&self) -> Result<u64> {
-        let pool_index: U256 = self
-            .contract
-            .query("pool_index", (), None, Options::default(), None)
-            .await?;
+        let pool_index: U256 = retry(
+            move || {
+                self.contract
+                    .query("pool_index", (), None, Options::default(), None)
+            },
+            &self.retry,
+        )
+        .await?;
 
-        Ok(pool_index.as_u64())
+        to_u64(pool_index, "Pool index")
     }
 
     async fn get_merkle_root(&self, index: u64) -> Result<Option<fawkes_crypto::engines::U256>> {
-        let root: U256 = self
-            .contract
-            .query("roots", index, None, Options::default(), None)
-            .await?;
+        let root: U256 = retry(
+            move || {
+                self.contract
+                    .query("roots", index, None, Options::default(), None)
+            },
+            &self.retry,
+        )
+        .await?;
 
         let root = fawkes_crypto::engines::U256::new(root.0);
 
         Ok(Some(root))
     }
 
+    async fn get_pool_id(&self) -> Result<Option<u64>> {
+        let pool_id: U256 = retry(
+            move || {
+                self.contract
+                    .query("pool_id", (), None, Options::default(), None)
+            },
+            &self.retry,
+        )
+        .await?;
+
+        Ok(Some(to_u64(pool_id, "Pool id")?))
+    }
+
     fn parse_calldata(&self, calldata: Vec<u8>) -> Result<TxData<Fr, Proof>> {
-        let r = &mut calldata.as_slice();
-        let tx = zeropool_tx::evm::read(r)?;
-        Ok(tx)
+        read_calldata(&calldata)
+    }
+
+    fn validate_withdraw_address(&self, memo: &[u8]) -> Result<(), TxValidationError> {
+        if memo.len() < WITHDRAW_ADDRESS_OFFSET + ADDRESS_LENGTH {
+            return Err(TxValidationError::InvalidWithdrawAddress);
+        }
+
+        Ok(())
+    }
+
+    fn disable_signer(&self, address: &str) -> Result<bool> {
+        Ok(self.signers.disable(address.parse()?))
     }
 
-    fn parse_hash(&self, hash: &str) -> Result<Vec<u8>> {
-        let hash = hex::decode(hash)?;
-        Ok(hash)
+    fn hash_encoding(&self) -> HashEncoding {
+        HASH_ENCODING
+    }
+}
+
+fn read_calldata(calldata: &[u8]) -> Result<TxData<Fr, Proof>> {
+    let r = &mut &calldata[..];
+    let tx = zeropool_tx::evm::read(r)?;
+    Ok(tx)
+}
+
+/// Fails instead of truncating a value returned by the contract.
+fn to_u64(value: U256, name: &str) -> Result<u64> {
+    u64::try_from(value).map_err(|_| anyhow::anyhow!("{name} {value} doesn't fit in u64"))
+}
+
+/// Transport and nonce errors are retryable, other RPC errors (e.g. a revert during gas
+/// estimation) are not.
+fn send_error(err: web3::Error) -> SendError {
+    match &err {
+        web3::Error::Transport(_) => SendError::Retryable(err.into()),
+        web3::Error::Rpc(rpc) if is_nonce_error(&rpc.message) => SendError::Retryable(err.into()),
+        _ => SendError::Fatal(err.into()),
+    }
+}
+
+/// Matches e.g. "nonce too low" and "replacement transaction underpriced" from geth.
+fn is_nonce_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("nonce") || message.contains("replacement transaction")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+    use web3::signing::{Key, SecretKeyRef};
+
+    use super::*;
+    use crate::tx_worker::mock_proof;
+
+    #[test]
+    fn test_tx_parameters() {
+        let to = Address::repeat_byte(1);
+
+        let legacy = tx_parameters(to, vec![1, 2, 3], 7, None);
+        assert_eq!(legacy.to, Some(to));
+        assert_eq!(legacy.nonce, Some(U256::from(7)));
+        assert_eq!(legacy.data.0, vec![1, 2, 3]);
+        assert_eq!(legacy.transaction_type, None);
+        assert_eq!(legacy.max_fee_per_gas, None);
+        assert_eq!(legacy.max_priority_fee_per_gas, None);
+
+        let fees = Eip1559Fees::new(U256::from(100), U256::from(2));
+        let eip1559 = tx_parameters(to, vec![1, 2, 3], 8, Some(fees));
+        assert_eq!(eip1559.to, Some(to));
+        assert_eq!(eip1559.nonce, Some(U256::from(8)));
+        assert_eq!(eip1559.data.0, vec![1, 2, 3]);
+        assert_eq!(eip1559.transaction_type, Some(U64::from(2)));
+        assert_eq!(eip1559.max_fee_per_gas, Some(U256::from(202)));
+        assert_eq!(eip1559.max_priority_fee_per_gas, Some(U256::from(2)));
+        assert_eq!(eip1559.gas_price, None);
+    }
+
+    #[test]
+    fn test_is_nonce_error() {
+        assert!(is_nonce_error("nonce too low"));
+        assert!(is_nonce_error("Nonce too high"));
+        assert!(is_nonce_error("replacement transaction underpriced"));
+        assert!(!is_nonce_error("execution reverted"));
+    }
+
+    #[tokio::test]
+    async fn test_validate_deposit_signature() {
+        let sk = SecretKey::from_slice(&[1; 32]).unwrap();
+        let backend = EvmBackend::new(
+            Config {
+                rpc_url: "http://127.0.0.1:1".to_owned(),
+                pool_address: format!("{:?}", Address::repeat_byte(1)),
+                token_address: format!("{:?}", Address::repeat_byte(2)),
+                sk: Some(Secret(hex::encode([1; 32]))),
+                sks: vec![],
+                min_signer_balance: 0,
+                tx_type: EvmTxType::Legacy,
+                max_priority_fee_per_gas: default_max_priority_fee_per_gas(),
+                rpc_timeout_secs: 1,
+                submission_mode: SubmissionModeKind::Public,
+                relay_url: None,
+                relay_auth_key: None,
+                relay_method: default_relay_method(),
+                relay_fallback_to_public: false,
+                relay_max_failures: default_relay_max_failures(),
+            },
+            RetryPolicy {
+                max_attempts: 1,
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            },
+            false,
+        )
+        .unwrap();
+
+        let nullifier = Num::from(7u64);
+        let signature = signature::sign(&sk, &nullifier.0.to_uint().to_big_endian());
+        let depositor = SecretKeyRef::new(&sk).address();
+        let deposit = |extra_data: Vec<u8>| ParsedTxData {
+            tx_type: TxType::Deposit,
+            proof: mock_proof(),
+            inputs: vec![],
+            delta: Num::ZERO,
+            out_commit: Num::ZERO,
+            nullifier,
+            memo: vec![],
+            extra_data,
+        };
+
+        let valid = [signature.clone(), depositor.as_bytes().to_vec()].concat();
+        assert!(backend
+            .validate_tx(&deposit(valid.clone()))
+            .await
+            .is_empty());
+
+        let mut tampered = valid;
+        tampered[10] ^= 1;
+        assert!(matches!(
+            backend.validate_tx(&deposit(tampered)).await[..],
+            [TxValidationError::InvalidSignature]
+        ));
+    }
+
+    #[test]
+    fn test_to_u64() {
+        assert_eq!(
+            to_u64(U256::from(u64::MAX), "Pool index").unwrap(),
+            u64::MAX
+        );
+        assert!(to_u64(U256::from(u64::MAX) + 1, "Pool index").is_err());
+    }
+
+    #[test]
+    fn test_parse_tx_type() {
+        let parse = |s: &str| serde_json::from_str::<EvmTxType>(&format!("\"{s}\""));
+
+        assert_eq!(parse("legacy").unwrap(), EvmTxType::Legacy);
+        assert_eq!(parse("eip1559").unwrap(), EvmTxType::Eip1559);
+        assert!(parse("eip2930").is_err());
+        assert_eq!(EvmTxType::default(), EvmTxType::Legacy);
     }
 
-    fn format_hash(&self, hash: &[u8]) -> String {
-        hex::encode(hash)
+    #[test]
+    fn test_read_calldata() {
+        util::check_calldata_codec(
+            |tx| {
+                let mut calldata = Vec::new();
+                zeropool_tx::evm::write(tx, &mut calldata).unwrap();
+                calldata
+            },
+            read_calldata,
+        );
     }
 }
@@ -0,0 +1,93 @@
+use std::future::Future;
+
+use tokio::sync::Mutex;
+
+/// Assigns account nonces locally, so that transactions can be sent without waiting for the
+/// previous ones to reach the node's pending pool.
+#[derive(Default)]
+pub struct NonceManager {
+    /// `None` until fetched from the node, or after a reset.
+    next: Mutex<Option<u64>>,
+}
+
+impl NonceManager {
+    /// Returns the next nonce. `fetch` is called to get the pending transaction count of the
+    /// account if the nonce is unknown.
+    pub async fn next<F, Fut, E>(&self, fetch: F) -> Result<u64, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<u64, E>>,
+    {
+        let mut next = self.next.lock().await;
+        let nonce = match *next {
+            Some(nonce) => nonce,
+            None => fetch().await?,
+        };
+        *next = Some(nonce + 1);
+
+        Ok(nonce)
+    }
+
+    /// Forces a resync with the node on the next call to [`NonceManager::next`].
+    pub async fn reset(&self) {
+        *self.next.lock().await = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sequential_nonces() {
+        let nonces = NonceManager::default();
+        let fetches = AtomicU64::new(0);
+        let fetch = |count| {
+            let fetches = &fetches;
+            move || async move {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, ()>(count)
+            }
+        };
+
+        for expected in 5..8 {
+            assert_eq!(nonces.next(fetch(5)).await, Ok(expected));
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        nonces.reset().await;
+        assert_eq!(nonces.next(fetch(10)).await, Ok(10));
+        assert_eq!(nonces.next(fetch(10)).await, Ok(11));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // A failed fetch doesn't consume a nonce
+        nonces.reset().await;
+        assert_eq!(nonces.next(|| async { Err(()) }).await, Err(()));
+        assert_eq!(nonces.next(fetch(12)).await, Ok(12));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_nonces() {
+        let nonces = Arc::new(NonceManager::default());
+
+        let handles = (0..16)
+            .map(|_| {
+                let nonces = nonces.clone();
+                tokio::spawn(async move { nonces.next(|| async { Ok::<_, ()>(0) }).await })
+            })
+            .collect::<Vec<_>>();
+
+        let mut assigned = Vec::new();
+        for handle in handles {
+            assigned.push(handle.await.unwrap().unwrap());
+        }
+        assigned.sort_unstable();
+
+        assert_eq!(assigned, (0..16).collect::<Vec<_>>());
+    }
+}
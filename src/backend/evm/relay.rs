@@ -0,0 +1,270 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{backend::SendError, config::Secret};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubmissionModeKind {
+    #[default]
+    Public,
+    Private,
+}
+
+/// Where signed transactions are sent.
+pub enum Submission {
+    /// `eth_sendRawTransaction` on the RPC node, i.e. the public mempool.
+    Public,
+    Private(PrivateRelay),
+}
+
+impl Submission {
+    /// Sends the signed transaction `raw`. `public` sends it to the public mempool, it's used
+    /// directly in the public mode and as the fallback in the private one.
+    pub async fn send<F, Fut>(&self, raw: &[u8], public: F) -> Result<(), SendError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(), SendError>>,
+    {
+        match self {
+            Submission::Public => public().await,
+            Submission::Private(relay) => relay.send(raw, public).await,
+        }
+    }
+}
+
+/// A private transaction relay, e.g. a Flashbots-style `eth_sendPrivateTransaction` endpoint or
+/// a bundler with the same interface. Transactions sent through it don't show up in the public
+/// mempool, so they can't be front-run.
+pub struct PrivateRelay {
+    http: reqwest::Client,
+    url: String,
+    /// Sent as `Authorization: Bearer <auth_key>`.
+    auth_key: Option<Secret>,
+    method: String,
+    /// Number of consecutive relay failures after which transactions are sent to the public
+    /// mempool. Never if not set.
+    fallback_after: Option<u32>,
+    failures: AtomicU32,
+}
+
+#[derive(Serialize)]
+struct PrivateTransaction {
+    tx: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    #[serde(default)]
+    result: Option<serde_json::Value>,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl PrivateRelay {
+    pub fn new(
+        http: reqwest::Client,
+        url: String,
+        auth_key: Option<Secret>,
+        method: String,
+        fallback_after: Option<u32>,
+    ) -> Self {
+        Self {
+            http,
+            url,
+            auth_key,
+            method,
+            fallback_after,
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    async fn send<F, Fut>(&self, raw: &[u8], public: F) -> Result<(), SendError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(), SendError>>,
+    {
+        let err = match self.send_private(raw).await {
+            Ok(()) => {
+                self.failures.store(0, Ordering::SeqCst);
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.fallback_after {
+            Some(max_failures) if failures >= max_failures => {
+                tracing::warn!(
+                    "Private relay failed {failures} times in a row, sending to the public \
+                     mempool: {err:#}"
+                );
+                public().await
+            }
+            // The relay might be temporarily unavailable.
+            _ => Err(SendError::Retryable(err)),
+        }
+    }
+
+    async fn send_private(&self, raw: &[u8]) -> anyhow::Result<()> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": self.method,
+            "params": [PrivateTransaction {
+                tx: format!("0x{}", hex::encode(raw)),
+            }],
+        });
+
+        let mut request = self.http.post(&self.url).json(&body);
+        if let Some(auth_key) = &self.auth_key {
+            request = request.bearer_auth(&auth_key.0);
+        }
+
+        let response = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<RpcResponse>()
+            .await?;
+
+        match (response.result, response.error) {
+            (_, Some(RpcError { code, message })) => Err(anyhow!("Relay error {code}: {message}")),
+            (Some(_), None) => Ok(()),
+            (None, None) => Err(anyhow!("Relay returned neither a result nor an error")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicBool, Arc, Mutex};
+
+    use axum::{extract::State, http::HeaderMap, routing::post, Json, Router};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_utils;
+
+    #[derive(Default)]
+    struct Relay {
+        requests: Mutex<Vec<(Option<String>, Value)>>,
+        failing: AtomicBool,
+    }
+
+    async fn relay_handler(
+        State(relay): State<Arc<Relay>>,
+        headers: HeaderMap,
+        Json(body): Json<Value>,
+    ) -> Json<Value> {
+        let auth = headers
+            .get("authorization")
+            .map(|value| value.to_str().unwrap().to_owned());
+        relay.requests.lock().unwrap().push((auth, body));
+
+        Json(if relay.failing.load(Ordering::SeqCst) {
+            json!({ "jsonrpc": "2.0", "id": 1, "error": { "code": -32000, "message": "busy" } })
+        } else {
+            json!({ "jsonrpc": "2.0", "id": 1, "result": "0x01" })
+        })
+    }
+
+    fn serve(relay: Arc<Relay>) -> String {
+        let router = Router::new()
+            .route("/", post(relay_handler))
+            .with_state(relay);
+        let addr = test_utils::serve(router);
+
+        format!("http://{addr}/")
+    }
+
+    fn submission(url: String, fallback_after: Option<u32>) -> Submission {
+        Submission::Private(PrivateRelay::new(
+            reqwest::Client::new(),
+            url,
+            Some(Secret("key".to_owned())),
+            "eth_sendPrivateTransaction".to_owned(),
+            fallback_after,
+        ))
+    }
+
+    /// Sends `raw`, returning whether it went to the public mempool.
+    async fn send(submission: &Submission, raw: &[u8]) -> Result<bool, SendError> {
+        let public = AtomicBool::new(false);
+        submission
+            .send(raw, || async {
+                public.store(true, Ordering::SeqCst);
+                Ok(())
+            })
+            .await?;
+
+        Ok(public.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_private_relay_request() {
+        let relay = Arc::new(Relay::default());
+        let submission = submission(serve(relay.clone()), None);
+
+        assert!(!send(&submission, &[0xab, 0xcd]).await.unwrap());
+        // Public submission doesn't touch the relay
+        assert!(send(&Submission::Public, &[1]).await.unwrap());
+
+        let requests = relay.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let (auth, body) = &requests[0];
+        assert_eq!(auth.as_deref(), Some("Bearer key"));
+        assert_eq!(
+            body,
+            &json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "eth_sendPrivateTransaction",
+                "params": [{ "tx": "0xabcd" }],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_private_relay_fallback() {
+        let relay = Arc::new(Relay::default());
+        relay.failing.store(true, Ordering::SeqCst);
+        let url = serve(relay.clone());
+
+        // Without the fallback, relay errors are retryable
+        let submission = self::submission(url.clone(), None);
+        for _ in 0..3 {
+            let err = send(&submission, &[1]).await.unwrap_err();
+            assert!(matches!(err, SendError::Retryable(_)));
+        }
+
+        let submission = self::submission(url, Some(2));
+        assert!(send(&submission, &[1]).await.is_err());
+        assert!(send(&submission, &[1]).await.unwrap());
+        assert!(send(&submission, &[1]).await.unwrap());
+
+        // A successful relay submission resets the failure count
+        relay.failing.store(false, Ordering::SeqCst);
+        assert!(!send(&submission, &[1]).await.unwrap());
+        relay.failing.store(true, Ordering::SeqCst);
+        assert!(send(&submission, &[1]).await.is_err());
+
+        // Unreachable relay
+        let submission = self::submission("http://127.0.0.1:1/".to_owned(), Some(1));
+        assert!(send(&submission, &[1]).await.unwrap());
+
+        assert_eq!(relay.requests.lock().unwrap().len(), 8);
+    }
+}
@@ -0,0 +1,117 @@
+//! Deposit signatures. The depositor signs the nullifier of the deposit with `eth_sign`, so that
+//! the pool contract can pull the deposited tokens from the recovered address. The `extra_data`
+//! of a deposit is the signature followed by the address of the depositor, which the contract
+//! ignores.
+
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    Message, Secp256k1,
+};
+use web3::{signing::keccak256, types::Address};
+
+/// Compact signature (EIP-2098): `r` followed by `vs`, the top bit of which is the recovery id.
+pub const SIGNATURE_LENGTH: usize = 64;
+pub const EXTRA_DATA_LENGTH: usize = SIGNATURE_LENGTH + super::ADDRESS_LENGTH;
+
+/// Hash signed by `eth_sign` for a 32 byte message.
+fn signed_message_hash(message: &[u8]) -> [u8; 32] {
+    keccak256(&[b"\x19Ethereum Signed Message:\n32", message].concat())
+}
+
+/// Recovers the address that signed the nullifier (big-endian). Returns `None` if the signature
+/// is malformed.
+pub fn recover_depositor(nullifier: &[u8], signature: &[u8]) -> Option<Address> {
+    if signature.len() != SIGNATURE_LENGTH {
+        return None;
+    }
+
+    let mut compact = [0; SIGNATURE_LENGTH];
+    compact.copy_from_slice(signature);
+    let recovery_id = RecoveryId::from_i32((compact[32] >> 7) as i32).ok()?;
+    compact[32] &= 0x7f;
+
+    let signature = RecoverableSignature::from_compact(&compact, recovery_id).ok()?;
+    let message = Message::from_slice(&signed_message_hash(nullifier)).ok()?;
+    let public_key = Secp256k1::verification_only()
+        .recover_ecdsa(&message, &signature)
+        .ok()?;
+
+    let hash = keccak256(&public_key.serialize_uncompressed()[1..]);
+    Some(Address::from_slice(&hash[12..]))
+}
+
+/// Returns the depositor named in the `extra_data` of a deposit if it has signed the nullifier.
+/// A malformed or tampered signature recovers some other address, so it's rejected as well.
+pub fn verify_depositor(nullifier: &[u8], extra_data: &[u8]) -> Option<Address> {
+    if extra_data.len() != EXTRA_DATA_LENGTH {
+        return None;
+    }
+
+    let (signature, depositor) = extra_data.split_at(SIGNATURE_LENGTH);
+    let depositor = Address::from_slice(depositor);
+    (recover_depositor(nullifier, signature)? == depositor).then_some(depositor)
+}
+
+/// Signs the nullifier the way a wallet does with `eth_sign`.
+#[cfg(test)]
+pub fn sign(sk: &secp256k1::SecretKey, nullifier: &[u8]) -> Vec<u8> {
+    let message = Message::from_slice(&signed_message_hash(nullifier)).unwrap();
+    let (recovery_id, mut compact) = Secp256k1::signing_only()
+        .sign_ecdsa_recoverable(&message, sk)
+        .serialize_compact();
+    compact[32] |= (recovery_id.to_i32() as u8) << 7;
+
+    compact.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::SecretKey;
+    use web3::signing::{Key, SecretKeyRef};
+
+    use super::*;
+
+    #[test]
+    fn test_recover_depositor() {
+        let sk = SecretKey::from_slice(&[1; 32]).unwrap();
+        let address = SecretKeyRef::new(&sk).address();
+        let nullifier = [7; 32];
+        let signature = sign(&sk, &nullifier);
+
+        assert_eq!(recover_depositor(&nullifier, &signature), Some(address));
+
+        // Signed for another deposit
+        assert_ne!(recover_depositor(&[8; 32], &signature), Some(address));
+
+        let mut tampered = signature.clone();
+        tampered[0] ^= 1;
+        assert_ne!(recover_depositor(&nullifier, &tampered), Some(address));
+
+        assert_eq!(recover_depositor(&nullifier, &signature[1..]), None);
+        assert_eq!(recover_depositor(&nullifier, &[]), None);
+    }
+    #[test]
+    fn test_verify_depositor() {
+        let sk = SecretKey::from_slice(&[1; 32]).unwrap();
+        let address = SecretKeyRef::new(&sk).address();
+        let nullifier = [7; 32];
+        let extra_data = [sign(&sk, &nullifier), address.as_bytes().to_vec()].concat();
+
+        assert_eq!(verify_depositor(&nullifier, &extra_data), Some(address));
+
+        // Someone else's address
+        let mut other = extra_data.clone();
+        other[SIGNATURE_LENGTH] ^= 1;
+        assert_eq!(verify_depositor(&nullifier, &other), None);
+
+        let mut tampered = extra_data.clone();
+        tampered[0] ^= 1;
+        assert_eq!(verify_depositor(&nullifier, &tampered), None);
+
+        // Without the address
+        assert_eq!(
+            verify_depositor(&nullifier, &extra_data[..SIGNATURE_LENGTH]),
+            None
+        );
+    }
+}
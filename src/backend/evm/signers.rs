@@ -0,0 +1,202 @@
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use secp256k1::SecretKey;
+use web3::{
+    signing::{Key, SecretKeyRef},
+    types::{Address, U256},
+};
+
+use super::nonce::NonceManager;
+
+pub struct Signer {
+    pub sk: SecretKey,
+    pub address: Address,
+    pub nonces: NonceManager,
+    disabled: AtomicBool,
+}
+
+/// Signing keys of the relayer, used in round-robin order.
+pub struct Signers {
+    signers: Vec<Signer>,
+    /// Index of the signer to try first on the next send.
+    next: AtomicUsize,
+    /// Signers with a lower native balance are skipped.
+    min_balance: U256,
+}
+
+impl Signers {
+    pub fn new(sks: Vec<SecretKey>, min_balance: U256) -> Self {
+        let signers = sks
+            .into_iter()
+            .map(|sk| Signer {
+                address: SecretKeyRef::new(&sk).address(),
+                sk,
+                nonces: NonceManager::default(),
+                disabled: AtomicBool::new(false),
+            })
+            .collect();
+
+        Self {
+            signers,
+            next: AtomicUsize::new(0),
+            min_balance,
+        }
+    }
+
+    /// Returns the next enabled signer with a balance of at least `min_balance`, or `None` if
+    /// there is no such signer. `balance` is called to get the balance of each candidate. A
+    /// candidate whose balance can't be fetched is skipped; if no signer is found, the last such
+    /// error is returned.
+    pub async fn select<F, Fut, E>(&self, balance: F) -> Result<Option<&Signer>, E>
+    where
+        F: Fn(Address) -> Fut,
+        Fut: Future<Output = Result<U256, E>>,
+        E: Display,
+    {
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        let mut last_err = None;
+
+        for offset in 0..self.signers.len() {
+            let signer = &self.signers[(start + offset) % self.signers.len()];
+            if signer.disabled.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            match balance(signer.address).await {
+                Ok(balance) if balance < self.min_balance => {
+                    tracing::warn!("Signer {:?} is below the minimum balance", signer.address);
+                }
+                Ok(_) => return Ok(Some(signer)),
+                Err(err) => {
+                    tracing::warn!("Failed to get the balance of {:?}: {err}", signer.address);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns `false` if `address` is not one of the signers.
+    pub fn disable(&self, address: Address) -> bool {
+        match self.signers.iter().find(|s| s.address == address) {
+            Some(signer) => {
+                signer.disabled.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use super::*;
+
+    fn signers(count: u8, min_balance: u64) -> Signers {
+        let sks = (1..=count)
+            .map(|i| SecretKey::from_slice(&[i; 32]).unwrap())
+            .collect();
+
+        Signers::new(sks, min_balance.into())
+    }
+
+    /// Stands in for `eth_getBalance`, recording the queried addresses.
+    struct Balances {
+        balances: HashMap<Address, U256>,
+        queried: Mutex<Vec<Address>>,
+    }
+
+    impl Balances {
+        fn new(balances: impl IntoIterator<Item = (Address, u64)>) -> Self {
+            Self {
+                balances: balances
+                    .into_iter()
+                    .map(|(address, balance)| (address, balance.into()))
+                    .collect(),
+                queried: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn get(&self, address: Address) -> impl Future<Output = Result<U256, String>> {
+            self.queried.lock().unwrap().push(address);
+            let balance = self.balances.get(&address).copied();
+            async move { balance.ok_or_else(|| format!("Unknown address {address:?}")) }
+        }
+    }
+
+    async fn select(signers: &Signers, balances: &Balances) -> Option<Address> {
+        signers
+            .select(|address| balances.get(address))
+            .await
+            .unwrap()
+            .map(|signer| signer.address)
+    }
+
+    #[tokio::test]
+    async fn test_rotation_order() {
+        let signers = signers(3, 0);
+        let addresses: Vec<_> = signers.signers.iter().map(|s| s.address).collect();
+        let balances = Balances::new(addresses.iter().map(|&address| (address, 0)));
+
+        for i in 0..6 {
+            assert_eq!(select(&signers, &balances).await, Some(addresses[i % 3]));
+        }
+
+        // Disabled signers are skipped
+        assert!(signers.disable(addresses[1]));
+        assert!(!signers.disable(Address::repeat_byte(0xff)));
+        for expected in [0, 2, 2, 0] {
+            assert_eq!(select(&signers, &balances).await, Some(addresses[expected]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skip_low_balance() {
+        let signers = signers(3, 100);
+        let addresses: Vec<_> = signers.signers.iter().map(|s| s.address).collect();
+        let balances =
+            Balances::new([(addresses[0], 100), (addresses[1], 99), (addresses[2], 500)]);
+
+        for expected in [0, 2, 2, 0] {
+            assert_eq!(select(&signers, &balances).await, Some(addresses[expected]));
+        }
+        assert_eq!(balances.queried.lock().unwrap()[..3], addresses[..]);
+
+        // No signer left with enough balance
+        signers.disable(addresses[0]);
+        signers.disable(addresses[2]);
+        assert_eq!(select(&signers, &balances).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_skip_balance_errors() {
+        let signers = signers(3, 100);
+        let addresses: Vec<_> = signers.signers.iter().map(|s| s.address).collect();
+        // No balance for the first signer
+        let balances = Balances::new([(addresses[1], 99), (addresses[2], 500)]);
+
+        assert_eq!(select(&signers, &balances).await, Some(addresses[2]));
+        assert_eq!(balances.queried.lock().unwrap()[..], addresses[..]);
+
+        // Without a signer, the error is passed through
+        signers.disable(addresses[2]);
+        assert!(signers
+            .select(|address| balances.get(address))
+            .await
+            .is_err());
+
+        // Unless there is no error at all
+        signers.disable(addresses[0]);
+        assert_eq!(select(&signers, &balances).await, None);
+    }
+}
@@ -1,66 +1,341 @@
-use anyhow::Result;
+use std::{collections::BTreeMap, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
 use axum::async_trait;
 use libzeropool_rs::libzeropool::fawkes_crypto::engines::U256;
+use serde::Deserialize;
 use tokio::sync::Mutex;
-use zeropool_tx::{TxData, TxType};
+use zeropool_tx::TxData;
 
 use crate::{
-    backend::{BlockchainBackend, TxCalldata, TxHash},
+    backend::{
+        BlockchainBackend, HashEncoding, SendError, SentTx, TxCalldata, TxConfirmation, TxHash,
+    },
     tx::{ParsedTxData, TxValidationError},
+    tx_worker::TX_SIZE,
     Fr, Proof,
 };
 
+/// Hashes of mock transactions are their big-endian commit indices.
+pub(crate) const HASH_ENCODING: HashEncoding = HashEncoding::Hex { size: 8 };
+/// Root of an empty pool tree.
+const EMPTY_ROOT: &str =
+    "11469701942666298368112882412133877458305516134926649826543144744382391691533";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Artificial latency of `send_tx`.
+    #[serde(default)]
+    pub send_latency_ms: u64,
+    /// Time after `send_tx` returns until the transaction is considered mined and the pool index
+    /// advances.
+    #[serde(default = "default_mining_delay_ms")]
+    pub mining_delay_ms: u64,
+    /// Fail every n-th `send_tx` call.
+    #[serde(default)]
+    pub fail_every_n: Option<u64>,
+    /// Fail `send_tx` for transactions with these commit indices (pool index / `TX_SIZE`).
+    #[serde(default)]
+    pub fail_indices: Vec<u64>,
+    /// Fail the first n `send_tx` calls for every commit index with a retryable error.
+    #[serde(default)]
+    pub transient_failures: u64,
+}
+
+impl Config {
+    /// `attempt` is the 1-based number of the `send_tx` call.
+    fn should_fail(&self, commit_index: u64, attempt: u64) -> bool {
+        let fail_nth = matches!(self.fail_every_n, Some(n) if n > 0 && attempt % n == 0);
+        fail_nth || self.fail_indices.contains(&commit_index)
+    }
+}
+
+fn default_mining_delay_ms() -> u64 {
+    100
+}
+
+/// Mines instantly and fails as configured.
+#[cfg(test)]
+pub(crate) fn mock_config(fail_every_n: Option<u64>, fail_indices: Vec<u64>) -> Config {
+    Config {
+        send_latency_ms: 0,
+        mining_delay_ms: 0,
+        fail_every_n,
+        fail_indices,
+        transient_failures: 0,
+    }
+}
+
+/// Transaction with zero fields, as far as the mock is concerned the same as any other.
+#[cfg(test)]
+pub(crate) fn mock_tx() -> TxData<Fr, Proof> {
+    use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+
+    TxData {
+        tx_type: zeropool_tx::TxType::Deposit,
+        delta: Num::ZERO,
+        token_id: String::new(),
+        out_commit: Num::ZERO,
+        nullifier: Num::ZERO,
+        proof: crate::tx_worker::mock_proof(),
+        root_after: Num::ZERO,
+        tree_proof: crate::tx_worker::mock_proof(),
+        memo: vec![],
+        extra_data: vec![],
+    }
+}
+
+struct PoolState {
+    /// Index of the next transaction to be sent.
+    next_index: u64,
+    /// Index of the last mined transaction.
+    pool_index: u64,
+    /// Roots of the mined transactions by pool index.
+    roots: BTreeMap<u64, U256>,
+    /// Number of `send_tx` calls.
+    send_attempts: u64,
+    /// Mined transactions.
+    txs: Vec<TxCalldata>,
+    /// Number of transient failures by commit index.
+    transient_failures: BTreeMap<u64, u64>,
+}
+
 pub struct MockBackend {
-    pool_index: Mutex<u64>,
+    config: Config,
+    state: Arc<Mutex<PoolState>>,
 }
 
 impl MockBackend {
-    pub fn new() -> Self {
+    pub fn new(config: Config) -> Self {
+        let mut roots = BTreeMap::new();
+        roots.insert(0, U256::from_str(EMPTY_ROOT).unwrap());
+
         Self {
-            pool_index: Mutex::new(0),
+            config,
+            state: Arc::new(Mutex::new(PoolState {
+                next_index: 0,
+                pool_index: 0,
+                roots,
+                send_attempts: 0,
+                txs: Vec::new(),
+                transient_failures: BTreeMap::new(),
+            })),
         }
     }
 }
 
+#[cfg(test)]
+impl MockBackend {
+    /// Immediately mines a transaction with the given resulting root.
+    pub async fn mine(&self, root: U256) {
+        let mut state = self.state.lock().await;
+        state.pool_index += TX_SIZE;
+        state.next_index = state.pool_index;
+        let pool_index = state.pool_index;
+        state.roots.insert(pool_index, root);
+    }
+
+    /// Overrides the root at `pool_index`, e.g. to simulate a pool with another tree height.
+    pub async fn set_root(&self, pool_index: u64, root: U256) {
+        self.state.lock().await.roots.insert(pool_index, root);
+    }
+
+    /// Simulates a reorg that reverts all transactions after `pool_index`.
+    pub async fn reorg(&self, pool_index: u64) {
+        let mut state = self.state.lock().await;
+        state.pool_index = pool_index;
+        state.next_index = pool_index;
+        state.roots.retain(|&index, _| index <= pool_index);
+        state.txs.truncate((pool_index / TX_SIZE) as usize);
+    }
+}
+
 #[async_trait]
 impl BlockchainBackend for MockBackend {
     fn name(&self) -> &'static str {
         "mock"
     }
 
-    async fn fetch_latest_transactions(&self) -> Result<Vec<TxCalldata>> {
-        Ok(vec![])
+    async fn chain_id(&self) -> Result<String> {
+        Ok("mock".to_owned())
+    }
+
+    async fn fetch_latest_transactions(&self, from: u64, limit: u64) -> Result<Vec<TxCalldata>> {
+        let txs = &self.state.lock().await.txs;
+        Ok(txs
+            .iter()
+            .skip(from as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
     }
 
     async fn validate_tx(&self, _tx: &ParsedTxData) -> Vec<TxValidationError> {
         vec![]
     }
 
-    /// Sign and send a transaction to the blockchain.
-    async fn send_tx(&self, _tx: TxData<Fr, Proof>) -> Result<TxHash> {
-        let mut pool_index = self.pool_index.lock().await;
-        *pool_index += 128;
-        Ok(pool_index.to_be_bytes().to_vec())
+    /// Simulates sending a transaction: the pool index advances by `TX_SIZE` once the
+    /// transaction is "mined", `mining_delay_ms` after this method returns.
+    async fn send_tx(&self, tx: TxData<Fr, Proof>) -> Result<SentTx, SendError> {
+        tokio::time::sleep(Duration::from_millis(self.config.send_latency_ms)).await;
+
+        let index = {
+            let mut state = self.state.lock().await;
+            state.send_attempts += 1;
+
+            let commit_index = state.next_index / TX_SIZE;
+            let transient_failures = state.transient_failures.entry(commit_index).or_default();
+            if *transient_failures < self.config.transient_failures {
+                *transient_failures += 1;
+                return Err(SendError::Retryable(anyhow!(
+                    "Simulated transient failure for commit index {commit_index}"
+                )));
+            }
+
+            if self.config.should_fail(commit_index, state.send_attempts) {
+                return Err(anyhow!("Simulated failure for commit index {commit_index}").into());
+            }
+
+            state.next_index += TX_SIZE;
+            state.next_index
+        };
+
+        let root = tx.root_after.to_uint().0;
+        let hash = index.to_be_bytes().to_vec();
+        let calldata = TxCalldata {
+            hash: hash.clone(),
+            calldata: bincode::serialize(&tx).map_err(anyhow::Error::from)?,
+        };
+        let state = self.state.clone();
+        let mining_delay = Duration::from_millis(self.config.mining_delay_ms);
+        tokio::spawn(async move {
+            tokio::time::sleep(mining_delay).await;
+
+            let mut state = state.lock().await;
+            state.pool_index = state.pool_index.max(index);
+            state.roots.insert(index, root);
+            state.txs.push(calldata);
+        });
+
+        Ok(hash.into())
+    }
+
+    /// Transactions are never reverted, they are confirmed once mined.
+    async fn get_tx_status(&self, hash: &TxHash) -> Result<TxConfirmation> {
+        let state = self.state.lock().await;
+        if state.txs.iter().any(|tx| &tx.hash == hash) {
+            Ok(TxConfirmation::Confirmed)
+        } else {
+            Ok(TxConfirmation::Pending)
+        }
     }
 
     async fn get_pool_index(&self) -> Result<u64> {
-        Ok(*self.pool_index.lock().await)
+        Ok(self.state.lock().await.pool_index)
     }
 
     async fn get_merkle_root(&self, index: u64) -> Result<Option<U256>> {
-        return Ok(Some(U256::from(index)));
+        Ok(self.state.lock().await.roots.get(&index).copied())
     }
 
     fn parse_calldata(&self, calldata: Vec<u8>) -> Result<TxData<Fr, Proof>> {
         Ok(bincode::deserialize(&calldata)?)
     }
 
-    fn parse_hash(&self, hash: &str) -> Result<Vec<u8>> {
-        let hash = hex::decode(hash)?;
-        Ok(hash)
+    fn hash_encoding(&self) -> HashEncoding {
+        HASH_ENCODING
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zeropool_tx::TxType;
+
+    use super::*;
+
+    #[test]
+    fn test_mock_should_fail() {
+        let config = mock_config(None, vec![]);
+        assert!((0..10).all(|i| !config.should_fail(i, i + 1)));
+
+        let config = mock_config(Some(3), vec![]);
+        let failed = (0..9)
+            .filter(|&i| config.should_fail(i, i + 1))
+            .collect::<Vec<_>>();
+        assert_eq!(failed, [2, 5, 8]);
+
+        let config = mock_config(None, vec![1, 4]);
+        let failed = (0..9)
+            .filter(|&i| config.should_fail(i, i + 1))
+            .collect::<Vec<_>>();
+        assert_eq!(failed, [1, 4]);
+
+        let config = mock_config(Some(0), vec![]);
+        assert!(!config.should_fail(0, 1));
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        let backend = MockBackend::new(mock_config(None, vec![]));
+
+        for len in 0..64 {
+            let memo = vec![0xab; len];
+            for tx_type in [TxType::Deposit, TxType::Transfer, TxType::Withdraw] {
+                let ciphertext = backend.extract_ciphertext_from_memo(&memo, tx_type);
+                assert!(ciphertext.len() < len.max(1));
+            }
+
+            let calldata = bincode::serialize(&mock_tx()).unwrap();
+            let truncated = calldata[..len.min(calldata.len() - 1)].to_vec();
+            assert!(backend.parse_calldata(truncated).is_err());
+            assert!(backend.parse_calldata(memo).is_err());
+        }
     }
 
-    fn format_hash(&self, hash: &[u8]) -> String {
-        hex::encode(hash)
+    #[tokio::test]
+    async fn test_mock_transient_failures() {
+        let backend = MockBackend::new(Config {
+            transient_failures: 2,
+            ..mock_config(None, vec![])
+        });
+
+        for _ in 0..2 {
+            let err = backend.send_tx(mock_tx()).await.unwrap_err();
+            assert!(matches!(err, SendError::Retryable(_)));
+        }
+        assert!(backend.send_tx(mock_tx()).await.is_ok());
+
+        // The counter is per commit index
+        assert!(matches!(
+            backend.send_tx(mock_tx()).await,
+            Err(SendError::Retryable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mock_tx_status() {
+        let backend = MockBackend::new(Config {
+            mining_delay_ms: 50,
+            ..mock_config(None, vec![])
+        });
+
+        let hash = backend.send_tx(mock_tx()).await.unwrap().hash;
+        assert_eq!(
+            backend.get_tx_status(&hash).await.unwrap(),
+            TxConfirmation::Pending
+        );
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            backend.get_tx_status(&hash).await.unwrap(),
+            TxConfirmation::Confirmed
+        );
+
+        // Reverted by a reorg
+        backend.reorg(0).await;
+        assert_eq!(
+            backend.get_tx_status(&hash).await.unwrap(),
+            TxConfirmation::Pending
+        );
     }
 }
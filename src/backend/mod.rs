@@ -15,6 +15,12 @@ pub mod mock;
 pub mod near;
 #[cfg(feature = "substrate_backend")]
 pub mod substrate;
+#[cfg(any(
+    feature = "evm_backend",
+    feature = "near_backend",
+    feature = "waves_backend"
+))]
+mod util;
 #[cfg(feature = "waves_backend")]
 pub mod waves;
 
@@ -22,37 +28,267 @@ pub mod waves;
 pub trait BlockchainBackend: Sync + Send {
     fn name(&self) -> &'static str;
 
-    /// Fetch latest uncached transactions from the blockchain.
-    async fn fetch_latest_transactions(&self) -> Result<Vec<TxCalldata>>;
+    /// Identifier of the network the backend is connected to, e.g. the EVM chain id.
+    async fn chain_id(&self) -> Result<String>;
+
+    /// Fetch up to `limit` mined transactions from the blockchain, starting with the `from`-th one
+    /// (pool index divided by `TX_SIZE`). Fewer than `limit` transactions means there are no more.
+    async fn fetch_latest_transactions(&self, from: u64, limit: u64) -> Result<Vec<TxCalldata>>;
 
     /// Validate transaction data.
     async fn validate_tx(&self, tx: &ParsedTxData) -> Vec<TxValidationError>;
 
     /// Create, sign, and send transaction to the blockchain.
-    async fn send_tx(&self, tx: TxData<Fr, Proof>) -> Result<TxHash>;
+    async fn send_tx(&self, tx: TxData<Fr, Proof>) -> Result<SentTx, SendError>;
+
+    /// Check whether a sent transaction is mined.
+    async fn get_tx_status(&self, hash: &TxHash) -> Result<TxConfirmation>;
 
     /// Fetch the current pool index from the blockchain.
     async fn get_pool_index(&self) -> Result<u64>;
 
     async fn get_merkle_root(&self, index: u64) -> Result<Option<U256>>;
 
+    /// Pool id the contract was deployed with, if the contract exposes it.
+    async fn get_pool_id(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
     fn parse_calldata(&self, calldata: Vec<u8>) -> Result<TxData<Fr, Proof>>;
+
+    /// The memo starts with the fee, withdrawals also have the native amount and the receiver
+    /// address before the ciphertext. The default is for 20-byte EVM addresses, backends with
+    /// other addresses override it. Returns an empty ciphertext if the memo is truncated.
     fn extract_ciphertext_from_memo<'a>(&self, memo: &'a [u8], tx_type: TxType) -> &'a [u8] {
         let offset = match tx_type {
             TxType::Deposit | TxType::Transfer => 8,
-            TxType::Withdraw => 36,
+            TxType::Withdraw => 8 + 8 + 20,
         };
 
-        &memo[offset..]
+        memo.get(offset..).unwrap_or_default()
+    }
+
+    /// Check that the receiver address in a withdrawal memo is well-formed.
+    fn validate_withdraw_address(&self, _memo: &[u8]) -> Result<(), TxValidationError> {
+        Ok(())
     }
 
-    fn parse_hash(&self, hash: &str) -> Result<Vec<u8>>;
-    fn format_hash(&self, hash: &[u8]) -> String;
+    /// Takes a signing key out of rotation until restart. Returns `false` if the backend has no
+    /// such key.
+    fn disable_signer(&self, _address: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// How transaction hashes are written in logs and the API. Every backend must pass
+    /// `test_hash_encoding_conformance` with it.
+    fn hash_encoding(&self) -> HashEncoding;
+
+    /// Inverse of [`BlockchainBackend::format_hash`]. Only accepts hashes of the exact size of
+    /// the backend's hashes, so a parsed hash is always safe to format and to look up.
+    fn parse_hash(&self, hash: &str) -> Result<TxHash, ParseHashError> {
+        self.hash_encoding().parse(hash)
+    }
+
+    /// Formats a hash the way the chain's explorers and clients do.
+    fn format_hash(&self, hash: &[u8]) -> String {
+        self.hash_encoding().format(hash)
+    }
 }
 
 pub type TxHash = Vec<u8>;
 
+/// Text encoding of transaction hashes of a fixed size in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashEncoding {
+    /// Lowercase hex without a prefix, an optional `0x` prefix is accepted when parsing.
+    Hex {
+        size: usize,
+    },
+    Base58 {
+        size: usize,
+    },
+}
+
+/// A malformed hash in user input, responded to with 400 Bad Request.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseHashError {
+    #[error("Invalid hash encoding: {0}")]
+    Encoding(String),
+    #[error("Invalid hash size: expected {expected} bytes, got {actual}")]
+    Size { expected: usize, actual: usize },
+}
+
+impl HashEncoding {
+    /// Encoding of the backend named as in `BACKEND`, for offline commands that don't connect to
+    /// the chain.
+    pub fn of_backend(name: &str) -> Option<Self> {
+        match name {
+            "mock" => Some(mock::HASH_ENCODING),
+            #[cfg(feature = "evm_backend")]
+            "evm" => Some(evm::HASH_ENCODING),
+            #[cfg(feature = "near_backend")]
+            "near" => Some(near::HASH_ENCODING),
+            #[cfg(feature = "substrate_backend")]
+            "substrate" => Some(substrate::HASH_ENCODING),
+            #[cfg(feature = "waves_backend")]
+            "waves" => Some(waves::HASH_ENCODING),
+            _ => None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match *self {
+            HashEncoding::Hex { size } | HashEncoding::Base58 { size } => size,
+        }
+    }
+
+    pub fn parse(&self, hash: &str) -> Result<TxHash, ParseHashError> {
+        let bytes = match self {
+            HashEncoding::Hex { .. } => {
+                let hash = hash.strip_prefix("0x").unwrap_or(hash);
+                hex::decode(hash).map_err(|err| ParseHashError::Encoding(err.to_string()))?
+            }
+            HashEncoding::Base58 { .. } => bs58::decode(hash)
+                .into_vec()
+                .map_err(|err| ParseHashError::Encoding(err.to_string()))?,
+        };
+
+        if bytes.len() != self.size() {
+            return Err(ParseHashError::Size {
+                expected: self.size(),
+                actual: bytes.len(),
+            });
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn format(&self, hash: &[u8]) -> String {
+        match self {
+            HashEncoding::Hex { .. } => hex::encode(hash),
+            HashEncoding::Base58 { .. } => bs58::encode(hash).into_string(),
+        }
+    }
+}
+
+pub struct SentTx {
+    pub hash: TxHash,
+    /// Account that signed the transaction, for backends that rotate between several keys.
+    pub sender: Option<String>,
+}
+
+impl From<TxHash> for SentTx {
+    fn from(hash: TxHash) -> Self {
+        Self { hash, sender: None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxConfirmation {
+    /// Not mined yet, or not known to the node.
+    Pending,
+    Confirmed,
+    /// Mined, but reverted.
+    Failed,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    /// The transaction might go through if sent again later, e.g. after an RPC timeout.
+    #[error("{0:#}")]
+    Retryable(anyhow::Error),
+    /// The transaction is rejected, e.g. reverted on chain.
+    #[error(transparent)]
+    Fatal(#[from] anyhow::Error),
+}
+
+#[derive(Clone)]
 pub struct TxCalldata {
     pub hash: TxHash,
     pub calldata: Vec<u8>,
 }
+
+#[cfg(any(feature = "evm_backend", feature = "near_backend"))]
+fn default_rpc_timeout_secs() -> u64 {
+    30
+}
+
+/// Client for the outbound requests of a backend. A request to a hung provider fails with a
+/// transport error after `timeout_secs` instead of blocking the worker.
+#[cfg(any(feature = "evm_backend", feature = "near_backend"))]
+fn http_client(timeout_secs: u64) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(timeout_secs))
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    /// Encodings of all backends enabled in this build.
+    fn hash_encodings() -> Vec<(&'static str, HashEncoding)> {
+        vec![
+            ("mock", mock::HASH_ENCODING),
+            #[cfg(feature = "evm_backend")]
+            ("evm", evm::HASH_ENCODING),
+            #[cfg(feature = "near_backend")]
+            ("near", near::HASH_ENCODING),
+            #[cfg(feature = "substrate_backend")]
+            ("substrate", substrate::HASH_ENCODING),
+            #[cfg(feature = "waves_backend")]
+            ("waves", waves::HASH_ENCODING),
+        ]
+    }
+
+    #[test]
+    fn test_hash_encoding_conformance() {
+        for (name, encoding) in hash_encodings() {
+            let size = encoding.size();
+            assert!(size > 0 && size <= 32, "{name}");
+
+            for i in 0u64..100 {
+                let hash = Sha256::digest(i.to_le_bytes())[..size].to_vec();
+                let formatted = encoding.format(&hash);
+                assert_eq!(encoding.parse(&formatted), Ok(hash.clone()), "{name}");
+
+                let too_long = [hash.as_slice(), &[i as u8]].concat();
+                assert_eq!(
+                    encoding.parse(&encoding.format(&too_long)),
+                    Err(ParseHashError::Size {
+                        expected: size,
+                        actual: size + 1
+                    }),
+                    "{name}"
+                );
+                assert!(
+                    encoding.parse(&encoding.format(&hash[1..])).is_err(),
+                    "{name}"
+                );
+            }
+
+            for malformed in ["", " ", "0x", "zz", "hash with spaces", "0OIl", "\u{1F600}"] {
+                assert!(encoding.parse(malformed).is_err(), "{name}: {malformed:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_hex_prefix() {
+        let encoding = HashEncoding::Hex { size: 32 };
+        let hash = "5c504ed432cb51138bcf09aa5e8a410dd4a1e204ef84bfed1be16dfba1b22060";
+
+        assert_eq!(encoding.parse(&format!("0x{hash}")), encoding.parse(hash));
+        assert_eq!(encoding.format(&encoding.parse(hash).unwrap()), hash);
+        assert!(encoding.parse(&format!("0x0x{hash}")).is_err());
+        assert_eq!(
+            encoding.parse(&hash[2..]),
+            Err(ParseHashError::Size {
+                expected: 32,
+                actual: 31
+            })
+        );
+    }
+}
@@ -6,9 +6,13 @@ use borsh::BorshDeserialize;
 use itertools::Itertools;
 use libzeropool_rs::libzeropool::fawkes_crypto::{engines::U256, ff_uint::Uint};
 use near_crypto::InMemorySigner;
-use near_jsonrpc_client::{methods, JsonRpcClient};
-use near_jsonrpc_primitives::types::query::QueryResponseKind;
+use near_jsonrpc_client::{
+    errors::{JsonRpcError, JsonRpcServerError},
+    methods, JsonRpcClient,
+};
+use near_jsonrpc_primitives::types::{query::QueryResponseKind, transactions::RpcTransactionError};
 use near_primitives::{
+    hash::CryptoHash,
     transaction::{Action, FunctionCallAction, Transaction},
     types::{AccountId, BlockReference, Finality, FunctionArgs},
     views::{ActionView, FinalExecutionOutcomeView, FinalExecutionStatus, QueryRequest},
@@ -19,11 +23,19 @@ use tokio::time::sleep;
 use zeropool_tx::{TxData, TxType};
 
 use crate::{
-    backend::{BlockchainBackend, TxCalldata, TxHash},
+    backend::{
+        http_client,
+        util::{self, retry},
+        BlockchainBackend, HashEncoding, SendError, SentTx, TxCalldata, TxConfirmation, TxHash,
+    },
+    job_queue::RetryPolicy,
     tx::{ParsedTxData, TxValidationError},
     Fr, Proof,
 };
 
+/// Transaction hashes are sha256 digests.
+pub(crate) const HASH_ENCODING: HashEncoding = HashEncoding::Base58 { size: 32 };
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub network: String,
@@ -33,26 +45,49 @@ pub struct Config {
     pub pool_address: AccountId,
     pub relayer_account_id: AccountId,
     pub token_id: AccountId,
+    /// Timeout of requests to the RPC nodes and nearblocks, 30 seconds by default.
+    #[serde(default = "crate::backend::default_rpc_timeout_secs")]
+    pub rpc_timeout_secs: u64,
 }
 
 pub struct NearBackend {
     config: Config,
     client: JsonRpcClient,
+    /// Client for the archive node and nearblocks.
+    http: reqwest::Client,
     signer: InMemorySigner,
+    retry: RetryPolicy,
+    dry_run: bool,
 }
 
 impl NearBackend {
-    pub fn new(config: Config) -> Result<Self> {
-        let client = JsonRpcClient::connect(&config.rpc_url);
+    pub fn new(config: Config, retry: RetryPolicy, dry_run: bool) -> Result<Self> {
+        let http = http_client(config.rpc_timeout_secs)?;
+        let client = JsonRpcClient::with(http.clone()).connect(&config.rpc_url);
         let signer =
             InMemorySigner::from_secret_key(config.relayer_account_id.clone(), config.sk.parse()?);
 
         Ok(Self {
             config,
             client,
+            http,
             signer,
+            retry,
+            dry_run,
         })
     }
+
+    /// Call of a view method of the pool contract at the final block.
+    fn view_call(&self, method_name: &str, args: Vec<u8>) -> methods::query::RpcQueryRequest {
+        methods::query::RpcQueryRequest {
+            block_reference: BlockReference::Finality(Finality::Final),
+            request: QueryRequest::CallFunction {
+                account_id: self.config.pool_address.clone(),
+                method_name: method_name.to_owned(),
+                args: FunctionArgs::from(args),
+            },
+        }
+    }
 }
 
 #[async_trait]
@@ -61,37 +96,56 @@ impl BlockchainBackend for NearBackend {
         "near"
     }
 
-    async fn fetch_latest_transactions(&self) -> Result<Vec<TxCalldata>> {
+    async fn chain_id(&self) -> Result<String> {
+        Ok(self.config.network.clone())
+    }
+
+    /// Assumes that every indexed transaction contains a single `transact` call, so that pages
+    /// before `from` and after `from + limit` can be skipped.
+    async fn fetch_latest_transactions(&self, from: u64, limit: u64) -> Result<Vec<TxCalldata>> {
         const PAGE_SIZE: u64 = 25;
 
-        let client = NearblocksClient::new(&self.config.network, &self.config.pool_address)?;
-        let tx_count = client.get_tx_count().await?;
+        let client = NearblocksClient::new(
+            self.http.clone(),
+            &self.config.network,
+            &self.config.pool_address,
+        )?;
+        let tx_count = retry(|| client.get_tx_count(), &self.retry).await?;
 
-        if tx_count == 0 {
+        if tx_count <= from || limit == 0 {
             return Ok(vec![]);
         }
 
+        let to = tx_count.min(from.saturating_add(limit));
         let mut txs = Vec::new();
-        for page in 1..=(tx_count / PAGE_SIZE + 1) {
+        for page in (from / PAGE_SIZE + 1)..=((to - 1) / PAGE_SIZE + 1) {
             tracing::info!("Fetching page {} of {}", page, tx_count / PAGE_SIZE + 1);
 
-            let pairs = client.get_zeropool_txns(page, PAGE_SIZE).await?;
+            let pairs = retry(|| client.get_zeropool_txns(page, PAGE_SIZE), &self.retry).await?;
+            // The first page might start before `from`.
+            let skip = from.saturating_sub((page - 1) * PAGE_SIZE) as usize;
 
             // Fetch transaction data from the archive node.
-            for IndexerTx { hash, sender } in pairs {
-                let client = reqwest::Client::new();
-                let res: serde_json::Value = client
-                    .post(&self.config.archive_rpc_url)
-                    .json(&serde_json::json!({
-                        "jsonrpc": "2.0",
-                        "id": "dontcare",
-                        "method": "tx",
-                        "params": [hash, sender]
-                    }))
-                    .send()
-                    .await?
-                    .json()
-                    .await?;
+            for IndexerTx { hash, sender } in pairs.into_iter().skip(skip) {
+                let request = &serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": "dontcare",
+                    "method": "tx",
+                    "params": [hash, sender]
+                });
+                let res: serde_json::Value = retry(
+                    move || async move {
+                        self.http
+                            .post(&self.config.archive_rpc_url)
+                            .json(request)
+                            .send()
+                            .await?
+                            .json()
+                            .await
+                    },
+                    &self.retry,
+                )
+                .await?;
 
                 let tx =
                     serde_json::from_value::<FinalExecutionOutcomeView>(res["result"].clone())?;
@@ -117,6 +171,7 @@ impl BlockchainBackend for NearBackend {
             }
         }
 
+        txs.truncate(limit as usize);
         Ok(txs)
     }
 
@@ -125,7 +180,14 @@ impl BlockchainBackend for NearBackend {
     }
 
     /// Sign and send a transaction to the blockchain.
-    async fn send_tx(&self, tx: TxData<Fr, Proof>) -> Result<TxHash> {
+    async fn send_tx(&self, tx: TxData<Fr, Proof>) -> Result<SentTx, SendError> {
+        let mut args: Vec<u8> = Vec::new();
+        zeropool_tx::near::write(&tx, &mut args).map_err(anyhow::Error::from)?;
+
+        if self.dry_run {
+            return Ok(util::dry_run(self.name(), &args));
+        }
+
         let access_key_query_response = self
             .client
             .call(methods::query::RpcQueryRequest {
@@ -135,16 +197,14 @@ impl BlockchainBackend for NearBackend {
                     public_key: self.signer.public_key.clone(),
                 },
             })
-            .await?;
+            .await
+            .map_err(send_error)?;
 
         let current_nonce = match access_key_query_response.kind {
             QueryResponseKind::AccessKey(access_key) => access_key.nonce,
-            _ => anyhow::bail!("Unexpected response from access key query"),
+            _ => return Err(anyhow::anyhow!("Unexpected response from access key query").into()),
         };
 
-        let mut args: Vec<u8> = Vec::new();
-        zeropool_tx::near::write(&tx, &mut args)?;
-
         let transaction = Transaction {
             signer_id: self.signer.account_id.clone(),
             public_key: self.signer.public_key.clone(),
@@ -164,80 +224,105 @@ impl BlockchainBackend for NearBackend {
         };
 
         // TODO: Check the status of the transaction
-        let tx_hash = self.client.call(request).await?;
+        let tx_hash = self.client.call(request).await.map_err(send_error)?;
 
         tracing::debug!("Near transaction sent: {}", tx_hash);
 
+        let hash = tx_hash.0.to_vec();
         loop {
             tracing::info!("Checking transaction status");
-            let status_req = methods::tx::RpcTransactionStatusRequest {
-                transaction_info: methods::tx::TransactionInfo::TransactionId {
-                    hash: tx_hash,
-                    account_id: self.signer.account_id.clone(),
-                },
-            };
 
-            let response = match self.client.call(status_req).await {
-                Ok(res) => res,
-                Err(err) => {
-                    // TODO: Limit number of attempts?
-                    tracing::warn!("Failed to fetch tx status: {:?}", err);
-                    continue;
-                }
-            };
-
-            match response.status {
-                FinalExecutionStatus::Failure(err) => {
+            match self.get_tx_status(&hash).await {
+                Ok(TxConfirmation::Failed) => {
                     tracing::error!("Transaction failed");
-                    anyhow::bail!("Transaction failed: {:?}", err);
+                    return Err(anyhow::anyhow!("Transaction {tx_hash} failed").into());
                 }
-                FinalExecutionStatus::SuccessValue(_) => {
+                Ok(TxConfirmation::Confirmed) => {
                     tracing::info!("Transaction succeeded");
                     break;
                 }
-                _ => {
+                Ok(TxConfirmation::Pending) => {
                     tracing::info!("Transaction pending");
                     sleep(Duration::from_secs(1)).await; // TODO: exponential backoff
                 }
-            };
+                Err(err) => {
+                    // TODO: Limit number of attempts?
+                    tracing::warn!("Failed to fetch tx status: {:?}", err);
+                    sleep(Duration::from_secs(1)).await;
+                }
+            }
         }
 
-        Ok(tx_hash.0.to_vec())
+        Ok(hash.into())
     }
 
-    async fn get_pool_index(&self) -> Result<u64> {
-        let request = methods::query::RpcQueryRequest {
-            block_reference: BlockReference::Finality(Finality::Final),
-            request: QueryRequest::CallFunction {
-                account_id: self.config.pool_address.clone(),
-                method_name: "pool_index".to_owned(),
-                args: FunctionArgs::from(Vec::new()),
+    async fn get_tx_status(&self, hash: &TxHash) -> Result<TxConfirmation> {
+        let request = methods::tx::RpcTransactionStatusRequest {
+            transaction_info: methods::tx::TransactionInfo::TransactionId {
+                hash: CryptoHash::try_from(hash.as_slice())
+                    .map_err(|err| anyhow::anyhow!("Invalid transaction hash: {err}"))?,
+                account_id: self.signer.account_id.clone(),
             },
         };
 
-        let response = self.client.call(request).await?;
+        let response = match self.client.call(request).await {
+            Ok(response) => response,
+            Err(JsonRpcError::ServerError(JsonRpcServerError::HandlerError(
+                RpcTransactionError::UnknownTransaction { .. },
+            ))) => return Ok(TxConfirmation::Pending),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(match response.status {
+            FinalExecutionStatus::Failure(err) => {
+                tracing::debug!("Transaction failed: {:?}", err);
+                TxConfirmation::Failed
+            }
+            FinalExecutionStatus::SuccessValue(_) => TxConfirmation::Confirmed,
+            FinalExecutionStatus::NotStarted | FinalExecutionStatus::Started => {
+                TxConfirmation::Pending
+            }
+        })
+    }
+
+    async fn get_pool_index(&self) -> Result<u64> {
+        let response = retry(
+            || self.client.call(self.view_call("pool_index", Vec::new())),
+            &self.retry,
+        )
+        .await?;
 
         if let QueryResponseKind::CallResult(result) = response.kind {
-            let num = U256::from_little_endian(&result.result);
-            Ok(num.as_u64())
+            le_u64(&result.result, "Pool index")
         } else {
             Err(anyhow::anyhow!("get_pool_index: Unexpected response"))
         }
     }
 
+    async fn get_pool_id(&self) -> Result<Option<u64>> {
+        let response = retry(
+            || self.client.call(self.view_call("pool_id", Vec::new())),
+            &self.retry,
+        )
+        .await?;
+
+        if let QueryResponseKind::CallResult(result) = response.kind {
+            Ok(Some(le_u64(&result.result, "Pool id")?))
+        } else {
+            Err(anyhow::anyhow!("get_pool_id: Unexpected response"))
+        }
+    }
+
     async fn get_merkle_root(&self, index: u64) -> Result<Option<U256>> {
-        let index = U256::from(index);
-        let args = FunctionArgs::from(borsh::to_vec(&index)?);
-        let request = methods::query::RpcQueryRequest {
-            block_reference: BlockReference::Finality(Finality::Final),
-            request: QueryRequest::CallFunction {
-                account_id: self.config.pool_address.clone(),
-                method_name: "merkle_root".to_owned(),
-                args,
+        let args = borsh::to_vec(&U256::from(index))?;
+        let response = retry(
+            || {
+                self.client
+                    .call(self.view_call("merkle_root", args.clone()))
             },
-        };
-
-        let response = self.client.call(request).await?;
+            &self.retry,
+        )
+        .await?;
 
         if let QueryResponseKind::CallResult(result) = response.kind {
             Ok(<Option<U256>>::try_from_slice(&result.result)?)
@@ -247,32 +332,62 @@ impl BlockchainBackend for NearBackend {
     }
 
     fn parse_calldata(&self, calldata: Vec<u8>) -> Result<TxData<Fr, Proof>> {
-        let r = &mut calldata.as_slice();
-        let tx = zeropool_tx::near::read(r)?;
-        Ok(tx)
+        read_calldata(&calldata)
     }
 
     fn extract_ciphertext_from_memo<'a>(&self, memo: &'a [u8], tx_type: TxType) -> &'a [u8] {
         let offset: usize = match tx_type {
             TxType::Deposit | TxType::Transfer => 8,
             TxType::Withdraw => {
-                let addr_len_bytes: [u8; 4] = memo[20..24].try_into().unwrap_or_default();
-                let addr_len = u32::from_le_bytes(addr_len_bytes) as usize;
-
-                16 + 4 + addr_len
+                WITHDRAW_ADDRESS_OFFSET + 4 + withdraw_address(memo).map_or(0, |addr| addr.len())
             }
         };
 
-        &memo[offset..]
+        memo.get(offset..).unwrap_or_default()
+    }
+
+    fn validate_withdraw_address(&self, memo: &[u8]) -> Result<(), TxValidationError> {
+        let addr = withdraw_address(memo).ok_or(TxValidationError::InvalidWithdrawAddress)?;
+
+        std::str::from_utf8(addr)
+            .ok()
+            .and_then(|addr| addr.parse::<AccountId>().ok())
+            .ok_or(TxValidationError::InvalidWithdrawAddress)?;
+
+        Ok(())
     }
 
-    fn parse_hash(&self, hash: &str) -> Result<Vec<u8>> {
-        bs58::decode(hash).into_vec().map_err(Into::into)
+    fn hash_encoding(&self) -> HashEncoding {
+        HASH_ENCODING
     }
+}
+
+fn read_calldata(calldata: &[u8]) -> Result<TxData<Fr, Proof>> {
+    let r = &mut &calldata[..];
+    let tx = zeropool_tx::near::read(r)?;
+    Ok(tx)
+}
 
-    fn format_hash(&self, hash: &[u8]) -> String {
-        bs58::encode(hash).into_string()
+/// Reads a little-endian U256 returned by the contract, fails instead of truncating it.
+fn le_u64(bytes: &[u8], name: &str) -> Result<u64> {
+    let num = U256::from_little_endian(bytes);
+    if bytes.iter().skip(8).any(|&byte| byte != 0) {
+        anyhow::bail!("{name} {num} doesn't fit in u64");
     }
+
+    Ok(num.as_u64())
+}
+
+/// Withdrawal memo layout: fee (8 bytes), native amount (8 bytes), receiver account id as a
+/// borsh string (4 bytes of length + data).
+const WITHDRAW_ADDRESS_OFFSET: usize = 16;
+
+fn withdraw_address(memo: &[u8]) -> Option<&[u8]> {
+    let len_bytes = memo.get(WITHDRAW_ADDRESS_OFFSET..WITHDRAW_ADDRESS_OFFSET + 4)?;
+    let len = u32::from_le_bytes(len_bytes.try_into().ok()?) as usize;
+    let start = WITHDRAW_ADDRESS_OFFSET + 4;
+
+    memo.get(start..start + len)
 }
 
 struct IndexerTx {
@@ -281,12 +396,13 @@ struct IndexerTx {
 }
 
 struct NearblocksClient {
+    http: reqwest::Client,
     url: Url,
     account: String,
 }
 
 impl NearblocksClient {
-    fn new(network: &str, account: &str) -> Result<Self> {
+    fn new(http: reqwest::Client, network: &str, account: &str) -> Result<Self> {
         let url = match network {
             "mainnet" => format!("https://api.nearblocks.io/v1/account/{}", account),
             "testnet" => format!("https://api-testnet.nearblocks.io/v1/account/{}", account),
@@ -296,6 +412,7 @@ impl NearblocksClient {
         let url = Url::parse(&url)?;
 
         Ok(Self {
+            http,
             url,
             account: account.to_string(),
         })
@@ -315,7 +432,7 @@ impl NearblocksClient {
         let mut url = self.url.clone();
         url.path_segments_mut().unwrap().push("txns").push("count");
 
-        let response = reqwest::get(url).await?.json::<Response>().await?;
+        let response = self.http.get(url).send().await?.json::<Response>().await?;
         let count = response
             .txns
             .into_iter()
@@ -363,7 +480,7 @@ impl NearblocksClient {
 
         tracing::debug!("Fetching transaction hashes from {}", url);
 
-        let mut response = reqwest::get(url).await?.json::<Response>().await?;
+        let mut response = self.http.get(url).send().await?.json::<Response>().await?;
 
         let relevant_txs = response.txns.drain(..).filter_map(|tx| {
             if tx.receiver_account_id != self.account.as_str() || !tx.outcomes.status {
@@ -383,3 +500,41 @@ impl NearblocksClient {
         Ok(relevant_txs.collect())
     }
 }
+
+/// Transport errors are retryable, errors returned by the node are not.
+fn send_error<E>(err: JsonRpcError<E>) -> SendError
+where
+    JsonRpcError<E>: std::error::Error + Send + Sync + 'static,
+{
+    match err {
+        JsonRpcError::TransportError(_) => SendError::Retryable(err.into()),
+        err => SendError::Fatal(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_le_u64() {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(le_u64(&bytes, "Pool index").unwrap(), u64::MAX);
+
+        bytes[8] = 1;
+        assert!(le_u64(&bytes, "Pool index").is_err());
+    }
+
+    #[test]
+    fn test_read_calldata() {
+        util::check_calldata_codec(
+            |tx| {
+                let mut calldata = Vec::new();
+                zeropool_tx::near::write(tx, &mut calldata).unwrap();
+                calldata
+            },
+            read_calldata,
+        );
+    }
+}
@@ -2,14 +2,18 @@ use anyhow::Result;
 use axum::async_trait;
 use libzeropool_rs::libzeropool::fawkes_crypto::engines::U256;
 use serde::Deserialize;
-use zeropool_tx::{TxData, TxType};
+use zeropool_tx::TxData;
 
 use crate::{
-    backend::{BlockchainBackend, TxCalldata, TxHash},
+    backend::{
+        BlockchainBackend, HashEncoding, SendError, SentTx, TxCalldata, TxConfirmation, TxHash,
+    },
     tx::{ParsedTxData, TxValidationError},
     Fr, Proof,
 };
 
+pub(crate) const HASH_ENCODING: HashEncoding = HashEncoding::Base58 { size: 32 };
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {}
 
@@ -37,7 +41,11 @@ impl BlockchainBackend for SubstrateBackend {
         "substrate"
     }
 
-    async fn fetch_latest_transactions(&self) -> Result<Vec<TxCalldata>> {
+    async fn chain_id(&self) -> Result<String> {
+        todo!()
+    }
+
+    async fn fetch_latest_transactions(&self, _from: u64, _limit: u64) -> Result<Vec<TxCalldata>> {
         todo!()
     }
 
@@ -46,31 +54,27 @@ impl BlockchainBackend for SubstrateBackend {
     }
 
     /// Sign and send a transaction to the blockchain.
-    async fn send_tx(&self, _tx: TxData<Fr, Proof>) -> Result<TxHash> {
+    async fn send_tx(&self, _tx: TxData<Fr, Proof>) -> Result<SentTx, SendError> {
         todo!()
     }
 
-    async fn get_pool_index(&self) -> Result<u64> {
+    async fn get_tx_status(&self, _hash: &TxHash) -> Result<TxConfirmation> {
         todo!()
     }
 
-    async fn get_merkle_root(&self, _index: u64) -> Result<Option<U256>> {
+    async fn get_pool_index(&self) -> Result<u64> {
         todo!()
     }
 
-    fn parse_calldata(&self, _calldata: Vec<u8>) -> Result<TxData<Fr, Proof>> {
+    async fn get_merkle_root(&self, _index: u64) -> Result<Option<U256>> {
         todo!()
     }
 
-    fn extract_ciphertext_from_memo(&self, _memo: &[u8], _tx_type: TxType) -> &[u8] {
+    fn parse_calldata(&self, _calldata: Vec<u8>) -> Result<TxData<Fr, Proof>> {
         todo!()
     }
 
-    fn parse_hash(&self, hash: &str) -> Result<Vec<u8>> {
-        bs58::decode(hash).into_vec().map_err(Into::into)
-    }
-
-    fn format_hash(&self, hash: &[u8]) -> String {
-        bs58::encode(hash).into_string()
+    fn hash_encoding(&self) -> HashEncoding {
+        HASH_ENCODING
     }
 }
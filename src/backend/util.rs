@@ -0,0 +1,156 @@
+use std::{fmt::Display, future::Future};
+
+use sha2::{Digest, Sha256};
+
+use super::SentTx;
+use crate::job_queue::RetryPolicy;
+
+/// Calls `op` until it succeeds or `policy.max_attempts` calls have failed, with exponential
+/// backoff in between. Only for idempotent calls, e.g. reads: a call that failed on our side
+/// might still have been executed by the node.
+pub async fn retry<T, E, F, Fut>(mut op: F, policy: &RetryPolicy) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < policy.max_attempts => {
+                let backoff = policy.backoff(attempt);
+                tracing::warn!(
+                    "RPC call failed (attempt {attempt} of {}), retrying in {backoff:?}: {err:#}",
+                    policy.max_attempts
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Takes the place of broadcasting in dry run mode (`DRY_RUN`): logs the encoded calldata and
+/// returns its sha256 as the transaction hash.
+pub fn dry_run(backend: &str, calldata: &[u8]) -> SentTx {
+    let hash = Sha256::digest(calldata).to_vec();
+    tracing::info!(
+        "Dry run, not sending {backend} transaction {}: {}",
+        hex::encode(&hash),
+        hex::encode(calldata)
+    );
+
+    SentTx { hash, sender: None }
+}
+
+/// Checks a calldata codec on a sample transfer: it round-trips, every truncation of the encoded
+/// transaction is an error and random bytes don't panic. Calldata is read from the chain, where
+/// anyone can send anything to the pool.
+#[cfg(test)]
+pub fn check_calldata_codec(
+    write: impl Fn(&zeropool_tx::TxData<crate::Fr, crate::Proof>) -> Vec<u8>,
+    read: impl Fn(&[u8]) -> anyhow::Result<zeropool_tx::TxData<crate::Fr, crate::Proof>>,
+) {
+    use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+
+    let tx = zeropool_tx::TxData {
+        tx_type: zeropool_tx::TxType::Transfer,
+        delta: Num::from(3u64),
+        token_id: String::new(),
+        out_commit: Num::from(2u64),
+        nullifier: Num::from(1u64),
+        proof: crate::tx_worker::mock_proof(),
+        root_after: Num::from(4u64),
+        tree_proof: crate::tx_worker::mock_proof(),
+        memo: (0..72).collect(),
+        extra_data: vec![],
+    };
+    let calldata = write(&tx);
+
+    let parsed = read(&calldata).unwrap();
+    assert_eq!(parsed.nullifier, tx.nullifier);
+    assert_eq!(parsed.out_commit, tx.out_commit);
+    assert_eq!(parsed.root_after, tx.root_after);
+    assert_eq!(parsed.memo, tx.memo);
+
+    for len in 0..calldata.len() {
+        assert!(read(&calldata[..len]).is_err(), "truncated to {len}");
+    }
+
+    // Fixed xorshift, so that a failure can be reproduced.
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..1000 {
+        let len = next() as usize % (2 * calldata.len());
+        let bytes = (0..len).map(|_| next() as u8).collect::<Vec<_>>();
+        let _ = read(&bytes);
+
+        // Valid calldata with a few bytes flipped.
+        let mut corrupted = calldata.clone();
+        for _ in 0..4 {
+            let index = next() as usize % corrupted.len();
+            corrupted[index] ^= next() as u8;
+        }
+        let _ = read(&corrupted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+
+    use anyhow::{anyhow, Result};
+
+    use super::*;
+
+    fn policy(max_attempts: u64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let calls = &AtomicU64::new(0);
+        let op = move || async move {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(anyhow!("Connection reset")),
+                n => Ok(n),
+            }
+        };
+
+        let result: Result<u64> = retry(op, &policy(3)).await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Out of attempts
+        calls.store(0, Ordering::SeqCst);
+        let result: Result<u64> = retry(op, &policy(2)).await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let sent = dry_run("test", &[1, 2, 3]);
+        assert_eq!(
+            hex::encode(&sent.hash),
+            "039058c6f2c0cb492c533b0a4d14ef77cc0f78abccced5287d84a1a2011cfb81"
+        );
+        assert_eq!(sent.hash, dry_run("test", &[1, 2, 3]).hash);
+        assert_ne!(sent.hash, dry_run("test", &[1, 2]).hash);
+        assert_eq!(sent.sender, None);
+    }
+}
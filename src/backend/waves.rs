@@ -8,15 +8,19 @@ use waves_rust::{
     api::{Node, Profile},
     model::{
         data_entry::DataEntry, Address, Amount, ApplicationStatus, Arg, Base64String, ByteString,
-        Function, InvokeScriptTransaction, PrivateKey, PublicKey, Transaction, TransactionData,
-        TransactionDataInfo,
+        Function, Id, InvokeScriptTransaction, PrivateKey, PublicKey, Status, Transaction,
+        TransactionData, TransactionDataInfo,
     },
     util::get_current_epoch_millis,
 };
-use zeropool_tx::TxData;
+use zeropool_tx::{TxData, TxType};
 
 use crate::{
-    backend::{BlockchainBackend, TxCalldata, TxHash},
+    backend::{
+        util::{self, retry},
+        BlockchainBackend, HashEncoding, SendError, SentTx, TxCalldata, TxConfirmation, TxHash,
+    },
+    job_queue::RetryPolicy,
     tx::{ParsedTxData, TxValidationError},
     Fr, Proof,
 };
@@ -25,13 +29,18 @@ use crate::{
 // 0.01 WAVES
 const TX_FEE: u64 = 10_000_000;
 
+/// Binary Waves addresses are 26 bytes long: version, chain id, public key hash and checksum.
+const ADDRESS_SIZE: usize = 26;
+/// Transaction ids are blake2b256 digests.
+pub(crate) const HASH_ENCODING: HashEncoding = HashEncoding::Base58 { size: 32 };
+
 // TODO: Specify pool address separately from relayer address.
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     seed: String,
     profile: String,
-    pool_address: String,
+    pub pool_address: String,
 }
 
 pub struct WavesBackend {
@@ -40,10 +49,12 @@ pub struct WavesBackend {
     address: Address,
     node: Node,
     chain_id: u8,
+    retry: RetryPolicy,
+    dry_run: bool,
 }
 
 impl WavesBackend {
-    pub async fn new(config: Config) -> Result<Self> {
+    pub async fn new(config: Config, retry: RetryPolicy, dry_run: bool) -> Result<Self> {
         let profile = match config.profile.as_str() {
             "MAINNET" => Profile::MAINNET,
             "TESTNET" => Profile::TESTNET,
@@ -67,6 +78,8 @@ impl WavesBackend {
             address,
             node,
             chain_id,
+            retry,
+            dry_run,
         })
     }
 }
@@ -77,15 +90,23 @@ impl BlockchainBackend for WavesBackend {
         "waves"
     }
 
-    async fn fetch_latest_transactions(&self) -> Result<Vec<TxCalldata>> {
+    async fn chain_id(&self) -> Result<String> {
+        Ok(char::from(self.chain_id).to_string())
+    }
+
+    async fn fetch_latest_transactions(&self, from: u64, limit: u64) -> Result<Vec<TxCalldata>> {
         let mut txs = Vec::new();
 
         let mut latest_tx_id = None; // FIXME: initialize with latest tx id
         loop {
-            let result = self
-                .node
-                .get_transactions_by_address(&self.address, 100, latest_tx_id.clone())
-                .await?;
+            let result = retry(
+                || {
+                    self.node
+                        .get_transactions_by_address(&self.address, 100, latest_tx_id.clone())
+                },
+                &self.retry,
+            )
+            .await?;
 
             if result.is_empty() {
                 break;
@@ -124,7 +145,12 @@ impl BlockchainBackend for WavesBackend {
             }
         }
 
-        Ok(txs)
+        // TODO: Stop paginating once `from + limit` is reached.
+        Ok(txs
+            .into_iter()
+            .skip(from as usize)
+            .take(limit as usize)
+            .collect())
     }
 
     async fn validate_tx(&self, _tx: &ParsedTxData) -> Vec<TxValidationError> {
@@ -132,9 +158,13 @@ impl BlockchainBackend for WavesBackend {
     }
 
     /// Sign and send a transaction to the blockchain.
-    async fn send_tx(&self, tx: TxData<Fr, Proof>) -> Result<TxHash> {
+    async fn send_tx(&self, tx: TxData<Fr, Proof>) -> Result<SentTx, SendError> {
         let mut tx_bytes = Vec::new();
-        zeropool_tx::waves::write(&tx, &mut tx_bytes)?;
+        zeropool_tx::waves::write(&tx, &mut tx_bytes).map_err(anyhow::Error::from)?;
+
+        if self.dry_run {
+            return Ok(util::dry_run(self.name(), &tx_bytes));
+        }
 
         let base64_tx = Base64String::from_bytes(tx_bytes);
 
@@ -155,15 +185,38 @@ impl BlockchainBackend for WavesBackend {
             3,
             self.chain_id,
         )
-        .sign(&self.private_key)?;
+        .sign(&self.private_key)
+        .map_err(anyhow::Error::from)?;
 
-        let res = self.node.broadcast(&signed_tx).await?;
-        let tx_id = res.id()?;
-        Ok(ByteString::bytes(&tx_id))
+        // TODO: Distinguish network errors from rejected transactions.
+        let res = self
+            .node
+            .broadcast(&signed_tx)
+            .await
+            .map_err(anyhow::Error::from)?;
+        let tx_id = res.id().map_err(anyhow::Error::from)?;
+        Ok(ByteString::bytes(&tx_id).into())
+    }
+
+    async fn get_tx_status(&self, hash: &TxHash) -> Result<TxConfirmation> {
+        let status = self
+            .node
+            .get_transaction_status(&Id::from_bytes(hash))
+            .await?;
+
+        Ok(match (status.status(), status.application_status()) {
+            (Status::Confirmed, ApplicationStatus::Succeed) => TxConfirmation::Confirmed,
+            (Status::Confirmed, ApplicationStatus::ScriptExecutionFailed) => TxConfirmation::Failed,
+            _ => TxConfirmation::Pending,
+        })
     }
 
     async fn get_pool_index(&self) -> Result<u64> {
-        let index = self.node.get_data_by_key(&self.address, "PoolIndex").await;
+        let index = retry(
+            || self.node.get_data_by_key(&self.address, "PoolIndex"),
+            &self.retry,
+        )
+        .await;
 
         match index {
             Ok(DataEntry::IntegerEntry { value, .. }) => Ok(value as u64),
@@ -177,6 +230,17 @@ impl BlockchainBackend for WavesBackend {
         }
     }
 
+    async fn get_pool_id(&self) -> Result<Option<u64>> {
+        let pool_id = self.node.get_data_by_key(&self.address, "PoolId").await;
+
+        match pool_id {
+            Ok(DataEntry::IntegerEntry { value, .. }) => Ok(Some(value as u64)),
+            Ok(_) => bail!("PoolId is not an integer"),
+            // Not set by older deployments
+            Err(_) => Ok(None),
+        }
+    }
+
     async fn get_merkle_root(&self, index: u64) -> Result<Option<U256>> {
         if index == 0 {
             let first_root = U256::from_str(
@@ -186,10 +250,12 @@ impl BlockchainBackend for WavesBackend {
             return Ok(Some(first_root));
         }
 
-        let result = self
-            .node
-            .get_data_by_key(&self.address, &format!("R:{index}"))
-            .await;
+        let key = format!("R:{index}");
+        let result = retry(
+            || self.node.get_data_by_key(&self.address, &key),
+            &self.retry,
+        )
+        .await;
 
         match result {
             Ok(DataEntry::BinaryEntry { value, .. }) => {
@@ -208,16 +274,42 @@ impl BlockchainBackend for WavesBackend {
     }
 
     fn parse_calldata(&self, calldata: Vec<u8>) -> Result<TxData<Fr, Proof>> {
-        let r = &mut calldata.as_slice();
-        let tx = zeropool_tx::waves::read(r)?;
-        Ok(tx)
+        read_calldata(&calldata)
     }
 
-    fn parse_hash(&self, hash: &str) -> Result<Vec<u8>> {
-        bs58::decode(hash).into_vec().map_err(Into::into)
+    fn extract_ciphertext_from_memo<'a>(&self, memo: &'a [u8], tx_type: TxType) -> &'a [u8] {
+        let offset = match tx_type {
+            TxType::Deposit | TxType::Transfer => 8,
+            TxType::Withdraw => 8 + 8 + ADDRESS_SIZE,
+        };
+
+        memo.get(offset..).unwrap_or_default()
+    }
+
+    fn hash_encoding(&self) -> HashEncoding {
+        HASH_ENCODING
     }
+}
+
+fn read_calldata(calldata: &[u8]) -> Result<TxData<Fr, Proof>> {
+    let r = &mut &calldata[..];
+    let tx = zeropool_tx::waves::read(r)?;
+    Ok(tx)
+}
 
-    fn format_hash(&self, hash: &[u8]) -> String {
-        bs58::encode(hash).into_string()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_calldata() {
+        util::check_calldata_codec(
+            |tx| {
+                let mut calldata = Vec::new();
+                zeropool_tx::waves::write(tx, &mut calldata).unwrap();
+                calldata
+            },
+            read_calldata,
+        );
     }
 }
@@ -0,0 +1,43 @@
+//! Build metadata embedded by `build.rs`.
+
+use zeropool_relayer_client::BuildInfo;
+
+#[cfg(feature = "groth16")]
+pub const PROVING_SYSTEM: &str = "groth16";
+#[cfg(feature = "plonk")]
+pub const PROVING_SYSTEM: &str = "plonk";
+
+pub fn current() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        git_hash: env!("GIT_HASH").to_owned(),
+        features: env!("BUILD_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+        build_timestamp: env!("BUILD_TIMESTAMP").parse().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let info = current();
+
+        assert!(!info.version.is_empty());
+        assert!(!info.git_hash.is_empty());
+        assert!(info.build_timestamp > 0);
+        #[cfg(feature = "groth16")]
+        assert!(info.features.iter().any(|feature| feature == "groth16"));
+        #[cfg(feature = "plonk")]
+        assert!(info.features.iter().any(|feature| feature == "plonk"));
+        assert!(info
+            .features
+            .iter()
+            .any(|feature| feature == PROVING_SYSTEM));
+    }
+}
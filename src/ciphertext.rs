@@ -0,0 +1,176 @@
+//! Layout of the memo ciphertext produced by libzeropool's `cipher::encrypt`:
+//!
+//! ```text
+//! items count (u32 LE), n = notes + 1
+//! hashes of the account and the notes, n * 32
+//! ephemeral key, 32
+//! shared secret ciphertext, n * 32 + tag
+//! account ciphertext, account + tag
+//! for each note: ephemeral key, 32 + note ciphertext, note + tag
+//! ```
+//!
+//! Wallets only need the ephemeral keys to find out which outputs are theirs, so these are
+//! indexed separately from the full records.
+
+use libzeropool_rs::libzeropool::constants;
+
+use crate::Fr;
+
+pub const PREFIX_SIZE: usize = constants::U256_SIZE;
+
+const COUNT_SIZE: usize = 4;
+/// Output commitment at the start of a stored record.
+const RECORD_COMMIT_SIZE: usize = constants::U256_SIZE;
+const TAG_SIZE: usize = constants::POLY_1305_TAG_SIZE;
+
+fn account_size() -> usize {
+    constants::account_size_bits::<Fr>() / 8
+}
+
+fn note_size() -> usize {
+    constants::note_size_bits::<Fr>() / 8
+}
+
+/// Offset of the first note in a ciphertext with `count` items.
+fn first_note_offset(count: usize) -> usize {
+    COUNT_SIZE
+        + count * PREFIX_SIZE
+        + PREFIX_SIZE
+        + count * PREFIX_SIZE
+        + TAG_SIZE
+        + account_size()
+        + TAG_SIZE
+}
+
+fn note_stride() -> usize {
+    PREFIX_SIZE + note_size() + TAG_SIZE
+}
+
+fn ciphertext_size(count: usize) -> usize {
+    first_note_offset(count) + (count - 1) * note_stride()
+}
+
+/// Returns the first [`PREFIX_SIZE`] bytes of every output ciphertext: the ephemeral key of the
+/// account, followed by the ephemeral key of every note. The n-th prefix belongs to the
+/// commitment index of the transaction + n.
+///
+/// Returns `None` if the ciphertext doesn't follow the layout.
+pub fn output_prefixes(ciphertext: &[u8]) -> Option<Vec<&[u8]>> {
+    let count = u32::from_le_bytes(ciphertext.get(..COUNT_SIZE)?.try_into().ok()?) as usize;
+    let notes = count.checked_sub(1)?;
+    if notes > constants::OUT || ciphertext.len() != ciphertext_size(count) {
+        return None;
+    }
+
+    let account_key = COUNT_SIZE + count * PREFIX_SIZE;
+    let first_note = first_note_offset(count);
+    let notes = (0..notes).map(|i| first_note + i * note_stride());
+    let prefixes = std::iter::once(account_key)
+        .chain(notes)
+        .map(|offset| &ciphertext[offset..offset + PREFIX_SIZE])
+        .collect();
+
+    Some(prefixes)
+}
+
+/// Finds the ciphertext in a stored record, see [`crate::tx_storage::TxStorage::get`].
+///
+/// Records don't store the length of the transaction hash, which differs between backends, but
+/// the size of a ciphertext follows from its item count, so every possible count is tried.
+pub fn find_in_record(record: &[u8]) -> Option<&[u8]> {
+    let data = record.get(RECORD_COMMIT_SIZE..)?;
+
+    (1..=constants::OUT + 1).find_map(|count| {
+        let start = data.len().checked_sub(ciphertext_size(count))?;
+        let ciphertext = &data[start..];
+        (ciphertext[..COUNT_SIZE] == (count as u32).to_le_bytes()).then_some(ciphertext)
+    })
+}
+
+/// Builds a ciphertext with `notes` notes, in which every ephemeral key is filled with its item
+/// number and everything else with `0xff`.
+#[cfg(test)]
+pub fn test_ciphertext(notes: usize) -> Vec<u8> {
+    let count = notes + 1;
+    let mut ciphertext = (count as u32).to_le_bytes().to_vec();
+    ciphertext.extend(vec![0xff; count * PREFIX_SIZE]);
+    ciphertext.extend([0; PREFIX_SIZE]);
+    ciphertext.extend(vec![0xff; count * PREFIX_SIZE + TAG_SIZE]);
+    ciphertext.extend(vec![0xff; account_size() + TAG_SIZE]);
+    for i in 1..=notes {
+        ciphertext.extend([i as u8; PREFIX_SIZE]);
+        ciphertext.extend(vec![0xff; note_size() + TAG_SIZE]);
+    }
+
+    ciphertext
+}
+
+#[cfg(test)]
+mod tests {
+    use zeropool_tx::TxType;
+
+    use super::*;
+    use crate::backend::{
+        mock::{mock_config, MockBackend},
+        BlockchainBackend,
+    };
+
+    #[test]
+    fn test_output_prefixes() {
+        for notes in [0, 1, 2, constants::OUT] {
+            let ciphertext = test_ciphertext(notes);
+            let prefixes = output_prefixes(&ciphertext).unwrap();
+
+            assert_eq!(prefixes.len(), notes + 1);
+            for (i, prefix) in prefixes.into_iter().enumerate() {
+                assert_eq!(prefix, [i as u8; PREFIX_SIZE]);
+            }
+        }
+
+        let ciphertext = test_ciphertext(2);
+        assert!(output_prefixes(&ciphertext[..ciphertext.len() - 1]).is_none());
+        assert!(output_prefixes(&[&ciphertext[..], &[0]].concat()).is_none());
+        assert!(output_prefixes(&[0, 0, 0, 0]).is_none());
+        assert!(output_prefixes(&[]).is_none());
+    }
+
+    #[test]
+    fn test_output_prefixes_from_memo() {
+        let backend = MockBackend::new(mock_config(None, vec![]));
+        let ciphertext = test_ciphertext(3);
+
+        // Fee for deposits and transfers, also the native amount and the receiver for withdrawals
+        for (tx_type, header_size) in [
+            (TxType::Deposit, 8),
+            (TxType::Transfer, 8),
+            (TxType::Withdraw, 36),
+        ] {
+            let memo = [vec![0xee; header_size], ciphertext.clone()].concat();
+            let extracted = backend.extract_ciphertext_from_memo(&memo, tx_type);
+
+            assert_eq!(extracted, ciphertext, "{tx_type:?}");
+            let prefixes = output_prefixes(extracted).unwrap();
+            assert_eq!(prefixes.len(), 4);
+            assert_eq!(prefixes[3], [3; PREFIX_SIZE]);
+        }
+    }
+
+    #[test]
+    fn test_find_in_record() {
+        for hash_size in [8, 32] {
+            for notes in [0, 1, constants::OUT] {
+                let ciphertext = test_ciphertext(notes);
+                let record = [
+                    vec![0xee; RECORD_COMMIT_SIZE + hash_size],
+                    ciphertext.clone(),
+                ]
+                .concat();
+
+                assert_eq!(find_in_record(&record), Some(&ciphertext[..]));
+            }
+        }
+
+        assert!(find_in_record(&[0; 64]).is_none());
+        assert!(find_in_record(&[]).is_none());
+    }
+}
@@ -0,0 +1,370 @@
+//! Offline maintenance commands, run instead of the server when arguments are passed.
+//!
+//! ```text
+//! zeropool-relayer root [<index>]
+//! zeropool-relayer rollback <index> --confirm
+//! zeropool-relayer state export <file> [--force]
+//! zeropool-relayer state import <file> [--force]
+//! zeropool-relayer tree export <file>
+//! zeropool-relayer tree import <file> --confirm
+//! ```
+//!
+//! Indices are leaf indices of the merkle tree (pool index / `TX_SIZE`).
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::{PrimeField, Uint};
+
+use crate::{
+    backend::HashEncoding,
+    config,
+    merkle_tree::MerkleTree,
+    state::{TREE_PATH, TX_STORAGE_PATH},
+    state_archive::{self, StateArchive},
+    tx_storage::TxStorage,
+    tx_worker::TX_SIZE,
+};
+
+const USAGE: &str = "Usage:
+    zeropool-relayer root [<index>]       Print the current root, number of leaves and the
+                                          historic root at <index>
+    zeropool-relayer rollback <index> --confirm
+                                          Roll back the tree and transaction storage to <index>
+    zeropool-relayer state export <file> [--force]
+                                          Write the tree leaves and transactions to <file>,
+                                          --force if some transactions are not sent yet
+    zeropool-relayer state import <file> [--force]
+                                          Replace the local state with the one in <file>,
+                                          --force if it was exported for another backend
+    zeropool-relayer tree export <file>   Write the tree nodes to <file>, same as
+                                          GET /admin/tree/export
+    zeropool-relayer tree import <file> --confirm
+                                          Replace the tree with the one in <file>, up to the
+                                          first leaf without a matching transaction";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Root { index: Option<u64> },
+    Rollback { index: u64, confirm: bool },
+    StateExport { path: String, force: bool },
+    StateImport { path: String, force: bool },
+    TreeExport { path: String },
+    TreeImport { path: String, confirm: bool },
+}
+
+impl Command {
+    pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<Self> {
+        let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+        let confirm = args.contains(&"--confirm");
+        let force = args.contains(&"--force");
+        let positional: Vec<&str> = args
+            .iter()
+            .copied()
+            .filter(|arg| !arg.starts_with("--"))
+            .collect();
+
+        if let Some(flag) = args
+            .iter()
+            .find(|arg| arg.starts_with("--") && !["--confirm", "--force"].contains(arg))
+        {
+            bail!("Unknown flag: {flag}\n{USAGE}");
+        }
+
+        let command = match positional.as_slice() {
+            ["root"] => Command::Root { index: None },
+            ["root", index] => Command::Root {
+                index: Some(parse_index(index)?),
+            },
+            ["rollback", index] => Command::Rollback {
+                index: parse_index(index)?,
+                confirm,
+            },
+            ["state", "export", path] => Command::StateExport {
+                path: path.to_string(),
+                force,
+            },
+            ["state", "import", path] => Command::StateImport {
+                path: path.to_string(),
+                force,
+            },
+            ["tree", "export", path] => Command::TreeExport {
+                path: path.to_string(),
+            },
+            ["tree", "import", path] => Command::TreeImport {
+                path: path.to_string(),
+                confirm,
+            },
+            _ => bail!("{USAGE}"),
+        };
+
+        let takes_force = matches!(
+            command,
+            Command::StateExport { .. } | Command::StateImport { .. }
+        );
+        if force && !takes_force {
+            bail!("Unknown flag: --force\n{USAGE}");
+        }
+
+        Ok(command)
+    }
+
+    pub fn run(self) -> Result<()> {
+        let tree_path = storage_path(TREE_PATH);
+        let tx_storage_path = storage_path(TX_STORAGE_PATH);
+        if state_archive::finish_import(&tree_path, &tx_storage_path)? {
+            println!("Finished an interrupted state import");
+        }
+
+        match self {
+            Command::Root { index } => {
+                let tree = MerkleTree::open(&tree_path)?;
+                println!("root: {}", tree.root()?);
+                println!("num_leaves: {}", tree.num_leaves());
+
+                if let Some(index) = index {
+                    match tree.historic_root(index)? {
+                        Some(root) => println!("historic root at {index}: {root}"),
+                        None => println!("historic root at {index}: not found"),
+                    }
+                }
+            }
+            Command::Rollback { index, confirm } => {
+                if !confirm {
+                    bail!("Rollback is destructive, pass --confirm to proceed");
+                }
+
+                let tree = MerkleTree::open(&tree_path)?;
+                let transactions = TxStorage::open(&tx_storage_path)?;
+
+                let removed = transactions.rollback(index * TX_SIZE)?;
+                tree.rollback(index)?;
+
+                println!("Removed {removed} transactions");
+                println!("root: {}", tree.root()?);
+                println!("num_leaves: {}", tree.num_leaves());
+            }
+            Command::StateExport { path, force } => {
+                let tree = MerkleTree::open(&tree_path)?;
+                let transactions = TxStorage::open(&tx_storage_path)?;
+
+                let backend = std::env::var("BACKEND")?;
+                let hash_encoding = HashEncoding::of_backend(&backend)
+                    .ok_or_else(|| anyhow!("Unknown backend: {backend}"))?;
+                let archive =
+                    StateArchive::export(&tree, &transactions, hash_encoding.size(), force)?;
+                archive.save(&path)?;
+
+                println!("Exported {} transactions to {path}", archive.leaves.len());
+                println!("root: {}", archive.root);
+            }
+            Command::StateImport { path, force } => {
+                let archive = StateArchive::load(&path)?;
+
+                let backend = std::env::var("BACKEND").ok();
+                if let (Some(expected), Some(exported)) = (&backend, &archive.backend) {
+                    if expected != exported && !force {
+                        bail!(
+                            "{path} was exported for the {exported} backend, not {expected}, \
+                             pass --force to import anyway"
+                        );
+                    }
+                }
+
+                archive.import(&tree_path, &tx_storage_path)?;
+
+                println!("Imported {} transactions from {path}", archive.leaves.len());
+                println!("root: {}", archive.root);
+                println!("pool index: {}", archive.pool_index);
+            }
+            Command::TreeExport { path } => {
+                let tree = MerkleTree::open(&tree_path)?;
+                tree.export(BufWriter::new(File::create(&path)?))?;
+
+                println!("Exported the tree to {path}");
+                println!("root: {}", tree.root()?);
+                println!("num_leaves: {}", tree.num_leaves());
+            }
+            Command::TreeImport { path, confirm } => {
+                if !confirm {
+                    bail!("Import replaces the tree, pass --confirm to proceed");
+                }
+
+                let new_tree_path = format!("{tree_path}.import");
+                if Path::new(&new_tree_path).exists() {
+                    std::fs::remove_file(&new_tree_path)?;
+                }
+
+                let (root, num_leaves, imported) = {
+                    let tree = MerkleTree::open(&new_tree_path)?;
+                    tree.import(BufReader::new(File::open(&path)?))?;
+                    if !tree.verify_consistency()? {
+                        std::fs::remove_file(&new_tree_path)?;
+                        bail!("{path} is not a consistent tree");
+                    }
+                    let imported = tree.num_leaves();
+
+                    let transactions = TxStorage::open(&tx_storage_path)?;
+                    align_with_transactions(&tree, &transactions)?;
+
+                    (tree.root()?, tree.num_leaves(), imported)
+                };
+                std::fs::rename(&new_tree_path, &tree_path)?;
+
+                println!("Imported the tree from {path}");
+                if num_leaves < imported {
+                    println!(
+                        "Kept {num_leaves} of {imported} leaves, the transactions don't match \
+                         the rest. They are synced from the chain on the next start."
+                    );
+                }
+                println!("root: {root}");
+                println!("num_leaves: {num_leaves}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Rolls back `tree` and `transactions` to the longest prefix where every leaf has a stored
+/// transaction with the same out commitment, so that neither has entries the other lacks.
+fn align_with_transactions(tree: &MerkleTree, transactions: &TxStorage) -> Result<()> {
+    let mut matching = 0;
+    for record in transactions.iter()? {
+        let (index, data) = record?;
+        if matching == tree.num_leaves() || index != matching * TX_SIZE {
+            break;
+        }
+        let leaf = tree.leaf(matching)?.0.to_uint().to_big_endian();
+        if data.get(..32) != Some(&leaf[..]) {
+            break;
+        }
+        matching += 1;
+    }
+
+    if matching < tree.num_leaves() {
+        tree.rollback(matching)?;
+    }
+    transactions.rollback(matching * TX_SIZE)?;
+
+    Ok(())
+}
+
+/// Path of a storage file in `STORAGE_DIR`, like the server uses.
+fn storage_path(file_name: &str) -> String {
+    config::storage_dir()
+        .join(file_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn parse_index(index: &str) -> Result<u64> {
+    index
+        .parse()
+        .map_err(|_| anyhow!("Invalid index: {index}\n{USAGE}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Command::parse(&["root"]).unwrap(),
+            Command::Root { index: None }
+        );
+        assert_eq!(
+            Command::parse(&["root", "5"]).unwrap(),
+            Command::Root { index: Some(5) }
+        );
+        assert_eq!(
+            Command::parse(&["rollback", "5"]).unwrap(),
+            Command::Rollback {
+                index: 5,
+                confirm: false
+            }
+        );
+        assert_eq!(
+            Command::parse(&["rollback", "--confirm", "5"]).unwrap(),
+            Command::Rollback {
+                index: 5,
+                confirm: true
+            }
+        );
+
+        assert_eq!(
+            Command::parse(&["state", "export", "state.bin"]).unwrap(),
+            Command::StateExport {
+                path: "state.bin".to_owned(),
+                force: false
+            }
+        );
+        assert_eq!(
+            Command::parse(&["state", "import", "--force", "state.bin"]).unwrap(),
+            Command::StateImport {
+                path: "state.bin".to_owned(),
+                force: true
+            }
+        );
+
+        assert_eq!(
+            Command::parse(&["tree", "import", "tree.bin", "--confirm"]).unwrap(),
+            Command::TreeImport {
+                path: "tree.bin".to_owned(),
+                confirm: true
+            }
+        );
+
+        assert!(Command::parse(&["rollback"]).is_err());
+        assert!(Command::parse(&["state", "export"]).is_err());
+        assert!(Command::parse(&["rollback", "x", "--confirm"]).is_err());
+        assert!(Command::parse(&["root", "--force"]).is_err());
+        assert!(Command::parse(&["compact"]).is_err());
+    }
+
+    #[test]
+    fn test_rollback_requires_confirm() {
+        let res = Command::Rollback {
+            index: 0,
+            confirm: false,
+        }
+        .run();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_align_with_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        let tree = MerkleTree::open(&path("tree.persy")).unwrap();
+        let transactions = TxStorage::open(&path("transactions.persy")).unwrap();
+
+        for i in 0..5 {
+            tree.add_leaf(Num::from(i + 1)).unwrap();
+        }
+        for i in 0..4 {
+            let out_commit = if i == 3 { Num::ZERO } else { Num::from(i + 1) };
+            transactions
+                .push(i * TX_SIZE, out_commit, &[0; 32], &[])
+                .unwrap();
+        }
+
+        align_with_transactions(&tree, &transactions).unwrap();
+        assert_eq!(tree.num_leaves(), 3);
+        assert_eq!(transactions.next_index().unwrap(), 3 * TX_SIZE);
+
+        // Transactions after the last leaf are removed
+        tree.rollback(2).unwrap();
+        align_with_transactions(&tree, &transactions).unwrap();
+        assert_eq!(tree.num_leaves(), 2);
+        assert_eq!(transactions.next_index().unwrap(), 2 * TX_SIZE);
+    }
+}
@@ -1,9 +1,15 @@
-use anyhow::Result;
-use serde::de::DeserializeOwned;
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use axum::http::HeaderValue;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::job_queue::{JobRetention, RetryPolicy};
 
 #[derive(Debug, Clone)]
 pub enum BackendKind {
-    Mock,
+    Mock(crate::backend::mock::Config),
     #[cfg(feature = "evm_backend")]
     Evm(crate::backend::evm::Config),
     #[cfg(feature = "near_backend")]
@@ -15,7 +21,7 @@ pub enum BackendKind {
 impl BackendKind {
     pub fn token_id(&self) -> String {
         match self {
-            BackendKind::Mock => "mock".to_string(),
+            BackendKind::Mock(_) => "mock".to_string(),
             #[cfg(feature = "evm_backend")]
             BackendKind::Evm(config) => config.token_address.clone(),
             #[cfg(feature = "near_backend")]
@@ -24,15 +30,308 @@ impl BackendKind {
             BackendKind::Waves(_config) => String::new(),
         }
     }
+
+    pub fn pool_address(&self) -> String {
+        match self {
+            BackendKind::Mock(_) => "mock".to_string(),
+            #[cfg(feature = "evm_backend")]
+            BackendKind::Evm(config) => config.pool_address.clone(),
+            #[cfg(feature = "near_backend")]
+            BackendKind::Near(config) => config.pool_address.to_string(),
+            #[cfg(feature = "waves_backend")]
+            BackendKind::Waves(config) => config.pool_address.clone(),
+        }
+    }
+}
+
+/// A string that is not printed in logs.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// An address to serve the HTTP API on: either `host:port` or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(anyhow!("Empty unix socket path"));
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else {
+            Ok(ListenAddr::Tcp(s.parse()?))
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Origins allowed to make cross-origin requests to the public API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorsOrigins {
+    Any,
+    /// CORS is disabled if the list is empty.
+    List(Vec<HeaderValue>),
+}
+
+impl FromStr for CorsOrigins {
+    type Err = anyhow::Error;
+
+    /// Parses either `*` or a comma-separated list of origins.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.trim() == "*" {
+            return Ok(CorsOrigins::Any);
+        }
+
+        let origins = s
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                if origin == "*" {
+                    return Err(anyhow!("Wildcard can't be combined with other origins"));
+                }
+                Ok(HeaderValue::from_str(origin)?)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(CorsOrigins::List(origins))
+    }
+}
+
+/// Minimum fee in the memo of a transaction, in pool units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeModel {
+    /// `FEE`.
+    Flat(u64),
+    /// `FEE_PERCENTAGE_BPS` basis points of the public token amount, rounded up, but at least
+    /// `FEE`.
+    Percentage { bps: u64, min: u64 },
+}
+
+const BPS_PER_UNIT: u64 = 10_000;
+
+impl FeeModel {
+    pub fn new(fee: u64, percentage_bps: Option<u64>) -> Result<Self> {
+        let model = match percentage_bps {
+            None => FeeModel::Flat(fee),
+            Some(bps) => FeeModel::Percentage { bps, min: fee },
+        };
+        model.validate()?;
+
+        Ok(model)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            FeeModel::Percentage { bps, .. } if bps > BPS_PER_UNIT => Err(anyhow!(
+                "FEE_PERCENTAGE_BPS must be at most {BPS_PER_UNIT}, got {bps}"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fee required for a transaction with the given absolute token amount.
+    pub fn required_fee(&self, amount: u64) -> u64 {
+        match *self {
+            FeeModel::Flat(fee) => fee,
+            FeeModel::Percentage { bps, min } => {
+                let fee = (amount as u128 * bps as u128).div_ceil(BPS_PER_UNIT as u128);
+                // Doesn't exceed `amount` since `bps` is at most 100%.
+                (fee as u64).max(min)
+            }
+        }
+    }
+}
+
+/// Settings that can be changed while the relayer is running, with `PUT /admin/settings` or by
+/// sending SIGHUP to re-read them from the environment and `.env`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RuntimeSettings {
+    /// `FEE` and `FEE_PERCENTAGE_BPS`.
+    pub fee: FeeModel,
+    /// `RATE_LIMIT_PER_MINUTE`, the maximum number of submitted transactions per client IP per
+    /// minute. The limit is kept in memory and applies to each relayer instance separately.
+    /// Disabled if not set.
+    #[serde(default)]
+    pub rate_limit_per_minute: Option<u64>,
+    /// `MAX_PENDING_JOBS`, submissions are rejected while this many jobs are queued. Not limited
+    /// if not set.
+    #[serde(default)]
+    pub max_pending_jobs: Option<u64>,
+}
+
+impl RuntimeSettings {
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    /// Reads the settings from the variables returned by `var`, named as in the environment.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        Ok(Self {
+            fee: FeeModel::new(
+                var("FEE")
+                    .ok_or_else(|| anyhow!("FEE must be set"))?
+                    .parse()?,
+                var("FEE_PERCENTAGE_BPS")
+                    .map(|var| var.parse())
+                    .transpose()?,
+            )?,
+            rate_limit_per_minute: var("RATE_LIMIT_PER_MINUTE")
+                .map(|var| var.parse::<u64>())
+                .transpose()?
+                .filter(|&limit| limit > 0),
+            max_pending_jobs: var("MAX_PENDING_JOBS")
+                .map(|var| var.parse::<u64>())
+                .transpose()?,
+        })
+    }
+
+    /// Checks settings that didn't come from `from_env`, e.g. `PUT /admin/settings`.
+    pub fn validate(&self) -> Result<()> {
+        self.fee.validate()?;
+        if self.rate_limit_per_minute == Some(0) {
+            return Err(anyhow!("Rate limit must be positive, unset it to disable"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Storage of the job queue, selected with `JOB_QUEUE_KIND`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobQueueKind {
+    /// `REDIS_URL`.
+    Redis { url: String },
+    /// Jobs are kept in the relayer process and are lost on restart.
+    Memory,
+}
+
+impl JobQueueKind {
+    /// `persistent` is `JOB_QUEUE_PERSISTENT`, set to require a queue that survives restarts.
+    pub fn new(kind: Option<&str>, redis_url: Option<String>, persistent: bool) -> Result<Self> {
+        match kind.unwrap_or("redis") {
+            "redis" => Ok(JobQueueKind::Redis {
+                url: redis_url.ok_or_else(|| anyhow!("REDIS_URL must be set"))?,
+            }),
+            "memory" if persistent => Err(anyhow!(
+                "The in-memory job queue loses all jobs on restart, but JOB_QUEUE_PERSISTENT is \
+                 set. Use JOB_QUEUE_KIND=redis or unset JOB_QUEUE_PERSISTENT"
+            )),
+            "memory" => Ok(JobQueueKind::Memory),
+            kind => Err(anyhow!("Unknown job queue kind: {kind}")),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
-    pub port: u16,
+    /// Addresses for the public API. Falls back to `0.0.0.0:$PORT` if `LISTEN` is not set.
+    pub listen: Vec<ListenAddr>,
+    /// Addresses for the admin and metrics routes. If empty, these routes are served on `listen`.
+    pub admin_listen: Vec<ListenAddr>,
+    /// `CORS_ALLOWED_ORIGINS`, `*` or a comma-separated list. CORS is disabled if not set.
+    pub cors_allowed_origins: CorsOrigins,
+    /// Maximum size of a transaction submission body in bytes.
+    pub max_body_size: usize,
+    /// Requests that take longer than this are logged with their route and the time spent on
+    /// the tree lock. Disabled if not set.
+    pub slow_request_threshold_ms: Option<u64>,
+    /// Take the client IP from the last entry of `X-Forwarded-For`. Only enable behind a single
+    /// reverse proxy that appends the address of its client to the header.
+    pub rate_limit_trust_forwarded_for: bool,
+    /// If not empty, transaction submissions require `Authorization: Bearer <key>` with one of
+    /// these keys.
+    pub api_keys: Vec<Secret>,
+    /// Also require an API key for the read routes.
+    pub api_keys_protect_reads: bool,
     pub backend: BackendKind,
-    pub redis_url: String,
-    pub fee: u64,
+    pub job_queue: JobQueueKind,
+    /// Initial runtime settings, the current ones are in `AppState::settings`.
+    pub settings: RuntimeSettings,
+    /// Id of the pool deployment, transactions with another pool id in the delta are rejected.
+    pub pool_id: u64,
+    /// Maximum distance between the transfer index of a transaction and the pool index it's sent
+    /// at. Queued jobs that fall further behind expire. Not limited if not set.
+    pub max_tx_index_lag: Option<u64>,
+    /// Skip proving the tree updates and verifying the transfer proofs, the proving parameters
+    /// are not loaded. Only allowed with the mock backend.
     pub mock_prover: bool,
+    /// Build and encode transactions, but log them instead of sending. The local state advances
+    /// as if they were sent, with hashes derived from the calldata. For staging environments.
+    pub dry_run: bool,
+    /// Bearer token for the `/admin` routes. The admin API is disabled if not set.
+    pub admin_token: Option<Secret>,
+    pub maintenance_interval_secs: u64,
+    /// Number of latest historic roots to keep. All roots are kept if not set.
+    pub roots_retention: Option<u64>,
+    /// How often to poll the pool state for reorgs.
+    pub reorg_check_interval_secs: u64,
+    /// How often to compare the mined root with the local historic root. Disabled if not set.
+    pub root_check_interval_secs: Option<u64>,
+    /// Maximum number of archived failed jobs.
+    pub failed_jobs_max_count: u64,
+    /// Archived failed jobs older than this are removed.
+    pub failed_jobs_max_age_secs: u64,
+    /// URLs of read-only replicas, advertised in `/info`.
+    pub replica_urls: Vec<String>,
+    /// Maximum number of jobs that are proving or sending at the same time. Each of them holds
+    /// its transaction and proofs in memory, the rest wait in the job queue. Not limited if not
+    /// set.
+    pub max_concurrent_jobs: Option<usize>,
+    /// Retries of jobs that failed to send their transaction because of a transient error.
+    pub job_retry: RetryPolicy,
+    /// `JOB_STATUS_TTL_SECS` and `JOB_HISTORY_MAX_COUNT`.
+    pub job_retention: JobRetention,
+    /// Retries of the backend read calls (pool index, roots, mined transactions) that failed,
+    /// e.g. because of a network error. Sending a transaction is never retried this way.
+    pub rpc_retry: RetryPolicy,
+    /// Wait until a sent transaction is mined before updating the permanent state.
+    pub wait_for_confirmation: bool,
+    /// The job fails if its transaction is not mined in time.
+    pub confirmation_timeout_secs: u64,
+    /// Directory of the tree, transaction and failed job storages, see `storage_dir`.
+    pub storage_dir: PathBuf,
+    /// Locations of the proving parameters and verification keys (`PARAMS_*`).
+    pub params: crate::params::Config,
+    /// Audit log of state-mutating operations (`AUDIT_LOG_*`).
+    pub audit: crate::audit::Config,
+    /// Serve a Swagger UI for `/openapi.json` at `/docs`.
+    pub openapi_ui: bool,
+    /// Bearer token for `/internal/optimistic-log`, shared by a primary and its standby. The log
+    /// is not served if not set.
+    pub peer_token: Option<Secret>,
+    /// Run as a standby of the relayer whose admin routes are served at this URL, see
+    /// `peer_sync`.
+    pub standby_primary_url: Option<String>,
+    pub standby_sync_interval_ms: u64,
+    /// TTL of the single-instance lock that the relayer holds in the Redis of the job queue,
+    /// see `instance_lock`.
+    pub instance_lock_ttl_ms: u64,
+    /// Run as a read-only replica (`REPLICA_*`) instead of failing if another instance holds the
+    /// lock.
+    pub instance_lock_fallback_replica: bool,
 }
 
 impl Config {
@@ -40,7 +339,7 @@ impl Config {
         let backend_name = std::env::var("BACKEND")?;
 
         let backend = match backend_name.as_str() {
-            "mock" => BackendKind::Mock,
+            "mock" => BackendKind::Mock(prefixed_config("MOCK")?),
             #[cfg(feature = "evm_backend")]
             "evm" => BackendKind::Evm(prefixed_config("EVM")?),
             #[cfg(feature = "near_backend")]
@@ -50,18 +349,438 @@ impl Config {
             _ => panic!("Unknown backend: {backend_name}"),
         };
 
+        let mock_prover = std::env::var("MOCK_PROVER")
+            .map(|var| var.parse::<bool>())
+            .unwrap_or(Ok(false))?;
+        // Transfer proofs aren't verified either, which is only safe without a real pool.
+        if mock_prover && !matches!(backend, BackendKind::Mock(_)) {
+            return Err(anyhow!("MOCK_PROVER requires BACKEND=mock"));
+        }
+
+        let listen = match std::env::var("LISTEN") {
+            Ok(var) => parse_listen_addrs(&var)?,
+            Err(_) => {
+                let port: u16 = std::env::var("PORT")?.parse()?;
+                vec![ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], port)))]
+            }
+        };
+
         Ok(Config {
-            port: std::env::var("PORT")?.parse()?,
-            redis_url: std::env::var("REDIS_URL")?,
-            fee: std::env::var("FEE")?.parse()?,
-            mock_prover: std::env::var("MOCK_PROVER")
+            listen,
+            admin_listen: std::env::var("ADMIN_LISTEN")
+                .map(|var| parse_listen_addrs(&var))
+                .unwrap_or(Ok(Vec::new()))?,
+            cors_allowed_origins: std::env::var("CORS_ALLOWED_ORIGINS")
+                .map(|var| var.parse::<CorsOrigins>())
+                .unwrap_or(Ok(CorsOrigins::List(Vec::new())))?,
+            max_body_size: std::env::var("MAX_BODY_SIZE")
+                .map(|var| var.parse::<usize>())
+                .unwrap_or(Ok(1024 * 1024))?,
+            slow_request_threshold_ms: std::env::var("SLOW_REQUEST_THRESHOLD_MS")
+                .ok()
+                .map(|var| var.parse::<u64>())
+                .transpose()?,
+            rate_limit_trust_forwarded_for: std::env::var("RATE_LIMIT_TRUST_FORWARDED_FOR")
+                .map(|var| var.parse::<bool>())
+                .unwrap_or(Ok(false))?,
+            api_keys: std::env::var("API_KEYS")
+                .map(|var| {
+                    var.split(',')
+                        .map(str::trim)
+                        .filter(|key| !key.is_empty())
+                        .map(|key| Secret(key.to_owned()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            api_keys_protect_reads: std::env::var("API_KEYS_PROTECT_READS")
+                .map(|var| var.parse::<bool>())
+                .unwrap_or(Ok(false))?,
+            job_queue: JobQueueKind::new(
+                std::env::var("JOB_QUEUE_KIND").ok().as_deref(),
+                std::env::var("REDIS_URL").ok(),
+                std::env::var("JOB_QUEUE_PERSISTENT")
+                    .map(|var| var.parse::<bool>())
+                    .unwrap_or(Ok(false))?,
+            )?,
+            settings: RuntimeSettings::from_env()?,
+            pool_id: std::env::var("POOL_ID")
+                .map_err(|_| anyhow!("POOL_ID must be set"))?
+                .parse()?,
+            max_tx_index_lag: std::env::var("MAX_TX_INDEX_LAG")
+                .ok()
+                .map(|var| var.parse::<u64>())
+                .transpose()?,
+            mock_prover,
+            dry_run: std::env::var("DRY_RUN")
+                .map(|var| var.parse::<bool>())
+                .unwrap_or(Ok(false))?,
+            admin_token: std::env::var("ADMIN_TOKEN").ok().map(Secret),
+            maintenance_interval_secs: std::env::var("MAINTENANCE_INTERVAL_SECS")
+                .map(|var| var.parse::<u64>())
+                .unwrap_or(Ok(60 * 60))?,
+            roots_retention: std::env::var("ROOTS_RETENTION")
+                .ok()
+                .map(|var| var.parse::<u64>())
+                .transpose()?,
+            reorg_check_interval_secs: std::env::var("REORG_CHECK_INTERVAL_SECS")
+                .map(|var| var.parse::<u64>())
+                .unwrap_or(Ok(15))?,
+            root_check_interval_secs: Some(
+                std::env::var("ROOT_CHECK_INTERVAL_SECS")
+                    .map(|var| var.parse::<u64>())
+                    .unwrap_or(Ok(60))?,
+            )
+            .filter(|&secs| secs > 0),
+            failed_jobs_max_count: std::env::var("FAILED_JOBS_MAX_COUNT")
+                .map(|var| var.parse::<u64>())
+                .unwrap_or(Ok(1000))?,
+            failed_jobs_max_age_secs: std::env::var("FAILED_JOBS_MAX_AGE_SECS")
+                .map(|var| var.parse::<u64>())
+                .unwrap_or(Ok(60 * 60 * 24 * 30))?,
+            replica_urls: std::env::var("REPLICA_URLS")
+                .map(|var| {
+                    var.split(',')
+                        .map(str::trim)
+                        .filter(|url| !url.is_empty())
+                        .map(str::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            max_concurrent_jobs: std::env::var("MAX_CONCURRENT_JOBS")
+                .ok()
+                .map(|var| var.parse::<usize>())
+                .transpose()?
+                .filter(|&limit| limit > 0),
+            job_retry: RetryPolicy {
+                max_attempts: std::env::var("JOB_MAX_ATTEMPTS")
+                    .map(|var| var.parse::<u64>())
+                    .unwrap_or(Ok(3))?,
+                initial_backoff: Duration::from_millis(
+                    std::env::var("JOB_RETRY_BACKOFF_MS")
+                        .map(|var| var.parse::<u64>())
+                        .unwrap_or(Ok(5000))?,
+                ),
+                max_backoff: Duration::from_millis(
+                    std::env::var("JOB_RETRY_MAX_BACKOFF_MS")
+                        .map(|var| var.parse::<u64>())
+                        .unwrap_or(Ok(60 * 1000))?,
+                ),
+            },
+            job_retention: JobRetention {
+                status_ttl: Duration::from_secs(
+                    std::env::var("JOB_STATUS_TTL_SECS")
+                        .map(|var| var.parse::<u64>())
+                        .unwrap_or(Ok(JobRetention::default().status_ttl.as_secs()))?,
+                ),
+                max_history: std::env::var("JOB_HISTORY_MAX_COUNT")
+                    .map(|var| var.parse::<u64>())
+                    .unwrap_or(Ok(JobRetention::default().max_history))?,
+            },
+            rpc_retry: RetryPolicy {
+                max_attempts: std::env::var("RPC_MAX_ATTEMPTS")
+                    .map(|var| var.parse::<u64>())
+                    .unwrap_or(Ok(3))?,
+                initial_backoff: Duration::from_millis(
+                    std::env::var("RPC_RETRY_BACKOFF_MS")
+                        .map(|var| var.parse::<u64>())
+                        .unwrap_or(Ok(500))?,
+                ),
+                max_backoff: Duration::from_millis(
+                    std::env::var("RPC_RETRY_MAX_BACKOFF_MS")
+                        .map(|var| var.parse::<u64>())
+                        .unwrap_or(Ok(5000))?,
+                ),
+            },
+            wait_for_confirmation: std::env::var("WAIT_FOR_CONFIRMATION")
+                .map(|var| var.parse::<bool>())
+                .unwrap_or(Ok(false))?,
+            confirmation_timeout_secs: std::env::var("CONFIRMATION_TIMEOUT_SECS")
+                .map(|var| var.parse::<u64>())
+                .unwrap_or(Ok(10 * 60))?,
+            storage_dir: storage_dir(),
+            params: prefixed_config("PARAMS")?,
+            audit: prefixed_config("AUDIT_LOG")?,
+            openapi_ui: std::env::var("OPENAPI_UI")
+                .map(|var| var.parse::<bool>())
+                .unwrap_or(Ok(false))?,
+            peer_token: std::env::var("PEER_TOKEN").ok().map(Secret),
+            standby_primary_url: std::env::var("STANDBY_PRIMARY_URL").ok(),
+            standby_sync_interval_ms: std::env::var("STANDBY_SYNC_INTERVAL_MS")
+                .map(|var| var.parse::<u64>())
+                .unwrap_or(Ok(1000))?,
+            instance_lock_ttl_ms: std::env::var("INSTANCE_LOCK_TTL_MS")
+                .map(|var| var.parse::<u64>())
+                .unwrap_or(Ok(30 * 1000))?,
+            instance_lock_fallback_replica: std::env::var("INSTANCE_LOCK_FALLBACK_REPLICA")
                 .map(|var| var.parse::<bool>())
                 .unwrap_or(Ok(false))?,
             backend,
         })
     }
+
+    /// A relayer with the mock backend, the in-memory job queue and the mock prover, listening
+    /// on a random local port and keeping its files in `storage_dir`. For tests and examples,
+    /// see [`crate::run`].
+    pub fn mock(storage_dir: impl Into<PathBuf>) -> Self {
+        let storage_dir = storage_dir.into();
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+        };
+
+        Config {
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)))],
+            admin_listen: Vec::new(),
+            cors_allowed_origins: CorsOrigins::List(Vec::new()),
+            max_body_size: 1024 * 1024,
+            slow_request_threshold_ms: None,
+            rate_limit_trust_forwarded_for: false,
+            api_keys: Vec::new(),
+            api_keys_protect_reads: false,
+            backend: BackendKind::Mock(crate::backend::mock::Config {
+                send_latency_ms: 0,
+                mining_delay_ms: 0,
+                fail_every_n: None,
+                fail_indices: Vec::new(),
+                transient_failures: 0,
+            }),
+            job_queue: JobQueueKind::Memory,
+            settings: RuntimeSettings {
+                fee: FeeModel::Flat(0),
+                rate_limit_per_minute: None,
+                max_pending_jobs: None,
+            },
+            pool_id: 0,
+            max_tx_index_lag: None,
+            mock_prover: true,
+            dry_run: false,
+            admin_token: None,
+            maintenance_interval_secs: 60 * 60,
+            roots_retention: None,
+            reorg_check_interval_secs: 15,
+            root_check_interval_secs: None,
+            failed_jobs_max_count: 1000,
+            failed_jobs_max_age_secs: 60 * 60 * 24 * 30,
+            replica_urls: Vec::new(),
+            max_concurrent_jobs: None,
+            job_retry: retry.clone(),
+            job_retention: JobRetention::default(),
+            rpc_retry: retry,
+            wait_for_confirmation: false,
+            confirmation_timeout_secs: 10 * 60,
+            params: Default::default(),
+            audit: crate::audit::Config {
+                path: storage_dir.join("audit.jsonl"),
+                fsync: crate::audit::FsyncPolicy::Never,
+                ..Default::default()
+            },
+            storage_dir,
+            openapi_ui: false,
+            peer_token: None,
+            standby_primary_url: None,
+            standby_sync_interval_ms: 1000,
+            instance_lock_ttl_ms: 30 * 1000,
+            instance_lock_fallback_replica: false,
+        }
+    }
+
+    /// Path of a storage file in `storage_dir`.
+    pub fn storage_path(&self, file_name: &str) -> String {
+        self.storage_dir
+            .join(file_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// `STORAGE_DIR`, the working directory if not set.
+pub fn storage_dir() -> PathBuf {
+    std::env::var_os("STORAGE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Parses a comma-separated list of listen addresses. At least one address is required.
+pub fn parse_listen_addrs(s: &str) -> Result<Vec<ListenAddr>> {
+    let addrs = s
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(ListenAddr::from_str)
+        .collect::<Result<Vec<_>>>()?;
+
+    if addrs.is_empty() {
+        return Err(anyhow!("No listen address in {s:?}"));
+    }
+
+    Ok(addrs)
 }
 
 fn prefixed_config<T: DeserializeOwned>(prefix: &str) -> Result<T> {
     Ok(envy::prefixed(format!("{prefix}_")).from_env()?)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addrs() {
+        let addrs =
+            parse_listen_addrs("0.0.0.0:3000, unix:/run/relayer.sock,127.0.0.1:9100").unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                ListenAddr::Tcp("0.0.0.0:3000".parse().unwrap()),
+                ListenAddr::Unix(PathBuf::from("/run/relayer.sock")),
+                ListenAddr::Tcp("127.0.0.1:9100".parse().unwrap()),
+            ]
+        );
+
+        assert!(parse_listen_addrs("unix:").is_err());
+        assert!(parse_listen_addrs("localhost").is_err());
+        assert!(parse_listen_addrs("").is_err());
+        assert!(parse_listen_addrs(" , ").is_err());
+    }
+
+    #[test]
+    fn test_parse_cors_origins() {
+        assert_eq!("*".parse::<CorsOrigins>().unwrap(), CorsOrigins::Any);
+        assert_eq!(
+            "".parse::<CorsOrigins>().unwrap(),
+            CorsOrigins::List(vec![])
+        );
+        assert_eq!(
+            "https://wallet.example, http://localhost:8080"
+                .parse::<CorsOrigins>()
+                .unwrap(),
+            CorsOrigins::List(vec![
+                HeaderValue::from_static("https://wallet.example"),
+                HeaderValue::from_static("http://localhost:8080"),
+            ])
+        );
+
+        assert!("https://wallet.example,*".parse::<CorsOrigins>().is_err());
+    }
+
+    #[test]
+    fn test_fee_model() {
+        assert_eq!(FeeModel::new(100, None).unwrap(), FeeModel::Flat(100));
+        assert_eq!(
+            FeeModel::new(100, Some(30)).unwrap(),
+            FeeModel::Percentage { bps: 30, min: 100 }
+        );
+        assert!(FeeModel::new(100, Some(10_001)).is_err());
+
+        let flat = FeeModel::Flat(100);
+        assert_eq!(flat.required_fee(0), 100);
+        assert_eq!(flat.required_fee(1_000_000), 100);
+
+        let percentage = FeeModel::Percentage { bps: 30, min: 100 };
+        assert_eq!(percentage.required_fee(0), 100);
+        assert_eq!(percentage.required_fee(10_000), 100);
+        assert_eq!(percentage.required_fee(1_000_000), 3_000);
+        // Rounded up
+        assert_eq!(percentage.required_fee(1_000_001), 3_001);
+
+        let full = FeeModel::Percentage {
+            bps: 10_000,
+            min: 0,
+        };
+        assert_eq!(full.required_fee(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn test_runtime_settings_json() {
+        let settings: RuntimeSettings = serde_json::from_str(
+            r#"{"fee":{"percentage":{"bps":30,"min":100}},"rateLimitPerMinute":60,"maxPendingJobs":null}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            RuntimeSettings {
+                fee: FeeModel::Percentage { bps: 30, min: 100 },
+                rate_limit_per_minute: Some(60),
+                max_pending_jobs: None,
+            }
+        );
+        settings.validate().unwrap();
+
+        let flat: RuntimeSettings = serde_json::from_str(r#"{"fee":{"flat":100}}"#).unwrap();
+        assert_eq!(flat.fee, FeeModel::Flat(100));
+        assert_eq!(flat.rate_limit_per_minute, None);
+
+        let invalid = |json: &str| {
+            serde_json::from_str::<RuntimeSettings>(json)
+                .map_err(anyhow::Error::from)
+                .and_then(|settings| settings.validate())
+                .is_err()
+        };
+        assert!(invalid(r#"{"fee":{"percentage":{"bps":10001,"min":0}}}"#));
+        assert!(invalid(r#"{"fee":{"flat":100},"rateLimitPerMinute":0}"#));
+        assert!(invalid(r#"{"fee":{"flat":100},"feePercentageBps":30}"#));
+        assert!(invalid(r#"{"fee":100}"#));
+    }
+
+    #[test]
+    fn test_runtime_settings_from_vars() {
+        let vars = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+                .collect::<HashMap<_, _>>();
+            RuntimeSettings::from_vars(|key| vars.get(key).cloned())
+        };
+
+        assert_eq!(
+            vars(&[("FEE", "100"), ("RATE_LIMIT_PER_MINUTE", "0")]).unwrap(),
+            RuntimeSettings {
+                fee: FeeModel::Flat(100),
+                rate_limit_per_minute: None,
+                max_pending_jobs: None,
+            }
+        );
+        assert_eq!(
+            vars(&[
+                ("FEE", "100"),
+                ("FEE_PERCENTAGE_BPS", "30"),
+                ("MAX_PENDING_JOBS", "10"),
+            ])
+            .unwrap(),
+            RuntimeSettings {
+                fee: FeeModel::Percentage { bps: 30, min: 100 },
+                rate_limit_per_minute: None,
+                max_pending_jobs: Some(10),
+            }
+        );
+        assert!(vars(&[]).is_err());
+        assert!(vars(&[("FEE", "-1")]).is_err());
+    }
+
+    #[test]
+    fn test_job_queue_kind() {
+        let url = || Some("redis://localhost".to_owned());
+        let redis = JobQueueKind::Redis {
+            url: "redis://localhost".to_owned(),
+        };
+
+        assert_eq!(JobQueueKind::new(None, url(), false).unwrap(), redis);
+        assert_eq!(
+            JobQueueKind::new(Some("redis"), url(), true).unwrap(),
+            redis
+        );
+        assert!(JobQueueKind::new(None, None, false).is_err());
+
+        assert_eq!(
+            JobQueueKind::new(Some("memory"), None, false).unwrap(),
+            JobQueueKind::Memory
+        );
+        let err = JobQueueKind::new(Some("memory"), None, true).unwrap_err();
+        assert!(err.to_string().contains("JOB_QUEUE_PERSISTENT"));
+
+        assert!(JobQueueKind::new(Some("rabbitmq"), url(), false).is_err());
+    }
+}
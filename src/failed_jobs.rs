@@ -0,0 +1,206 @@
+use std::marker::PhantomData;
+
+use anyhow::Result;
+use persy::{Persy, PersyId, ValueMode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::job_queue::{Job, JobId};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedJob<D> {
+    pub job: Job<D>,
+    pub error: String,
+    /// Unix timestamp in seconds.
+    pub failed_at: u64,
+}
+
+/// Persistent archive of failed jobs, kept for post-mortems and manual retries.
+pub struct FailedJobStorage<D> {
+    db: Persy,
+    _phantom: PhantomData<D>,
+}
+
+impl<D> FailedJobStorage<D>
+where
+    D: Serialize + DeserializeOwned,
+{
+    pub fn open(path: &str) -> Result<Self> {
+        let db = Persy::open_or_create_with(path, Default::default(), |db| {
+            let mut tx = db.begin()?;
+            tx.create_segment("data")?;
+            tx.create_index::<JobId, PersyId>("keys", ValueMode::Replace)?;
+            tx.prepare()?.commit()?;
+
+            Ok(())
+        })?;
+
+        Ok(Self {
+            db,
+            _phantom: Default::default(),
+        })
+    }
+
+    pub fn add(&self, failed_job: &FailedJob<D>) -> Result<()> {
+        let data = bincode::serialize(failed_job)?;
+
+        let mut tx = self.db.begin()?;
+        if let Some(id) = self.db.one::<JobId, PersyId>("keys", &failed_job.job.id)? {
+            tx.delete("data", &id)?;
+        }
+        let id = tx.insert("data", &data)?;
+        tx.put::<JobId, PersyId>("keys", failed_job.job.id, id)?;
+        tx.prepare()?.commit()?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, job_id: JobId) -> Result<Option<FailedJob<D>>> {
+        let Some(id) = self.db.one::<JobId, PersyId>("keys", &job_id)? else {
+            return Ok(None);
+        };
+
+        match self.db.read("data", &id)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns up to `limit` most recent failed jobs, newest first.
+    pub fn list(&self, limit: usize) -> Result<Vec<FailedJob<D>>> {
+        let mut failed_jobs = Vec::new();
+        for (_, mut id) in self.db.range::<JobId, PersyId, _>("keys", ..)?.rev() {
+            if failed_jobs.len() >= limit {
+                break;
+            }
+
+            // The key and the record are written in one transaction, but a key removed by a
+            // concurrent `remove` can still show up in the range.
+            let Some(id) = id.next() else {
+                continue;
+            };
+            let Some(data) = self.db.read("data", &id)? else {
+                continue;
+            };
+            failed_jobs.push(bincode::deserialize(&data)?);
+        }
+
+        Ok(failed_jobs)
+    }
+
+    pub fn remove(&self, job_id: JobId) -> Result<bool> {
+        let Some(id) = self.db.one::<JobId, PersyId>("keys", &job_id)? else {
+            return Ok(false);
+        };
+
+        let mut tx = self.db.begin()?;
+        tx.remove::<JobId, PersyId>("keys", job_id, None)?;
+        tx.delete("data", &id)?;
+        tx.prepare()?.commit()?;
+
+        Ok(true)
+    }
+
+    /// Removes the oldest records so that at most `max_count` remain, and all records that failed
+    /// before `min_failed_at`. Returns the number of removed records.
+    pub fn prune(&self, max_count: u64, min_failed_at: u64) -> Result<u64> {
+        let keys = self
+            .db
+            .range::<JobId, PersyId, _>("keys", ..)?
+            .filter_map(|(job_id, mut id)| Some((job_id, id.next()?)))
+            .collect::<Vec<_>>();
+        let excess = keys.len().saturating_sub(max_count as usize);
+
+        let mut tx = self.db.begin()?;
+        let mut removed = 0;
+        for (n, (job_id, id)) in keys.into_iter().enumerate() {
+            if n >= excess {
+                let Some(data) = self.db.read("data", &id)? else {
+                    continue;
+                };
+                let failed_job: FailedJob<D> = bincode::deserialize(&data)?;
+                // Job ids grow with time, so the rest of the records are newer.
+                if failed_job.failed_at >= min_failed_at {
+                    break;
+                }
+            }
+
+            tx.remove::<JobId, PersyId>("keys", job_id, None)?;
+            tx.delete("data", &id)?;
+            removed += 1;
+        }
+        tx.prepare()?.commit()?;
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use scopeguard::defer;
+
+    use super::*;
+
+    fn failed_job(id: JobId, failed_at: u64) -> FailedJob<String> {
+        FailedJob {
+            job: Job {
+                id,
+                data: format!("job {id}"),
+            },
+            error: "error".to_owned(),
+            failed_at,
+        }
+    }
+
+    #[test]
+    fn test_failed_jobs_add_list_remove() {
+        const FILE_NAME: &str = "failed_jobs_test_add_list_remove.persy";
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        let storage = FailedJobStorage::<String>::open(FILE_NAME).unwrap();
+        for id in 1..=3 {
+            storage.add(&failed_job(id, 100)).unwrap();
+        }
+
+        let ids = storage
+            .list(2)
+            .unwrap()
+            .into_iter()
+            .map(|failed_job| failed_job.job.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![3, 2]);
+
+        assert_eq!(storage.get(2).unwrap().unwrap().job.data, "job 2");
+        assert!(storage.remove(2).unwrap());
+        assert!(!storage.remove(2).unwrap());
+        assert!(storage.get(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_failed_jobs_prune() {
+        const FILE_NAME: &str = "failed_jobs_test_prune.persy";
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        let storage = FailedJobStorage::<String>::open(FILE_NAME).unwrap();
+        for id in 1..=5 {
+            storage.add(&failed_job(id, id * 10)).unwrap();
+        }
+
+        // By count
+        assert_eq!(storage.prune(4, 0).unwrap(), 1);
+        assert!(storage.get(1).unwrap().is_none());
+
+        // By age
+        assert_eq!(storage.prune(4, 40).unwrap(), 2);
+        let ids = storage
+            .list(10)
+            .unwrap()
+            .into_iter()
+            .map(|failed_job| failed_job.job.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![5, 4]);
+    }
+}
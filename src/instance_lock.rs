@@ -0,0 +1,249 @@
+//! Single-instance lock. Two relayers sharing the storage and the job queue would both pop jobs
+//! and mutate the tree, so the relayer holds `relayer:lock:{pool_id}` in Redis for as long as it
+//! runs. The lock expires if it's not refreshed, so a crashed relayer doesn't block a restart
+//! for longer than `INSTANCE_LOCK_TTL_MS`.
+//!
+//! The storage files are also locked by persy itself, which covers relayers sharing the files
+//! but not the Redis.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use redis::{AsyncCommands, Client, Script};
+use uuid::Uuid;
+
+/// Extends the lock only if it's still held by this instance.
+const REFRESH_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+else
+    return 0
+end
+"#;
+
+/// Removes the lock only if it's still held by this instance.
+const RELEASE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+else
+    return 0
+end
+"#;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Another relayer instance ({holder}) is running, lock {key} is taken")]
+pub struct AlreadyLocked {
+    pub key: String,
+    pub holder: String,
+}
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("Instance lock is lost")]
+pub struct LockLost;
+
+pub struct InstanceLock {
+    client: Client,
+    key: String,
+    instance_id: String,
+    ttl: Duration,
+    /// The lock is held at least until then. Counted from before the last successful
+    /// acquisition or refresh, so that a slow response doesn't extend it.
+    held_until: Mutex<Instant>,
+}
+
+impl InstanceLock {
+    pub async fn acquire(url: &str, pool_id: u64, ttl: Duration) -> Result<Self> {
+        Self::acquire_key(url, format!("relayer:lock:{pool_id}"), ttl).await
+    }
+
+    /// Fails with [`AlreadyLocked`] if another instance holds the lock.
+    pub async fn acquire_key(url: &str, key: String, ttl: Duration) -> Result<Self> {
+        let client = Client::open(url)?;
+        let mut con = client.get_async_connection().await?;
+        let instance_id = Uuid::new_v4().to_string();
+
+        let started = Instant::now();
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(&instance_id)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut con)
+            .await?;
+
+        if acquired.is_none() {
+            let holder: Option<String> = con.get(&key).await?;
+            return Err(AlreadyLocked {
+                key,
+                holder: holder.unwrap_or_else(|| "unknown".to_owned()),
+            }
+            .into());
+        }
+
+        tracing::info!("Acquired instance lock {key} as {instance_id}");
+
+        Ok(Self {
+            client,
+            key,
+            instance_id,
+            ttl,
+            held_until: Mutex::new(started + ttl),
+        })
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    fn held_until(&self) -> Instant {
+        *self.held_until.lock().unwrap()
+    }
+
+    /// Extends the lock by its TTL. Returns `false` if the lock has expired or was taken by
+    /// another instance.
+    pub async fn refresh(&self) -> Result<bool> {
+        let started = Instant::now();
+        let refreshed: i64 = Script::new(REFRESH_SCRIPT)
+            .key(&self.key)
+            .arg(&self.instance_id)
+            .arg(self.ttl.as_millis() as u64)
+            .invoke_async(&mut self.client.get_async_connection().await?)
+            .await?;
+
+        if refreshed == 0 {
+            return Ok(false);
+        }
+
+        *self.held_until.lock().unwrap() = started + self.ttl;
+
+        Ok(true)
+    }
+
+    /// Fails with [`LockLost`] unless the lock is still held by this instance. The lock is
+    /// considered lost once its TTL has passed since the last refresh, even if Redis still has
+    /// it, e.g. after a long pause of the process.
+    pub async fn ensure_held(&self) -> Result<()> {
+        if Instant::now() >= self.held_until() {
+            return Err(LockLost.into());
+        }
+
+        let holder: Option<String> = self
+            .client
+            .get_async_connection()
+            .await?
+            .get(&self.key)
+            .await?;
+        if holder.as_deref() != Some(self.instance_id.as_str()) {
+            return Err(LockLost.into());
+        }
+
+        Ok(())
+    }
+
+    /// Lets another instance start right away instead of waiting for the lock to expire.
+    pub async fn release(&self) -> Result<()> {
+        let _: i64 = Script::new(RELEASE_SCRIPT)
+            .key(&self.key)
+            .arg(&self.instance_id)
+            .invoke_async(&mut self.client.get_async_connection().await?)
+            .await?;
+
+        tracing::info!("Released instance lock {}", self.key);
+
+        Ok(())
+    }
+}
+
+/// Refreshes the lock three times per TTL. Failed refreshes are retried until the lock expires.
+/// Returns [`LockLost`] once the lock is lost, the relayer must stop then.
+pub async fn run(lock: Arc<InstanceLock>) -> Result<()> {
+    let mut interval = tokio::time::interval(lock.ttl() / 3);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        match lock.refresh().await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::error!("Instance lock was taken over or expired");
+                return Err(LockLost.into());
+            }
+            Err(err) if Instant::now() < lock.held_until() => {
+                tracing::warn!("Failed to refresh instance lock: {err:#}");
+            }
+            Err(err) => {
+                tracing::error!("Failed to refresh instance lock before it expired: {err:#}");
+                return Err(LockLost.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REDIS_URL: &str = "redis://localhost:6379";
+
+    fn test_key() -> String {
+        format!("test:{}:relayer:lock", Uuid::new_v4())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_second_instance() -> Result<()> {
+        let key = test_key();
+        let ttl = Duration::from_secs(10);
+
+        let first = InstanceLock::acquire_key(REDIS_URL, key.clone(), ttl).await?;
+        let err = InstanceLock::acquire_key(REDIS_URL, key.clone(), ttl)
+            .await
+            .err()
+            .unwrap();
+        let err = err.downcast_ref::<AlreadyLocked>().unwrap();
+        assert_eq!(err.holder, first.instance_id);
+
+        assert!(first.refresh().await?);
+        first.ensure_held().await?;
+
+        first.release().await?;
+        let second = InstanceLock::acquire_key(REDIS_URL, key, ttl).await?;
+        assert!(first.ensure_held().await.unwrap_err().is::<LockLost>());
+        second.release().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_lost_lock() -> Result<()> {
+        let key = test_key();
+        let ttl = Duration::from_millis(200);
+
+        let first = InstanceLock::acquire_key(REDIS_URL, key.clone(), ttl).await?;
+        first.ensure_held().await?;
+
+        // The process was paused for longer than the TTL and another instance took over.
+        tokio::time::sleep(ttl * 2).await;
+        assert!(first.ensure_held().await.unwrap_err().is::<LockLost>());
+        let second = InstanceLock::acquire_key(REDIS_URL, key, ttl).await?;
+
+        assert!(!first.refresh().await?);
+        assert!(first.ensure_held().await.unwrap_err().is::<LockLost>());
+
+        // Releasing a lost lock doesn't affect the new holder.
+        first.release().await?;
+        second.ensure_held().await?;
+
+        assert!(run(Arc::new(first)).await.unwrap_err().is::<LockLost>());
+        second.release().await?;
+
+        Ok(())
+    }
+}
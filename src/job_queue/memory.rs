@@ -0,0 +1,291 @@
+//! Job queue kept in the relayer process, for deployments without Redis. Jobs, statuses and
+//! mappings are lost on restart.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use axum::async_trait;
+use tokio::sync::Notify;
+
+use super::{
+    unix_timestamp, JobId, JobQueueBackend, JobQueueStats, JobRetention, JobStatus,
+    FAILED_JOBS_WINDOW_SECONDS, MAPPING_RESERVATION_TTL,
+};
+
+#[derive(Default)]
+pub struct MemoryJobQueue {
+    state: Mutex<State>,
+    /// Wakes up the worker waiting in `pop`.
+    pushed: Notify,
+    retention: JobRetention,
+}
+
+#[derive(Default)]
+struct State {
+    job_counter: JobId,
+    jobs: VecDeque<Vec<u8>>,
+    statuses: Expiring<JobId, JobStatus>,
+    senders: Expiring<JobId, String>,
+    tx_hashes: Expiring<JobId, String>,
+    commit_indices: Expiring<JobId, u64>,
+    attempts: Expiring<JobId, u64>,
+    /// `None` if the mapping is only reserved.
+    mappings: Expiring<String, Option<JobId>>,
+    /// Failure timestamps, for the stats.
+    failed_jobs: HashMap<JobId, u64>,
+    /// Ids and creation timestamps, oldest first.
+    history: VecDeque<(JobId, u64)>,
+}
+
+/// Mirrors the expiry of the Redis keys, so that the memory usage stays bounded.
+struct Expiring<K, V>(HashMap<K, (V, Instant)>);
+
+impl<K, V> Default for Expiring<K, V> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<K: Eq + Hash, V> Expiring<K, V> {
+    fn get(&self, key: &K) -> Option<&V> {
+        self.0
+            .get(key)
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(value, _)| value)
+    }
+
+    fn insert(&mut self, key: K, value: V, ttl: Duration) {
+        self.0.insert(key, (value, Instant::now() + ttl));
+    }
+
+    fn values(&self) -> impl Iterator<Item = &V> {
+        let now = Instant::now();
+        self.0
+            .values()
+            .filter(move |(_, expires_at)| *expires_at > now)
+            .map(|(value, _)| value)
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.0.remove(key);
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.0.retain(|_, (_, expires_at)| *expires_at > now);
+    }
+}
+
+impl MemoryJobQueue {
+    pub fn new(retention: JobRetention) -> Self {
+        Self {
+            state: Default::default(),
+            pushed: Default::default(),
+            retention,
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut State) -> T) -> Result<T> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Job queue state is poisoned"))?;
+
+        Ok(f(&mut state))
+    }
+}
+
+#[async_trait]
+impl JobQueueBackend for MemoryJobQueue {
+    async fn next_job_id(&self) -> Result<JobId> {
+        self.with_state(|state| {
+            state.job_counter += 1;
+            state.job_counter
+        })
+    }
+
+    async fn push(&self, job_id: JobId, data: Vec<u8>) -> Result<()> {
+        let ttl = self.retention.status_ttl;
+        self.with_state(|state| {
+            state.statuses.prune();
+            state.senders.prune();
+            state.tx_hashes.prune();
+            state.commit_indices.prune();
+            state.attempts.prune();
+            state.mappings.prune();
+
+            state.jobs.push_back(data);
+            state.statuses.insert(job_id, JobStatus::Pending, ttl);
+
+            let created_at = unix_timestamp();
+            let expired_before = created_at.saturating_sub(ttl.as_secs());
+            state.history.push_back((job_id, created_at));
+            while let Some(&(_, oldest)) = state.history.front() {
+                if oldest >= expired_before
+                    && state.history.len() as u64 <= self.retention.max_history
+                {
+                    break;
+                }
+                state.history.pop_front();
+            }
+        })?;
+        self.pushed.notify_one();
+
+        Ok(())
+    }
+
+    async fn pop(&self) -> Result<Vec<u8>> {
+        loop {
+            // Created before checking the queue, so that a push in between is not missed.
+            let pushed = self.pushed.notified();
+
+            if let Some(data) = self.with_state(|state| state.jobs.pop_front())? {
+                return Ok(data);
+            }
+
+            pushed.await;
+        }
+    }
+
+    async fn queued_jobs(&self) -> Result<Vec<Vec<u8>>> {
+        self.with_state(|state| state.jobs.iter().cloned().collect())
+    }
+
+    async fn remove_queued(&self, jobs: &[Vec<u8>]) -> Result<()> {
+        self.with_state(|state| state.jobs.retain(|data| !jobs.contains(data)))
+    }
+
+    async fn queue_len(&self) -> Result<u64> {
+        self.with_state(|state| state.jobs.len() as u64)
+    }
+
+    async fn status(&self, job_id: JobId) -> Result<Option<JobStatus>> {
+        self.with_state(|state| state.statuses.get(&job_id).copied())
+    }
+
+    async fn set_status(&self, job_id: JobId, status: JobStatus) -> Result<()> {
+        let ttl = self.retention.status_ttl;
+        self.with_state(|state| state.statuses.insert(job_id, status, ttl))
+    }
+
+    async fn sender(&self, job_id: JobId) -> Result<Option<String>> {
+        self.with_state(|state| state.senders.get(&job_id).cloned())
+    }
+
+    async fn set_sender(&self, job_id: JobId, sender: &str) -> Result<()> {
+        let ttl = self.retention.status_ttl;
+        self.with_state(|state| state.senders.insert(job_id, sender.to_owned(), ttl))
+    }
+
+    async fn tx_hash(&self, job_id: JobId) -> Result<Option<String>> {
+        self.with_state(|state| state.tx_hashes.get(&job_id).cloned())
+    }
+
+    async fn set_tx_hash(&self, job_id: JobId, tx_hash: &str) -> Result<()> {
+        let ttl = self.retention.status_ttl;
+        self.with_state(|state| state.tx_hashes.insert(job_id, tx_hash.to_owned(), ttl))
+    }
+
+    async fn commit_index(&self, job_id: JobId) -> Result<Option<u64>> {
+        self.with_state(|state| state.commit_indices.get(&job_id).copied())
+    }
+
+    async fn set_commit_index(&self, job_id: JobId, commit_index: u64) -> Result<()> {
+        let ttl = self.retention.status_ttl;
+        self.with_state(|state| state.commit_indices.insert(job_id, commit_index, ttl))
+    }
+
+    async fn history(
+        &self,
+        status: Option<JobStatus>,
+        before: Option<u64>,
+        limit: u64,
+    ) -> Result<Vec<(JobId, u64)>> {
+        self.with_state(|state| {
+            state
+                .history
+                .iter()
+                .rev()
+                .filter(|&&(_, created_at)| before.map_or(true, |before| created_at < before))
+                .filter(|(id, _)| {
+                    status.map_or(true, |status| state.statuses.get(id) == Some(&status))
+                })
+                .take(limit as usize)
+                .copied()
+                .collect()
+        })
+    }
+
+    async fn record_failed_attempt(&self, job_id: JobId) -> Result<u64> {
+        let ttl = self.retention.status_ttl;
+        self.with_state(|state| {
+            let attempts = state.attempts.get(&job_id).copied().unwrap_or(0) + 1;
+            state.attempts.insert(job_id, attempts, ttl);
+            attempts
+        })
+    }
+
+    async fn record_job_failed(&self, job_id: JobId) -> Result<()> {
+        self.with_state(|state| {
+            state.failed_jobs.insert(job_id, unix_timestamp());
+        })
+    }
+
+    async fn stats(&self) -> Result<JobQueueStats> {
+        let window_start = unix_timestamp().saturating_sub(FAILED_JOBS_WINDOW_SECONDS);
+
+        self.with_state(|state| JobQueueStats {
+            pending: state.jobs.len() as u64,
+            in_progress: state
+                .statuses
+                .values()
+                .filter(|&&status| status == JobStatus::InProgress)
+                .count() as u64,
+            failed_last_24h: state
+                .failed_jobs
+                .values()
+                .filter(|&&failed_at| failed_at >= window_start)
+                .count() as u64,
+        })
+    }
+
+    async fn prune_stats(&self) -> Result<()> {
+        let window_start = unix_timestamp().saturating_sub(FAILED_JOBS_WINDOW_SECONDS);
+
+        self.with_state(|state| {
+            state
+                .failed_jobs
+                .retain(|_, failed_at| *failed_at >= window_start)
+        })
+    }
+
+    async fn mapping(&self, key: &str) -> Result<Option<JobId>> {
+        self.with_state(|state| state.mappings.get(&key.to_owned()).copied().flatten())
+    }
+
+    async fn set_mapping(&self, key: &str, job_id: JobId) -> Result<()> {
+        let ttl = self.retention.status_ttl;
+        self.with_state(|state| state.mappings.insert(key.to_owned(), Some(job_id), ttl))
+    }
+
+    async fn reserve_mapping(&self, key: &str) -> Result<bool> {
+        self.with_state(|state| {
+            let key = key.to_owned();
+            if state.mappings.get(&key).is_some() {
+                return false;
+            }
+
+            state.mappings.insert(key, None, MAPPING_RESERVATION_TTL);
+            true
+        })
+    }
+
+    async fn remove_mapping(&self, key: &str) -> Result<()> {
+        self.with_state(|state| state.mappings.remove(&key.to_owned()))
+    }
+}
@@ -0,0 +1,1098 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use axum::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinHandle};
+
+pub use self::{memory::MemoryJobQueue, redis::RedisJobQueue};
+use crate::{config::JobQueueKind, monitoring};
+
+mod memory;
+mod redis;
+
+const FAILED_JOBS_WINDOW_SECONDS: u64 = 60 * 60 * 24; // 1 day
+/// A reserved mapping expires after this long if the job is never created, e.g. if the relayer
+/// is killed in between.
+const MAPPING_RESERVATION_TTL: Duration = Duration::from_secs(60);
+
+// TODO: Implement a proper job queue/explore limitations of this particular design.
+//       Also, redis or rabbitmq? Redis is not used for anything else in the project, so rabbitmq
+//       might be preferable.
+
+pub type JobId = u64;
+
+pub use zeropool_relayer_client::{JobStatus, JobSummary};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JobQueueStats {
+    pub pending: u64,
+    pub in_progress: u64,
+    pub failed_last_24h: u64,
+}
+
+/// Retries of jobs that failed with a [`Retryable`] error.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u64,
+    /// Doubled after every failed attempt, up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before the next attempt after `attempt` failed attempts.
+    pub fn backoff(&self, attempt: u64) -> Duration {
+        let exp = attempt.saturating_sub(1).min(u32::MAX as u64) as u32;
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(exp))
+            .min(self.max_backoff)
+    }
+}
+
+/// How long jobs are kept track of.
+#[derive(Debug, Clone)]
+pub struct JobRetention {
+    /// Statuses, senders, mappings and the other per-job keys expire after this long without an
+    /// update. Jobs also leave the history once they are older.
+    pub status_ttl: Duration,
+    /// Maximum number of jobs in the history, see [`JobQueue::history`]. The oldest ones are
+    /// dropped first.
+    pub max_history: u64,
+}
+
+impl Default for JobRetention {
+    fn default() -> Self {
+        Self {
+            status_ttl: Duration::from_secs(60 * 60 * 24 * 7), // 1 week
+            max_history: 10_000,
+        }
+    }
+}
+
+/// Context for job errors that are worth retrying, e.g. `err.context(Retryable)`. Other errors
+/// fail the job immediately.
+#[derive(Debug, Clone, Copy)]
+pub struct Retryable;
+
+impl fmt::Display for Retryable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Retryable error")
+    }
+}
+
+/// Context for errors of jobs that expired before they could be done, e.g.
+/// `err.context(Expired)`. The job cleans up after itself, so the error handler is not called.
+#[derive(Debug, Clone, Copy)]
+pub struct Expired;
+
+impl fmt::Display for Expired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Job expired")
+    }
+}
+
+/// Context for errors of jobs that had to stop without touching the relayer state, e.g.
+/// `err.context(Aborted)` once the instance lock is lost. The job fails, but the error handler is
+/// not called, since another instance might be working on the state already.
+#[derive(Debug, Clone, Copy)]
+pub struct Aborted;
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Job aborted")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job<D> {
+    pub id: JobId,
+    pub data: D,
+}
+
+/// Storage of the job queue. Jobs are passed around serialized, so that the implementations
+/// don't depend on the job type.
+#[async_trait]
+pub trait JobQueueBackend: Send + Sync {
+    async fn next_job_id(&self) -> Result<JobId>;
+    /// Appends the job to the queue, marks it as pending and adds it to the history. The history
+    /// is trimmed to the [`JobRetention`] limits.
+    async fn push(&self, job_id: JobId, data: Vec<u8>) -> Result<()>;
+    /// Waits for the next job and removes it from the queue.
+    async fn pop(&self) -> Result<Vec<u8>>;
+    /// Jobs that are yet to be popped, in order.
+    async fn queued_jobs(&self) -> Result<Vec<Vec<u8>>>;
+    /// Removes the given jobs from the queue. Jobs that are already popped are ignored.
+    async fn remove_queued(&self, jobs: &[Vec<u8>]) -> Result<()>;
+    async fn queue_len(&self) -> Result<u64>;
+    async fn status(&self, job_id: JobId) -> Result<Option<JobStatus>>;
+    async fn set_status(&self, job_id: JobId, status: JobStatus) -> Result<()>;
+    async fn sender(&self, job_id: JobId) -> Result<Option<String>>;
+    async fn set_sender(&self, job_id: JobId, sender: &str) -> Result<()>;
+    async fn tx_hash(&self, job_id: JobId) -> Result<Option<String>>;
+    async fn set_tx_hash(&self, job_id: JobId, tx_hash: &str) -> Result<()>;
+    async fn commit_index(&self, job_id: JobId) -> Result<Option<u64>>;
+    async fn set_commit_index(&self, job_id: JobId, commit_index: u64) -> Result<()>;
+    /// Ids and creation timestamps of the jobs in the history created before `before` (unix
+    /// timestamp in seconds, exclusive), newest first, at most `limit` of them. Only the jobs
+    /// with `status`, if it's set.
+    async fn history(
+        &self,
+        status: Option<JobStatus>,
+        before: Option<u64>,
+        limit: u64,
+    ) -> Result<Vec<(JobId, u64)>>;
+    /// Returns the number of failed attempts of the job so far.
+    async fn record_failed_attempt(&self, job_id: JobId) -> Result<u64>;
+    async fn record_job_failed(&self, job_id: JobId) -> Result<()>;
+    /// Jobs in progress are counted by their status.
+    async fn stats(&self) -> Result<JobQueueStats>;
+    /// Forgets the failures that fell out of the stats window.
+    async fn prune_stats(&self) -> Result<()>;
+    /// Returns `None` if there is no mapping or if it's only reserved.
+    async fn mapping(&self, key: &str) -> Result<Option<JobId>>;
+    async fn set_mapping(&self, key: &str, job_id: JobId) -> Result<()>;
+    /// Atomically reserves the mapping for [`MAPPING_RESERVATION_TTL`]. Returns `false` if it's
+    /// already reserved or set.
+    async fn reserve_mapping(&self, key: &str) -> Result<bool>;
+    async fn remove_mapping(&self, key: &str) -> Result<()>;
+}
+
+pub struct JobQueue<D, C> {
+    backend: Arc<dyn JobQueueBackend>,
+    _phantom: std::marker::PhantomData<(D, C)>,
+}
+
+impl<D, C> JobQueue<D, C>
+where
+    D: Clone + Serialize + DeserializeOwned + Send + 'static,
+    C: Send + Sync + 'static,
+{
+    pub fn new(kind: &JobQueueKind, retention: JobRetention) -> Result<Self> {
+        let backend: Arc<dyn JobQueueBackend> = match kind {
+            JobQueueKind::Redis { url } => Arc::new(RedisJobQueue::new(url, retention)?),
+            JobQueueKind::Memory => Arc::new(MemoryJobQueue::new(retention)),
+        };
+
+        Ok(Self::with_backend(backend))
+    }
+
+    pub fn with_backend(backend: Arc<dyn JobQueueBackend>) -> Self {
+        Self {
+            backend,
+            _phantom: Default::default(),
+        }
+    }
+
+    /// `err_f` is called once the job has failed with a non-retryable error or has run out of
+    /// attempts.
+    ///
+    /// Each job holds one of `permits` from before it's popped until it's done, including retries
+    /// and `err_f`. The jobs beyond the limit stay in the queue meanwhile, where they can still be
+    /// cancelled and survive a restart with the Redis queue. Since the permits are taken in queue
+    /// order, the earliest unfinished job always has one and a job waiting for its turn can't
+    /// starve the jobs before it.
+    pub fn start<F, ErrF, Fut, ErrFut>(
+        &self,
+        ctx: Arc<C>,
+        retry: RetryPolicy,
+        permits: Arc<Semaphore>,
+        f: F,
+        err_f: ErrF,
+    ) -> Result<JoinHandle<Result<()>>>
+    where
+        Fut: Future<Output = Result<()>> + Send + 'static,
+        ErrFut: Future<Output = Result<()>> + Send + 'static,
+        F: Fn(Job<D>, Arc<C>) -> Fut + Clone + Send + Sync + 'static,
+        ErrF: Fn(Job<D>, String, Arc<C>) -> ErrFut + Clone + Send + Sync + 'static,
+    {
+        let backend = self.backend.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                let permit = permits.clone().acquire_owned().await?;
+                let data = backend.pop().await?;
+                let job: Job<D> = bincode::deserialize(&data)?;
+                let job_id = job.id;
+
+                if backend.status(job_id).await? == Some(JobStatus::Cancelled) {
+                    tracing::info!("Job {job_id} is cancelled, skipping");
+                    continue;
+                }
+
+                backend.set_status(job_id, JobStatus::InProgress).await?;
+
+                let backend = backend.clone();
+                let f = f.clone();
+                let ctx = ctx.clone();
+                let err_f = err_f.clone();
+                let retry = retry.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let started = Instant::now();
+                    let res = loop {
+                        let res = f(job.clone(), ctx.clone()).await;
+                        let Err(err) = &res else { break res };
+                        if !err.is::<Retryable>() {
+                            break res;
+                        }
+
+                        let attempts = match backend.record_failed_attempt(job_id).await {
+                            Ok(attempts) => attempts,
+                            Err(err) => {
+                                tracing::error!("Failed to record job attempt: {err}");
+                                break res;
+                            }
+                        };
+                        if attempts >= retry.max_attempts {
+                            tracing::warn!("Job {job_id} failed after {attempts} attempts");
+                            break res;
+                        }
+
+                        let backoff = retry.backoff(attempts);
+                        tracing::warn!(
+                            "Job {job_id} failed (attempt {attempts}/{}), retrying in {backoff:?}: \
+                             {err:#}",
+                            retry.max_attempts
+                        );
+                        tokio::time::sleep(backoff).await;
+                    };
+                    monitoring::record_job_duration(started.elapsed(), res.is_ok());
+
+                    if res.is_err() {
+                        if let Err(err) = backend.record_job_failed(job_id).await {
+                            tracing::error!("Failed to update job stats: {err}");
+                        }
+                    }
+
+                    match res {
+                        Ok(_) => {
+                            if let Err(err) = backend.set_status(job_id, JobStatus::Completed).await
+                            {
+                                tracing::error!("Failed to set job status: {err}");
+                            }
+
+                            tracing::info!("Job {} done", job_id);
+                        }
+                        Err(e) if e.is::<Expired>() => {
+                            if let Err(err) = backend.set_status(job_id, JobStatus::Expired).await {
+                                tracing::error!("Failed to set job status: {err}");
+                            }
+
+                            tracing::warn!("Job {job_id} expired: {}", e.root_cause());
+                        }
+                        Err(e) if e.is::<Aborted>() => {
+                            if let Err(err) = backend.set_status(job_id, JobStatus::Failed).await {
+                                tracing::error!("Failed to set job status: {err}");
+                            }
+
+                            tracing::error!("Job {job_id} aborted: {}", e.root_cause());
+                        }
+                        Err(e) => {
+                            let res = err_f(job, format!("{e:#}"), ctx.clone()).await;
+                            if let Err(err) = res {
+                                tracing::error!("Error handling failed for job {job_id}: {err}");
+                            }
+
+                            // A cancelled job fails before sending its transaction, keep the
+                            // more specific status.
+                            let cancelled = matches!(
+                                backend.status(job_id).await,
+                                Ok(Some(JobStatus::Cancelled))
+                            );
+                            if cancelled {
+                                tracing::info!("Job {job_id} cancelled: {e}");
+                            } else {
+                                if let Err(err) =
+                                    backend.set_status(job_id, JobStatus::Failed).await
+                                {
+                                    tracing::error!("Failed to set job status: {err}");
+                                }
+
+                                tracing::error!("Job {job_id} failed: {e}");
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(handle)
+    }
+
+    pub async fn push(&self, msg: D) -> Result<JobId> {
+        let job_id = self.backend.next_job_id().await?;
+
+        let job = Job {
+            id: job_id,
+            data: msg,
+        };
+
+        self.backend.push(job_id, bincode::serialize(&job)?).await?;
+
+        tracing::debug!("New job {}", job_id);
+
+        Ok(job_id)
+    }
+
+    pub async fn queue_len(&self) -> Result<u64> {
+        self.backend.queue_len().await
+    }
+
+    /// `in_progress` counts the jobs with that status, so a job whose worker was killed is
+    /// counted until its status expires.
+    pub async fn stats(&self) -> Result<JobQueueStats> {
+        self.backend.stats().await
+    }
+
+    /// Called by the maintenance task, so that reading the stats doesn't write.
+    pub async fn prune_stats(&self) -> Result<()> {
+        self.backend.prune_stats().await
+    }
+
+    pub async fn wait(&self, job_id: JobId) -> Result<()> {
+        loop {
+            match self.backend.status(job_id).await? {
+                Some(JobStatus::Completed) => return Ok(()),
+                Some(JobStatus::Failed) => anyhow::bail!("Job failed"),
+                Some(JobStatus::Cancelled) => anyhow::bail!("Job cancelled"),
+                Some(JobStatus::Expired) => anyhow::bail!("Job expired"),
+                Some(JobStatus::Pending | JobStatus::InProgress) => {
+                    // TODO: use pub/sub?
+                    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+                }
+                None => anyhow::bail!("Job not found"),
+            }
+        }
+    }
+
+    pub async fn job_status(&self, job_id: JobId) -> Result<Option<JobStatus>> {
+        self.backend.status(job_id).await
+    }
+
+    /// Records the account that sent the job's transaction.
+    pub async fn set_job_sender(&self, job_id: JobId, sender: &str) -> Result<()> {
+        self.backend.set_sender(job_id, sender).await
+    }
+
+    pub async fn job_sender(&self, job_id: JobId) -> Result<Option<String>> {
+        self.backend.sender(job_id).await
+    }
+
+    /// Records the hash of the job's transaction, as formatted by the backend.
+    pub async fn set_job_tx_hash(&self, job_id: JobId, tx_hash: &str) -> Result<()> {
+        self.backend.set_tx_hash(job_id, tx_hash).await
+    }
+
+    /// Records the commit index the job's transaction is prepared for.
+    pub async fn set_job_commit_index(&self, job_id: JobId, commit_index: u64) -> Result<()> {
+        self.backend.set_commit_index(job_id, commit_index).await
+    }
+
+    /// Recent jobs, newest first, optionally only the ones with `status`. Jobs created at or
+    /// after `before` (unix timestamp in seconds) are skipped, so the next page starts before the
+    /// `created_at` of the last job. A full page doesn't end in the middle of a second, unless
+    /// all of its jobs were created in the same one. Jobs whose status has expired are not
+    /// listed.
+    pub async fn history(
+        &self,
+        status: Option<JobStatus>,
+        before: Option<u64>,
+        limit: u64,
+    ) -> Result<Vec<JobSummary>> {
+        let mut page = self.backend.history(status, before, limit).await?;
+        if page.len() as u64 == limit {
+            if let Some(&(_, last)) = page.last() {
+                let rest = page
+                    .iter()
+                    .take_while(|&&(_, created_at)| created_at != last)
+                    .count();
+                if rest > 0 {
+                    page.truncate(rest);
+                }
+            }
+        }
+
+        let mut jobs = Vec::with_capacity(page.len());
+        for (id, created_at) in page {
+            let Some(job_status) = self.backend.status(id).await? else {
+                continue;
+            };
+            // Changed since the page was read
+            if status.map_or(false, |status| status != job_status) {
+                continue;
+            }
+
+            jobs.push(JobSummary {
+                id,
+                status: job_status,
+                created_at,
+                tx_hash: self.backend.tx_hash(id).await?,
+                commit_index: self.backend.commit_index(id).await?,
+            });
+        }
+
+        Ok(jobs)
+    }
+
+    pub async fn is_job_cancelled(&self, job_id: JobId) -> Result<bool> {
+        Ok(self.backend.status(job_id).await? == Some(JobStatus::Cancelled))
+    }
+
+    /// Maps `{namespace}:{key}` to a job id.
+    pub async fn add_job_mapping<T: ToString>(
+        &self,
+        namespace: &str,
+        job_id: JobId,
+        key: T,
+    ) -> Result<()> {
+        self.backend
+            .set_mapping(&mapping_key(namespace, key), job_id)
+            .await
+    }
+
+    /// Returns `None` if there is no mapping or if it's only reserved.
+    pub async fn get_job_mapping<T: ToString>(
+        &self,
+        namespace: &str,
+        key: T,
+    ) -> Result<Option<JobId>> {
+        self.backend.mapping(&mapping_key(namespace, key)).await
+    }
+
+    /// Atomically reserves `{namespace}:{key}` for a job that is yet to be created. Returns
+    /// `false` if the mapping is already reserved or set.
+    pub async fn reserve_job_mapping<T: ToString>(&self, namespace: &str, key: T) -> Result<bool> {
+        self.backend
+            .reserve_mapping(&mapping_key(namespace, key))
+            .await
+    }
+
+    pub async fn remove_job_mapping<T: ToString>(&self, namespace: &str, key: T) -> Result<()> {
+        self.backend
+            .remove_mapping(&mapping_key(namespace, key))
+            .await
+    }
+
+    /// Marks the job as cancelled. A queued job is skipped by the worker, a job in progress stops
+    /// before sending its transaction.
+    pub async fn cancel_job(&self, job_id: JobId) -> Result<()> {
+        self.backend.set_status(job_id, JobStatus::Cancelled).await
+    }
+
+    /// Cancels the queued jobs with ids greater than `job_id` and removes them from the queue.
+    /// Jobs in progress are not affected, use [`Self::cancel_job`] for them. Returns the
+    /// cancelled jobs.
+    pub async fn cancel_jobs_after(&self, job_id: JobId) -> Result<Vec<Job<D>>> {
+        let mut cancelled = Vec::new();
+        for data in self.backend.queued_jobs().await? {
+            let job: Job<D> = bincode::deserialize(&data)?;
+            if job.id > job_id {
+                // Marked first, in case the worker pops the job before it's removed.
+                self.backend
+                    .set_status(job.id, JobStatus::Cancelled)
+                    .await?;
+                cancelled.push((job, data));
+            }
+        }
+
+        let data = cancelled
+            .iter()
+            .map(|(_, data)| data.clone())
+            .collect::<Vec<_>>();
+        self.backend.remove_queued(&data).await?;
+
+        Ok(cancelled.into_iter().map(|(job, _)| job).collect())
+    }
+}
+
+fn mapping_key<T: ToString>(namespace: &str, key: T) -> String {
+    format!("{namespace}:{}", key.to_string())
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use tokio::sync::Semaphore;
+
+    use super::*;
+    use crate::backend::{
+        mock::{self, mock_config, mock_tx, MockBackend},
+        BlockchainBackend, SendError,
+    };
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(25),
+        }
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let retry = retry_policy();
+        let backoffs = (1..=4).map(|i| retry.backoff(i)).collect::<Vec<_>>();
+        assert_eq!(
+            backoffs,
+            [10, 20, 25, 25].map(Duration::from_millis).to_vec()
+        );
+        assert_eq!(retry.backoff(u64::MAX), Duration::from_millis(25));
+    }
+
+    fn unlimited() -> Arc<Semaphore> {
+        Arc::new(Semaphore::new(Semaphore::MAX_PERMITS))
+    }
+
+    fn job_queue<D, C>(backend: &Arc<dyn JobQueueBackend>) -> JobQueue<D, C>
+    where
+        D: Clone + Serialize + DeserializeOwned + Send + 'static,
+        C: Send + Sync + 'static,
+    {
+        JobQueue::with_backend(backend.clone())
+    }
+
+    async fn wait_for_status(
+        queue: &JobQueue<u64, Semaphore>,
+        job_id: JobId,
+        status: JobStatus,
+    ) -> Result<()> {
+        for _ in 0..100 {
+            if queue.job_status(job_id).await? == Some(status) {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        anyhow::bail!("Job {job_id} is not {status:?}")
+    }
+
+    /// Runs the same checks against every implementation. `backend` must return an empty queue
+    /// on each call.
+    async fn job_queue_suite(
+        backend: impl Fn(JobRetention) -> Arc<dyn JobQueueBackend>,
+    ) -> Result<()> {
+        let default = || backend(JobRetention::default());
+        status_transitions(default()).await?;
+        cancel_jobs(default()).await?;
+        job_sender(default()).await?;
+        reserve_job_mapping_race(default()).await?;
+        job_queue_stats(default()).await?;
+        in_progress_stats(default()).await?;
+        job_retry(default()).await?;
+        job_retry_exhausted(default()).await?;
+        job_expired(default()).await?;
+        job_aborted(default()).await?;
+        concurrency_limit(default()).await?;
+        job_history(default()).await?;
+        job_history_trimming(&backend).await?;
+
+        Ok(())
+    }
+
+    async fn status_transitions(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, Semaphore>(&backend);
+        let ok = queue.push(1).await?;
+        let failed = queue.push(2).await?;
+        assert_ne!(ok, failed);
+        assert_eq!(queue.job_status(ok).await?, Some(JobStatus::Pending));
+        assert_eq!(queue.job_status(JobId::MAX).await?, None);
+        assert_eq!(queue.queue_len().await?, 2);
+
+        // Jobs block until released
+        let release = Arc::new(Semaphore::new(0));
+        let _handle = queue.start(
+            release.clone(),
+            retry_policy(),
+            unlimited(),
+            |job, release| async move {
+                let _permit = release.acquire().await?;
+                if job.data == 2 {
+                    anyhow::bail!("Job failed");
+                }
+
+                Ok(())
+            },
+            |_, _, _| async { Ok(()) },
+        )?;
+
+        wait_for_status(&queue, ok, JobStatus::InProgress).await?;
+        wait_for_status(&queue, failed, JobStatus::InProgress).await?;
+        assert_eq!(queue.queue_len().await?, 0);
+
+        release.add_permits(2);
+        queue.wait(ok).await?;
+        assert!(queue.wait(failed).await.is_err());
+        assert_eq!(queue.job_status(ok).await?, Some(JobStatus::Completed));
+        assert_eq!(queue.job_status(failed).await?, Some(JobStatus::Failed));
+        assert!(queue.wait(JobId::MAX).await.is_err());
+
+        Ok(())
+    }
+
+    async fn queued_ids(backend: &Arc<dyn JobQueueBackend>) -> Result<Vec<JobId>> {
+        backend
+            .queued_jobs()
+            .await?
+            .iter()
+            .map(|data| Ok(bincode::deserialize::<Job<u64>>(data)?.id))
+            .collect()
+    }
+
+    async fn cancel_jobs(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, Semaphore>(&backend);
+        let mut ids = Vec::new();
+        for data in 1..=5 {
+            ids.push(queue.push(data).await?);
+        }
+
+        let cancelled = queue.cancel_jobs_after(ids[1]).await?;
+        assert_eq!(
+            cancelled.iter().map(|job| job.id).collect::<Vec<_>>(),
+            ids[2..]
+        );
+        for &id in &ids[..2] {
+            assert_eq!(queue.job_status(id).await?, Some(JobStatus::Pending));
+            assert!(!queue.is_job_cancelled(id).await?);
+        }
+        for &id in &ids[2..] {
+            assert_eq!(queue.job_status(id).await?, Some(JobStatus::Cancelled));
+            assert!(queue.is_job_cancelled(id).await?);
+        }
+        assert_eq!(queued_ids(&backend).await?, ids[..2]);
+        assert_eq!(queue.queue_len().await?, 2);
+        assert!(queue.wait(ids[2]).await.is_err());
+        assert!(!queue.is_job_cancelled(JobId::MAX).await?);
+
+        // A cancelled queued job is skipped by the worker
+        queue.cancel_job(ids[0]).await?;
+        assert_eq!(queued_ids(&backend).await?, ids[..2]);
+
+        // Jobs fail once released, like a cancelled job in progress does
+        let release = Arc::new(Semaphore::new(0));
+        let failures = Arc::new(AtomicU64::new(0));
+        let _handle = queue.start(
+            release.clone(),
+            retry_policy(),
+            unlimited(),
+            |_, release| async move {
+                let _permit = release.acquire().await?;
+                anyhow::bail!("Job cancelled")
+            },
+            {
+                let failures = failures.clone();
+                move |_, _, _| {
+                    failures.fetch_add(1, Ordering::SeqCst);
+                    async { Ok(()) }
+                }
+            },
+        )?;
+
+        wait_for_status(&queue, ids[1], JobStatus::InProgress).await?;
+        assert_eq!(queue.job_status(ids[0]).await?, Some(JobStatus::Cancelled));
+        assert_eq!(queue.queue_len().await?, 0);
+
+        // A job cancelled in progress keeps its status when it fails
+        queue.cancel_job(ids[1]).await?;
+        release.add_permits(1);
+        for _ in 0..100 {
+            if failures.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+        assert_eq!(queue.job_status(ids[1]).await?, Some(JobStatus::Cancelled));
+        assert_eq!(queue.stats().await?.in_progress, 0);
+
+        Ok(())
+    }
+
+    async fn job_sender(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, ()>(&backend);
+        let job_id = queue.push(1).await?;
+
+        assert_eq!(queue.job_sender(job_id).await?, None);
+        queue.set_job_sender(job_id, "0xabc").await?;
+        assert_eq!(queue.job_sender(job_id).await?.as_deref(), Some("0xabc"));
+
+        Ok(())
+    }
+
+    async fn reserve_job_mapping_race(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = Arc::new(job_queue::<String, ()>(&backend));
+        let key = uuid::Uuid::new_v4();
+
+        let handles = (0..16)
+            .map(|_| {
+                let queue = queue.clone();
+                tokio::spawn(async move { queue.reserve_job_mapping("test", key).await })
+            })
+            .collect::<Vec<_>>();
+
+        let mut reserved = 0;
+        for handle in handles {
+            if handle.await?? {
+                reserved += 1;
+            }
+        }
+
+        assert_eq!(reserved, 1);
+        assert_eq!(queue.get_job_mapping("test", key).await?, None);
+
+        queue.add_job_mapping("test", 42, key).await?;
+        assert_eq!(queue.get_job_mapping("test", key).await?, Some(42));
+        assert!(!queue.reserve_job_mapping("test", key).await?);
+
+        queue.remove_job_mapping("test", key).await?;
+        assert_eq!(queue.get_job_mapping("test", key).await?, None);
+        assert!(queue.reserve_job_mapping("test", key).await?);
+
+        Ok(())
+    }
+
+    async fn job_queue_stats(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, ()>(&backend);
+        assert_eq!(queue.stats().await?, JobQueueStats::default());
+
+        let ok = queue.push(1).await?;
+        let failed = queue.push(2).await?;
+        assert_eq!(queue.stats().await?.pending, 2);
+
+        let _handle = queue.start(
+            Arc::new(()),
+            retry_policy(),
+            unlimited(),
+            |job, _| async move {
+                if job.data == 2 {
+                    anyhow::bail!("Job failed");
+                }
+
+                Ok(())
+            },
+            |_, _, _| async { Ok(()) },
+        )?;
+
+        queue.wait(ok).await?;
+        assert!(queue.wait(failed).await.is_err());
+
+        let stats = queue.stats().await?;
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.in_progress, 0);
+        assert_eq!(stats.failed_last_24h, 1);
+
+        Ok(())
+    }
+
+    async fn in_progress_stats(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, ()>(&backend);
+        let job_id = queue.push(1).await?;
+        backend.pop().await?;
+
+        // Counted by the status, there's no counter to drift when a worker dies.
+        backend.set_status(job_id, JobStatus::InProgress).await?;
+        assert_eq!(queue.stats().await?.in_progress, 1);
+        backend.set_status(job_id, JobStatus::Completed).await?;
+        assert_eq!(queue.stats().await?.in_progress, 0);
+
+        backend.record_job_failed(job_id).await?;
+        queue.prune_stats().await?;
+        assert_eq!(queue.stats().await?.failed_last_24h, 1);
+
+        Ok(())
+    }
+
+    async fn job_retry(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        // The first two sends fail with a transient error, the third one goes through.
+        let queue = job_queue::<u64, MockBackend>(&backend);
+        let chain = Arc::new(MockBackend::new(mock::Config {
+            transient_failures: 2,
+            ..mock_config(None, vec![])
+        }));
+        let attempts = Arc::new(AtomicU64::new(0));
+        let failures = Arc::new(AtomicU64::new(0));
+
+        let _handle = queue.start(
+            chain,
+            retry_policy(),
+            unlimited(),
+            {
+                let attempts = attempts.clone();
+                move |_, chain| {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        match chain.send_tx(mock_tx()).await {
+                            Ok(_) => Ok(()),
+                            Err(SendError::Retryable(err)) => Err(err.context(Retryable)),
+                            Err(SendError::Fatal(err)) => Err(err),
+                        }
+                    }
+                }
+            },
+            {
+                let failures = failures.clone();
+                move |_, _, _| {
+                    failures.fetch_add(1, Ordering::SeqCst);
+                    async { Ok(()) }
+                }
+            },
+        )?;
+
+        let job_id = queue.push(1).await?;
+        queue.wait(job_id).await?;
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+
+    async fn job_retry_exhausted(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, AtomicU64>(&backend);
+        let attempts = Arc::new(AtomicU64::new(0));
+        let failures = Arc::new(AtomicU64::new(0));
+
+        let _handle = queue.start(
+            attempts.clone(),
+            retry_policy(),
+            unlimited(),
+            |_, attempts| async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(anyhow::anyhow!("Timeout").context(Retryable))
+            },
+            {
+                let failures = failures.clone();
+                move |_, _, _| {
+                    failures.fetch_add(1, Ordering::SeqCst);
+                    async { Ok(()) }
+                }
+            },
+        )?;
+
+        let job_id = queue.push(1).await?;
+        assert!(queue.wait(job_id).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), retry_policy().max_attempts);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    async fn job_expired(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, Semaphore>(&backend);
+        let failures = Arc::new(AtomicU64::new(0));
+
+        let _handle = queue.start(
+            Arc::new(Semaphore::new(0)),
+            retry_policy(),
+            unlimited(),
+            |_, _| async { Err(anyhow::anyhow!("Stale").context(Expired)) },
+            {
+                let failures = failures.clone();
+                move |_, _, _| {
+                    failures.fetch_add(1, Ordering::SeqCst);
+                    async { Ok(()) }
+                }
+            },
+        )?;
+
+        let job_id = queue.push(1).await?;
+        assert!(queue.wait(job_id).await.is_err());
+        assert_eq!(queue.job_status(job_id).await?, Some(JobStatus::Expired));
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+
+    async fn job_aborted(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, Semaphore>(&backend);
+        let failures = Arc::new(AtomicU64::new(0));
+
+        let _handle = queue.start(
+            Arc::new(Semaphore::new(0)),
+            retry_policy(),
+            unlimited(),
+            |_, _| async { Err(anyhow::anyhow!("Instance lock is lost").context(Aborted)) },
+            {
+                let failures = failures.clone();
+                move |_, _, _| {
+                    failures.fetch_add(1, Ordering::SeqCst);
+                    async { Ok(()) }
+                }
+            },
+        )?;
+
+        let job_id = queue.push(1).await?;
+        assert!(queue.wait(job_id).await.is_err());
+        assert_eq!(queue.job_status(job_id).await?, Some(JobStatus::Failed));
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+
+    async fn job_history(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, ()>(&backend);
+        assert!(queue.history(None, None, 10).await?.is_empty());
+
+        let ids = [
+            queue.push(1).await?,
+            queue.push(2).await?,
+            queue.push(3).await?,
+        ];
+        queue.set_job_commit_index(ids[0], 7).await?;
+        queue.set_job_tx_hash(ids[0], "0xabc").await?;
+
+        let _handle = queue.start(
+            Arc::new(()),
+            retry_policy(),
+            unlimited(),
+            |job, _| async move {
+                if job.data == 2 {
+                    anyhow::bail!("Job failed");
+                }
+
+                Ok(())
+            },
+            |_, _, _| async { Ok(()) },
+        )?;
+        for id in ids {
+            queue.wait(id).await.ok();
+        }
+
+        let jobs = queue.history(None, None, 10).await?;
+        assert_eq!(
+            jobs.iter().map(|job| job.id).collect::<Vec<_>>(),
+            [ids[2], ids[1], ids[0]]
+        );
+        assert_eq!(jobs[1].status, JobStatus::Failed);
+        assert_eq!(jobs[2].status, JobStatus::Completed);
+        assert_eq!(jobs[2].tx_hash.as_deref(), Some("0xabc"));
+        assert_eq!(jobs[2].commit_index, Some(7));
+        assert_eq!(jobs[0].tx_hash, None);
+        assert!(jobs.iter().all(|job| job.created_at > 0));
+
+        // Filtered by status, across pages
+        let completed = queue.history(Some(JobStatus::Completed), None, 1).await?;
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].id, ids[2]);
+        let completed = queue.history(Some(JobStatus::Completed), None, 10).await?;
+        assert_eq!(completed.len(), 2);
+        let failed = queue.history(Some(JobStatus::Failed), None, 1).await?;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].id, ids[1]);
+        assert!(queue
+            .history(Some(JobStatus::Pending), None, 10)
+            .await?
+            .is_empty());
+
+        // Limited and before a timestamp
+        assert_eq!(queue.history(None, None, 2).await?.len(), 2);
+        assert!(queue.history(None, None, 0).await?.is_empty());
+        assert!(queue.history(None, Some(0), 10).await?.is_empty());
+        let created_at = jobs[0].created_at;
+        assert_eq!(
+            queue.history(None, Some(created_at + 1), 10).await?.len(),
+            3
+        );
+
+        Ok(())
+    }
+
+    async fn job_history_trimming(
+        backend: &impl Fn(JobRetention) -> Arc<dyn JobQueueBackend>,
+    ) -> Result<()> {
+        let ids = |jobs: Vec<JobSummary>| jobs.into_iter().map(|job| job.id).collect::<Vec<_>>();
+
+        // By count
+        let queue = job_queue::<u64, ()>(&backend(JobRetention {
+            max_history: 2,
+            ..Default::default()
+        }));
+        let mut pushed = Vec::new();
+        for data in 0..4 {
+            pushed.push(queue.push(data).await?);
+        }
+        assert_eq!(
+            ids(queue.history(None, None, 10).await?),
+            [pushed[3], pushed[2]]
+        );
+        // Only the history is trimmed, the statuses are still there
+        assert_eq!(queue.job_status(pushed[0]).await?, Some(JobStatus::Pending));
+
+        // By age
+        let queue = job_queue::<u64, ()>(&backend(JobRetention {
+            status_ttl: Duration::from_secs(1),
+            ..Default::default()
+        }));
+        let old = queue.push(1).await?;
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(queue.job_status(old).await?, None);
+        let new = queue.push(2).await?;
+        assert_eq!(ids(queue.history(None, None, 10).await?), [new]);
+        assert_eq!(queue.backend.history(None, 0, 10).await?.len(), 1);
+
+        Ok(())
+    }
+
+    async fn concurrency_limit(backend: Arc<dyn JobQueueBackend>) -> Result<()> {
+        let queue = job_queue::<u64, Semaphore>(&backend);
+        let first = queue.push(1).await?;
+        let second = queue.push(2).await?;
+
+        let release = Arc::new(Semaphore::new(0));
+        let _handle = queue.start(
+            release.clone(),
+            retry_policy(),
+            Arc::new(Semaphore::new(1)),
+            |_, release| async move {
+                release.acquire().await?.forget();
+                Ok(())
+            },
+            |_, _, _| async { Ok(()) },
+        )?;
+
+        // The second job stays queued until the first one is done.
+        wait_for_status(&queue, first, JobStatus::InProgress).await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(queue.job_status(second).await?, Some(JobStatus::Pending));
+        assert_eq!(queue.queue_len().await?, 1);
+
+        release.add_permits(1);
+        queue.wait(first).await?;
+        wait_for_status(&queue, second, JobStatus::InProgress).await?;
+        assert_eq!(queue.queue_len().await?, 0);
+
+        release.add_permits(1);
+        queue.wait(second).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_job_queue() -> Result<()> {
+        job_queue_suite(|retention| Arc::new(MemoryJobQueue::new(retention))).await
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_redis_job_queue() -> Result<()> {
+        job_queue_suite(|retention| {
+            let prefix = format!("test:{}:", uuid::Uuid::new_v4());
+            Arc::new(
+                RedisJobQueue::with_prefix("redis://localhost:6379", prefix, retention).unwrap(),
+            )
+        })
+        .await
+    }
+}
@@ -0,0 +1,360 @@
+use std::fmt::Display;
+
+use anyhow::Result;
+use axum::async_trait;
+use redis::{AsyncCommands, Client};
+
+use super::{
+    unix_timestamp, JobId, JobQueueBackend, JobQueueStats, JobRetention, JobStatus,
+    FAILED_JOBS_WINDOW_SECONDS, MAPPING_RESERVATION_TTL,
+};
+
+const JOB_STATUSES: [JobStatus; 6] = [
+    JobStatus::Pending,
+    JobStatus::InProgress,
+    JobStatus::Completed,
+    JobStatus::Failed,
+    JobStatus::Cancelled,
+    JobStatus::Expired,
+];
+
+pub struct RedisJobQueue {
+    client: Client,
+    /// Prepended to all keys. Empty outside of tests.
+    prefix: String,
+    retention: JobRetention,
+}
+
+impl RedisJobQueue {
+    pub fn new(url: &str, retention: JobRetention) -> Result<Self> {
+        Self::with_prefix(url, String::new(), retention)
+    }
+
+    pub fn with_prefix(url: &str, prefix: String, retention: JobRetention) -> Result<Self> {
+        Ok(Self {
+            client: Client::open(url)?,
+            prefix,
+            retention,
+        })
+    }
+
+    /// Expiry of the per-job keys in seconds.
+    fn ttl(&self) -> usize {
+        self.retention.status_ttl.as_secs().max(1) as usize
+    }
+
+    fn key(&self, key: impl Display) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    /// Same as `jobs:by_time`, for the jobs with the given status.
+    fn status_history_key(&self, status: JobStatus) -> String {
+        self.key(format_args!("jobs:by_status:{status:?}"))
+    }
+
+    async fn connection(&self) -> Result<redis::aio::Connection> {
+        Ok(self.client.get_async_connection().await?)
+    }
+}
+
+#[async_trait]
+impl JobQueueBackend for RedisJobQueue {
+    async fn next_job_id(&self) -> Result<JobId> {
+        Ok(self
+            .connection()
+            .await?
+            .incr(self.key("job_counter"), 1)
+            .await?)
+    }
+
+    async fn push(&self, job_id: JobId, data: Vec<u8>) -> Result<()> {
+        let mut con = self.connection().await?;
+        con.rpush(self.key("jobs"), &[data]).await?;
+        con.set_ex(
+            self.key(format_args!("job:{job_id}")),
+            bincode::serialize(&JobStatus::Pending)?,
+            self.ttl(),
+        )
+        .await?;
+
+        let history = self.key("jobs:by_time");
+        let created_at = unix_timestamp();
+        let expired_before = format!(
+            "({}",
+            created_at.saturating_sub(self.retention.status_ttl.as_secs())
+        );
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .zadd(&history, job_id, created_at)
+            .ignore()
+            .zadd(
+                self.status_history_key(JobStatus::Pending),
+                job_id,
+                created_at,
+            )
+            .ignore()
+            .zrembyscore(&history, "-inf", &expired_before)
+            .ignore();
+        for status in JOB_STATUSES {
+            pipe.zrembyscore(self.status_history_key(status), "-inf", &expired_before)
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut con).await?;
+
+        // The oldest jobs over the limit leave the status indices as well.
+        let max_history = self.retention.max_history.min(isize::MAX as u64) as isize;
+        let trimmed: Vec<JobId> = con.zrange(&history, 0, -max_history - 1).await?;
+        if !trimmed.is_empty() {
+            let mut pipe = redis::pipe();
+            pipe.atomic().zrem(&history, &trimmed).ignore();
+            for status in JOB_STATUSES {
+                pipe.zrem(self.status_history_key(status), &trimmed)
+                    .ignore();
+            }
+            pipe.query_async::<_, ()>(&mut con).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn pop(&self) -> Result<Vec<u8>> {
+        loop {
+            let mut con = self.connection().await?;
+
+            if let Ok(Some((_, data))) = con
+                .blpop::<_, Option<(String, Vec<u8>)>>(self.key("jobs"), 0)
+                .await
+            {
+                return Ok(data);
+            }
+        }
+    }
+
+    async fn queued_jobs(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .connection()
+            .await?
+            .lrange(self.key("jobs"), 0, -1)
+            .await?)
+    }
+
+    async fn remove_queued(&self, jobs: &[Vec<u8>]) -> Result<()> {
+        let mut con = self.connection().await?;
+        for data in jobs {
+            con.lrem(self.key("jobs"), 1, data).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn queue_len(&self) -> Result<u64> {
+        Ok(self.connection().await?.llen(self.key("jobs")).await?)
+    }
+
+    async fn status(&self, job_id: JobId) -> Result<Option<JobStatus>> {
+        let status: Option<Vec<u8>> = self
+            .connection()
+            .await?
+            .get(self.key(format_args!("job:{job_id}")))
+            .await?;
+
+        match status {
+            Some(status) => Ok(Some(bincode::deserialize(&status)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Also moves the job to the history index of the new status, if it's still in the history.
+    async fn set_status(&self, job_id: JobId, status: JobStatus) -> Result<()> {
+        let mut con = self.connection().await?;
+        let created_at: Option<u64> = con.zscore(self.key("jobs:by_time"), job_id).await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .set_ex(
+                self.key(format_args!("job:{job_id}")),
+                bincode::serialize(&status)?,
+                self.ttl(),
+            )
+            .ignore();
+        if let Some(created_at) = created_at {
+            for other in JOB_STATUSES.into_iter().filter(|&other| other != status) {
+                pipe.zrem(self.status_history_key(other), job_id).ignore();
+            }
+            pipe.zadd(self.status_history_key(status), job_id, created_at)
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut con).await?;
+
+        Ok(())
+    }
+
+    async fn sender(&self, job_id: JobId) -> Result<Option<String>> {
+        Ok(self
+            .connection()
+            .await?
+            .get(self.key(format_args!("job_sender:{job_id}")))
+            .await?)
+    }
+
+    async fn set_sender(&self, job_id: JobId, sender: &str) -> Result<()> {
+        self.connection()
+            .await?
+            .set_ex(
+                self.key(format_args!("job_sender:{job_id}")),
+                sender,
+                self.ttl(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn tx_hash(&self, job_id: JobId) -> Result<Option<String>> {
+        Ok(self
+            .connection()
+            .await?
+            .get(self.key(format_args!("job_tx_hash:{job_id}")))
+            .await?)
+    }
+
+    async fn set_tx_hash(&self, job_id: JobId, tx_hash: &str) -> Result<()> {
+        self.connection()
+            .await?
+            .set_ex(
+                self.key(format_args!("job_tx_hash:{job_id}")),
+                tx_hash,
+                self.ttl(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn commit_index(&self, job_id: JobId) -> Result<Option<u64>> {
+        Ok(self
+            .connection()
+            .await?
+            .get(self.key(format_args!("job_commit_index:{job_id}")))
+            .await?)
+    }
+
+    async fn set_commit_index(&self, job_id: JobId, commit_index: u64) -> Result<()> {
+        self.connection()
+            .await?
+            .set_ex(
+                self.key(format_args!("job_commit_index:{job_id}")),
+                commit_index,
+                self.ttl(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn history(
+        &self,
+        status: Option<JobStatus>,
+        before: Option<u64>,
+        limit: u64,
+    ) -> Result<Vec<(JobId, u64)>> {
+        let key = match status {
+            Some(status) => self.status_history_key(status),
+            None => self.key("jobs:by_time"),
+        };
+        let max = match before {
+            Some(before) => format!("({before}"),
+            None => "+inf".to_owned(),
+        };
+
+        Ok(self
+            .connection()
+            .await?
+            .zrevrangebyscore_limit_withscores(key, max, "-inf", 0, limit as isize)
+            .await?)
+    }
+
+    async fn record_failed_attempt(&self, job_id: JobId) -> Result<u64> {
+        let mut con = self.connection().await?;
+        let key = self.key(format_args!("job_attempts:{job_id}"));
+        let attempts = con.incr(&key, 1).await?;
+        con.expire(&key, self.ttl()).await?;
+
+        Ok(attempts)
+    }
+
+    async fn record_job_failed(&self, job_id: JobId) -> Result<()> {
+        self.connection()
+            .await?
+            .zadd(self.key("failed_jobs"), job_id, unix_timestamp())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<JobQueueStats> {
+        let mut con = self.connection().await?;
+
+        let pending: u64 = con.llen(self.key("jobs")).await?;
+        let in_progress: u64 = con
+            .zcard(self.status_history_key(JobStatus::InProgress))
+            .await?;
+        let window_start = unix_timestamp().saturating_sub(FAILED_JOBS_WINDOW_SECONDS);
+        let failed_last_24h: u64 = con
+            .zcount(self.key("failed_jobs"), window_start, "+inf")
+            .await?;
+
+        Ok(JobQueueStats {
+            pending,
+            in_progress,
+            failed_last_24h,
+        })
+    }
+
+    async fn prune_stats(&self) -> Result<()> {
+        let window_start = unix_timestamp().saturating_sub(FAILED_JOBS_WINDOW_SECONDS);
+        self.connection()
+            .await?
+            .zrembyscore(self.key("failed_jobs"), "-inf", format!("({window_start}"))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn mapping(&self, key: &str) -> Result<Option<JobId>> {
+        let job_id: Option<Vec<u8>> = self.connection().await?.get(self.key(key)).await?;
+
+        match job_id {
+            Some(job_id) if !job_id.is_empty() => Ok(Some(bincode::deserialize(&job_id)?)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn set_mapping(&self, key: &str, job_id: JobId) -> Result<()> {
+        self.connection()
+            .await?
+            .set_ex(self.key(key), bincode::serialize(&job_id)?, self.ttl())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn reserve_mapping(&self, key: &str) -> Result<bool> {
+        let reserved: Option<String> = redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(Vec::<u8>::new())
+            .arg("NX")
+            .arg("PX")
+            .arg(MAPPING_RESERVATION_TTL.as_millis() as u64)
+            .query_async(&mut self.connection().await?)
+            .await?;
+
+        Ok(reserved.is_some())
+    }
+
+    async fn remove_mapping(&self, key: &str) -> Result<()> {
+        self.connection().await?.del(self.key(key)).await?;
+
+        Ok(())
+    }
+}
@@ -1,119 +1,539 @@
-use std::sync::Arc;
+use std::{
+    io::{BufWriter, Write},
+    ops::Range,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
+    extract::{
+        rejection::JsonRejection,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, Path, Query, State,
+    },
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    middleware,
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{get, post, MethodRouter},
     Json, Router,
 };
 use byteorder::{BigEndian, ReadBytesExt};
+use hyper::{body::Sender, Body};
 #[cfg(feature = "groth16")]
 use libzeropool_rs::libzeropool::fawkes_crypto::backend::bellman_groth16::verifier::verify;
 #[cfg(feature = "plonk")]
 use libzeropool_rs::libzeropool::fawkes_crypto::backend::plonk::verifier::verify;
 use libzeropool_rs::libzeropool::{
-    fawkes_crypto::{engines::U256, ff_uint::Uint},
+    fawkes_crypto::{
+        engines::U256,
+        ff_uint::{Num, Uint},
+    },
     native::tx::parse_delta,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use tokio::{runtime::Handle, sync::broadcast::error::RecvError};
 use tower_http::{
-    cors::{Any, CorsLayer},
+    cors::{AllowOrigin, Any, CorsLayer},
     trace::TraceLayer,
 };
+use utoipa::IntoParams;
+use uuid::Uuid;
+use zeropool_relayer_client::{
+    CiphertextPrefix, CreateTransactionResponse, ErrorResponse, Hex, InfoResponse, JobStatus,
+    JobStatusResponse, JobSummary, RootLogEntry, RootsResponse, ValidateTransactionResponse,
+    ValidationError, VersionResponse,
+};
 use zeropool_tx::TxType;
 
 use crate::{
-    job_queue::JobStatus,
+    api_key::{self, ApiKeys},
+    audit::{AuditEntry, AuditEvent},
+    backend::ParseHashError,
+    build_info,
+    config::{CorsOrigins, FeeModel, RuntimeSettings, Secret},
+    failed_jobs::FailedJob,
+    job_queue::{unix_timestamp, JobId},
+    maintenance,
+    merkle_tree::MerkleTree,
+    monitoring,
+    openapi::{self, TransactionRequest},
+    peer_sync::{self, LogEntry, Promotion},
+    rate_limit,
     state::AppState,
-    tx::{ParsedTxData, ProofWithInputs, TxValidationError},
-    tx_worker::prepare_job,
+    timed_mutex,
+    tx::{MalformedInputs, ParsedTxData, ProofWithInputs, TxEvent, TxValidationError},
+    tx_storage::{FeeRecord, TxStorage, SECONDS_PER_DAY},
+    tx_worker::{prepare_job, Payload, IDEMPOTENCY_MAPPING, INDEX_MAPPING, TX_SIZE},
+    Fr,
 };
 
 pub fn routes(ctx: Arc<AppState>) -> Router {
-    let cors = CorsLayer::new()
-        .allow_headers(Any)
-        .allow_origin(Any)
-        .allow_methods(Any);
+    // Always installed, the limit can be enabled at runtime.
+    let rate_limiter = ctx.rate_limiter.clone();
+    let api_keys = ApiKeys::new(&ctx.config.api_keys).map(Arc::new);
+    let protect_reads = ctx.config.api_keys_protect_reads;
+    let require_api_key =
+        |keys: &Arc<ApiKeys>| middleware::from_fn_with_state(keys.clone(), api_key::middleware);
+
+    // Only transaction submissions are rate limited.
+    let max_body_size = ctx.config.max_body_size;
+    let submission = |route: MethodRouter<Arc<AppState>>| {
+        let route = route.layer(DefaultBodyLimit::max(max_body_size));
+        // With `protect_reads`, the key is checked for the whole router instead.
+        let route = match &api_keys {
+            Some(keys) if !protect_reads => route.layer(require_api_key(keys)),
+            _ => route,
+        };
+        route.layer(middleware::from_fn_with_state(
+            rate_limiter.clone(),
+            rate_limit::middleware,
+        ))
+    };
 
-    Router::new()
+    let router = Router::new()
         .route(
             "/transactions",
-            get(get_transactions).post(create_transaction),
+            get(get_transactions).merge(submission(post(create_transaction))),
+        )
+        .route(
+            "/transactions/validate",
+            submission(post(validate_transaction)),
         )
         .route("/transactions/v2", get(get_transactions_legacy))
+        .route("/ws/transactions", get(transactions_ws))
+        .route("/ciphertexts", get(get_ciphertexts))
         // For compatibility with old API
-        .route("/sendTransactions", post(create_transaction_legacy))
+        .route(
+            "/sendTransactions",
+            submission(post(create_transaction_legacy)),
+        )
+        .route("/jobs", get(jobs))
         .route("/job/:id", get(job))
+        .route("/jobByIndex/:index", get(job_by_index))
+        .route("/roots/:index", get(roots))
         .route("/info", get(info))
-        .layer(TraceLayer::new_for_http())
-        .layer(cors)
-        .with_state(ctx)
+        .route("/version", get(version))
+        .route("/openapi.json", get(openapi::spec));
+
+    let router = if ctx.config.openapi_ui {
+        router.route("/docs", get(openapi::swagger_ui))
+    } else {
+        router
+    };
+
+    let router = match &api_keys {
+        Some(keys) if protect_reads => router.layer(require_api_key(keys)),
+        _ => router,
+    };
+    let router = log_slow_requests(router, ctx.config.slow_request_threshold_ms);
+    let router = router.layer(TraceLayer::new_for_http());
+
+    let router = match cors_layer(&ctx.config.cors_allowed_origins) {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+
+    router.with_state(ctx)
 }
 
-#[derive(Deserialize)]
+/// Returns `None` if cross-origin requests are disabled.
+pub(crate) fn cors_layer(origins: &CorsOrigins) -> Option<CorsLayer> {
+    let allow_origin = match origins {
+        CorsOrigins::Any => AllowOrigin::any(),
+        CorsOrigins::List(origins) if origins.is_empty() => return None,
+        CorsOrigins::List(origins) => AllowOrigin::list(origins.iter().cloned()),
+    };
+
+    Some(
+        CorsLayer::new()
+            .allow_headers(Any)
+            .allow_origin(allow_origin)
+            .allow_methods(Any),
+    )
+}
+
+/// Admin and metrics routes. Served on a separate listener if `ADMIN_LISTEN` is set.
+pub fn admin_routes(ctx: Arc<AppState>) -> Router {
+    let router = Router::new()
+        .route("/admin/compact", post(admin_compact))
+        .route("/admin/resync", post(admin_resync))
+        .route("/admin/tree/export", get(admin_export_tree))
+        .route("/admin/pause", post(admin_pause))
+        .route("/admin/resume", post(admin_resume))
+        .route(
+            "/admin/settings",
+            get(admin_settings).put(admin_update_settings),
+        )
+        .route("/admin/failed-jobs", get(admin_failed_jobs))
+        .route("/admin/failed-jobs/:id", get(admin_failed_job))
+        .route("/admin/failed-jobs/:id/retry", post(admin_retry_failed_job))
+        .route("/admin/keys/:address/disable", post(admin_disable_key))
+        .route("/admin/audit", get(admin_audit))
+        .route("/admin/fees", get(admin_fees))
+        .route("/admin/promote", post(admin_promote))
+        .route("/internal/optimistic-log", get(optimistic_log));
+
+    #[cfg(feature = "metrics")]
+    let router = router.route("/metrics", get(metrics));
+
+    let router = log_slow_requests(router, ctx.config.slow_request_threshold_ms);
+    router.layer(TraceLayer::new_for_http()).with_state(ctx)
+}
+
+fn log_slow_requests(
+    router: Router<Arc<AppState>>,
+    threshold_ms: Option<u64>,
+) -> Router<Arc<AppState>> {
+    match threshold_ms {
+        Some(threshold_ms) => router.layer(middleware::from_fn_with_state(
+            Duration::from_millis(threshold_ms),
+            timed_mutex::slow_request_middleware,
+        )),
+        None => router,
+    }
+}
+
+/// Maximum number of transactions returned by a single `/transactions` request.
+const MAX_TX_LIMIT: u64 = 1000;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct TxPaginationQuery {
+    /// Pool index to start from, 0 by default.
     pub offset: Option<u64>,
+    /// Maximum number of transactions, 100 by default and at most 1000.
     pub limit: Option<u64>,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct CreateTransactionResponse {
-    pub job_id: u64,
+impl TxPaginationQuery {
+    /// Range of pool indices covered by the query.
+    pub fn range(&self) -> Range<u64> {
+        let limit = self.limit.unwrap_or(100).min(MAX_TX_LIMIT);
+        let offset = self.offset.unwrap_or(0);
+
+        offset..offset.saturating_add(limit * TX_SIZE)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Query of `/transactions/v2`, named like in the old relayer.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 #[serde(rename_all = "camelCase")]
-pub struct TxDataRequest {
-    pub tx_type: TxType,
-    pub proof: ProofWithInputs,
-    #[serde(with = "hex")]
-    pub memo: Vec<u8>,
-    #[serde(with = "hex", default)]
-    pub extra_data: Vec<u8>,
+pub struct LegacyTxQuery {
+    /// Number of transactions to skip, 0 by default. Unlike `/transactions`, not a pool index.
+    pub offset: Option<u64>,
+    /// Maximum number of transactions, 100 by default and at most 1000.
+    pub limit: Option<u64>,
+    /// Include the transactions that are not mined yet, `true` by default.
+    pub optimistic_state: Option<bool>,
 }
 
+impl LegacyTxQuery {
+    /// Range of pool indices covered by the query, up to `pool_index` without the optimistic
+    /// state.
+    pub fn range(&self, pool_index: u64) -> Range<u64> {
+        let range = TxPaginationQuery {
+            offset: self.offset.map(|offset| offset.saturating_mul(TX_SIZE)),
+            limit: self.limit,
+        }
+        .range();
+
+        if self.optimistic_state.unwrap_or(true) {
+            range
+        } else {
+            range.start..range.end.min(pool_index).max(range.start)
+        }
+    }
+}
+
+pub type TxDataRequest = zeropool_relayer_client::TxDataRequest<ProofWithInputs>;
+
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Submissions with the same `Idempotency-Key` header (or the same nullifier, if the header is
+/// absent) return the job created by the first one instead of creating a new job. The key is
+/// released once the job fails or is cancelled.
+#[tracing::instrument(skip_all, fields(request_id))]
+#[utoipa::path(
+    post,
+    path = "/transactions",
+    tag = "transactions",
+    request_body = TransactionRequest,
+    params(("Idempotency-Key" = Option<String>, Header)),
+    security((), ("api_key" = [])),
+    responses(
+        (status = 200, body = CreateTransactionResponse),
+        (status = 400, description = "Malformed or invalid transaction", body = ErrorResponse),
+        (status = 401, description = "Missing or unknown API key", body = ErrorResponse),
+        (status = 413, body = ErrorResponse),
+        (status = 429, description = "Rate limit exceeded", body = ErrorResponse),
+        (status = 503, description = "Relayer is paused or the queue is full", body = ErrorResponse),
+    ),
+)]
 async fn create_transaction(
     State(state): State<Arc<AppState>>,
-    Json(tx_data): Json<TxDataRequest>,
+    headers: HeaderMap,
+    payload: Result<Json<TxDataRequest>, JsonRejection>,
 ) -> AppResult<Json<CreateTransactionResponse>> {
-    let mut validation_errors = Vec::new();
+    let Json(tx_data) = payload.map_err(json_rejection)?;
+
+    // Correlates the request with the job that is created for it.
+    let request_id = Uuid::new_v4();
+    tracing::Span::current().record("request_id", tracing::field::display(request_id));
+
+    if !state.accepting.load(Ordering::SeqCst) {
+        return Err(AppError::Paused);
+    }
+
+    if let Some(max_pending_jobs) = state.settings().max_pending_jobs {
+        if state.job_queue.queue_len().await? >= max_pending_jobs {
+            return Err(AppError::QueueFull);
+        }
+    }
+
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) => key
+            .to_str()
+            .map_err(|_| AppError::BadRequest(anyhow!("Invalid {IDEMPOTENCY_KEY_HEADER}")))?
+            .to_owned(),
+        None => tx_data
+            .proof
+            .inputs
+            .get(1)
+            .ok_or_else(|| AppError::BadRequest(anyhow!("Missing nullifier")))?
+            .0
+            .clone(),
+    };
+
+    let job_queue = &state.job_queue;
+    if !job_queue
+        .reserve_job_mapping(IDEMPOTENCY_MAPPING, &idempotency_key)
+        .await?
+    {
+        let Some(job_id) = job_queue
+            .get_job_mapping(IDEMPOTENCY_MAPPING, &idempotency_key)
+            .await?
+        else {
+            return Err(AppError::Conflict(anyhow!(
+                "Transaction is already being processed"
+            )));
+        };
+
+        tracing::info!("Duplicate submission of job {job_id}");
+        return Ok(Json(CreateTransactionResponse { job_id }));
+    }
+
+    match submit_transaction(&state, request_id, tx_data, idempotency_key.clone()).await {
+        Ok(job_id) => {
+            job_queue
+                .add_job_mapping(IDEMPOTENCY_MAPPING, job_id, &idempotency_key)
+                .await?;
+
+            // The job might have failed and released the key before it was set.
+            let status = job_queue.job_status(job_id).await?;
+            if matches!(
+                status,
+                Some(JobStatus::Failed | JobStatus::Cancelled | JobStatus::Expired)
+            ) {
+                job_queue
+                    .remove_job_mapping(IDEMPOTENCY_MAPPING, &idempotency_key)
+                    .await?;
+            }
+
+            Ok(Json(CreateTransactionResponse { job_id }))
+        }
+        Err(err) => {
+            // Allow resubmitting a rejected transaction.
+            if let Err(err) = job_queue
+                .remove_job_mapping(IDEMPOTENCY_MAPPING, &idempotency_key)
+                .await
+            {
+                tracing::warn!("Failed to remove idempotency key: {err}");
+            }
+
+            Err(err)
+        }
+    }
+}
+
+/// Public inputs of the transfer proof: root, nullifier, out commitment, delta, memo hash.
+const TRANSFER_INPUTS: usize = 5;
+/// Well above the memo of a transaction with the maximum number of outputs.
+const MAX_MEMO_SIZE: usize = 64 * 1024;
+const MAX_EXTRA_DATA_SIZE: usize = 4 * 1024;
+
+/// Checks the shape of the request before anything is verified.
+fn parse_tx(tx_data: TxDataRequest) -> AppResult<ParsedTxData> {
+    let mut errors = Vec::new();
+
+    let raw_inputs = tx_data.proof.inputs;
+    if raw_inputs.len() != TRANSFER_INPUTS {
+        errors.push(TxValidationError::MalformedProofInputs(
+            MalformedInputs::WrongCount {
+                expected: TRANSFER_INPUTS,
+                got: raw_inputs.len(),
+            },
+        ));
+    }
+
+    let mut inputs = Vec::with_capacity(raw_inputs.len());
+    for (index, input) in raw_inputs.iter().enumerate() {
+        match input.parse() {
+            Some(input) => inputs.push(input),
+            None => errors.push(TxValidationError::MalformedProofInputs(
+                MalformedInputs::NonCanonical { index },
+            )),
+        }
+    }
+
+    if tx_data.memo.len() > MAX_MEMO_SIZE {
+        errors.push(TxValidationError::MemoTooLong { max: MAX_MEMO_SIZE });
+    }
+    if tx_data.extra_data.len() > MAX_EXTRA_DATA_SIZE {
+        errors.push(TxValidationError::ExtraDataTooLong {
+            max: MAX_EXTRA_DATA_SIZE,
+        });
+    }
 
-    validation_errors.extend(validate_tx(&tx_data, state.as_ref()).await);
+    if !errors.is_empty() {
+        return Err(AppError::TxValidationErrors(errors));
+    }
 
-    let tx = ParsedTxData {
+    Ok(ParsedTxData {
         tx_type: tx_data.tx_type,
         proof: tx_data.proof.proof,
-        delta: tx_data.proof.inputs[3],
-        out_commit: tx_data.proof.inputs[2],
-        nullifier: tx_data.proof.inputs[1],
+        delta: inputs[3],
+        out_commit: inputs[2],
+        nullifier: inputs[1],
+        inputs,
         memo: tx_data.memo,
         extra_data: tx_data.extra_data,
-    };
+    })
+}
+
+async fn submit_transaction(
+    state: &Arc<AppState>,
+    request_id: Uuid,
+    tx_data: TxDataRequest,
+    idempotency_key: String,
+) -> AppResult<JobId> {
+    submit_parsed_transaction(state, request_id, parse_tx(tx_data)?, Some(idempotency_key)).await
+}
 
-    validation_errors.extend(state.backend.validate_tx(&tx).await);
+pub(crate) async fn submit_parsed_transaction(
+    state: &Arc<AppState>,
+    request_id: Uuid,
+    tx: ParsedTxData,
+    idempotency_key: Option<String>,
+) -> AppResult<JobId> {
+    let validation_errors = collect_validation_errors(&tx, state).await;
 
     if !validation_errors.is_empty() {
+        monitoring::record_rejected_tx(&validation_errors);
         return Err(AppError::TxValidationErrors(validation_errors));
     }
 
-    let payload = prepare_job(tx, state.clone()).await?;
+    // Kept for a standby relayer, see `peer_sync`.
+    let out_commit = tx.out_commit;
+    let encoded = state
+        .config
+        .peer_token
+        .is_some()
+        .then(|| bincode::serialize(&tx))
+        .transpose()?;
+
+    let payload = prepare_job(tx, request_id, idempotency_key, state.clone()).await?;
+    let index = payload.pool_index();
+    if let Some(encoded) = encoded {
+        let mined_index = *state.pool_index.read().await;
+        state
+            .optimistic_log
+            .insert(index, out_commit, encoded, mined_index);
+    }
     let job_id = state.job_queue.push(payload).await?;
+    // Updated by the worker if the transaction ends up at another index.
+    state
+        .job_queue
+        .add_job_mapping(INDEX_MAPPING, job_id, index / TX_SIZE)
+        .await?;
+    tracing::info!("Created job {job_id} for request {request_id}");
+    state.audit.record(AuditEvent::TransactionAccepted {
+        job_id,
+        request_id,
+        index,
+    });
+    monitoring::record_accepted_tx();
 
-    Ok(Json(CreateTransactionResponse { job_id }))
+    Ok(job_id)
+}
+
+/// Checks a transaction the same way as `POST /transactions`, but stops before a job is created,
+/// so neither the tree nor the storage nor the job queue is touched.
+#[utoipa::path(
+    post,
+    path = "/transactions/validate",
+    tag = "transactions",
+    request_body = TransactionRequest,
+    security((), ("api_key" = [])),
+    responses(
+        (status = 200, body = ValidateTransactionResponse),
+        (status = 400, description = "Malformed or invalid transaction", body = ErrorResponse),
+        (status = 401, description = "Missing or unknown API key", body = ErrorResponse),
+        (status = 413, body = ErrorResponse),
+        (status = 429, description = "Rate limit exceeded", body = ErrorResponse),
+    ),
+)]
+async fn validate_transaction(
+    State(state): State<Arc<AppState>>,
+    payload: Result<Json<TxDataRequest>, JsonRejection>,
+) -> AppResult<Json<ValidateTransactionResponse>> {
+    let Json(tx_data) = payload.map_err(json_rejection)?;
+    let tx = parse_tx(tx_data)?;
+
+    let validation_errors = collect_validation_errors(&tx, &state).await;
+    if !validation_errors.is_empty() {
+        return Err(AppError::TxValidationErrors(validation_errors));
+    }
+
+    // Submitted transactions are added to the optimistic tree right away.
+    let num_leaves = state.optimistic_tree_state.borrow().1;
+
+    Ok(Json(ValidateTransactionResponse {
+        valid: true,
+        estimated_index: num_leaves * TX_SIZE,
+    }))
 }
 
 #[derive(Serialize, Deserialize)]
 struct TxDataRequestLegacy(Vec<TxDataRequest>);
 
 /// Legacy API compatibility
+#[utoipa::path(
+    post,
+    path = "/sendTransactions",
+    tag = "transactions",
+    request_body = [TransactionRequest],
+    security((), ("api_key" = [])),
+    responses(
+        (status = 200, body = CreateTransactionResponse),
+        (status = 400, description = "Malformed or invalid transaction", body = ErrorResponse),
+        (status = 401, description = "Missing or unknown API key", body = ErrorResponse),
+        (status = 413, body = ErrorResponse),
+        (status = 429, description = "Rate limit exceeded", body = ErrorResponse),
+        (status = 503, description = "Relayer is paused", body = ErrorResponse),
+    ),
+)]
 async fn create_transaction_legacy(
     state: State<Arc<AppState>>,
-    Json(tx_data): Json<TxDataRequestLegacy>,
+    headers: HeaderMap,
+    payload: Result<Json<TxDataRequestLegacy>, JsonRejection>,
 ) -> AppResult<Json<CreateTransactionResponse>> {
+    let Json(tx_data) = payload.map_err(json_rejection)?;
+
     if tx_data.0.len() > 1 {
         return Err(AppError::BadRequest(anyhow!(
             "Can only process one transaction at a time"
@@ -128,50 +548,70 @@ async fn create_transaction_legacy(
             "No transaction data provided"
         )))?;
 
-    create_transaction(state, Json(tx_data)).await
+    create_transaction(state, headers, Ok(Json(tx_data))).await
 }
 
-async fn validate_tx(tx: &TxDataRequest, state: &AppState) -> Vec<TxValidationError> {
+/// All checks of a submission, shared by `POST /transactions` and `POST /transactions/validate`.
+async fn collect_validation_errors(tx: &ParsedTxData, state: &AppState) -> Vec<TxValidationError> {
+    let mut errors = validate_tx(tx, state).await;
+    errors.extend(state.backend.validate_tx(tx).await);
+
+    errors
+}
+
+async fn validate_tx(tx: &ParsedTxData, state: &AppState) -> Vec<TxValidationError> {
     let mut errors = Vec::new();
 
     // TODO: Cache nullifiers
 
+    // Not verified with the mock prover.
     #[cfg(feature = "groth16")]
-    if !verify(
-        &state.groth16_params.transfer_vk,
-        &tx.proof.proof,
-        &tx.proof.inputs,
-    ) {
-        errors.push(TxValidationError::InvalidTransferProof);
+    if let Some(params) = &state.groth16_params {
+        if !verify(&params.transfer_vk, &tx.proof, &tx.inputs) {
+            errors.push(TxValidationError::InvalidTransferProof);
+        }
     }
 
     #[cfg(feature = "plonk")]
-    if !verify(
-        &state.plonk_params.params,
-        &state.plonk_params.transfer_vk,
-        &tx.proof.proof,
-        &tx.proof.inputs,
-    ) {
-        errors.push(TxValidationError::InvalidTransferProof);
+    if let Some(params) = &state.plonk_params {
+        if !verify(&params.params, &params.transfer_vk, &tx.proof, &tx.inputs) {
+            errors.push(TxValidationError::InvalidTransferProof);
+        }
     }
 
+    let (token_amount, energy_amount, transfer_index, pool_id) = parse_delta(tx.delta);
+
     // Should at least contain fee
-    if tx.memo.len() < 8 {
-        errors.push(TxValidationError::EmptyMemo);
+    match (&mut &tx.memo[..]).read_u64::<BigEndian>() {
+        Ok(fee) if fee < required_fee(&state.settings().fee, tx.tx_type, token_amount) => {
+            errors.push(TxValidationError::FeeTooLow)
+        }
+        Ok(_) => {}
+        Err(_) => errors.push(TxValidationError::EmptyMemo),
     }
 
-    let memo_reader = &mut &tx.memo[..];
-    let fee = memo_reader.read_u64::<BigEndian>().unwrap();
-
-    if fee < state.fee {
-        errors.push(TxValidationError::FeeTooLow);
-    }
+    errors.extend(check_pool_id(pool_id, state.config.pool_id));
 
-    let delta = tx.proof.inputs[3];
-    let (token_amount, energy_amount, transfer_index, _pool_id) = parse_delta(delta);
+    // Submitted transactions are added to the optimistic tree right away.
+    let pool_index = *state.pool_index.read().await;
+    let send_index = state.optimistic_tree_state.borrow().1 * TX_SIZE;
+    errors.extend(check_transfer_index(
+        transfer_index,
+        pool_index,
+        send_index,
+        state.config.max_tx_index_lag,
+    ));
 
-    if transfer_index.to_uint().0 > U256::from(*state.pool_index.read().await) {
-        errors.push(TxValidationError::InvalidTxIndex);
+    let pool_root = *state.pool_root.read().await;
+    match check_root(
+        &*state.tree.lock("submit_tx").await,
+        tx.inputs[0],
+        transfer_index,
+        pool_root,
+    ) {
+        Ok(err) => errors.extend(err),
+        // The contract checks the root as well.
+        Err(err) => tracing::warn!("Failed to check the root: {err:#}"),
     }
 
     let token_amount = token_amount.to_uint().0;
@@ -197,26 +637,143 @@ async fn validate_tx(tx: &TxDataRequest, state: &AppState) -> Vec<TxValidationEr
             if is_token_amount_positive || is_energy_amount_positive {
                 errors.push(TxValidationError::InvalidValues);
             }
+
+            if let Err(err) = state.backend.validate_withdraw_address(&tx.memo) {
+                errors.push(err);
+            }
         }
     }
 
     errors
 }
 
-#[derive(Serialize)]
-struct Hex(#[serde(with = "hex")] Vec<u8>);
+/// Transfers don't reveal their amount, so only the minimum of a percentage fee applies to them.
+fn required_fee(model: &FeeModel, tx_type: TxType, token_amount: Num<Fr>) -> u64 {
+    let amount = match tx_type {
+        TxType::Deposit | TxType::Withdraw => {
+            // Withdrawals have a negative amount.
+            let amount = token_amount.to_uint().0.min((-token_amount).to_uint().0);
+            if amount > U256::from(u64::MAX) {
+                u64::MAX
+            } else {
+                amount.low_u64()
+            }
+        }
+        TxType::Transfer => 0,
+    };
+
+    model.required_fee(amount)
+}
+
+/// Proofs made for another deployment of the same circuit would otherwise only fail on chain.
+fn check_pool_id(pool_id: Num<Fr>, expected: u64) -> Option<TxValidationError> {
+    let got = pool_id.to_uint().0;
 
+    (got != U256::from(expected)).then(|| TxValidationError::WrongPoolId {
+        expected,
+        got: got.low_u64(),
+    })
+}
+
+/// The transfer proof must be made against the current pool root or the root at its transfer
+/// index. Roots removed by `ROOTS_RETENTION` are unknown as well.
+fn check_root(
+    tree: &MerkleTree,
+    root: Num<Fr>,
+    transfer_index: Num<Fr>,
+    pool_root: U256,
+) -> anyhow::Result<Option<TxValidationError>> {
+    if root.to_uint().0 == pool_root {
+        return Ok(None);
+    }
+
+    let historic_root = tree.historic_root(transfer_index.to_uint().0.low_u64() / TX_SIZE)?;
+    Ok((historic_root != Some(root)).then_some(TxValidationError::RootMismatch))
+}
+
+/// The transfer proof must be made against a mined root. With `max_lag` set, the root must also
+/// be recent enough at `send_index`, the pool index the transaction is going to be sent at.
+pub fn check_transfer_index(
+    transfer_index: Num<Fr>,
+    pool_index: u64,
+    send_index: u64,
+    max_lag: Option<u64>,
+) -> Option<TxValidationError> {
+    let transfer_index = transfer_index.to_uint().0;
+    if transfer_index > U256::from(pool_index) {
+        return Some(TxValidationError::InvalidTxIndex);
+    }
+
+    let lag = send_index.saturating_sub(transfer_index.low_u64());
+    matches!(max_lag, Some(max_lag) if lag > max_lag).then_some(TxValidationError::ExpiredTxIndex)
+}
+
+#[utoipa::path(
+    get,
+    path = "/transactions/v2",
+    tag = "transactions",
+    params(LegacyTxQuery),
+    responses((
+        status = 200,
+        description = "Hex-encoded transaction records, prefixed with `1` if mined and `0` if not",
+        body = [String],
+    )),
+)]
 async fn get_transactions_legacy(
     State(state): State<Arc<AppState>>,
-    Query(pagination): Query<TxPaginationQuery>,
+    Query(query): Query<LegacyTxQuery>,
 ) -> AppResult<Json<Vec<String>>> {
-    let limit = pagination.limit.unwrap_or(100);
-    let offset = pagination.offset.unwrap_or(0);
     let pool_index = *state.pool_index.read().await;
+    let txs = read_transactions_legacy(&state.transactions, pool_index, &query)?;
+
+    Ok(Json(txs))
+}
+
+#[utoipa::path(
+    get,
+    path = "/transactions",
+    tag = "transactions",
+    params(TxPaginationQuery),
+    responses((status = 200, description = "Hex-encoded transaction records", body = [Hex])),
+)]
+async fn get_transactions(
+    State(state): State<Arc<AppState>>,
+    Query(pagination): Query<TxPaginationQuery>,
+) -> Response {
+    stream_transactions_json(state, |state| &state.transactions, pagination.range())
+}
 
-    let txs = state
+#[utoipa::path(
+    get,
+    path = "/ciphertexts",
+    tag = "transactions",
+    params(TxPaginationQuery),
+    responses((status = 200, body = [CiphertextPrefix])),
+)]
+async fn get_ciphertexts(
+    State(state): State<Arc<AppState>>,
+    Query(pagination): Query<TxPaginationQuery>,
+) -> AppResult<Json<Vec<CiphertextPrefix>>> {
+    let prefixes = state
         .transactions
-        .iter_range(offset..(offset + limit * 128))?
+        .ciphertexts_range(pagination.range())?
+        .map(|(index, prefix)| CiphertextPrefix {
+            index,
+            prefix: Hex(prefix),
+        })
+        .collect();
+
+    Ok(Json(prefixes))
+}
+
+/// Records are prefixed with `1` if mined and `0` if optimistic.
+pub(crate) fn read_transactions_legacy(
+    transactions: &TxStorage,
+    pool_index: u64,
+    query: &LegacyTxQuery,
+) -> anyhow::Result<Vec<String>> {
+    transactions
+        .iter_range(query.range(pool_index))?
         .map(|res| {
             res.map(|(index, data)| {
                 let is_mined = (index < pool_index) as u8;
@@ -224,80 +781,961 @@ async fn get_transactions_legacy(
                 format!("{is_mined}{h}")
             })
         })
-        .collect::<Result<_, _>>()?;
+        .collect()
+}
 
-    Ok(Json(txs))
+/// Responds with a JSON array of hex-encoded records. The records are serialized one at a time
+/// while they are read instead of collecting the whole range first. If reading fails after the
+/// response has started, the body is aborted so that the client doesn't get a truncated array.
+pub(crate) fn stream_transactions_json<S, F>(
+    state: Arc<S>,
+    storage: F,
+    range: Range<u64>,
+) -> Response
+where
+    S: Send + Sync + 'static,
+    F: FnOnce(&S) -> &TxStorage + Send + 'static,
+{
+    let (mut sender, body) = Body::channel();
+    let runtime = Handle::current();
+
+    // Persy reads are blocking.
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = send_transactions_json(&runtime, storage(&state), range, &mut sender) {
+            tracing::warn!("Failed to stream transactions: {err:#}");
+            sender.abort();
+        }
+    });
+
+    ([(CONTENT_TYPE, "application/json")], body).into_response()
 }
 
-async fn get_transactions(
+fn send_transactions_json(
+    runtime: &Handle,
+    transactions: &TxStorage,
+    range: Range<u64>,
+    sender: &mut Sender,
+) -> anyhow::Result<()> {
+    let mut chunk = b"[".to_vec();
+    for (i, res) in transactions.iter_range(range)?.enumerate() {
+        let (_, data) = res?;
+        if i > 0 {
+            chunk.push(b',');
+        }
+        serde_json::to_writer(&mut chunk, &Hex(data))?;
+
+        if runtime
+            .block_on(sender.send_data(std::mem::take(&mut chunk).into()))
+            .is_err()
+        {
+            // The client has disconnected.
+            return Ok(());
+        }
+    }
+    chunk.push(b']');
+    let _ = runtime.block_on(sender.send_data(chunk.into()));
+
+    Ok(())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TxStreamQuery {
+    /// Pool index to send the already committed transactions from.
+    pub from: Option<u64>,
+}
+
+/// Streams newly committed transactions. If `from` is specified, already committed transactions
+/// starting from that index are sent first.
+#[utoipa::path(
+    get,
+    path = "/ws/transactions",
+    tag = "transactions",
+    params(TxStreamQuery),
+    responses((
+        status = 101,
+        description = "WebSocket of committed transactions, one JSON message per transaction",
+    )),
+)]
+async fn transactions_ws(
     State(state): State<Arc<AppState>>,
-    Query(pagination): Query<TxPaginationQuery>,
-) -> AppResult<Json<Vec<Hex>>> {
-    let limit = pagination.limit.unwrap_or(100);
-    let offset = pagination.offset.unwrap_or(0);
-    // let pool_index = *state.pool_index.read().await;
+    Query(query): Query<TxStreamQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| async move {
+        if let Err(err) = stream_transactions(socket, state, query.from).await {
+            tracing::debug!("Transaction stream closed: {err}");
+        }
+    })
+}
 
-    let txs = state
-        .transactions
-        .iter_range(offset..(offset + limit * 128))?
-        .map(|res| res.map(|(_, data)| Hex(data)))
-        .collect::<Result<_, _>>()?;
+async fn stream_transactions(
+    mut socket: WebSocket,
+    state: Arc<AppState>,
+    from: Option<u64>,
+) -> anyhow::Result<()> {
+    // Subscribe before reading the storage so that no transaction falls in between.
+    let mut events = state.tx_events.subscribe();
+    let mut next_index = 0;
 
-    Ok(Json(txs))
+    if let Some(from) = from {
+        let pool_index = *state.pool_index.read().await;
+        let backfill = state
+            .transactions
+            .iter_range(from..pool_index.max(from))?
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        next_index = from.max(pool_index);
+
+        for (index, data) in backfill {
+            let event = serde_json::to_string(&TxEvent { index, data })?;
+            socket.send(Message::Text(event)).await?;
+        }
+    }
+
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if event.index < next_index {
+                    continue;
+                }
+
+                socket
+                    .send(Message::Text(serde_json::to_string(&event)?))
+                    .await?;
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!("Dropping slow transaction stream client ({skipped} events behind)");
+                return Ok(());
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
 }
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct JobStatusResponse {
-    state: JobStatus, // tx_hash: Option<String>,
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct JobsQuery {
+    /// Only list jobs with this status.
+    pub status: Option<JobStatus>,
+    /// Unix timestamp in seconds, exclusive. Jobs created at or after it are skipped, pass the
+    /// `createdAt` of the last job to get the next page.
+    pub before: Option<u64>,
+    /// 100 by default, at most 1000.
+    pub limit: Option<u64>,
+}
+
+const DEFAULT_JOBS_LIMIT: u64 = 100;
+const MAX_JOBS_LIMIT: u64 = 1000;
+
+/// Recent jobs, newest first. Jobs are kept for `JOB_STATUS_TTL_SECS`, up to
+/// `JOB_HISTORY_MAX_COUNT` of them. Requires the admin token or an API key.
+#[utoipa::path(
+    get,
+    path = "/jobs",
+    tag = "jobs",
+    params(JobsQuery),
+    security(("admin_token" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = [JobSummary]),
+        (status = 401, description = "Missing or wrong token"),
+        (status = 404, description = "Not found, neither an admin token nor API keys are set"),
+    ),
+)]
+async fn jobs(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<JobsQuery>,
+) -> AppResult<Json<Vec<JobSummary>>> {
+    check_admin_token_or_api_key(&state, &headers)?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_JOBS_LIMIT)
+        .min(MAX_JOBS_LIMIT);
+    let jobs = state
+        .job_queue
+        .history(query.status, query.before, limit)
+        .await?;
+
+    Ok(Json(jobs))
 }
 
+#[utoipa::path(
+    get,
+    path = "/job/{id}",
+    tag = "jobs",
+    params(("id" = u64, Path)),
+    responses(
+        (status = 200, body = JobStatusResponse),
+        (status = 404, description = "Unknown or expired job"),
+    ),
+)]
 async fn job(
     State(state): State<Arc<AppState>>,
     Path(id): Path<u64>,
 ) -> AppResult<Json<JobStatusResponse>> {
-    let state = state.job_queue.job_status(id).await?;
+    Ok(Json(job_status(&state, id).await?))
+}
 
-    let Some(state) = state else {
+/// Status of the job that created the transaction at pool index `index`. Any index of the
+/// transaction's notes can be used. Mappings expire together with the job status.
+#[utoipa::path(
+    get,
+    path = "/jobByIndex/{index}",
+    tag = "jobs",
+    params(("index" = u64, Path, description = "Pool index of the transaction")),
+    responses(
+        (status = 200, body = JobStatusResponse),
+        (status = 404, description = "No known job created the transaction"),
+    ),
+)]
+async fn job_by_index(
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<u64>,
+) -> AppResult<Json<JobStatusResponse>> {
+    let Some(id) = state
+        .job_queue
+        .get_job_mapping(INDEX_MAPPING, index / TX_SIZE)
+        .await?
+    else {
         return Err(AppError::NotFound);
     };
 
-    Ok(Json(JobStatusResponse { state }))
+    Ok(Json(job_status(&state, id).await?))
 }
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct InfoResponse {
-    backend: &'static str,
-    api_version: &'static str,
-    root: String,
-    optimistic_root: String,
-    pool_index: String,
-    optimistic_index: String,
+async fn job_status(state: &AppState, id: JobId) -> AppResult<JobStatusResponse> {
+    let Some(job_state) = state.job_queue.job_status(id).await? else {
+        return Err(AppError::NotFound);
+    };
+    let sender = state.job_queue.job_sender(id).await?;
+
+    Ok(JobStatusResponse {
+        state: job_state,
+        sender,
+    })
 }
 
+/// Every root the tree has had after the transactions up to pool index `index`, so that a client
+/// can tell a root that was rolled back from one that was pruned or never existed.
+#[utoipa::path(
+    get,
+    path = "/roots/{index}",
+    tag = "transactions",
+    params(("index" = u64, Path, description = "Pool index, a multiple of 128")),
+    responses(
+        (status = 200, body = RootsResponse),
+        (status = 400, description = "Not a multiple of 128", body = ErrorResponse),
+        (status = 404, description = "No roots recorded, e.g. because they were pruned"),
+    ),
+)]
+async fn roots(
+    State(state): State<Arc<AppState>>,
+    Path(index): Path<u64>,
+) -> AppResult<Json<RootsResponse>> {
+    if index % TX_SIZE != 0 {
+        return Err(AppError::BadRequest(anyhow!(
+            "Index must be a multiple of {TX_SIZE}"
+        )));
+    }
+
+    let tree = state.tree.lock("roots").await;
+    let roots = tree.root_log(index / TX_SIZE)?;
+    let supersede_counter = tree.supersede_counter()?;
+    drop(tree);
+
+    if roots.is_empty() {
+        return Err(AppError::NotFound);
+    }
+
+    Ok(Json(RootsResponse {
+        index,
+        supersede_counter,
+        roots: roots
+            .into_iter()
+            .map(|entry| RootLogEntry {
+                root: entry.root.to_string(),
+                supersede_counter: entry.supersede_counter,
+                canonical: entry.canonical,
+            })
+            .collect(),
+    }))
+}
+
+#[utoipa::path(get, path = "/info", responses((status = 200, body = InfoResponse)))]
 async fn info(State(state): State<Arc<AppState>>) -> AppResult<Json<InfoResponse>> {
     let pool_index = *state.pool_index.read().await;
 
     let root = state.pool_root.read().await.to_string();
-    let optimistic_root = state.tree.lock().await.root()?.to_string();
-    let optimistic_delta_index = state.tree.lock().await.num_leaves() * 128; // FIXME: use the constant
+    let (optimistic_root, num_leaves, supersede_counter) = *state.optimistic_tree_state.borrow();
+    let optimistic_root = optimistic_root.to_string();
+    let optimistic_delta_index = num_leaves * TX_SIZE;
+
+    // Stats are optional, so that /info keeps working when Redis is down.
+    let job_stats = state
+        .job_queue
+        .stats()
+        .await
+        .map_err(|err| tracing::warn!("Failed to fetch job queue stats: {err}"))
+        .ok();
+    let optimistic_transactions = state
+        .transactions
+        .count()
+        .map_err(|err| tracing::warn!("Failed to count transactions: {err}"))
+        .ok();
 
     Ok(Json(InfoResponse {
-        backend: state.backend.name(),
-        api_version: "3",
+        backend: state.backend.name().to_owned(),
+        chain_id: state.chain_id.clone(),
+        pool_id: state.config.pool_id,
+        api_version: "3".to_owned(),
         root,
         optimistic_root,
         pool_index: pool_index.to_string(),
         optimistic_index: optimistic_delta_index.to_string(),
+        paused: !state.accepting.load(Ordering::SeqCst),
+        sending_paused: !state.sending.load(Ordering::SeqCst),
+        pending_jobs: job_stats.map(|stats| stats.pending),
+        in_progress_jobs: job_stats.map(|stats| stats.in_progress),
+        failed_jobs_last24h: job_stats.map(|stats| stats.failed_last_24h),
+        mined_transactions: pool_index / TX_SIZE,
+        optimistic_transactions,
+        replicas: state.config.replica_urls.clone(),
+        pool_address: Some(state.config.backend.pool_address()),
+        build: Some(build_info::current()),
+        supersede_counter,
+    }))
+}
+
+/// Identifies the running binary. Unlike `/info`, doesn't depend on the pool state.
+#[utoipa::path(get, path = "/version", responses((status = 200, body = VersionResponse)))]
+async fn version(State(state): State<Arc<AppState>>) -> Json<VersionResponse> {
+    let build = build_info::current();
+
+    Json(VersionResponse {
+        version: build.version,
+        git_hash: build.git_hash,
+        features: build.features,
+        build_timestamp: build.build_timestamp,
+        proving_system: build_info::PROVING_SYSTEM.to_owned(),
+        backend: state.backend.name().to_owned(),
+    })
+}
+
+#[cfg(feature = "metrics")]
+async fn metrics(State(state): State<Arc<AppState>>) -> AppResult<String> {
+    let queue_depth = state.job_queue.queue_len().await?;
+    let pool_index = *state.pool_index.read().await;
+    let optimistic_index = state.optimistic_tree_state.borrow().1 * TX_SIZE;
+
+    monitoring::set_queue_depth(queue_depth);
+    monitoring::set_index_gap(optimistic_index.saturating_sub(pool_index));
+
+    Ok(state.metrics.render())
+}
+
+/// Only allows requests with `Authorization: Bearer <ADMIN_TOKEN>`. The admin API is disabled if
+/// no token is configured.
+fn check_admin_token(state: &AppState, headers: &HeaderMap) -> AppResult<()> {
+    check_bearer_token(state.config.admin_token.as_ref(), headers)
+}
+
+/// Lists other users' jobs, so it's not public even if the API keys only protect submissions.
+fn check_admin_token_or_api_key(state: &AppState, headers: &HeaderMap) -> AppResult<()> {
+    if let Some(keys) = ApiKeys::new(&state.config.api_keys) {
+        if keys.check_headers(headers) {
+            return Ok(());
+        }
+        if state.config.admin_token.is_none() {
+            return Err(AppError::Unauthorized);
+        }
+    }
+
+    check_admin_token(state, headers)
+}
+
+/// Routes protected by an unset token are reported as missing.
+fn check_bearer_token(expected: Option<&Secret>, headers: &HeaderMap) -> AppResult<()> {
+    let Some(expected) = expected else {
+        return Err(AppError::NotFound);
+    };
+
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if !token.map_or(false, |token| api_key::token_matches(expected, token)) {
+        return Err(AppError::Unauthorized);
+    }
+
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/compact",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 204),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+        (status = 409, description = "There are pending jobs"),
+    ),
+)]
+async fn admin_compact(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> AppResult<StatusCode> {
+    check_admin_token(&state, &headers)?;
+
+    if !maintenance::compact(&state).await? {
+        return Err(AppError::Conflict(anyhow!(
+            "Cannot compact while there are pending jobs"
+        )));
+    }
+    state.audit.record(AuditEvent::Compacted);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct ResyncResponse {
+    pool_index: u64,
+    /// Decimal, like the root in `/info`.
+    root: String,
+}
+
+/// Rebuilds the tree and transaction storage from the mined transactions. Queued and in-progress
+/// jobs are cancelled, their transactions have to be submitted again.
+#[utoipa::path(
+    post,
+    path = "/admin/resync",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "New pool index and root"),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_resync(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> AppResult<Json<ResyncResponse>> {
+    check_admin_token(&state, &headers)?;
+
+    let (pool_index, root) = state.resync().await?;
+
+    Ok(Json(ResyncResponse {
+        pool_index,
+        root: root.to_string(),
+    }))
+}
+
+/// Backup of the tree in the format of `MerkleTree::export`, taken while the relayer is running.
+#[utoipa::path(
+    get,
+    path = "/admin/tree/export",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Borsh encoded number of leaves and tree nodes", content_type = "application/octet-stream"),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_export_tree(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    check_admin_token(&state, &headers)?;
+
+    // The worker only waits for the snapshot, not for the export.
+    let snapshot = state.tree.lock("export").await.snapshot()?;
+    let (sender, body) = Body::channel();
+    let runtime = Handle::current();
+
+    // Persy reads are blocking.
+    tokio::task::spawn_blocking(move || {
+        let mut writer =
+            BufWriter::with_capacity(EXPORT_CHUNK_SIZE, BodyWriter { runtime, sender });
+        if let Err(err) = snapshot.export(&mut writer) {
+            tracing::warn!("Failed to stream the tree export: {err:#}");
+            let (body_writer, _) = writer.into_parts();
+            body_writer.sender.abort();
+        }
+    });
+
+    Ok(([(CONTENT_TYPE, "application/octet-stream")], body).into_response())
+}
+
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes into a response body from a blocking task. Fails once the client has disconnected.
+struct BodyWriter {
+    runtime: Handle,
+    sender: Sender,
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.runtime
+            .block_on(self.sender.send_data(buf.to_vec().into()))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PauseQuery {
+    /// Also stop sending already queued transactions.
+    #[serde(default)]
+    pub sending: bool,
+}
+
+/// Stops accepting new transactions. Already queued jobs are still processed unless `sending` is
+/// set.
+#[utoipa::path(
+    post,
+    path = "/admin/pause",
+    tag = "admin",
+    params(PauseQuery),
+    security(("admin_token" = [])),
+    responses(
+        (status = 204),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_pause(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PauseQuery>,
+    headers: HeaderMap,
+) -> AppResult<StatusCode> {
+    check_admin_token(&state, &headers)?;
+
+    state.accepting.store(false, Ordering::SeqCst);
+    if query.sending {
+        state.sending.store(false, Ordering::SeqCst);
+    }
+
+    tracing::warn!("Relayer paused (sending paused: {})", query.sending);
+    state.audit.record(AuditEvent::Paused {
+        sending: query.sending,
+    });
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/resume",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 204),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_resume(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> AppResult<StatusCode> {
+    check_admin_token(&state, &headers)?;
+
+    if state.standby.load(Ordering::SeqCst) {
+        return Err(AppError::Conflict(anyhow!(
+            "A standby relayer has to be promoted instead"
+        )));
+    }
+
+    state.accepting.store(true, Ordering::SeqCst);
+    state.sending.store(true, Ordering::SeqCst);
+
+    tracing::info!("Relayer resumed");
+    state.audit.record(AuditEvent::Resumed);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/settings",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, body = RuntimeSettings),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_settings(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> AppResult<Json<RuntimeSettings>> {
+    check_admin_token(&state, &headers)?;
+
+    Ok(Json(state.settings()))
+}
+
+/// Replaces the fee, rate limit and queue limit of the running relayer. The change is not
+/// persisted: a restart or SIGHUP loads the settings from the environment again.
+#[utoipa::path(
+    put,
+    path = "/admin/settings",
+    tag = "admin",
+    request_body = RuntimeSettings,
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The applied settings", body = RuntimeSettings),
+        (status = 400, description = "Invalid settings", body = ErrorResponse),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_update_settings(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    payload: Result<Json<RuntimeSettings>, JsonRejection>,
+) -> AppResult<Json<RuntimeSettings>> {
+    check_admin_token(&state, &headers)?;
+    let Json(settings) = payload.map_err(json_rejection)?;
+
+    state
+        .update_settings(settings, "api")
+        .map_err(AppError::BadRequest)?;
+
+    Ok(Json(state.settings()))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FailedJobsQuery {
+    /// 20 by default.
+    pub limit: Option<usize>,
+}
+
+const DEFAULT_FAILED_JOBS_LIMIT: usize = 20;
+
+/// Lists the most recent archived failed jobs, newest first.
+#[utoipa::path(
+    get,
+    path = "/admin/failed-jobs",
+    tag = "admin",
+    params(FailedJobsQuery),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Archived failed jobs, newest first"),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_failed_jobs(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<FailedJobsQuery>,
+    headers: HeaderMap,
+) -> AppResult<Json<Vec<FailedJob<Payload>>>> {
+    check_admin_token(&state, &headers)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_FAILED_JOBS_LIMIT);
+    Ok(Json(state.failed_jobs.list(limit)?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/failed-jobs/{id}",
+    tag = "admin",
+    params(("id" = u64, Path)),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Archived failed job"),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_failed_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<JobId>,
+    headers: HeaderMap,
+) -> AppResult<Json<FailedJob<Payload>>> {
+    check_admin_token(&state, &headers)?;
+
+    let failed_job = state.failed_jobs.get(id)?.ok_or(AppError::NotFound)?;
+    Ok(Json(failed_job))
+}
+
+/// Validates the transaction of an archived job against the current state and, if it's still
+/// valid, enqueues it as a new job. The archived job is removed on success.
+#[tracing::instrument(skip_all, fields(failed_job_id = %id, request_id))]
+#[utoipa::path(
+    post,
+    path = "/admin/failed-jobs/{id}/retry",
+    tag = "admin",
+    params(("id" = u64, Path)),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, body = CreateTransactionResponse),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+        (status = 400, description = "The transaction is no longer valid", body = ErrorResponse),
+    ),
+)]
+async fn admin_retry_failed_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<JobId>,
+    headers: HeaderMap,
+) -> AppResult<Json<CreateTransactionResponse>> {
+    check_admin_token(&state, &headers)?;
+
+    let request_id = Uuid::new_v4();
+    tracing::Span::current().record("request_id", tracing::field::display(request_id));
+
+    let failed_job = state.failed_jobs.get(id)?.ok_or(AppError::NotFound)?;
+    let tx = failed_job.job.data.tx().clone();
+
+    let job_id = submit_parsed_transaction(&state, request_id, tx, None).await?;
+    state.failed_jobs.remove(id)?;
+    tracing::info!(
+        "Retried failed job {id} (request {}) as job {job_id}",
+        failed_job.job.data.request_id()
+    );
+    state.audit.record(AuditEvent::FailedJobRetried {
+        failed_job_id: id,
+        job_id,
+    });
+
+    Ok(Json(CreateTransactionResponse { job_id }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Unix timestamp in seconds.
+    #[serde(default)]
+    pub since: u64,
+    /// 100 by default.
+    pub limit: Option<usize>,
+}
+
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+
+/// Lists audit log entries recorded at or after `since`, oldest first.
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    tag = "admin",
+    params(AuditQuery),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Audit log entries, oldest first"),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_audit(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AuditQuery>,
+    headers: HeaderMap,
+) -> AppResult<Json<Vec<AuditEntry>>> {
+    check_admin_token(&state, &headers)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT);
+    Ok(Json(state.audit.read(query.since, limit)?))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FeesQuery {
+    /// Unix timestamp in seconds, inclusive.
+    #[serde(default)]
+    pub from: u64,
+    /// Unix timestamp in seconds, exclusive. Now by default.
+    pub to: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct FeeDay {
+    /// Start of the UTC day, unix timestamp in seconds.
+    day: u64,
+    total: u64,
+}
+
+#[derive(Serialize)]
+struct FeesResponse {
+    /// Fees of the transactions sent within the range.
+    total: u64,
+    /// Fees of all stored transactions.
+    lifetime_total: u64,
+    /// Whole-day totals of the UTC days that overlap the range.
+    days: Vec<FeeDay>,
+    /// Ordered by timestamp.
+    transactions: Vec<FeeRecord>,
+}
+
+/// Fees collected by the transactions this relayer has sent, in pool units. Rolled back
+/// transactions are not counted.
+#[utoipa::path(
+    get,
+    path = "/admin/fees",
+    tag = "admin",
+    params(FeesQuery),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Fee totals and the fees of every transaction in the range"),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_fees(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<FeesQuery>,
+    headers: HeaderMap,
+) -> AppResult<Json<FeesResponse>> {
+    check_admin_token(&state, &headers)?;
+
+    let from = query.from;
+    let to = query.to.unwrap_or_else(|| unix_timestamp() + 1);
+
+    let transactions = state.transactions.fees_between(from..to)?;
+    let days = state
+        .transactions
+        .fee_days(from - from % SECONDS_PER_DAY..to)?
+        .into_iter()
+        .map(|(day, total)| FeeDay { day, total })
+        .collect();
+
+    Ok(Json(FeesResponse {
+        total: transactions.iter().map(|record| record.fee).sum(),
+        lifetime_total: state.transactions.fees_total()?,
+        days,
+        transactions,
     }))
 }
 
-type AppResult<T> = Result<T, AppError>;
+/// Takes a signing key out of rotation. Keys are enabled again on restart, so the key should
+/// also be removed from the config.
+#[utoipa::path(
+    post,
+    path = "/admin/keys/{address}/disable",
+    tag = "admin",
+    params(("address" = String, Path)),
+    security(("admin_token" = [])),
+    responses(
+        (status = 204),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+    ),
+)]
+async fn admin_disable_key(
+    State(state): State<Arc<AppState>>,
+    Path(address): Path<String>,
+    headers: HeaderMap,
+) -> AppResult<StatusCode> {
+    check_admin_token(&state, &headers)?;
+
+    if !state
+        .backend
+        .disable_signer(&address)
+        .map_err(AppError::BadRequest)?
+    {
+        return Err(AppError::NotFound);
+    }
+
+    tracing::warn!("Signer {address} disabled");
+    state.audit.record(AuditEvent::SignerDisabled { address });
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stops following the primary and starts processing the inherited optimistic transactions. The
+/// old primary must be stopped first.
+#[utoipa::path(
+    post,
+    path = "/admin/promote",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Numbers of resubmitted and dropped inherited transactions"),
+        (status = 401, description = "Wrong admin token"),
+        (status = 404, description = "Not found, or the admin API is disabled"),
+        (status = 409, description = "Not a standby relayer"),
+    ),
+)]
+async fn admin_promote(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> AppResult<Json<Promotion>> {
+    check_admin_token(&state, &headers)?;
+
+    Ok(Json(peer_sync::promote(&state).await?))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OptimisticLogQuery {
+    /// Pool index of the first entry.
+    #[serde(default)]
+    pub from_index: u64,
+    /// 100 by default, at most 1000.
+    pub limit: Option<u64>,
+}
+
+/// Storage records starting at `from_index`, with the payloads of the optimistic transactions
+/// that haven't been mined yet. Tailed by a standby relayer.
+#[utoipa::path(
+    get,
+    path = "/internal/optimistic-log",
+    tag = "internal",
+    params(OptimisticLogQuery),
+    security(("peer_token" = [])),
+    responses(
+        (status = 200, description = "Log entries in pool index order"),
+        (status = 401, description = "Wrong peer token"),
+        (status = 404, description = "Not found, or no peer token is configured"),
+    ),
+)]
+async fn optimistic_log(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<OptimisticLogQuery>,
+    headers: HeaderMap,
+) -> AppResult<Json<Vec<LogEntry>>> {
+    check_bearer_token(state.config.peer_token.as_ref(), &headers)?;
+
+    let limit = query
+        .limit
+        .unwrap_or(peer_sync::LOG_PAGE_SIZE)
+        .min(MAX_TX_LIMIT);
+    let entries = peer_sync::read_entries(
+        &state.transactions,
+        &state.optimistic_log,
+        query.from_index,
+        limit,
+    )?;
+
+    Ok(Json(entries))
+}
+
+pub(crate) type AppResult<T> = Result<T, AppError>;
 
-enum AppError {
+pub(crate) enum AppError {
     NotFound,
+    Unauthorized,
     BadRequest(anyhow::Error),
+    Conflict(anyhow::Error),
+    Paused,
+    /// `max_pending_jobs` jobs are queued.
+    QueueFull,
+    PayloadTooLarge,
     TxValidationErrors(Vec<TxValidationError>),
     InternalServerError(anyhow::Error),
 }
@@ -307,7 +1745,21 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self::InternalServerError(err.into())
+        let err = err.into();
+        if err.is::<ParseHashError>() {
+            return Self::BadRequest(err);
+        }
+
+        Self::InternalServerError(err)
+    }
+}
+
+/// Oversized bodies are rejected while being read, before they're buffered in full.
+fn json_rejection(rejection: JsonRejection) -> AppError {
+    if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        AppError::PayloadTooLarge
+    } else {
+        AppError::BadRequest(anyhow!(rejection.body_text()))
     }
 }
 
@@ -315,42 +1767,665 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         match self {
             Self::NotFound => StatusCode::NOT_FOUND.into_response(),
+            Self::Unauthorized => StatusCode::UNAUTHORIZED.into_response(),
             Self::TxValidationErrors(errors) => {
                 tracing::warn!("Tx validation error: {errors:#?}");
                 let errors = errors
                     .into_iter()
-                    .map(|err| json!({ "error": err.to_string(), "code": err }))
-                    .collect::<Vec<_>>();
-
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({
-                        "error": "Validation error",
-                        "errors": errors,
-                    })),
-                )
-                    .into_response()
+                    .map(|err| ValidationError {
+                        error: err.to_string(),
+                        code: err.code(),
+                    })
+                    .collect();
+
+                let body = ErrorResponse {
+                    error: "Validation error".to_owned(),
+                    code: None,
+                    errors,
+                };
+                (StatusCode::BAD_REQUEST, Json(body)).into_response()
             }
             Self::BadRequest(err) => {
                 tracing::warn!("Bad request: {err}");
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({
-                        "error": err.to_string(),
-                    })),
-                )
-                    .into_response()
+                error_response(StatusCode::BAD_REQUEST, err, None)
+            }
+            Self::Paused => error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Relayer is paused",
+                Some("RELAYER_PAUSED"),
+            ),
+            Self::QueueFull => error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many pending transactions, try again later",
+                Some("QUEUE_FULL"),
+            ),
+            Self::PayloadTooLarge => error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body is too large",
+                Some("PAYLOAD_TOO_LARGE"),
+            ),
+            Self::Conflict(err) => {
+                tracing::warn!("Conflict: {err}");
+                error_response(StatusCode::CONFLICT, err, None)
             }
             Self::InternalServerError(err) => {
                 tracing::warn!("Internal server error: {err}");
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({
-                        "error": err.to_string(),
-                    })),
-                )
-                    .into_response()
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, err, None)
             }
         }
     }
 }
+
+fn error_response(status: StatusCode, error: impl ToString, code: Option<&str>) -> Response {
+    let body = ErrorResponse {
+        error: error.to_string(),
+        code: code.map(str::to_owned),
+        errors: Vec::new(),
+    };
+    (status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderValue, Method};
+    use libzeropool_rs::libzeropool::native::tx::make_delta;
+    use scopeguard::defer;
+
+    use super::*;
+    use crate::{
+        backend::{
+            mock::{mock_config, MockBackend},
+            HashEncoding,
+        },
+        test_utils,
+        tx::ProofInput,
+        tx_worker::mock_proof,
+        Fr,
+    };
+
+    async fn preflight(origins: CorsOrigins, origin: &str) -> reqwest::Response {
+        let router = Router::new().route("/info", get(|| async { "ok" }));
+        let router = match cors_layer(&origins) {
+            Some(cors) => router.layer(cors),
+            None => router,
+        };
+
+        let addr = test_utils::serve(router);
+
+        reqwest::Client::new()
+            .request(Method::OPTIONS, format!("http://{addr}/info"))
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "GET")
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_hash_error_status() {
+        let status = |res: Result<_, ParseHashError>| {
+            let res: AppResult<()> = res.map(drop).map_err(Into::into);
+            res.unwrap_err().into_response().status()
+        };
+
+        let encoding = HashEncoding::Hex { size: 32 };
+        assert_eq!(status(encoding.parse("0x1234")), StatusCode::BAD_REQUEST);
+        assert_eq!(status(encoding.parse("xyz")), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            AppError::from(anyhow!("Storage error"))
+                .into_response()
+                .status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn test_pagination_range() {
+        let query = |offset, limit| TxPaginationQuery { offset, limit };
+
+        assert_eq!(query(None, None).range(), 0..100 * TX_SIZE);
+        assert_eq!(query(Some(TX_SIZE), Some(2)).range(), TX_SIZE..3 * TX_SIZE);
+        assert_eq!(
+            query(None, Some(u64::MAX)).range(),
+            0..MAX_TX_LIMIT * TX_SIZE
+        );
+        assert_eq!(
+            query(Some(u64::MAX - 1), Some(u64::MAX)).range(),
+            u64::MAX - 1..u64::MAX
+        );
+    }
+
+    #[test]
+    fn test_transactions_legacy() {
+        const FILE_NAME: &str = "json_api_test_transactions_legacy.persy";
+        let transactions = TxStorage::open(FILE_NAME).unwrap();
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        // EVM and NEAR hashes are 32 bytes long, Waves hashes are base58-decoded ids.
+        transactions
+            .push(0, Num::from(1), &[0xaa; 32], &[1, 0, 0, 0, 0xde, 0xad])
+            .unwrap();
+        transactions
+            .push(
+                TX_SIZE,
+                Num::from(2),
+                &[0xbb; 32],
+                &[2, 0, 0, 0, 0xbe, 0xef],
+            )
+            .unwrap();
+        transactions
+            .push(2 * TX_SIZE, Num::from(3), &[0; 32], &[1, 0, 0, 0])
+            .unwrap();
+
+        // Framing of the old relayer: mined flag, out commit, tx hash and memo. Written from its
+        // source, there is no old relayer to record the responses from here.
+        let golden = [
+            concat!(
+                "1",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+                "01000000dead",
+            ),
+            concat!(
+                "1",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+                "02000000beef",
+            ),
+            concat!(
+                "0",
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "01000000",
+            ),
+        ];
+
+        let read = |offset, limit, optimistic_state| {
+            let query = LegacyTxQuery {
+                offset,
+                limit,
+                optimistic_state,
+            };
+            read_transactions_legacy(&transactions, 2 * TX_SIZE, &query).unwrap()
+        };
+
+        assert_eq!(read(None, None, None), golden);
+        assert_eq!(read(None, None, Some(true)), golden);
+        assert_eq!(read(None, None, Some(false)), &golden[..2]);
+        assert_eq!(read(None, Some(2), None), &golden[..2]);
+        assert_eq!(read(Some(1), Some(1), None), &golden[1..2]);
+        assert_eq!(read(Some(1), Some(10), Some(false)), &golden[1..2]);
+        assert_eq!(read(Some(2), Some(1), None), &golden[2..]);
+        assert!(read(Some(2), None, Some(false)).is_empty());
+        assert!(read(Some(3), None, None).is_empty());
+        assert!(read(Some(u64::MAX), None, None).is_empty());
+    }
+
+    #[test]
+    fn test_parse_tx() {
+        let request = |inputs: u64| TxDataRequest {
+            tx_type: TxType::Transfer,
+            proof: ProofWithInputs {
+                proof: mock_proof(),
+                inputs: (0..inputs)
+                    .map(|input| ProofInput::from(Num::from(input)))
+                    .collect(),
+            },
+            memo: vec![],
+            extra_data: vec![],
+        };
+        let errors = |request| match parse_tx(request) {
+            Err(AppError::TxValidationErrors(errors)) => errors,
+            _ => panic!("Malformed request is accepted"),
+        };
+
+        let Ok(tx) = parse_tx(request(5)) else {
+            panic!("Valid inputs are rejected");
+        };
+        assert_eq!(tx.nullifier, Num::from(1));
+        assert_eq!(tx.out_commit, Num::from(2));
+        assert_eq!(tx.delta, Num::from(3));
+
+        // Used to panic on indexing the inputs.
+        for inputs in [0, 3, 6] {
+            assert!(matches!(
+                errors(request(inputs))[..],
+                [TxValidationError::MalformedProofInputs(
+                    MalformedInputs::WrongCount { expected: 5, got }
+                )] if got == inputs as usize
+            ));
+        }
+
+        // A nullifier that is only valid after reduction, or with a leading zero.
+        let modulus =
+            "21888242871839275222246405745257275088548364400416034343698204186575808495617";
+        for nullifier in [modulus, "01", "+1", " 1", "0x1"] {
+            let mut request = request(5);
+            request.proof.inputs[1] = ProofInput(nullifier.to_owned());
+            assert!(matches!(
+                errors(request)[..],
+                [TxValidationError::MalformedProofInputs(
+                    MalformedInputs::NonCanonical { index: 1 }
+                )]
+            ));
+        }
+
+        let mut request = request(5);
+        request.memo = vec![0; MAX_MEMO_SIZE + 1];
+        request.extra_data = vec![0; MAX_EXTRA_DATA_SIZE + 1];
+        assert!(matches!(
+            errors(request)[..],
+            [
+                TxValidationError::MemoTooLong { .. },
+                TxValidationError::ExtraDataTooLong { .. }
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_required_fee() {
+        let amount = |amount: i64| {
+            if amount < 0 {
+                -Num::from(amount.unsigned_abs())
+            } else {
+                Num::from(amount as u64)
+            }
+        };
+
+        let flat = FeeModel::Flat(100);
+        for (tx_type, token_amount) in [
+            (TxType::Deposit, amount(1_000_000)),
+            (TxType::Transfer, amount(0)),
+            (TxType::Withdraw, amount(-1_000_000)),
+        ] {
+            assert_eq!(required_fee(&flat, tx_type, token_amount), 100);
+        }
+
+        let percentage = FeeModel::Percentage { bps: 50, min: 100 };
+        assert_eq!(
+            required_fee(&percentage, TxType::Deposit, amount(1_000_000)),
+            5_000
+        );
+        assert_eq!(
+            required_fee(&percentage, TxType::Withdraw, amount(-1_000_000)),
+            5_000
+        );
+        assert_eq!(
+            required_fee(&percentage, TxType::Withdraw, amount(-1_000)),
+            100
+        );
+        assert_eq!(required_fee(&percentage, TxType::Transfer, amount(0)), 100);
+    }
+
+    #[test]
+    fn test_check_pool_id() {
+        let pool_id = |delta| parse_delta(delta).3;
+        let delta = |pool_id: u64| {
+            make_delta::<Fr>(Num::from(5), Num::ZERO, Num::from(128), Num::from(pool_id))
+        };
+
+        assert!(check_pool_id(pool_id(delta(0)), 0).is_none());
+        assert!(check_pool_id(pool_id(delta(7)), 7).is_none());
+
+        let err = check_pool_id(pool_id(delta(1)), 0).unwrap();
+        assert!(matches!(
+            err,
+            TxValidationError::WrongPoolId {
+                expected: 0,
+                got: 1
+            }
+        ));
+        assert_eq!(err.code(), "wrong_pool_id");
+        assert_eq!(TxValidationError::FeeTooLow.code(), "fee_too_low");
+    }
+
+    #[test]
+    fn test_check_transfer_index() {
+        let index = |index: u64| Num::from(index);
+
+        assert!(check_transfer_index(index(256), 256, 512, None).is_none());
+        assert!(check_transfer_index(index(256), 256, 512, Some(256)).is_none());
+        assert!(matches!(
+            check_transfer_index(index(384), 256, 512, None),
+            Some(TxValidationError::InvalidTxIndex)
+        ));
+        assert!(matches!(
+            check_transfer_index(index(128), 256, 512, Some(256)),
+            Some(TxValidationError::ExpiredTxIndex)
+        ));
+    }
+
+    #[test]
+    fn test_check_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = MerkleTree::open(dir.path().join("tree.persy").to_str().unwrap()).unwrap();
+        tree.add_leaf(Num::from(1)).unwrap();
+        let historic_root = tree.root().unwrap();
+        tree.add_leaf(Num::from(2)).unwrap();
+        let current_root = tree.root().unwrap();
+        let pool_root = current_root.to_uint().0;
+        let index = |index: u64| Num::from(index * TX_SIZE);
+
+        // Current root, at any transfer index
+        assert!(check_root(&tree, current_root, index(2), pool_root)
+            .unwrap()
+            .is_none());
+        assert!(check_root(&tree, current_root, index(1), pool_root)
+            .unwrap()
+            .is_none());
+
+        // Historic root at its transfer index
+        assert!(check_root(&tree, historic_root, index(1), pool_root)
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            check_root(&tree, historic_root, index(2), pool_root).unwrap(),
+            Some(TxValidationError::RootMismatch)
+        ));
+
+        // Unknown root
+        assert!(matches!(
+            check_root(&tree, Num::from(3), index(1), pool_root).unwrap(),
+            Some(TxValidationError::RootMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_transactions() {
+        const FILE_NAME: &str = "json_api_test_stream_transactions.persy";
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        let transactions = Arc::new(TxStorage::open(FILE_NAME).unwrap());
+        let router = Router::new()
+            .route(
+                "/transactions",
+                get(
+                    |State(transactions): State<Arc<TxStorage>>,
+                     Query(pagination): Query<TxPaginationQuery>| async move {
+                        stream_transactions_json(transactions, |t| t, pagination.range())
+                    },
+                ),
+            )
+            .with_state(transactions.clone());
+
+        let addr = test_utils::serve(router);
+
+        let get = |offset: u64, limit: u64| async move {
+            let res = reqwest::get(format!(
+                "http://{addr}/transactions?offset={offset}&limit={limit}"
+            ))
+            .await
+            .unwrap();
+            assert_eq!(res.headers()["content-type"], "application/json");
+            res.text().await.unwrap()
+        };
+
+        assert_eq!(get(0, 10).await, "[]");
+
+        for i in 0..5 {
+            transactions
+                .push(i * TX_SIZE, Num::from(i), &[i as u8], &[0; 8])
+                .unwrap();
+        }
+
+        for (offset, limit, expected) in [
+            (0, 10, 0..5),
+            (TX_SIZE, 2, 1..3),
+            (4 * TX_SIZE, 10, 4..5),
+            (5 * TX_SIZE, 10, 5..5),
+        ] {
+            let txs: Vec<Hex> = serde_json::from_str(&get(offset, limit).await).unwrap();
+            let expected: Vec<Hex> = expected.map(|i| Hex(vec![i])).collect();
+            assert_eq!(txs, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        async fn handler(
+            payload: Result<Json<serde_json::Value>, JsonRejection>,
+        ) -> AppResult<StatusCode> {
+            payload.map_err(json_rejection)?;
+            Ok(StatusCode::OK)
+        }
+
+        let router = Router::new().route(
+            "/transactions",
+            post(handler).layer(DefaultBodyLimit::max(1024)),
+        );
+
+        let addr = test_utils::serve(router);
+
+        let send = |body: String| {
+            reqwest::Client::new()
+                .post(format!("http://{addr}/transactions"))
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+        };
+
+        let res = send(format!("\"{}\"", "a".repeat(100))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = send(format!("\"{}\"", "a".repeat(4096))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        assert_eq!(body["code"], "PAYLOAD_TOO_LARGE");
+
+        let res = send("{".to_owned()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight() {
+        let origins = CorsOrigins::List(vec![HeaderValue::from_static("https://wallet.example")]);
+
+        let res = preflight(origins.clone(), "https://wallet.example").await;
+        assert_eq!(
+            res.headers()["access-control-allow-origin"],
+            "https://wallet.example"
+        );
+        assert!(res.headers().contains_key("access-control-allow-methods"));
+
+        let res = preflight(origins, "https://evil.example").await;
+        assert!(!res.headers().contains_key("access-control-allow-origin"));
+
+        let res = preflight(CorsOrigins::Any, "https://evil.example").await;
+        assert_eq!(res.headers()["access-control-allow-origin"], "*");
+
+        let res = preflight(CorsOrigins::List(vec![]), "https://wallet.example").await;
+        assert!(!res.headers().contains_key("access-control-allow-origin"));
+    }
+
+    /// Zero fee deposit made against `root`, valid with the mock prover.
+    fn deposit_request(root: Num<Fr>, seed: u64) -> TxDataRequest {
+        let delta = make_delta::<Fr>(Num::from(1000u64), Num::ZERO, Num::ZERO, Num::ZERO);
+        let inputs = [
+            root,
+            Num::from(seed),
+            Num::from(1_000_000 + seed),
+            delta,
+            Num::ZERO,
+        ];
+
+        TxDataRequest {
+            tx_type: TxType::Deposit,
+            proof: ProofWithInputs {
+                proof: mock_proof(),
+                inputs: inputs.into_iter().map(ProofInput::from).collect(),
+            },
+            memo: vec![0; 8],
+            extra_data: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_transaction_has_no_side_effects() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(MockBackend::new(mock_config(None, vec![])));
+        let ctx = test_utils::app_state(dir.path(), backend, |_| {}).await;
+        let addr = test_utils::serve(routes(ctx.clone()));
+        let client = reqwest::Client::new();
+
+        let root = ctx.tree.lock("test").await.root().unwrap();
+        let request = deposit_request(root, 1);
+
+        for _ in 0..2 {
+            let res = client
+                .post(format!("http://{addr}/transactions/validate"))
+                .json(&request)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let res: ValidateTransactionResponse = res.json().await.unwrap();
+            assert!(res.valid);
+            assert_eq!(res.estimated_index, 0);
+        }
+
+        {
+            let tree = ctx.tree.lock("test").await;
+            assert_eq!(tree.num_leaves(), 0);
+            assert_eq!(tree.root().unwrap(), root);
+        }
+        assert_eq!(ctx.optimistic_tree_state.borrow().1, 0);
+        assert_eq!(ctx.transactions.next_index().unwrap(), 0);
+        assert_eq!(ctx.job_queue.queue_len().await.unwrap(), 0);
+
+        // The transaction is still accepted, at the estimated index.
+        let res = client
+            .post(format!("http://{addr}/transactions"))
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(ctx.tree.lock("test").await.num_leaves(), 1);
+        assert_eq!(ctx.job_queue.queue_len().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_job_by_index_of_queued_job() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(MockBackend::new(mock_config(None, vec![])));
+        let ctx = test_utils::app_state(dir.path(), backend, |_| {}).await;
+        let addr = test_utils::serve(routes(ctx.clone()));
+        let client = reqwest::Client::new();
+        let get = |index: u64| reqwest::get(format!("http://{addr}/jobByIndex/{index}"));
+
+        for seed in 1..3 {
+            let root = ctx.tree.lock("test").await.root().unwrap();
+            let res = client
+                .post(format!("http://{addr}/transactions"))
+                .json(&deposit_request(root, seed))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        // Without a worker, both jobs stay in the queue.
+        for index in [0, TX_SIZE, TX_SIZE + 1] {
+            let res: JobStatusResponse = get(index).await.unwrap().json().await.unwrap();
+            assert_eq!(res.state, JobStatus::Pending);
+        }
+        assert_eq!(
+            get(2 * TX_SIZE).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_export_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(MockBackend::new(mock_config(None, vec![])));
+        let ctx = test_utils::app_state(dir.path(), backend, |config| {
+            config.admin_token = Some(Secret("admin".to_owned()));
+        })
+        .await;
+        for seed in 1..4 {
+            prepare_job(test_utils::deposit(seed), Uuid::new_v4(), None, ctx.clone())
+                .await
+                .unwrap();
+        }
+        let addr = test_utils::serve(admin_routes(ctx.clone()));
+
+        let res = reqwest::Client::new()
+            .get(format!("http://{addr}/admin/tree/export"))
+            .bearer_auth("admin")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let export = res.bytes().await.unwrap();
+
+        let imported =
+            MerkleTree::open(dir.path().join("imported.persy").to_str().unwrap()).unwrap();
+        imported.import(&export[..]).unwrap();
+        let tree = ctx.tree.lock("test").await;
+        assert_eq!(imported.num_leaves(), 3);
+        assert_eq!(imported.root().unwrap(), tree.root().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(MockBackend::new(mock_config(None, vec![])));
+        let ctx = test_utils::app_state(dir.path(), backend, |_| {}).await;
+        let addr = test_utils::serve(routes(ctx.clone()));
+        let get = |index: u64| reqwest::get(format!("http://{addr}/roots/{index}"));
+
+        prepare_job(test_utils::deposit(1), Uuid::new_v4(), None, ctx.clone())
+            .await
+            .unwrap();
+        let rolled_back = ctx.tree.lock("test").await.root().unwrap();
+
+        let res: RootsResponse = get(TX_SIZE).await.unwrap().json().await.unwrap();
+        assert_eq!(res.index, TX_SIZE);
+        assert_eq!(res.supersede_counter, 0);
+        assert_eq!(
+            res.roots,
+            vec![RootLogEntry {
+                root: rolled_back.to_string(),
+                supersede_counter: 0,
+                canonical: true,
+            }]
+        );
+
+        // Another transaction takes the place of the rolled back one.
+        ctx.transactions.rollback(0).unwrap();
+        ctx.tree.lock("test").await.rollback(0).unwrap();
+        prepare_job(test_utils::deposit(2), Uuid::new_v4(), None, ctx.clone())
+            .await
+            .unwrap();
+        let root = ctx.tree.lock("test").await.root().unwrap();
+
+        let res: RootsResponse = get(TX_SIZE).await.unwrap().json().await.unwrap();
+        assert_eq!(res.supersede_counter, 1);
+        assert_eq!(
+            res.roots,
+            vec![
+                RootLogEntry {
+                    root: rolled_back.to_string(),
+                    supersede_counter: 0,
+                    canonical: false,
+                },
+                RootLogEntry {
+                    root: root.to_string(),
+                    supersede_counter: 1,
+                    canonical: true,
+                },
+            ]
+        );
+
+        assert_eq!(get(1).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            get(2 * TX_SIZE).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
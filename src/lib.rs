@@ -0,0 +1,347 @@
+//! ZeroPool relayer. [`run`] starts the relayer the same way the `zeropool-relayer` binary does,
+//! so it can be embedded into other services and tests.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use libzeropool_rs::libzeropool::{fawkes_crypto::ff_uint::Num, native::tx::make_delta};
+//! use zeropool_relayer::{
+//!     config::Config,
+//!     json_api::TxDataRequest,
+//!     tx::{ProofInput, ProofWithInputs},
+//!     tx_worker::mock_proof,
+//!     Fr,
+//! };
+//! use zeropool_relayer_client::RelayerClient;
+//! use zeropool_tx::TxType;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! // The mock backend, the in-memory job queue and the mock prover, on a random local port.
+//! let dir = tempfile::tempdir()?;
+//! let relayer = zeropool_relayer::run(Config::mock(dir.path())).await?;
+//! let client = RelayerClient::new(&format!("http://{}", relayer.local_addrs()[0]));
+//!
+//! // A deposit made against the current root. The mock prover doesn't verify the proof.
+//! let root: Num<Fr> = client.info().await?.root.parse().unwrap();
+//! let delta = make_delta::<Fr>(Num::from(1000u64), Num::ZERO, Num::ZERO, Num::ZERO);
+//! let nullifier = Num::from(1u64);
+//! let out_commit = Num::from(2u64);
+//! let tx = TxDataRequest {
+//!     tx_type: TxType::Deposit,
+//!     proof: ProofWithInputs {
+//!         proof: mock_proof(),
+//!         inputs: [root, nullifier, out_commit, delta, Num::ZERO]
+//!             .into_iter()
+//!             .map(ProofInput::from)
+//!             .collect(),
+//!     },
+//!     // Starts with the fee.
+//!     memo: 0u64.to_be_bytes().to_vec(),
+//!     extra_data: vec![],
+//! };
+//!
+//! let job_id = client.submit_transaction(&tx).await?;
+//! client.wait_for_job(job_id, Duration::from_secs(60)).await?;
+//! assert_eq!(client.info().await?.mined_transactions, 1);
+//!
+//! relayer.shutdown_trigger().trigger();
+//! relayer.wait().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+#[cfg(feature = "groth16")]
+use libzeropool_rs::libzeropool::fawkes_crypto::backend::bellman_groth16::{
+    engines::Bn256, prover::Proof as Groth16Proof, verifier::VK as VerifyingKey,
+    Parameters as Groth16Parameters,
+};
+#[cfg(feature = "plonk")]
+use libzeropool_rs::libzeropool::fawkes_crypto::backend::plonk::{
+    engines::Bn256, prover::Proof as PlonkProof, setup::VerifyingKey, Parameters as PlonkParameters,
+};
+use libzeropool_rs::libzeropool::native::params::{PoolBN256, PoolParams as PoolParamsTrait};
+use tokio::{
+    sync::Notify,
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    backend::BlockchainBackend,
+    config::{Config, JobQueueKind},
+    instance_lock::InstanceLock,
+    readiness::Readiness,
+    server::Listener,
+    state::{AppState, SyncProgress},
+};
+
+pub type PoolParams = PoolBN256;
+pub type Fr = <PoolParams as PoolParamsTrait>::Fr;
+pub type Fs = <PoolParams as PoolParamsTrait>::Fs;
+pub type Engine = Bn256;
+#[cfg(feature = "groth16")]
+pub type Proof = Groth16Proof<Engine>;
+#[cfg(feature = "plonk")]
+pub type Proof = PlonkProof;
+pub type VK = VerifyingKey<Bn256>;
+#[cfg(feature = "groth16")]
+pub type Parameters = Groth16Parameters<Engine>;
+#[cfg(feature = "plonk")]
+pub type Parameters = PlonkParameters<Engine>;
+
+mod api_key;
+pub mod audit;
+pub mod backend;
+pub mod build_info;
+mod ciphertext;
+pub mod cli;
+pub mod config;
+mod failed_jobs;
+pub mod instance_lock;
+pub mod job_queue;
+pub mod json_api;
+mod maintenance;
+mod merkle_tree;
+mod monitoring;
+mod openapi;
+pub mod params;
+mod peer_sync;
+mod rate_limit;
+mod readiness;
+mod reorg;
+pub mod replica;
+mod root_check;
+mod server;
+mod settings_reload;
+pub mod state;
+mod state_archive;
+mod timed_mutex;
+pub mod tx;
+mod tx_storage;
+pub mod tx_worker;
+
+#[cfg(test)]
+mod test_utils;
+
+/// Stops a relayer started with [`run`].
+#[derive(Clone, Default)]
+pub struct ShutdownTrigger(Arc<Notify>);
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        // Stores a permit, so the shutdown isn't missed if `RelayerHandle::wait` isn't polled yet.
+        self.0.notify_one();
+    }
+
+    async fn triggered(&self) {
+        self.0.notified().await
+    }
+}
+
+/// Tasks of a running relayer, see [`run`].
+pub struct RelayerHandle {
+    state: Arc<AppState>,
+    servers: JoinSet<Result<()>>,
+    worker: JoinHandle<Result<()>>,
+    lock_heartbeat: JoinHandle<Result<()>>,
+    /// Maintenance, reorg detection and the other periodic tasks.
+    background: JoinSet<()>,
+    shutdown: ShutdownTrigger,
+    local_addrs: Vec<SocketAddr>,
+}
+
+impl RelayerHandle {
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    pub fn shutdown_trigger(&self) -> ShutdownTrigger {
+        self.shutdown.clone()
+    }
+
+    /// Bound addresses of the TCP listeners of the public API, see `Config::listen`.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Waits until the relayer is stopped with the [`ShutdownTrigger`] or one of its tasks fails,
+    /// then stops the remaining tasks and releases the instance lock. Jobs that are already being
+    /// processed are not waited for.
+    pub async fn wait(mut self) -> Result<()> {
+        let result = tokio::select! {
+            _ = self.shutdown.triggered() => {
+                tracing::info!("Shutting down");
+                Ok(())
+            }
+            Some(res) = self.servers.join_next() => {
+                Err(anyhow!("JSON API critical error: {:?}", flatten(res)))
+            }
+            res = &mut self.worker => {
+                Err(anyhow!("Worker critical error: {:?}", flatten(res)))
+            }
+            res = &mut self.lock_heartbeat => {
+                Err(anyhow!("Stopping, the instance lock is lost: {:?}", flatten(res)))
+            }
+        };
+
+        self.servers.shutdown().await;
+        self.background.shutdown().await;
+        self.worker.abort();
+        self.lock_heartbeat.abort();
+
+        if let Some(lock) = &self.state.instance_lock {
+            if let Err(err) = lock.release().await {
+                tracing::warn!("Failed to release the instance lock: {err:#}");
+            }
+        }
+
+        result
+    }
+}
+
+fn flatten(res: Result<Result<()>, tokio::task::JoinError>) -> Result<()> {
+    res?
+}
+
+/// Initializes the state, syncing it with the chain, and starts the API servers, the worker and
+/// the periodic tasks.
+///
+/// With the Redis job queue, the instance lock is taken first. Fails with
+/// [`instance_lock::AlreadyLocked`] if another relayer is running.
+pub async fn run(config: Config) -> Result<RelayerHandle> {
+    start(config, None).await
+}
+
+/// Same as [`run`], with a backend created by the caller instead of the configured one, e.g. a
+/// [`backend::mock::MockBackend`] shared by a primary relayer and its standby.
+pub async fn run_with_backend(
+    config: Config,
+    backend: Arc<dyn BlockchainBackend>,
+) -> Result<RelayerHandle> {
+    start(config, Some(backend)).await
+}
+
+async fn start(
+    config: Config,
+    backend: Option<Arc<dyn BlockchainBackend>>,
+) -> Result<RelayerHandle> {
+    // Bound first, so that the addresses are known even if they were configured with port 0.
+    let listeners = config
+        .listen
+        .iter()
+        .map(Listener::bind)
+        .collect::<Result<Vec<_>>>()?;
+    let local_addrs = listeners.iter().filter_map(Listener::local_addr).collect();
+
+    // Taken before the storage is opened. The in-memory job queue is not shared, and the storage
+    // files are locked by persy.
+    let instance_lock = match &config.job_queue {
+        JobQueueKind::Redis { url } => {
+            let ttl = Duration::from_millis(config.instance_lock_ttl_ms);
+            Some(Arc::new(
+                InstanceLock::acquire(url, config.pool_id, ttl).await?,
+            ))
+        }
+        JobQueueKind::Memory => None,
+    };
+    let mut lock_heartbeat = tokio::spawn({
+        let instance_lock = instance_lock.clone();
+        async move {
+            match instance_lock {
+                Some(lock) => instance_lock::run(lock).await,
+                None => std::future::pending().await,
+            }
+        }
+    });
+
+    let admin_listen = config.admin_listen.clone();
+
+    // The servers are started before the state is initialized, so that `/health/ready` can
+    // report the sync progress.
+    let progress = Arc::new(SyncProgress::default());
+    let public = Readiness::new(progress.clone());
+    let admin = Readiness::new(progress.clone());
+
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        servers.spawn(server::serve_listener(listener, public.router()));
+    }
+    for addr in &admin_listen {
+        servers.spawn(server::serve(addr.clone(), admin.router()));
+    }
+
+    let init = async {
+        match backend {
+            Some(backend) => {
+                AppState::init_with_backend(config, backend, progress, instance_lock.clone()).await
+            }
+            None => AppState::init(config, progress, instance_lock.clone()).await,
+        }
+    };
+    let ctx = tokio::select! {
+        ctx = init => match ctx {
+            Ok(ctx) => Arc::new(ctx),
+            Err(err) => {
+                lock_heartbeat.abort();
+                if let Some(lock) = &instance_lock {
+                    lock.release().await.ok();
+                }
+                return Err(err);
+            }
+        },
+        Some(res) = servers.join_next() => {
+            lock_heartbeat.abort();
+            if let Some(lock) = &instance_lock {
+                lock.release().await.ok();
+            }
+            return Err(anyhow!("JSON API critical error: {:?}", flatten(res)));
+        }
+        res = &mut lock_heartbeat => {
+            servers.shutdown().await;
+            return Err(anyhow!("Stopping, the instance lock is lost: {:?}", flatten(res)));
+        }
+    };
+
+    let worker = ctx.job_queue.start(
+        ctx.clone(),
+        ctx.config.job_retry.clone(),
+        ctx.job_permits.clone(),
+        tx_worker::process_job,
+        tx_worker::process_failure,
+    )?;
+
+    let mut background = JoinSet::new();
+    background.spawn(maintenance::run(ctx.clone()));
+    background.spawn(reorg::run(ctx.clone()));
+    background.spawn(settings_reload::run(ctx.clone()));
+    if let Some(interval_secs) = ctx.config.root_check_interval_secs {
+        background.spawn(root_check::run(ctx.clone(), interval_secs));
+    }
+    if let Some(primary_url) = ctx.config.standby_primary_url.clone() {
+        background.spawn(peer_sync::run(ctx.clone(), primary_url));
+    }
+
+    let routes = json_api::routes(ctx.clone());
+    let admin_routes = json_api::admin_routes(ctx.clone());
+
+    if admin_listen.is_empty() {
+        public.set_routes(routes.merge(admin_routes));
+    } else {
+        public.set_routes(routes);
+        admin.set_routes(admin_routes);
+    }
+    tracing::info!("Relayer is ready");
+
+    Ok(RelayerHandle {
+        state: ctx,
+        servers,
+        worker,
+        lock_heartbeat,
+        background,
+        shutdown: ShutdownTrigger::default(),
+        local_addrs,
+    })
+}
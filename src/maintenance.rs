@@ -0,0 +1,78 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+
+use crate::{job_queue::unix_timestamp, monitoring, state::AppState, tx_worker::TX_SIZE};
+
+/// Periodically reports database statistics and prunes old historic roots, failed jobs, job
+/// queue stats and the payloads of mined transactions.
+pub async fn run(ctx: Arc<AppState>) {
+    let period = Duration::from_secs(ctx.config.maintenance_interval_secs);
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = run_once(&ctx).await {
+            tracing::error!("Maintenance failed: {err}");
+        }
+    }
+}
+
+async fn run_once(ctx: &AppState) -> Result<()> {
+    let tree = ctx.tree.lock("maintenance").await;
+
+    if let Some(retention) = ctx.config.roots_retention {
+        let pruned = tree.prune_roots(tree.num_leaves().saturating_sub(retention))?;
+        if pruned > 0 {
+            tracing::info!("Pruned {pruned} historic roots");
+        }
+    }
+
+    let tree_size = tree.file_size()?;
+    let tree_records = tree.num_leaves();
+    drop(tree);
+
+    let pruned = ctx.failed_jobs.prune(
+        ctx.config.failed_jobs_max_count,
+        unix_timestamp().saturating_sub(ctx.config.failed_jobs_max_age_secs),
+    )?;
+    if pruned > 0 {
+        tracing::info!("Pruned {pruned} archived failed jobs");
+    }
+
+    ctx.job_queue.prune_stats().await?;
+
+    // Otherwise only pruned when the next transaction is accepted.
+    ctx.optimistic_log.prune(*ctx.pool_index.read().await);
+
+    let transactions_size = ctx.transactions.file_size()?;
+    let transactions_records = ctx.transactions.count()?;
+
+    tracing::debug!("Tree: {tree_size} bytes, {tree_records} leaves");
+    tracing::debug!("Transactions: {transactions_size} bytes, {transactions_records} records");
+    monitoring::set_db_stats("tree", tree_size, tree_records);
+    monitoring::set_db_stats("transactions", transactions_size, transactions_records);
+
+    Ok(())
+}
+
+/// Compacts the tree and transaction storage. Returns `false` without doing anything if there
+/// are pending jobs, since their writes could be lost.
+pub async fn compact(ctx: &AppState) -> Result<bool> {
+    // Holding the tree lock prevents new jobs from being created.
+    let mut tree = ctx.tree.lock("compact").await;
+
+    let queue_len = ctx.job_queue.queue_len().await?;
+    let pool_index = *ctx.pool_index.read().await;
+    if queue_len > 0 || tree.num_leaves() * TX_SIZE != pool_index {
+        return Ok(false);
+    }
+
+    tracing::info!("Compacting databases...");
+    tree.compact()?;
+    ctx.transactions.compact()?;
+    tracing::info!("Compaction complete");
+
+    Ok(true)
+}
@@ -1,7 +1,10 @@
-use std::str::FromStr;
+use std::{
+    io::{ErrorKind, Read, Write},
+    str::FromStr,
+};
 
-use anyhow::{anyhow, bail, Result};
-use borsh::BorshDeserialize;
+use anyhow::{anyhow, bail, Context, Result};
+use borsh::{BorshDeserialize, BorshSerialize};
 use libzeropool_rs::libzeropool::{
     constants,
     fawkes_crypto::{
@@ -11,19 +14,52 @@ use libzeropool_rs::libzeropool::{
     native::params::PoolParams,
     POOL_PARAMS,
 };
-use persy::{ByteVec, Persy, Transaction, ValueMode};
+use persy::{ByteVec, Persy, Snapshot, Transaction, ValueMode};
 
-use crate::Fr;
+use crate::{job_queue::unix_timestamp, Fr};
 
 type Hash = Num<Fr>;
 type Index = u64;
 
+/// Size of a borsh encoded `(depth, index, hash)` in a tree export.
+const NODE_RECORD_SIZE: usize = 8 + 8 + 32;
+
+/// A root that the tree had at some point, see [`MerkleTree::root_log`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootLogEntry {
+    pub root: Hash,
+    /// Number of rollbacks before the root was recorded.
+    pub supersede_counter: u64,
+    /// `false` once the leaves the root was computed for have been rolled back.
+    pub canonical: bool,
+}
+
 struct Storage {
     db: Persy,
+    path: String,
 }
 
 impl Storage {
     fn open(path: &str) -> Result<Self> {
+        // Fails with the I/O error itself, rather than one persy reports as a broken file.
+        if std::path::Path::new(path).exists() {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .with_context(|| format!("Failed to open the merkle tree {path}"))?;
+        }
+
+        let db = Self::open_db(path)
+            .with_context(|| format!("Failed to open the merkle tree {path}"))?;
+
+        Ok(Self {
+            db,
+            path: path.to_owned(),
+        })
+    }
+
+    fn open_db(path: &str) -> Result<Persy> {
         let db = Persy::open_or_create_with(path, Default::default(), |db| {
             let mut tx = db.begin()?;
 
@@ -40,18 +76,87 @@ impl Storage {
                 tx.create_index::<Index, String>("roots", ValueMode::Replace)?;
             }
 
-            tx.prepare().unwrap().commit().unwrap();
+            tx.prepare()?.commit()?;
 
             Ok(())
-        })
-        .unwrap();
+        })?;
 
-        Ok(Self { db })
+        // Added later, starts with the roots that are already stored
+        if !db.exists_index("root_log")? {
+            let mut tx = db.begin()?;
+            tx.create_index::<u128, String>("root_log", ValueMode::Replace)?;
+            tx.create_index::<u64, Index>("rollbacks", ValueMode::Replace)?;
+            for (index, mut roots) in db.range::<Index, String, _>("roots", ..)? {
+                if let Some(root) = roots.next() {
+                    tx.put::<u128, String>("root_log", Self::root_log_key(index, 0), root)?;
+                }
+            }
+            tx.prepare()?.commit()?;
+        }
+
+        Ok(db)
     }
 
-    fn clear(&self) -> Result<()> {
-        let mut tx = self.db.begin()?;
+    /// Copies all live records into a fresh file and replaces the current file with it.
+    fn compact(&mut self) -> Result<()> {
+        let tmp_path = format!("{}.compact", self.path);
+        if std::path::Path::new(&tmp_path).exists() {
+            std::fs::remove_file(&tmp_path)?;
+        }
+
+        let compacted = Self::open_db(&tmp_path)?;
+        let mut tx = compacted.begin()?;
 
+        for (key, mut values) in self.db.range::<Index, ByteVec, _>("data_index", ..)? {
+            if let Some(value) = values.next() {
+                tx.put::<Index, ByteVec>("data_index", key, value)?;
+            }
+        }
+
+        for (key, mut values) in self.db.range::<Index, String, _>("roots", ..)? {
+            if let Some(value) = values.next() {
+                tx.put::<Index, String>("roots", key, value)?;
+            }
+        }
+
+        for (key, mut values) in self.db.range::<u128, String, _>("root_log", ..)? {
+            if let Some(value) = values.next() {
+                tx.put::<u128, String>("root_log", key, value)?;
+            }
+        }
+
+        for (key, mut values) in self.db.range::<u64, Index, _>("rollbacks", ..)? {
+            if let Some(value) = values.next() {
+                tx.put::<u64, Index>("rollbacks", key, value)?;
+            }
+        }
+
+        tx.put(
+            "meta_index",
+            "num_leaves".to_owned(),
+            self.get_num_leaves()?,
+        )?;
+        tx.put(
+            "meta_index",
+            "supersede_counter".to_owned(),
+            self.get_supersede_counter()?,
+        )?;
+        tx.prepare()?.commit()?;
+        drop(compacted);
+
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.db = Self::open_db(&self.path)?;
+
+        Ok(())
+    }
+
+    fn file_size(&self) -> Result<u64> {
+        Ok(std::fs::metadata(&self.path)?.len())
+    }
+
+    /// The root log and the rollback markers are kept.
+    fn clear_tx(&self, tx: &mut Transaction) -> Result<()> {
+        let supersede_counter = self.get_supersede_counter()?;
         tx.drop_index("data_index")?;
         tx.drop_index("meta_index")?;
         tx.drop_index("roots")?;
@@ -59,8 +164,11 @@ impl Storage {
         tx.create_index::<String, Index>("meta_index", ValueMode::Replace)?;
         tx.create_index::<Index, String>("roots", ValueMode::Replace)?;
         tx.put::<String, Index>("meta_index", "num_leaves".to_owned(), 0)?;
-
-        tx.prepare()?.commit()?;
+        tx.put::<String, Index>(
+            "meta_index",
+            "supersede_counter".to_owned(),
+            supersede_counter,
+        )?;
 
         Ok(())
     }
@@ -95,6 +203,29 @@ impl Storage {
             .expect("No latest_leaf_index key in the database"))
     }
 
+    fn get_supersede_counter(&self) -> Result<u64> {
+        Ok(self
+            .db
+            .one("meta_index", &"supersede_counter".to_owned())?
+            .unwrap_or(0))
+    }
+
+    /// Records that the leaves with index >= `index` were removed.
+    fn add_rollback_marker_tx(&self, tx: &mut Transaction, index: Index) -> Result<()> {
+        let supersede_counter = tx
+            .one::<String, u64>("meta_index", &"supersede_counter".to_owned())?
+            .unwrap_or(0)
+            + 1;
+        tx.put(
+            "meta_index",
+            "supersede_counter".to_owned(),
+            supersede_counter,
+        )?;
+        tx.put::<u64, Index>("rollbacks", supersede_counter, index)?;
+
+        Ok(())
+    }
+
     fn set(&self, depth: Index, index: Index, value: Hash) -> Result<()> {
         let mut tx = self.db.begin()?;
         self.set_tx(&mut tx, depth, index, value)?;
@@ -135,6 +266,12 @@ impl Storage {
         Ok(res)
     }
 
+    fn snapshot(&self) -> Result<TreeSnapshot> {
+        Ok(TreeSnapshot {
+            snapshot: self.db.snapshot()?,
+        })
+    }
+
     fn delete(&self, depth: Index, index: Index) -> Result<()> {
         let mut tx = self.db.begin()?;
 
@@ -185,14 +322,61 @@ impl Storage {
 
     fn add_root(&self, index: Index, root: Hash) -> Result<()> {
         let mut tx = self.db.begin()?;
+        self.add_root_tx(&mut tx, index, root)?;
+        tx.prepare()?.commit()?;
+
+        Ok(())
+    }
 
+    fn add_root_tx(&self, tx: &mut Transaction, index: Index, root: Hash) -> Result<()> {
         tx.put::<Index, String>("roots", index, root.to_string())?;
 
-        tx.prepare()?.commit()?;
+        let supersede_counter = tx
+            .one::<String, u64>("meta_index", &"supersede_counter".to_owned())?
+            .unwrap_or(0);
+        tx.put::<u128, String>(
+            "root_log",
+            Self::root_log_key(index, supersede_counter),
+            root.to_string(),
+        )?;
 
         Ok(())
     }
 
+    /// Roots recorded for `index` leaves, oldest first.
+    fn get_root_log(&self, index: Index) -> Result<Vec<RootLogEntry>> {
+        let range = Self::root_log_key(index, 0)..Self::root_log_key(index + 1, 0);
+
+        let mut entries: Vec<RootLogEntry> = Vec::new();
+        for (key, mut roots) in self.db.range::<u128, String, _>("root_log", range)? {
+            let Some(root) = roots.next() else {
+                continue;
+            };
+            let root = Hash::from_str(&root).map_err(|_| anyhow!("Invalid hash"))?;
+            let supersede_counter = key as u64;
+
+            // Superseded by a later rollback below the index
+            let canonical = !self
+                .db
+                .range::<u64, Index, _>("rollbacks", supersede_counter + 1..)?
+                .any(|(_, mut to)| to.next().map_or(false, |to| to < index));
+
+            // The same root again after a rollback and the same leaves
+            entries.retain(|entry| entry.root != root);
+            entries.push(RootLogEntry {
+                root,
+                supersede_counter,
+                canonical,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn root_log_key(index: Index, supersede_counter: u64) -> u128 {
+        ((index as u128) << 64) | supersede_counter as u128
+    }
+
     fn get_root(&self, index: Index) -> Result<Option<Hash>> {
         let res = if let Some(data) = self.db.one::<Index, String>("roots", &index)? {
             Some(Hash::from_str(&data).map_err(|_| anyhow!("Invalid hash"))?)
@@ -220,20 +404,111 @@ impl Storage {
         Ok(())
     }
 
+    /// Removes all roots with index < `before` from the roots and the root log, returns the
+    /// number of removed roots. Rollback markers are never removed.
+    fn prune_roots(&self, before: Index) -> Result<u64> {
+        let indices = self.db.range::<Index, String, _>("roots", ..before)?;
+        let log_keys = self
+            .db
+            .range::<u128, String, _>("root_log", ..Self::root_log_key(before, 0))?;
+
+        let mut tx = self.db.begin()?;
+        let mut removed = 0;
+        for (index, _) in indices {
+            tx.remove::<Index, String>("roots", index, None)?;
+            removed += 1;
+        }
+        for (key, _) in log_keys {
+            tx.remove::<u128, String>("root_log", key, None)?;
+        }
+        tx.prepare()?.commit()?;
+
+        Ok(removed)
+    }
+
     fn key(depth: Index, index: Index) -> Index {
         (1 << depth) - 1 + index
     }
+
+    fn depth_and_index(key: Index) -> (Index, Index) {
+        let depth = (key + 1).ilog2() as Index;
+        (depth, key + 1 - (1 << depth))
+    }
 }
 
 const H: usize = constants::HEIGHT - constants::OUTPLUSONELOG;
 
+#[cfg(test)]
+thread_local! {
+    /// Name of the fail point at which the current tree operation should be aborted.
+    static FAIL_AT: std::cell::Cell<Option<&'static str>> = std::cell::Cell::new(None);
+}
+
+/// Aborts a multi-step operation in tests to simulate a crash between the steps.
+fn fail_point(_name: &'static str) -> Result<()> {
+    #[cfg(test)]
+    if FAIL_AT.with(|fail_at| fail_at.get()) == Some(_name) {
+        bail!("Simulated crash at {_name}");
+    }
+
+    Ok(())
+}
+
+type RootObserver = Box<dyn Fn(Hash, Index, u64) + Send + Sync>;
+
+/// See [`MerkleTree::snapshot`].
+pub struct TreeSnapshot {
+    snapshot: Snapshot,
+}
+
+impl TreeSnapshot {
+    /// Writes the number of leaves followed by every stored node as `(depth, index, hash)`, all
+    /// borsh encoded. Nodes that are not stored have the default hash of their depth. Historic
+    /// roots are not exported, [`MerkleTree::import`] only restores the current one.
+    pub fn export(&self, mut writer: impl Write) -> Result<()> {
+        self.num_leaves()?.serialize(&mut writer)?;
+        for node in self.nodes()? {
+            node?.serialize(&mut writer)?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn num_leaves(&self) -> Result<Index> {
+        Ok(self
+            .snapshot
+            .one("meta_index", &"num_leaves".to_owned())?
+            .expect("No latest_leaf_index key in the database"))
+    }
+
+    /// All stored nodes as `(depth, index, hash)`, ordered by depth, then by index.
+    fn nodes(&self) -> Result<impl Iterator<Item = Result<(Index, Index, Hash)>>> {
+        let nodes = self
+            .snapshot
+            .range::<Index, ByteVec, _>("data_index", ..)?
+            .filter_map(|(key, mut values)| Some((key, values.next()?)))
+            .map(|(key, data)| {
+                let (depth, index) = Storage::depth_and_index(key);
+                Ok((depth, index, Hash::try_from_slice(&data)?))
+            });
+
+        Ok(nodes)
+    }
+}
+
 pub struct MerkleTree {
     nodes: Storage,
     /// For empty nodes with index >= length
     default_nodes: Vec<Hash>,
+    /// Called with the new root, number of leaves and supersede counter after every committed
+    /// change of the root.
+    on_root_change: Option<RootObserver>,
 }
 
 impl MerkleTree {
+    /// Fails if the file at `path` is not a valid tree, see [`MerkleTree::clear_and_open`] and
+    /// [`is_corrupted`].
     pub fn open(path: &str) -> Result<Self> {
         let nodes = Storage::open(path)?;
 
@@ -252,18 +527,47 @@ impl MerkleTree {
         Ok(Self {
             nodes,
             default_nodes,
+            on_root_change: None,
         })
     }
 
+    /// Moves the file at `path` aside to `{path}.corrupted-{unix timestamp}` and opens an empty
+    /// tree instead. A missing file is not an error.
     pub fn clear_and_open(path: &str) -> Result<Self> {
-        std::fs::remove_file(&path)?;
+        match std::fs::rename(path, format!("{path}.corrupted-{}", unix_timestamp())) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
         Self::open(path)
     }
 
-    fn set_node(&self, depth: u64, index: u64, hash: Hash) -> Result<()> {
-        let mut tx = self.nodes.begin()?;
+    /// Registers a callback invoked with the new root, number of leaves and supersede counter
+    /// whenever `add_leaf`, `add_leaves_at` or `rollback` changes the tree. Replaces the previous
+    /// callback.
+    pub fn set_on_root_change<F>(&mut self, f: F)
+    where
+        F: Fn(Hash, Index, u64) + Send + Sync + 'static,
+    {
+        self.on_root_change = Some(Box::new(f));
+    }
+
+    fn notify_root_change(&self) -> Result<()> {
+        if let Some(on_root_change) = &self.on_root_change {
+            on_root_change(
+                self.root()?,
+                self.nodes.get_num_leaves()?,
+                self.nodes.get_supersede_counter()?,
+            );
+        }
 
-        self.nodes.set_tx(&mut tx, depth, index, hash)?;
+        Ok(())
+    }
+
+    /// Sets the node and recalculates its parents. All writes happen within `tx`.
+    fn set_node_tx(&self, tx: &mut Transaction, depth: u64, index: u64, hash: Hash) -> Result<()> {
+        self.nodes.set_tx(tx, depth, index, hash)?;
 
         let mut cur_hash = hash;
         for (i, depth) in (1..=depth).rev().enumerate() {
@@ -273,7 +577,7 @@ impl MerkleTree {
                 let sibling_index = cur_index ^ 1;
                 let sibling_hash = self
                     .nodes
-                    .get_tx(&mut tx, depth, sibling_index)?
+                    .get_tx(tx, depth, sibling_index)?
                     .unwrap_or(self.default_nodes[depth as usize]);
 
                 if cur_index & 1 == 0 {
@@ -290,111 +594,184 @@ impl MerkleTree {
 
             if cur_hash != self.default_nodes[parent_depth as usize] {
                 self.nodes
-                    .set_tx(&mut tx, parent_depth, parent_index, cur_hash)?;
+                    .set_tx(tx, parent_depth, parent_index, cur_hash)?;
             } else {
-                self.nodes.delete_tx(&mut tx, parent_depth, parent_index)?; // TODO: Move cleaning up into a separate function?
+                self.nodes.delete_tx(tx, parent_depth, parent_index)?; // TODO: Move cleaning up into a separate function?
             }
         }
 
-        self.nodes.commit(tx)?;
-
         Ok(())
     }
 
+    fn root_tx(&self, tx: &mut Transaction) -> Result<Hash> {
+        Ok(self
+            .nodes
+            .get_tx(tx, 0, 0)?
+            .unwrap_or_else(|| self.default_nodes[0]))
+    }
+
     fn set_leaf(&self, index: Index, hash: Hash) -> Result<()> {
-        self.set_node(H as Index, index, hash)?;
+        let num_leaves = self.nodes.get_num_leaves()?;
+        let mut tx = self.nodes.begin()?;
 
-        if self.get_node(H as Index, index)?.is_none() {
-            self.nodes.set_num_leaves(index + 1)?;
-        }
+        self.set_node_tx(&mut tx, H as Index, index, hash)?;
+        fail_point("set_leaf:nodes")?;
+
+        let num_leaves = num_leaves.max(index + 1);
+        self.nodes.set_num_leaves_tx(&mut tx, num_leaves)?;
+        fail_point("set_leaf:num_leaves")?;
 
-        self.nodes.add_root(index, hash)?;
+        let root = self.root_tx(&mut tx)?;
+        self.nodes.add_root_tx(&mut tx, num_leaves, root)?;
+
+        self.nodes.commit(tx)?;
+
+        self.notify_root_change()?;
 
         Ok(())
     }
 
     pub fn add_leaf(&self, hash: Hash) -> Result<()> {
         let index = self.nodes.get_num_leaves()?;
-        self.set_node(H as Index, index, hash)?;
-        self.nodes.set_num_leaves(index + 1)?;
+        let mut tx = self.nodes.begin()?;
+
+        self.set_node_tx(&mut tx, H as Index, index, hash)?;
+        fail_point("add_leaf:nodes")?;
+
+        self.nodes.set_num_leaves_tx(&mut tx, index + 1)?;
+        fail_point("add_leaf:num_leaves")?;
+
+        let root = self.root_tx(&mut tx)?;
+        self.nodes.add_root_tx(&mut tx, index + 1, root)?;
 
-        let root = self.root()?;
-        self.nodes.add_root(index + 1, root)?;
+        self.nodes.commit(tx)?;
+
+        self.notify_root_change()?;
 
         Ok(())
     }
 
-    // /// Provides a more efficient way to add multiple leaves at once. Not used anywhere yet.
-    // pub fn add_leaves_at<I: IntoIterator<Item = Hash>>(
-    //     &self,
-    //     index: Index,
-    //     leaves: I,
-    // ) -> Result<()> {
-    //     let mut tx = self.nodes.begin()?;
-    //
-    //     let leaves = leaves.into_iter();
-    //     let mut num_leaves = 0;
-    //     for (i, hash) in leaves.into_iter().enumerate() {
-    //         self.nodes
-    //             .set_tx(&mut tx, H as Index, index + i as Index, hash)?;
-    //         num_leaves += 1;
-    //     }
-    //
-    //     if num_leaves == 0 {
-    //         return Ok(());
-    //     }
-    //
-    //     for (i, depth) in (1..=H as u64).rev().enumerate() {
-    //         let mut cur_index = index >> i;
-    //         if cur_index & 1 == 1 {
-    //             cur_index -= 1;
-    //         }
-    //
-    //         let num_nodes = (num_leaves as u64 >> i).max(1);
-    //
-    //         for lhs_index in (cur_index..=(cur_index + num_nodes)).step_by(2) {
-    //             let rhs_index = lhs_index + 1;
-    //
-    //             let parent_hash = {
-    //                 let lhs_hash = self
-    //                     .nodes
-    //                     .get_tx(&mut tx, depth, lhs_index)?
-    //                     .unwrap_or(self.default_nodes[depth as usize]);
-    //
-    //                 let rhs_hash = self
-    //                     .nodes
-    //                     .get_tx(&mut tx, depth, rhs_index)?
-    //                     .unwrap_or(self.default_nodes[depth as usize]);
-    //
-    //                 poseidon(&[lhs_hash, rhs_hash], POOL_PARAMS.compress())
-    //             };
-    //
-    //             let parent_depth = depth - 1;
-    //             let parent_index = lhs_index / 2;
-    //
-    //             if parent_hash == self.default_nodes[parent_depth as usize] {
-    //                 self.nodes.delete_tx(&mut tx, parent_depth, parent_index)?;
-    //             } else {
-    //                 self.nodes
-    //                     .set_tx(&mut tx, parent_depth, parent_index, parent_hash)?;
-    //             }
-    //         }
-    //     }
-    //
-    //     let old_num_leaves = self.nodes.get_num_leaves()?;
-    //     let new_num_leaves = old_num_leaves + num_leaves;
-    //     self.nodes.set_num_leaves_tx(&mut tx, new_num_leaves)?;
-    //
-    //     self.nodes.commit(tx)?;
-    //
-    //     Ok(())
-    // }
+    /// Provides a more efficient way to add multiple leaves at once: every affected parent is
+    /// recalculated only once and all writes happen in a single transaction.
+    ///
+    /// Records the same historic roots as adding the leaves one by one would: one for every leaf
+    /// count past `index`.
+    pub fn add_leaves_at<I: IntoIterator<Item = Hash>>(
+        &self,
+        index: Index,
+        leaves: I,
+    ) -> Result<()> {
+        let old_num_leaves = self.nodes.get_num_leaves()?;
+
+        if index > old_num_leaves {
+            bail!("Cannot add leaves at {index}, the tree only has {old_num_leaves} leaves");
+        }
+
+        let mut tx = self.nodes.begin()?;
+
+        let mut num_leaves = 0;
+        for (i, hash) in leaves.into_iter().enumerate() {
+            self.nodes
+                .set_tx(&mut tx, H as Index, index + i as Index, hash)?;
+            num_leaves += 1;
+        }
+
+        if num_leaves == 0 {
+            return Ok(());
+        }
+
+        let last_index = index + num_leaves - 1;
+
+        for (i, depth) in (1..=H as Index).rev().enumerate() {
+            let parent_depth = depth - 1;
+            let first_parent_index = index >> (i + 1);
+            let last_parent_index = last_index >> (i + 1);
+
+            for parent_index in first_parent_index..=last_parent_index {
+                let parent_hash = {
+                    let lhs_hash = self
+                        .nodes
+                        .get_tx(&mut tx, depth, parent_index * 2)?
+                        .unwrap_or(self.default_nodes[depth as usize]);
+
+                    let rhs_hash = self
+                        .nodes
+                        .get_tx(&mut tx, depth, parent_index * 2 + 1)?
+                        .unwrap_or(self.default_nodes[depth as usize]);
+
+                    poseidon(&[lhs_hash, rhs_hash], POOL_PARAMS.compress())
+                };
+
+                if parent_hash == self.default_nodes[parent_depth as usize] {
+                    self.nodes.delete_tx(&mut tx, parent_depth, parent_index)?;
+                } else {
+                    self.nodes
+                        .set_tx(&mut tx, parent_depth, parent_index, parent_hash)?;
+                }
+            }
+        }
+
+        let new_num_leaves = old_num_leaves.max(last_index + 1);
+        self.nodes.set_num_leaves_tx(&mut tx, new_num_leaves)?;
+
+        for num_leaves in (index + 1)..=new_num_leaves {
+            let root = self.prefix_root_tx(&mut tx, num_leaves)?;
+            self.nodes.add_root_tx(&mut tx, num_leaves, root)?;
+        }
+
+        self.nodes.commit(tx)?;
+
+        self.notify_root_change()?;
+
+        Ok(())
+    }
+
+    /// The root of the tree made of only the first `num_leaves` leaves. Every left sibling on the
+    /// path of the last of them only covers earlier leaves, every right sibling is empty.
+    fn prefix_root_tx(&self, tx: &mut Transaction, num_leaves: Index) -> Result<Hash> {
+        if num_leaves == 0 {
+            return Ok(self.default_nodes[0]);
+        }
+
+        let mut index = num_leaves - 1;
+        let mut hash = self
+            .nodes
+            .get_tx(tx, H as Index, index)?
+            .unwrap_or(self.default_nodes[H]);
 
-    /// Deletes all leaves from the tree with i >= index, recalculating the parents.
+        for depth in (1..=H as Index).rev() {
+            let pair = if index & 1 == 1 {
+                let sibling = self
+                    .nodes
+                    .get_tx(tx, depth, index - 1)?
+                    .unwrap_or(self.default_nodes[depth as usize]);
+                [sibling, hash]
+            } else {
+                [hash, self.default_nodes[depth as usize]]
+            };
+
+            hash = poseidon(&pair, POOL_PARAMS.compress());
+            index >>= 1;
+        }
+
+        Ok(hash)
+    }
+
+    /// Deletes all leaves from the tree with i >= index, recalculating the parents. The roots
+    /// after the removed leaves stay in the root log, marked as superseded.
     pub fn rollback(&self, index: Index) -> Result<()> {
         if index == 0 {
-            self.nodes.clear()?;
-            self.nodes.set_num_leaves(0)?;
+            let num_leaves = self.nodes.get_num_leaves()?;
+            let mut tx = self.nodes.begin()?;
+            self.nodes.clear_tx(&mut tx)?;
+            if num_leaves > 0 {
+                self.nodes.add_rollback_marker_tx(&mut tx, 0)?;
+            }
+            fail_point("rollback:clear")?;
+            self.nodes.add_root_tx(&mut tx, 0, self.default_nodes[0])?;
+            self.nodes.commit(tx)?;
+
+            self.notify_root_change()?;
             return Ok(());
         }
 
@@ -405,8 +782,12 @@ impl MerkleTree {
         }
 
         let mut tx = self.nodes.begin()?;
-        self.nodes.delete_roots_tx(&mut tx, index..old_num_leaves)?;
+        self.nodes
+            .delete_roots_tx(&mut tx, (index + 1)..=old_num_leaves)?;
+        self.nodes.add_rollback_marker_tx(&mut tx, index)?;
+        fail_point("rollback:roots")?;
         self.nodes.set_num_leaves_tx(&mut tx, index)?;
+        fail_point("rollback:num_leaves")?;
         self.nodes.delete_tx(&mut tx, H as Index, index)?;
 
         for (h, depth) in (1..=H as Index).rev().enumerate() {
@@ -453,6 +834,82 @@ impl MerkleTree {
 
         self.nodes.commit(tx)?;
 
+        self.notify_root_change()?;
+
+        Ok(())
+    }
+
+    /// Recomputes every internal node of the filled part of the tree from its children and
+    /// compares it to the stored value, treating missing nodes as default. Also checks that no
+    /// nodes are left right after the last leaf at each level. Returns `false` at the first
+    /// mismatch.
+    pub fn verify_consistency(&self) -> Result<bool> {
+        let num_leaves = self.nodes.get_num_leaves()?;
+        if num_leaves == 0 {
+            return Ok(self.root()? == self.default_nodes[0]);
+        }
+
+        let last_index = num_leaves - 1;
+
+        for (i, depth) in (1..=H as Index).rev().enumerate() {
+            if self.get_node(depth, (last_index >> i) + 1)?.is_some() {
+                return Ok(false);
+            }
+
+            let parent_depth = depth - 1;
+            for parent_index in 0..=(last_index >> (i + 1)) {
+                let lhs_hash = self.get_node_with_default(depth, parent_index * 2)?;
+                let rhs_hash = self.get_node_with_default(depth, parent_index * 2 + 1)?;
+                let expected = poseidon(&[lhs_hash, rhs_hash], POOL_PARAMS.compress());
+
+                if self.get_node_with_default(parent_depth, parent_index)? != expected {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Read-only view of the tree as it is now, unaffected by later changes. Taking it is cheap,
+    /// so that the tree lock only needs to be held for that instead of for the whole export.
+    pub fn snapshot(&self) -> Result<TreeSnapshot> {
+        self.nodes.snapshot()
+    }
+
+    /// Same as [`TreeSnapshot::export`] for the current state of the tree.
+    pub fn export(&self, writer: impl Write) -> Result<()> {
+        self.snapshot()?.export(writer)
+    }
+
+    /// Restores a tree written by [`MerkleTree::export`]. The tree must be empty. All nodes are
+    /// written in a single transaction, so a failed import leaves the tree empty.
+    pub fn import(&self, mut reader: impl Read) -> Result<()> {
+        if self.nodes.get_num_leaves()? != 0 {
+            bail!("Cannot import into a tree that already has leaves");
+        }
+
+        let mut num_leaves = [0; 8];
+        reader.read_exact(&mut num_leaves)?;
+        let num_leaves = Index::try_from_slice(&num_leaves)?;
+
+        let mut tx = self.nodes.begin()?;
+        let mut record = [0; NODE_RECORD_SIZE];
+        while read_record(&mut reader, &mut record)? {
+            let (depth, index, hash) = <(Index, Index, Hash)>::try_from_slice(&record)?;
+            if depth > H as Index || index >= 1 << depth {
+                bail!("Invalid node ({depth}, {index}) in the tree export");
+            }
+            self.nodes.set_tx(&mut tx, depth, index, hash)?;
+        }
+
+        self.nodes.set_num_leaves_tx(&mut tx, num_leaves)?;
+        let root = self.root_tx(&mut tx)?;
+        self.nodes.add_root_tx(&mut tx, num_leaves, root)?;
+        self.nodes.commit(tx)?;
+
+        self.notify_root_change()?;
+
         Ok(())
     }
 
@@ -469,6 +926,11 @@ impl MerkleTree {
         Ok(root)
     }
 
+    /// Root of the tree without leaves, which only depends on the tree height.
+    pub fn empty_root(&self) -> Hash {
+        self.default_nodes[0]
+    }
+
     pub fn leaf(&self, index: Index) -> Result<Hash> {
         self.nodes
             .get(H as u64, index)
@@ -479,6 +941,31 @@ impl MerkleTree {
         self.nodes.get_root(index)
     }
 
+    /// Every root the tree had with `index` leaves, including the ones that were rolled back,
+    /// oldest first. Empty if the roots at `index` were pruned or never existed.
+    pub fn root_log(&self, index: Index) -> Result<Vec<RootLogEntry>> {
+        self.nodes.get_root_log(index)
+    }
+
+    /// Number of rollbacks so far. Not reset by a rollback to 0 or by compaction.
+    pub fn supersede_counter(&self) -> Result<u64> {
+        self.nodes.get_supersede_counter()
+    }
+
+    /// Removes historic roots for all leaf counts < `before`. Returns the number of removed roots.
+    pub fn prune_roots(&self, before: Index) -> Result<u64> {
+        self.nodes.prune_roots(before)
+    }
+
+    /// Rewrites the underlying file to reclaim the space left after rollbacks and pruning.
+    pub fn compact(&mut self) -> Result<()> {
+        self.nodes.compact()
+    }
+
+    pub fn file_size(&self) -> Result<u64> {
+        self.nodes.file_size()
+    }
+
     fn get_node(&self, depth: u64, index: u64) -> Result<Option<Hash>> {
         self.nodes.get(depth, index)
     }
@@ -504,7 +991,8 @@ impl MerkleTree {
 
     pub fn zp_merkle_proof(&self, index: Index) -> Result<MerkleProof<Fr, { H }>> {
         let leaves = self.merkle_proof(index).collect::<Result<_>>()?;
-        let path = (0..H).rev().map(|i| (index >> i) & 1 == 1).collect();
+        // Both siblings and path go from the leaf level up.
+        let path = (0..H).map(|i| (index >> i) & 1 == 1).collect();
 
         Ok(MerkleProof {
             sibling: leaves,
@@ -517,10 +1005,51 @@ impl MerkleTree {
     }
 }
 
+/// Fills `buf` from the reader. Returns `false` if the reader is at its end, fails if it ends in the
+/// middle of `buf`.
+fn read_record(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => bail!("Tree export ends in the middle of a node"),
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Whether an error of [`MerkleTree::open`] means that the file is corrupted and can only be
+/// replaced. Other I/O errors, e.g. a denied permission, a full disk or a file locked by another
+/// relayer, are not: the file may well be intact.
+pub fn is_corrupted(err: &anyhow::Error) -> bool {
+    !err.chain().any(|cause| {
+        let io = cause.downcast_ref::<std::io::Error>().map_or(false, |err| {
+            !matches!(
+                err.kind(),
+                ErrorKind::UnexpectedEof | ErrorKind::InvalidData
+            )
+        });
+        let locked = matches!(
+            cause.downcast_ref::<persy::PE<persy::OpenError>>(),
+            Some(persy::PE::PE(persy::OpenError::AlreadyInUse))
+        );
+        io || locked
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{str::FromStr, sync::atomic::AtomicU64};
+    use std::{
+        str::FromStr,
+        sync::{atomic::AtomicU64, Arc, Mutex},
+    };
 
+    use libzeropool_rs::libzeropool::fawkes_crypto::native::poseidon::poseidon_merkle_proof_root;
+    use scopeguard::defer;
     use test_case::test_case;
 
     use super::*;
@@ -587,13 +1116,58 @@ mod tests {
             tree.add_leaf(Hash::from_str(hash).unwrap()).unwrap();
         }
 
-        // tree.add_leaves_at(0, hashes.iter().map(|s| Hash::from_str(s).unwrap()))
-        //     .unwrap();
-
         assert_eq!(tree.root().unwrap().to_string(), expected_root);
         assert_eq!(tree.num_leaves() as usize, hashes.len());
     }
 
+    #[test_case(0, 1)]
+    #[test_case(0, 2)]
+    #[test_case(0, 3)]
+    #[test_case(1, 1)]
+    #[test_case(1, 2)]
+    #[test_case(3, 5)]
+    #[test_case(2, 64)]
+    #[test_case(5, 128)]
+    #[test_case(7, 129)]
+    #[test_case(0, 256)]
+    #[test_case(3, 257)]
+    fn test_tree_add_leaves_at_matches_add_leaf(offset: u64, count: u64) {
+        let (_, reference) = tree();
+        let (_, batched) = tree();
+
+        for i in 0..offset {
+            let hash = Hash::from(i + 1);
+            reference.add_leaf(hash).unwrap();
+            batched.add_leaf(hash).unwrap();
+        }
+
+        let batch = (0..count)
+            .map(|i| Hash::from(offset + i + 1000))
+            .collect::<Vec<_>>();
+
+        for hash in &batch {
+            reference.add_leaf(*hash).unwrap();
+        }
+
+        batched.add_leaves_at(offset, batch).unwrap();
+
+        assert_eq!(batched.root().unwrap(), reference.root().unwrap());
+        assert_eq!(batched.num_leaves(), reference.num_leaves());
+        for num_leaves in offset..=offset + count {
+            assert_eq!(
+                batched.historic_root(num_leaves).unwrap(),
+                reference.historic_root(num_leaves).unwrap(),
+                "historic root {num_leaves}"
+            );
+        }
+
+        for index in [offset, offset + count - 1, offset + count] {
+            let batched_proof = batched.merkle_proof(index).collect::<Result<Vec<_>>>();
+            let reference_proof = reference.merkle_proof(index).collect::<Result<Vec<_>>>();
+            assert_eq!(batched_proof.unwrap(), reference_proof.unwrap());
+        }
+    }
+
     #[test_case(
         &["21758523569841126314748171871054218043006161291554819416231684046987851067498"],
         0,
@@ -616,15 +1190,165 @@ mod tests {
             tree.add_leaf(Hash::from_str(hash).unwrap()).unwrap();
         }
 
-        // tree.add_leaves_at(0, hashes.iter().map(|s| Hash::from_str(s).unwrap()))
-        //     .unwrap();
-
         tree.rollback(rollback).unwrap();
 
         assert_eq!(tree.root().unwrap().to_string(), root);
         assert_eq!(tree.num_leaves(), rollback);
     }
 
+    fn simulate_crash_at<T>(fail_at: &'static str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        FAIL_AT.with(|cell| cell.set(Some(fail_at)));
+        let res = f();
+        FAIL_AT.with(|cell| cell.set(None));
+
+        res
+    }
+
+    #[test]
+    fn test_tree_open_corrupted() {
+        let tmp = TempFile::new();
+        let corrupted = || {
+            std::fs::read_dir(".")
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.starts_with(&format!("{}.corrupted-", tmp.path)))
+                .collect::<Vec<_>>()
+        };
+        defer! {
+            for name in corrupted() {
+                std::fs::remove_file(name).unwrap();
+            }
+        }
+
+        std::fs::write(&tmp.path, b"garbage".repeat(1024)).unwrap();
+        let err = MerkleTree::open(&tmp.path).err().unwrap();
+        assert!(err.to_string().contains(&tmp.path));
+        assert!(is_corrupted(&err));
+
+        let tree = MerkleTree::clear_and_open(&tmp.path).unwrap();
+        assert_eq!(tree.num_leaves(), 0);
+        tree.add_leaf(Hash::from(1)).unwrap();
+        assert_eq!(corrupted().len(), 1);
+
+        // Opened by someone else, not corrupted
+        let err = MerkleTree::open(&tmp.path).err().unwrap();
+        assert!(!is_corrupted(&err));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tree_open_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempFile::new();
+        drop(MerkleTree::open(&tmp.path).unwrap());
+        std::fs::set_permissions(&tmp.path, std::fs::Permissions::from_mode(0o400)).unwrap();
+
+        // Root can write anyway
+        if std::fs::OpenOptions::new()
+            .write(true)
+            .open(&tmp.path)
+            .is_ok()
+        {
+            return;
+        }
+
+        let err = MerkleTree::open(&tmp.path).err().unwrap();
+        assert!(!is_corrupted(&err));
+    }
+
+    #[test]
+    fn test_tree_clear_and_open_missing_file() {
+        let tmp = TempFile::new();
+
+        let tree = MerkleTree::clear_and_open(&tmp.path).unwrap();
+        assert_eq!(tree.num_leaves(), 0);
+        assert_eq!(tree.root().unwrap(), tree.default_nodes[0]);
+        let prefix = format!("{}.corrupted-", tmp.path);
+        let moved = std::fs::read_dir(".").unwrap().any(|entry| {
+            entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with(&prefix)
+        });
+        assert!(!moved);
+    }
+
+    #[test_case("add_leaf:nodes")]
+    #[test_case("add_leaf:num_leaves")]
+    fn test_tree_add_leaf_crash_consistency(fail_at: &'static str) {
+        let (tmp, tree) = tree();
+        tree.add_leaf(Hash::from(1)).unwrap();
+        let root = tree.root().unwrap();
+
+        let res = simulate_crash_at(fail_at, || tree.add_leaf(Hash::from(2)));
+        assert!(res.is_err());
+        drop(tree);
+
+        let tree = MerkleTree::open(&tmp.path).unwrap();
+        assert_eq!(tree.num_leaves(), 1);
+        assert_eq!(tree.root().unwrap(), root);
+        assert_eq!(tree.historic_root(1).unwrap(), Some(root));
+        assert_eq!(tree.historic_root(2).unwrap(), None);
+        assert!(tree.get_node(H as Index, 1).unwrap().is_none());
+    }
+
+    #[test_case(1, "rollback:roots")]
+    #[test_case(1, "rollback:num_leaves")]
+    #[test_case(0, "rollback:clear")]
+    fn test_tree_rollback_crash_consistency(rollback: u64, fail_at: &'static str) {
+        let (tmp, tree) = tree();
+        tree.add_leaf(Hash::from(1)).unwrap();
+        tree.add_leaf(Hash::from(2)).unwrap();
+        let root = tree.root().unwrap();
+
+        let res = simulate_crash_at(fail_at, || tree.rollback(rollback));
+        assert!(res.is_err());
+        drop(tree);
+
+        let tree = MerkleTree::open(&tmp.path).unwrap();
+        assert_eq!(tree.num_leaves(), 2);
+        assert_eq!(tree.root().unwrap(), root);
+        assert_eq!(tree.historic_root(2).unwrap(), Some(root));
+
+        tree.rollback(rollback).unwrap();
+        assert_eq!(tree.num_leaves(), rollback);
+        assert_eq!(
+            tree.historic_root(rollback).unwrap(),
+            Some(tree.root().unwrap())
+        );
+        assert_eq!(tree.historic_root(rollback + 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_tree_on_root_change() {
+        let (_tmp, mut tree) = tree();
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let observed = calls.clone();
+        tree.set_on_root_change(move |root, num_leaves, supersede_counter| {
+            observed
+                .lock()
+                .unwrap()
+                .push((root, num_leaves, supersede_counter));
+        });
+
+        tree.add_leaf(Hash::from(1)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), [(tree.root().unwrap(), 1, 0)]);
+
+        tree.add_leaf(Hash::from(2)).unwrap();
+        assert_eq!(calls.lock().unwrap().len(), 2);
+
+        tree.rollback(1).unwrap();
+        assert_eq!(calls.lock().unwrap().len(), 3);
+        assert_eq!(calls.lock().unwrap()[2], (tree.root().unwrap(), 1, 1));
+
+        tree.rollback(0).unwrap();
+        assert_eq!(calls.lock().unwrap().len(), 4);
+        assert_eq!(calls.lock().unwrap()[3], (tree.root().unwrap(), 0, 2));
+    }
+
     #[test]
     fn test_tree_historic_roots() {
         let (_, tree) = tree();
@@ -665,6 +1389,148 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_tree_root_log() {
+        let (_tmp, mut tree) = tree();
+
+        for i in 0..4 {
+            tree.add_leaf(Hash::from(i + 1)).unwrap();
+        }
+        let old_root = tree.historic_root(3).unwrap().unwrap();
+        assert_eq!(tree.supersede_counter().unwrap(), 0);
+
+        // Different leaves at the same indices after a rollback
+        tree.rollback(2).unwrap();
+        tree.add_leaf(Hash::from(10)).unwrap();
+        tree.add_leaf(Hash::from(11)).unwrap();
+        let new_root = tree.historic_root(3).unwrap().unwrap();
+        assert_ne!(new_root, old_root);
+        assert_eq!(tree.supersede_counter().unwrap(), 1);
+
+        let log = tree.root_log(3).unwrap();
+        assert_eq!(
+            log,
+            [
+                RootLogEntry {
+                    root: old_root,
+                    supersede_counter: 0,
+                    canonical: false,
+                },
+                RootLogEntry {
+                    root: new_root,
+                    supersede_counter: 1,
+                    canonical: true,
+                },
+            ]
+        );
+
+        // Roots at and below the rollback point stay canonical
+        let log = tree.root_log(2).unwrap();
+        assert_eq!(log.len(), 1);
+        assert!(log[0].canonical);
+        assert!(tree.root_log(5).unwrap().is_empty());
+
+        // Pruning drops old roots but not the rollback markers
+        tree.prune_roots(3).unwrap();
+        assert!(tree.root_log(2).unwrap().is_empty());
+        assert_eq!(tree.root_log(3).unwrap().len(), 2);
+
+        tree.compact().unwrap();
+        assert_eq!(tree.root_log(3).unwrap().len(), 2);
+        assert_eq!(tree.supersede_counter().unwrap(), 1);
+
+        tree.rollback(0).unwrap();
+        assert_eq!(tree.supersede_counter().unwrap(), 2);
+        assert!(tree
+            .root_log(3)
+            .unwrap()
+            .iter()
+            .all(|entry| !entry.canonical));
+    }
+
+    #[test]
+    fn test_tree_prune_roots_and_compact() {
+        let (_tmp, mut tree) = tree();
+
+        for i in 0..300 {
+            tree.add_leaf(Hash::from(i + 1)).unwrap();
+        }
+        tree.rollback(100).unwrap();
+
+        let root = tree.root().unwrap();
+        let proof = tree.zp_merkle_proof(50).unwrap();
+
+        assert_eq!(tree.prune_roots(90).unwrap(), 90);
+        assert_eq!(tree.historic_root(89).unwrap(), None);
+        assert!(tree.historic_root(90).unwrap().is_some());
+
+        let size_before = tree.file_size().unwrap();
+        tree.compact().unwrap();
+
+        assert!(tree.file_size().unwrap() < size_before);
+        assert_eq!(tree.root().unwrap(), root);
+        assert_eq!(tree.num_leaves(), 100);
+        assert_eq!(tree.historic_root(100).unwrap(), Some(root));
+        assert_eq!(tree.historic_root(89).unwrap(), None);
+
+        let compacted_proof = tree.zp_merkle_proof(50).unwrap();
+        assert!(compacted_proof.sibling.iter().eq(proof.sibling.iter()));
+        assert!(compacted_proof.path.iter().eq(proof.path.iter()));
+
+        tree.add_leaf(Hash::from(1000)).unwrap();
+        assert_eq!(tree.num_leaves(), 101);
+    }
+
+    #[test]
+    fn test_tree_verify_consistency() {
+        let (_tmp, tree) = tree();
+        assert!(tree.verify_consistency().unwrap());
+
+        for i in 0..200 {
+            tree.add_leaf(Hash::from(i + 1)).unwrap();
+        }
+        assert!(tree.verify_consistency().unwrap());
+
+        tree.rollback(70).unwrap();
+        assert!(tree.verify_consistency().unwrap());
+
+        tree.add_leaves_at(70, (0..90).map(|i| Hash::from(i + 1000)))
+            .unwrap();
+        assert!(tree.verify_consistency().unwrap());
+
+        // Corrupted internal node
+        let node = tree.get_node_with_default(H as Index - 3, 2).unwrap();
+        tree.nodes.set(H as Index - 3, 2, Hash::from(42)).unwrap();
+        assert!(!tree.verify_consistency().unwrap());
+        tree.nodes.set(H as Index - 3, 2, node).unwrap();
+        assert!(tree.verify_consistency().unwrap());
+
+        // Stale leaf after the last one
+        tree.nodes
+            .set(H as Index, tree.num_leaves(), Hash::from(42))
+            .unwrap();
+        assert!(!tree.verify_consistency().unwrap());
+    }
+
+    #[test_case(0)]
+    #[test_case(1)]
+    #[test_case(5)]
+    #[test_case(36)]
+    #[test_case(37; "first free leaf")]
+    fn test_tree_zp_merkle_proof_root(index: Index) {
+        let (_tmp, tree) = tree();
+        tree.add_leaves_at(0, (0..37).map(|i| Hash::from(i + 1)))
+            .unwrap();
+
+        let proof = tree.zp_merkle_proof(index).unwrap();
+        assert_eq!(proof.sibling.iter().count(), H);
+        assert_eq!(proof.path.iter().count(), H);
+
+        let root =
+            poseidon_merkle_proof_root(tree.leaf(index).unwrap(), &proof, POOL_PARAMS.compress());
+        assert_eq!(root, tree.root().unwrap());
+    }
+
     #[test]
     fn test_tree_zp_merkle_proof() {
         let mut old_tree = libzeropool_rs::merkle::MerkleTree::new_test(POOL_PARAMS.clone());
@@ -696,6 +1562,52 @@ mod tests {
         assert_proofs_eq(&proof, &reference_proof);
     }
 
+    #[test]
+    fn test_tree_export_import() {
+        let (_tmp, tree) = tree();
+        tree.add_leaves_at(0, (0..37).map(|i| Hash::from(i + 1)))
+            .unwrap();
+        tree.rollback(30).unwrap();
+        tree.add_leaf(Hash::from(100)).unwrap();
+
+        let mut export = Vec::new();
+        tree.export(&mut export).unwrap();
+
+        let (_imported_tmp, imported) = self::tree();
+        imported.import(export.as_slice()).unwrap();
+
+        assert_eq!(imported.num_leaves(), 31);
+        assert_eq!(imported.root().unwrap(), tree.root().unwrap());
+        assert_eq!(
+            imported.historic_root(31).unwrap(),
+            Some(tree.root().unwrap())
+        );
+        assert!(imported.verify_consistency().unwrap());
+        for index in [0, 1, 29, 30, 31] {
+            let proof = tree.zp_merkle_proof(index).unwrap();
+            let imported_proof = imported.zp_merkle_proof(index).unwrap();
+            assert!(proof.sibling.iter().eq(imported_proof.sibling.iter()));
+            assert!(proof.path.iter().eq(imported_proof.path.iter()));
+        }
+
+        // Exports the same nodes again
+        let mut reexport = Vec::new();
+        imported.export(&mut reexport).unwrap();
+        assert_eq!(reexport, export);
+
+        // A snapshot isn't affected by later changes
+        let snapshot = imported.snapshot().unwrap();
+        imported.add_leaf(Hash::from(200)).unwrap();
+        let mut snapshot_export = Vec::new();
+        snapshot.export(&mut snapshot_export).unwrap();
+        assert_eq!(snapshot_export, export);
+
+        assert!(imported.import(export.as_slice()).is_err());
+        let (_truncated_tmp, truncated) = self::tree();
+        assert!(truncated.import(&export[..export.len() - 1]).is_err());
+        assert_eq!(truncated.num_leaves(), 0);
+    }
+
     // TODO: Generate test cases on the fly
     #[test]
     #[ignore]
@@ -0,0 +1,178 @@
+//! Prometheus metrics. All recording functions are no-ops unless the `metrics` feature is enabled.
+
+#[cfg(feature = "metrics")]
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use anyhow::Result;
+#[cfg(feature = "metrics")]
+use metrics::{counter, gauge, histogram};
+#[cfg(feature = "metrics")]
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+#[cfg(feature = "metrics")]
+use crate::config::FeeModel;
+use crate::{config::RuntimeSettings, tx::TxValidationError};
+
+#[cfg(feature = "metrics")]
+const DURATION_BUCKETS: &[f64] = &[0.05, 0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+/// The tree lock is mostly held for milliseconds.
+#[cfg(feature = "metrics")]
+const LOCK_DURATION_BUCKETS: &[f64] = &[0.0001, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Installs the global Prometheus recorder on the first call. Later calls, e.g. from several
+/// relayers in one process, return the same handle.
+#[cfg(feature = "metrics")]
+pub fn install() -> Result<PrometheusHandle> {
+    static HANDLE: Mutex<Option<PrometheusHandle>> = Mutex::new(None);
+
+    let mut installed = HANDLE.lock().unwrap();
+    if let Some(handle) = &*installed {
+        return Ok(handle.clone());
+    }
+
+    let handle = PrometheusBuilder::new()
+        .set_buckets(DURATION_BUCKETS)?
+        .set_buckets_for_metric(
+            Matcher::Prefix("relayer_tree_lock".to_owned()),
+            LOCK_DURATION_BUCKETS,
+        )?
+        .install_recorder()?;
+    *installed = Some(handle.clone());
+
+    Ok(handle)
+}
+
+pub fn record_accepted_tx() {
+    #[cfg(feature = "metrics")]
+    counter!("relayer_transactions_accepted_total", 1);
+}
+
+pub fn record_rejected_tx(errors: &[TxValidationError]) {
+    #[cfg(feature = "metrics")]
+    for err in errors {
+        counter!("relayer_transactions_rejected_total", 1, "code" => err.code());
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = errors;
+}
+
+pub fn record_job_duration(duration: Duration, success: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let status = if success { "completed" } else { "failed" };
+        histogram!("relayer_job_duration_seconds", duration.as_secs_f64(), "status" => status);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (duration, success);
+}
+
+pub fn record_send_tx_duration(backend: &'static str, duration: Duration) {
+    #[cfg(feature = "metrics")]
+    histogram!("relayer_send_tx_duration_seconds", duration.as_secs_f64(), "backend" => backend);
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (backend, duration);
+}
+
+/// `site` is where the tree is locked, see `TimedMutex::lock`.
+pub fn record_tree_lock_wait(site: &'static str, duration: Duration) {
+    #[cfg(feature = "metrics")]
+    histogram!("relayer_tree_lock_wait_seconds", duration.as_secs_f64(), "site" => site);
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (site, duration);
+}
+
+pub fn record_tree_lock_hold(site: &'static str, duration: Duration) {
+    #[cfg(feature = "metrics")]
+    histogram!("relayer_tree_lock_hold_seconds", duration.as_secs_f64(), "site" => site);
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (site, duration);
+}
+
+#[cfg(feature = "metrics")]
+pub fn set_queue_depth(depth: u64) {
+    gauge!("relayer_job_queue_depth", depth as f64);
+}
+
+/// Difference between the optimistic and the mined pool index.
+#[cfg(feature = "metrics")]
+pub fn set_index_gap(gap: u64) {
+    gauge!("relayer_index_gap", gap as f64);
+}
+
+/// Current `RuntimeSettings`, unset limits are reported as 0.
+pub fn set_runtime_settings(settings: &RuntimeSettings) {
+    #[cfg(feature = "metrics")]
+    {
+        let (min_fee, bps) = match settings.fee {
+            FeeModel::Flat(fee) => (fee, 0),
+            FeeModel::Percentage { bps, min } => (min, bps),
+        };
+        gauge!("relayer_min_fee", min_fee as f64);
+        gauge!("relayer_fee_percentage_bps", bps as f64);
+        gauge!(
+            "relayer_rate_limit_per_minute",
+            settings.rate_limit_per_minute.unwrap_or(0) as f64
+        );
+        gauge!(
+            "relayer_max_pending_jobs",
+            settings.max_pending_jobs.unwrap_or(0) as f64
+        );
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = settings;
+}
+
+/// `depth` is the number of reverted transactions.
+pub fn record_reorg(depth: u64) {
+    #[cfg(feature = "metrics")]
+    {
+        counter!("relayer_reorgs_total", 1);
+        counter!("relayer_reorged_transactions_total", depth);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = depth;
+}
+
+pub fn record_root_mismatch() {
+    #[cfg(feature = "metrics")]
+    counter!("relayer_root_mismatches_total", 1);
+}
+
+pub fn set_db_stats(db: &'static str, size_bytes: u64, records: u64) {
+    #[cfg(feature = "metrics")]
+    {
+        gauge!("relayer_db_size_bytes", size_bytes as f64, "db" => db);
+        gauge!("relayer_db_records", records as f64, "db" => db);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (db, size_bytes, records);
+}
+
+/// Native balance of a signing key, in wei.
+#[cfg(feature = "evm_backend")]
+pub fn set_signer_balance(address: String, balance: f64) {
+    #[cfg(feature = "metrics")]
+    gauge!("relayer_signer_balance_wei", balance, "address" => address);
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (address, balance);
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    #[test]
+    fn test_install_twice() {
+        assert!(super::install().is_ok());
+        assert!(super::install().is_ok());
+    }
+}
@@ -0,0 +1,353 @@
+//! OpenAPI 3 description of the JSON API, served at `/openapi.json`. The handlers in `json_api`
+//! are annotated with `#[utoipa::path]`, the document is assembled from them at compile time.
+
+use axum::{
+    response::{Html, IntoResponse},
+    Json,
+};
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+use zeropool_relayer_client::{
+    BuildInfo, CiphertextPrefix, CreateTransactionResponse, ErrorResponse, Hex, InfoResponse,
+    JobStatus, JobStatusResponse, JobSummary, RootLogEntry, RootsResponse,
+    ValidateTransactionResponse, ValidationError, VersionResponse,
+};
+
+use crate::{
+    config::{FeeModel, RuntimeSettings},
+    json_api,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "ZeroPool relayer API"),
+    paths(
+        json_api::get_transactions,
+        json_api::create_transaction,
+        json_api::validate_transaction,
+        json_api::get_transactions_legacy,
+        json_api::transactions_ws,
+        json_api::get_ciphertexts,
+        json_api::create_transaction_legacy,
+        json_api::jobs,
+        json_api::job,
+        json_api::job_by_index,
+        json_api::roots,
+        json_api::info,
+        json_api::version,
+        json_api::admin_compact,
+        json_api::admin_resync,
+        json_api::admin_export_tree,
+        json_api::admin_pause,
+        json_api::admin_resume,
+        json_api::admin_settings,
+        json_api::admin_update_settings,
+        json_api::admin_failed_jobs,
+        json_api::admin_failed_job,
+        json_api::admin_retry_failed_job,
+        json_api::admin_disable_key,
+        json_api::admin_audit,
+        json_api::admin_fees,
+        json_api::admin_promote,
+        json_api::optimistic_log,
+    ),
+    components(schemas(
+        TransactionRequest,
+        ProofData,
+        CreateTransactionResponse,
+        ValidateTransactionResponse,
+        JobStatus,
+        JobStatusResponse,
+        JobSummary,
+        InfoResponse,
+        BuildInfo,
+        VersionResponse,
+        RootsResponse,
+        RootLogEntry,
+        CiphertextPrefix,
+        Hex,
+        ErrorResponse,
+        ValidationError,
+        RuntimeSettings,
+        FeeModel,
+    )),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "transactions"),
+        (name = "jobs"),
+        (name = "admin", description = "Require `Authorization: Bearer <ADMIN_TOKEN>`"),
+        (name = "internal", description = "Require `Authorization: Bearer <PEER_TOKEN>`"),
+    )
+)]
+pub struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        for name in ["api_key", "admin_token", "peer_token"] {
+            components.add_security_scheme(
+                name,
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+/// Body of `POST /transactions`. Like [`ProofData`], only describes the schema of the request.
+#[derive(Serialize, ToSchema)]
+#[allow(dead_code)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionRequest {
+    /// `"0000"` for deposits, `"0001"` for transfers and `"0002"` for withdrawals.
+    tx_type: String,
+    proof: ProofData,
+    /// Hex-encoded memo.
+    #[schema(format = "hex")]
+    memo: String,
+    /// Hex-encoded extra data, e.g. the deposit signature.
+    #[schema(format = "hex")]
+    extra_data: Option<String>,
+}
+
+/// Transfer proof with its public inputs.
+#[derive(Serialize, ToSchema)]
+#[allow(dead_code)]
+pub struct ProofData {
+    /// Proof points, field elements in decimal. The shape depends on the proving system.
+    #[schema(value_type = Object)]
+    proof: (),
+    /// Public inputs: root, nullifier, out commitment, delta and memo hash, field elements in
+    /// canonical decimal form.
+    inputs: Vec<String>,
+}
+
+pub async fn spec() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
+
+/// Swagger UI for the served spec, loaded from a CDN.
+pub async fn swagger_ui() -> Html<&'static str> {
+    Html(SWAGGER_UI)
+}
+
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>ZeroPool relayer API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.onload = () => {
+      window.ui = SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });
+    };
+  </script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::{Method, StatusCode};
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        backend::mock::{mock_config, MockBackend},
+        config::Secret,
+        test_utils,
+        tx_worker::prepare_job,
+    };
+
+    /// Checks `value` against the subset of JSON Schema generated by utoipa.
+    fn validate(doc: &Value, schema: &Value, value: &Value) -> Result<(), String> {
+        if let Some(target) = schema["$ref"].as_str() {
+            let name = target.trim_start_matches("#/components/schemas/");
+            return validate(doc, &doc["components"]["schemas"][name], value);
+        }
+        if value.is_null() && schema["nullable"] == true {
+            return Ok(());
+        }
+        if let Some(schemas) = schema["allOf"].as_array() {
+            for schema in schemas {
+                validate(doc, schema, value)?;
+            }
+        }
+        if let Some(schemas) = schema["oneOf"].as_array() {
+            if !schemas
+                .iter()
+                .any(|schema| validate(doc, schema, value).is_ok())
+            {
+                return Err(format!("{value} matches none of {schema}"));
+            }
+        }
+        if let Some(variants) = schema["enum"].as_array() {
+            if !variants.contains(value) {
+                return Err(format!("{value} is not one of {variants:?}"));
+            }
+        }
+
+        let matches = match schema["type"].as_str() {
+            Some("object") => {
+                let Some(object) = value.as_object() else {
+                    return Err(format!("{value} is not an object"));
+                };
+                for field in schema["required"].as_array().into_iter().flatten() {
+                    let field = field.as_str().unwrap();
+                    if !object.contains_key(field) {
+                        return Err(format!("Missing field {field} in {value}"));
+                    }
+                }
+                for (field, value) in object {
+                    if let Some(schema) = schema["properties"].get(field) {
+                        validate(doc, schema, value).map_err(|err| format!("{field}: {err}"))?;
+                    }
+                }
+                true
+            }
+            Some("array") => {
+                let Some(items) = value.as_array() else {
+                    return Err(format!("{value} is not an array"));
+                };
+                for item in items {
+                    validate(doc, &schema["items"], item)?;
+                }
+                true
+            }
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("number") => value.is_number(),
+            Some("boolean") => value.is_boolean(),
+            _ => true,
+        };
+
+        if !matches {
+            return Err(format!("{value} is not of type {}", schema["type"]));
+        }
+
+        Ok(())
+    }
+
+    /// Collects the targets of all `$ref`s in the document.
+    fn refs(value: &Value, out: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    match (key.as_str(), value) {
+                        ("$ref", Value::String(target)) => out.push(target.clone()),
+                        _ => refs(value, out),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter().for_each(|value| refs(value, out)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_openapi_document() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+
+        assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+        assert!(doc["info"]["title"].is_string());
+        assert!(doc["info"]["version"].is_string());
+
+        // Every operation has at least one response.
+        let paths = doc["paths"].as_object().unwrap();
+        for (path, item) in paths {
+            let operations = item.as_object().unwrap();
+            assert!(!operations.is_empty(), "{path}");
+            for (method, operation) in operations {
+                let responses = operation["responses"].as_object();
+                assert!(
+                    responses.map_or(false, |r| !r.is_empty()),
+                    "{method} {path}"
+                );
+            }
+        }
+
+        // Every reference resolves.
+        let mut targets = Vec::new();
+        refs(&doc, &mut targets);
+        assert!(!targets.is_empty());
+        for target in targets {
+            let name = target.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                doc["components"]["schemas"].get(name).is_some(),
+                "Unresolved reference {target}"
+            );
+        }
+    }
+
+    /// Sends a request for every documented operation to the real routers. GET responses must
+    /// have a documented status and match its schema, other methods must only reach a handler.
+    #[tokio::test]
+    async fn test_openapi_matches_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(MockBackend::new(mock_config(None, vec![])));
+        let ctx = test_utils::app_state(dir.path(), backend, |config| {
+            config.admin_token = Some(Secret("admin".to_owned()));
+        })
+        .await;
+        let payload = prepare_job(test_utils::deposit(1), Uuid::new_v4(), None, ctx.clone())
+            .await
+            .unwrap();
+        let job_id = ctx.job_queue.push(payload).await.unwrap();
+
+        let router = json_api::routes(ctx.clone()).merge(json_api::admin_routes(ctx));
+        let addr = test_utils::serve(router);
+        let client = reqwest::Client::new();
+
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for (path, item) in doc["paths"].as_object().unwrap() {
+            let url = path
+                .replace("{id}", &job_id.to_string())
+                .replace("{index}", "0")
+                .replace("{address}", "0x0000000000000000000000000000000000000000");
+
+            for (method, operation) in item.as_object().unwrap() {
+                let name = format!("{method} {path}");
+                let method = method.to_uppercase().parse::<Method>().unwrap();
+                let mut request = client.request(method.clone(), format!("http://{addr}{url}"));
+                // Without the token, other methods are rejected before they change anything.
+                if method == Method::GET {
+                    request = request.bearer_auth("admin");
+                }
+                let res = request.send().await.unwrap();
+                let status = res.status();
+                let body = res.bytes().await.unwrap();
+
+                // The fallback responds with an empty 404.
+                let routed = status != StatusCode::METHOD_NOT_ALLOWED
+                    && (status != StatusCode::NOT_FOUND || !body.is_empty());
+                assert!(routed, "{name} is not routed");
+
+                let responses = &operation["responses"];
+                // Can't be checked without a WebSocket handshake.
+                if method != Method::GET || responses.get("101").is_some() {
+                    continue;
+                }
+
+                let response = responses.get(status.as_str());
+                let response = response.unwrap_or_else(|| panic!("{name}: undocumented {status}"));
+                let schema = &response["content"]["application/json"]["schema"];
+                if !schema.is_null() {
+                    let value: Value = serde_json::from_slice(&body).unwrap();
+                    if let Err(err) = validate(&doc, schema, &value) {
+                        panic!("{name}: {err}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Loading of proving parameters and verification keys from local or remote storage.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_transfer_vk")]
+    pub transfer_vk: String,
+    pub transfer_vk_hash: Option<String>,
+    #[serde(default = "default_tree_vk")]
+    pub tree_vk: String,
+    pub tree_vk_hash: Option<String>,
+    #[serde(default = "default_tree_params")]
+    pub tree_params: String,
+    pub tree_params_hash: Option<String>,
+    #[serde(default = "default_plonk_params")]
+    pub plonk_params: String,
+    pub plonk_params_hash: Option<String>,
+    /// Where remote files are stored after download.
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: PathBuf,
+    /// Endpoint for `s3://` URIs. Requests are unsigned, so the objects must be public.
+    #[serde(default = "default_s3_endpoint")]
+    pub s3_endpoint: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            transfer_vk: default_transfer_vk(),
+            transfer_vk_hash: None,
+            tree_vk: default_tree_vk(),
+            tree_vk_hash: None,
+            tree_params: default_tree_params(),
+            tree_params_hash: None,
+            plonk_params: default_plonk_params(),
+            plonk_params_hash: None,
+            cache_dir: default_cache_dir(),
+            s3_endpoint: default_s3_endpoint(),
+        }
+    }
+}
+
+fn default_transfer_vk() -> String {
+    "params/transfer_verification_key.json".to_owned()
+}
+
+fn default_tree_vk() -> String {
+    "params/tree_verification_key.json".to_owned()
+}
+
+fn default_tree_params() -> String {
+    "params/tree_params.bin".to_owned()
+}
+
+fn default_plonk_params() -> String {
+    "params/plonk_params.bin".to_owned()
+}
+
+fn default_cache_dir() -> PathBuf {
+    PathBuf::from("params/cache")
+}
+
+fn default_s3_endpoint() -> String {
+    "https://s3.amazonaws.com".to_owned()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    File(PathBuf),
+    Http(String),
+}
+
+impl Source {
+    fn parse(uri: &str, s3_endpoint: &str) -> Result<Self> {
+        if let Some(path) = uri.strip_prefix("file://") {
+            Ok(Source::File(PathBuf::from(path)))
+        } else if uri.starts_with("http://") || uri.starts_with("https://") {
+            Ok(Source::Http(uri.to_owned()))
+        } else if let Some(object) = uri.strip_prefix("s3://") {
+            let (bucket, key) = object
+                .split_once('/')
+                .ok_or_else(|| anyhow!("Invalid S3 URI: {uri}"))?;
+            let endpoint = s3_endpoint.trim_end_matches('/');
+            Ok(Source::Http(format!("{endpoint}/{bucket}/{key}")))
+        } else if uri.contains("://") {
+            bail!("Unsupported URI scheme: {uri}")
+        } else {
+            Ok(Source::File(PathBuf::from(uri)))
+        }
+    }
+}
+
+impl Config {
+    /// Reads the file at `uri`, downloading it into the cache directory first if it's remote.
+    /// If `hash` (hex-encoded sha256) is set, the contents are verified against it.
+    pub async fn load(&self, uri: &str, hash: Option<&str>) -> Result<Vec<u8>> {
+        let data = match Source::parse(uri, &self.s3_endpoint)? {
+            Source::File(path) => tokio::fs::read(&path).await?,
+            Source::Http(url) => self.load_cached(&url, hash).await?,
+        };
+
+        if let Some(hash) = hash {
+            verify_checksum(&data, hash).map_err(|e| anyhow!("{uri}: {e}"))?;
+        }
+
+        Ok(data)
+    }
+
+    async fn load_cached(&self, url: &str, hash: Option<&str>) -> Result<Vec<u8>> {
+        let path = self.cache_path(url);
+
+        if let Ok(data) = tokio::fs::read(&path).await {
+            match hash.map(|hash| verify_checksum(&data, hash)) {
+                Some(Err(e)) => tracing::warn!("Cached {url} is invalid, downloading again: {e}"),
+                _ => return Ok(data),
+            }
+        }
+
+        tracing::info!("Downloading {url}");
+        let response = reqwest::get(url).await?.error_for_status()?;
+        let data = response.bytes().await?.to_vec();
+
+        // Write to a temporary file first so that an interrupted download is never cached.
+        tokio::fs::create_dir_all(&self.cache_dir).await?;
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, &data).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+
+        Ok(data)
+    }
+
+    fn cache_path(&self, url: &str) -> PathBuf {
+        let name = hex::encode(Sha256::digest(url.as_bytes()));
+        let file_name = Path::new(url)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.cache_dir.join(format!("{name}-{file_name}"))
+    }
+}
+
+fn verify_checksum(data: &[u8], expected: &str) -> Result<()> {
+    let actual = hex::encode(Sha256::digest(data));
+    if !actual.eq_ignore_ascii_case(expected.trim_start_matches("0x")) {
+        bail!("Checksum mismatch: expected {expected}, got {actual}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{routing::get, Router};
+
+    use super::*;
+    use crate::test_utils;
+
+    const DATA: &[u8] = b"params";
+
+    fn config(cache_dir: &Path) -> Config {
+        Config {
+            transfer_vk: default_transfer_vk(),
+            transfer_vk_hash: None,
+            tree_vk: default_tree_vk(),
+            tree_vk_hash: None,
+            tree_params: default_tree_params(),
+            tree_params_hash: None,
+            plonk_params: default_plonk_params(),
+            plonk_params_hash: None,
+            cache_dir: cache_dir.to_owned(),
+            s3_endpoint: default_s3_endpoint(),
+        }
+    }
+
+    #[test]
+    fn test_parse_source() {
+        let endpoint = "https://s3.example.com/";
+        assert_eq!(
+            Source::parse("params/a.bin", endpoint).unwrap(),
+            Source::File(PathBuf::from("params/a.bin"))
+        );
+        assert_eq!(
+            Source::parse("file:///params/a.bin", endpoint).unwrap(),
+            Source::File(PathBuf::from("/params/a.bin"))
+        );
+        assert_eq!(
+            Source::parse("https://example.com/a.bin", endpoint).unwrap(),
+            Source::Http("https://example.com/a.bin".to_owned())
+        );
+        assert_eq!(
+            Source::parse("s3://bucket/dir/a.bin", endpoint).unwrap(),
+            Source::Http("https://s3.example.com/bucket/dir/a.bin".to_owned())
+        );
+        assert!(Source::parse("s3://bucket", endpoint).is_err());
+        assert!(Source::parse("ftp://example.com/a.bin", endpoint).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_file_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.bin");
+        std::fs::write(&path, DATA).unwrap();
+        let uri = path.to_str().unwrap();
+        let config = config(dir.path());
+        let hash = hex::encode(Sha256::digest(DATA));
+
+        assert_eq!(config.load(uri, None).await.unwrap(), DATA);
+        assert_eq!(config.load(uri, Some(&hash)).await.unwrap(), DATA);
+        assert!(config
+            .load(uri, Some(&hex::encode([0u8; 32])))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_http_cached() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let router = Router::new().route(
+            "/a.bin",
+            get(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                DATA
+            }),
+        );
+        let addr = test_utils::serve(router);
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let uri = format!("http://{addr}/a.bin");
+        let hash = hex::encode(Sha256::digest(DATA));
+
+        assert_eq!(config.load(&uri, Some(&hash)).await.unwrap(), DATA);
+        assert_eq!(config.load(&uri, Some(&hash)).await.unwrap(), DATA);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // A corrupted cache entry is downloaded again.
+        std::fs::write(config.cache_path(&uri), b"garbage").unwrap();
+        assert_eq!(config.load(&uri, Some(&hash)).await.unwrap(), DATA);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
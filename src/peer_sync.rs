@@ -0,0 +1,530 @@
+//! Failover between two relayers of the same pool. The primary keeps the payloads of the
+//! transactions it has accepted but not yet mined and serves them, together with its storage
+//! records, at `/internal/optimistic-log`. A standby (`STANDBY_PRIMARY_URL`) tails the log and
+//! applies it to its own tree and storage without creating jobs, so that after
+//! `POST /admin/promote` it can re-validate and send the inherited transactions instead of
+//! dropping them.
+//!
+//! Only one of the two may be sending at a time: the old primary has to be stopped before the
+//! standby is promoted.
+
+use std::{
+    collections::BTreeMap,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use libzeropool_rs::libzeropool::fawkes_crypto::{
+    engines::U256,
+    ff_uint::{Num, NumRepr, Uint},
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use zeropool_relayer_client::Hex;
+
+use crate::{
+    audit::AuditEvent,
+    json_api::{submit_parsed_transaction, AppError, AppResult},
+    merkle_tree::MerkleTree,
+    state::AppState,
+    tx::ParsedTxData,
+    tx_storage::TxStorage,
+    tx_worker::TX_SIZE,
+    Fr,
+};
+
+/// Number of log entries requested from the primary at once.
+pub const LOG_PAGE_SIZE: u64 = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bincode-encoded [`ParsedTxData`] of optimistic transactions by pool index.
+#[derive(Default)]
+pub struct OptimisticLog {
+    txs: Mutex<BTreeMap<u64, (Num<Fr>, Vec<u8>)>>,
+}
+
+impl OptimisticLog {
+    /// Remembers the payload of the transaction at `index` and forgets those mined before
+    /// `mined_index`.
+    pub fn insert(&self, index: u64, out_commit: Num<Fr>, tx: Vec<u8>, mined_index: u64) {
+        self.prune(mined_index);
+        if index >= mined_index {
+            self.txs.lock().unwrap().insert(index, (out_commit, tx));
+        }
+    }
+
+    /// Forgets the payloads of the transactions mined before `mined_index`.
+    pub fn prune(&self, mined_index: u64) {
+        self.txs.lock().unwrap().retain(|&i, _| i >= mined_index);
+    }
+
+    /// The payload at `index`, unless it belongs to another transaction that has since been
+    /// rolled back.
+    pub fn get(&self, index: u64, out_commit: Num<Fr>) -> Option<Vec<u8>> {
+        match self.txs.lock().unwrap().get(&index) {
+            Some((commit, tx)) if *commit == out_commit => Some(tx.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn truncate(&self, from: u64) {
+        self.txs.lock().unwrap().retain(|&i, _| i < from);
+    }
+
+    /// Removes all payloads, returning those at `from` and after in order.
+    pub fn take_from(&self, from: u64) -> Vec<(u64, Num<Fr>, Vec<u8>)> {
+        let mut txs = std::mem::take(&mut *self.txs.lock().unwrap());
+        txs.split_off(&from)
+            .into_iter()
+            .map(|(index, (out_commit, tx))| (index, out_commit, tx))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub index: u64,
+    pub out_commit: Num<Fr>,
+    /// Storage record, see [`TxStorage::get`].
+    pub record: Hex,
+    /// Bincode-encoded [`ParsedTxData`]. Missing for mined transactions and for those accepted
+    /// before the primary was restarted.
+    pub tx: Option<Hex>,
+}
+
+/// The local transactions starting at `index` were removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub index: u64,
+    pub removed: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Promotion {
+    /// Inherited transactions that passed validation and got a job.
+    pub resubmitted: u64,
+    /// Inherited transactions that are no longer valid, e.g. because they were mined meanwhile.
+    pub dropped: u64,
+}
+
+fn record_out_commit(record: &[u8]) -> Option<Num<Fr>> {
+    let bytes = record.get(..32)?;
+    Num::from_uint(NumRepr(U256::from_big_endian(bytes)))
+}
+
+/// Start of the part of the log that can still change: the mined index, or the first
+/// transaction before it that is still stored without its hash.
+pub fn sync_start(transactions: &TxStorage, num_leaves: u64, mined_index: u64) -> Result<u64> {
+    let mut index = (num_leaves * TX_SIZE).min(mined_index);
+    while index > 0 {
+        let record = transactions.get(index - TX_SIZE)?.unwrap_or_default();
+        if record
+            .get(32..64)
+            .map_or(false, |hash| hash.iter().any(|&b| b != 0))
+        {
+            break;
+        }
+        index -= TX_SIZE;
+    }
+
+    Ok(index)
+}
+
+/// Up to `limit` entries of the primary's log starting at `from`.
+pub fn read_entries(
+    transactions: &TxStorage,
+    log: &OptimisticLog,
+    from: u64,
+    limit: u64,
+) -> Result<Vec<LogEntry>> {
+    transactions
+        .iter_range(from..from + limit * TX_SIZE)?
+        .map(|res| {
+            let (index, record) = res?;
+            let out_commit = record_out_commit(&record)
+                .ok_or_else(|| anyhow!("Malformed transaction record at {index}"))?;
+
+            Ok(LogEntry {
+                index,
+                out_commit,
+                record: Hex(record),
+                tx: log.get(index, out_commit).map(Hex),
+            })
+        })
+        .collect()
+}
+
+/// Applies consecutive log entries of the primary to the local tree and storage. Records that
+/// only differ in the tx hash (the transaction has been sent or mined since) are updated in place.
+/// A different out commitment means that the primary has rolled back and reused the index, so
+/// the local state is rolled back to it first.
+pub fn apply_entries(
+    tree: &MerkleTree,
+    transactions: &TxStorage,
+    log: &OptimisticLog,
+    mined_index: u64,
+    entries: Vec<LogEntry>,
+) -> Result<Option<Divergence>> {
+    let mut divergence = None;
+
+    for LogEntry {
+        index,
+        out_commit,
+        record,
+        tx,
+    } in entries
+    {
+        if record_out_commit(&record.0) != Some(out_commit) {
+            bail!("Malformed log entry at {index}");
+        }
+
+        let next_index = tree.num_leaves() * TX_SIZE;
+        if index > next_index {
+            bail!("Expected a log entry at {next_index}, got {index}");
+        }
+
+        if index < next_index {
+            let local = transactions.get(index)?;
+            if local.as_deref() == Some(record.0.as_slice()) {
+                // Up to date
+            } else if local.as_deref().and_then(record_out_commit) == Some(out_commit) {
+                transactions.set_raw(index, &record.0)?;
+            } else {
+                divergence = Some(roll_back(tree, transactions, log, index, mined_index)?);
+            }
+        }
+
+        if index == tree.num_leaves() * TX_SIZE {
+            tree.add_leaf(out_commit)?;
+            transactions.set_raw(index, &record.0)?;
+        }
+
+        if let Some(Hex(tx)) = tx {
+            log.insert(index, out_commit, tx, mined_index);
+        }
+    }
+
+    Ok(divergence)
+}
+
+fn roll_back(
+    tree: &MerkleTree,
+    transactions: &TxStorage,
+    log: &OptimisticLog,
+    index: u64,
+    mined_index: u64,
+) -> Result<Divergence> {
+    if index < mined_index {
+        bail!("The primary diverges at {index}, before the mined index {mined_index}");
+    }
+
+    let removed = transactions.rollback(index)?;
+    tree.rollback(index / TX_SIZE)?;
+    log.truncate(index);
+
+    Ok(Divergence { index, removed })
+}
+
+/// Tails the log of the primary until the relayer is promoted.
+pub async fn run(ctx: Arc<AppState>, primary_url: String) {
+    let period = Duration::from_millis(ctx.config.standby_sync_interval_ms);
+    let mut interval = tokio::time::interval(period);
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build the HTTP client");
+
+    tracing::info!("Standby of {primary_url}");
+
+    while ctx.standby.load(Ordering::SeqCst) {
+        interval.tick().await;
+
+        if let Err(err) = sync(&ctx, &client, &primary_url).await {
+            tracing::warn!("Failed to sync with the primary: {err:#}");
+        }
+    }
+}
+
+async fn fetch_entries(
+    client: &reqwest::Client,
+    ctx: &AppState,
+    primary_url: &str,
+    from: u64,
+) -> Result<Vec<LogEntry>> {
+    let mut request = client
+        .get(format!("{primary_url}/internal/optimistic-log"))
+        .query(&[("from_index", from), ("limit", LOG_PAGE_SIZE)]);
+    if let Some(token) = &ctx.config.peer_token {
+        request = request.bearer_auth(&token.0);
+    }
+
+    Ok(request.send().await?.error_for_status()?.json().await?)
+}
+
+/// Fetches the log starting at [`sync_start`], which is applied again on every sync since the
+/// optimistic transactions can still change on the primary.
+async fn sync(ctx: &AppState, client: &reqwest::Client, primary_url: &str) -> Result<()> {
+    // The cached pool index is only advanced by the reorg detector on a standby.
+    let mined_index = ctx.backend.get_pool_index().await?;
+    ctx.optimistic_log.prune(mined_index);
+    let num_leaves = ctx.tree.lock("peer_sync").await.num_leaves();
+    let mut index = sync_start(&ctx.transactions, num_leaves, mined_index)?;
+
+    let mut divergence = None;
+    loop {
+        let entries = fetch_entries(client, ctx, primary_url, index).await?;
+        let page_len = entries.len() as u64;
+
+        let tree = ctx.tree.lock("peer_sync").await;
+        // Promoted in the meantime
+        if !ctx.standby.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        index = entries.last().map_or(index, |entry| entry.index + TX_SIZE);
+        let page_divergence = apply_entries(
+            &tree,
+            &ctx.transactions,
+            &ctx.optimistic_log,
+            mined_index,
+            entries,
+        )?;
+        divergence = divergence.or(page_divergence);
+
+        if page_len < LOG_PAGE_SIZE {
+            // The primary has rolled back transactions without replacing them.
+            if divergence.is_none() && index < tree.num_leaves() * TX_SIZE {
+                divergence = Some(roll_back(
+                    &tree,
+                    &ctx.transactions,
+                    &ctx.optimistic_log,
+                    index,
+                    mined_index,
+                )?);
+            }
+            break;
+        }
+    }
+
+    if let Some(Divergence { index, removed }) = divergence {
+        tracing::warn!("Removed {removed} transactions starting at {index} to follow the primary");
+        ctx.audit.record(AuditEvent::Rollback {
+            job_id: None,
+            to: index,
+            removed,
+            cause: "Diverged from the primary".to_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Stops following the primary and takes over: the optimistic state is rebuilt from the
+/// inherited payloads, each of them validated again as if it was submitted now. Transactions
+/// the primary has accepted without passing on the payload are lost.
+pub async fn promote(state: &Arc<AppState>) -> AppResult<Promotion> {
+    if !state.standby.swap(false, Ordering::SeqCst) {
+        return Err(AppError::Conflict(anyhow!("Not a standby relayer")));
+    }
+
+    let inherited = {
+        let tree = state.tree.lock("promote").await;
+        // The cached pool index can lag behind, the transactions mined meanwhile must not be
+        // resubmitted.
+        let mined_index = state.backend.get_pool_index().await?;
+        let inherited = state.optimistic_log.take_from(mined_index);
+
+        let next_index = tree.num_leaves() * TX_SIZE;
+        if mined_index < next_index {
+            let removed = state.transactions.rollback(mined_index)?;
+            tree.rollback(mined_index / TX_SIZE)?;
+            state.audit.record(AuditEvent::Rollback {
+                job_id: None,
+                to: mined_index,
+                removed,
+                cause: "Promotion to primary".to_owned(),
+            });
+        }
+        state.sync_tree_from_chain(&tree).await?;
+
+        // The followed transactions were mined differently, e.g. the primary was not the only
+        // sender.
+        let chain_root = state.backend.get_merkle_root(mined_index).await?;
+        if chain_root != Some(tree.root()?.0.into()) {
+            tracing::warn!("The followed state doesn't match the chain, rebuilding it");
+            state.rebuild_from_chain(&tree).await?;
+        }
+
+        inherited
+    };
+
+    let mut promotion = Promotion::default();
+    for (index, _, tx) in inherited {
+        let tx: ParsedTxData = bincode::deserialize(&tx)?;
+        match submit_parsed_transaction(state, Uuid::new_v4(), tx, None).await {
+            Ok(job_id) => {
+                tracing::info!("Resubmitted inherited transaction {index} as job {job_id}");
+                promotion.resubmitted += 1;
+            }
+            Err(AppError::TxValidationErrors(errors)) => {
+                tracing::warn!("Dropped inherited transaction {index}: {errors:?}");
+                promotion.dropped += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    state.accepting.store(true, Ordering::SeqCst);
+    state.sending.store(true, Ordering::SeqCst);
+
+    tracing::warn!(
+        "Promoted to primary, resubmitted {} and dropped {} inherited transactions",
+        promotion.resubmitted,
+        promotion.dropped
+    );
+    state.audit.record(AuditEvent::Promoted {
+        resubmitted: promotion.resubmitted,
+        dropped: promotion.dropped,
+    });
+
+    Ok(promotion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Relayer {
+        tree: MerkleTree,
+        transactions: TxStorage,
+        log: OptimisticLog,
+        _dir: tempfile::TempDir,
+    }
+
+    impl Relayer {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+            Self {
+                tree: MerkleTree::open(&path("tree.persy")).unwrap(),
+                transactions: TxStorage::open(&path("transactions.persy")).unwrap(),
+                log: OptimisticLog::default(),
+                _dir: dir,
+            }
+        }
+
+        fn accept(&self, out_commit: u64, mined_index: u64) {
+            let index = self.tree.num_leaves() * TX_SIZE;
+            let out_commit = Num::from(out_commit);
+            self.tree.add_leaf(out_commit).unwrap();
+            self.transactions
+                .push(index, out_commit, &[0; 32], &[0; 8])
+                .unwrap();
+            self.log
+                .insert(index, out_commit, index.to_be_bytes().to_vec(), mined_index);
+        }
+
+        fn records(&self) -> Vec<Vec<u8>> {
+            let txs = self.transactions.iter().unwrap();
+            txs.map(|res| res.unwrap().1).collect()
+        }
+    }
+
+    fn follow(standby: &Relayer, primary: &Relayer, mined_index: u64) -> Option<Divergence> {
+        let from = sync_start(
+            &standby.transactions,
+            standby.tree.num_leaves(),
+            mined_index,
+        )
+        .unwrap();
+        let entries = read_entries(&primary.transactions, &primary.log, from, 1000).unwrap();
+        let divergence = apply_entries(
+            &standby.tree,
+            &standby.transactions,
+            &standby.log,
+            mined_index,
+            entries,
+        )
+        .unwrap();
+
+        assert_eq!(standby.tree.root().unwrap(), primary.tree.root().unwrap());
+        assert_eq!(standby.records(), primary.records());
+
+        divergence
+    }
+
+    #[test]
+    fn test_follow_primary() {
+        let primary = Relayer::new();
+        let standby = Relayer::new();
+
+        for i in 0..3 {
+            primary.accept(i, 0);
+        }
+        assert_eq!(follow(&standby, &primary, 0), None);
+        assert_eq!(standby.log.take_from(0).len(), 3);
+
+        // The first transaction is mined, its hash is updated in place
+        primary
+            .transactions
+            .set(0, Num::from(0), &[1; 32], &[0; 8])
+            .unwrap();
+        assert_eq!(follow(&standby, &primary, TX_SIZE), None);
+        assert_eq!(standby.transactions.next_index().unwrap(), 3 * TX_SIZE);
+
+        // The primary rolls back the last two and accepts another one
+        primary.transactions.rollback(TX_SIZE).unwrap();
+        primary.tree.rollback(1).unwrap();
+        primary.log.truncate(TX_SIZE);
+        primary.accept(10, TX_SIZE);
+        assert_eq!(
+            follow(&standby, &primary, TX_SIZE),
+            Some(Divergence {
+                index: TX_SIZE,
+                removed: 2
+            })
+        );
+        let inherited = standby.log.take_from(TX_SIZE);
+        assert_eq!(inherited.len(), 1);
+        assert_eq!(inherited[0].1, Num::from(10));
+
+        // Mined transactions are never rolled back
+        primary.transactions.rollback(0).unwrap();
+        primary.tree.rollback(0).unwrap();
+        primary.accept(20, 0);
+        let entries = read_entries(&primary.transactions, &primary.log, 0, 1000).unwrap();
+        assert!(apply_entries(
+            &standby.tree,
+            &standby.transactions,
+            &standby.log,
+            TX_SIZE,
+            entries
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_optimistic_log() {
+        let log = OptimisticLog::default();
+        for index in 0..4 {
+            log.insert(index * TX_SIZE, Num::from(index), vec![index as u8], 0);
+        }
+
+        // A payload of a rolled back transaction is not returned for its index
+        assert_eq!(log.get(TX_SIZE, Num::from(1)), Some(vec![1]));
+        assert_eq!(log.get(TX_SIZE, Num::from(5)), None);
+
+        // Mined payloads are forgotten
+        log.insert(4 * TX_SIZE, Num::from(4), vec![4], 2 * TX_SIZE);
+        assert_eq!(log.get(TX_SIZE, Num::from(1)), None);
+
+        log.truncate(4 * TX_SIZE);
+        let txs = log.take_from(0);
+        assert_eq!(
+            txs.iter().map(|(index, ..)| *index).collect::<Vec<_>>(),
+            vec![2 * TX_SIZE, 3 * TX_SIZE]
+        );
+        assert!(log.take_from(0).is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+//! Per-client token bucket rate limiting. The state is kept in memory, so the limit applies to
+//! each relayer instance separately, not to the whole cluster.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header::RETRY_AFTER, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+/// Full buckets are dropped once this many clients are tracked.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// The bucket is refilled at `capacity` tokens per minute.
+    fn refill(&mut self, now: Instant, capacity: f64) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.updated = now;
+    }
+}
+
+pub struct RateLimiter {
+    /// 0 if disabled.
+    per_minute: AtomicU64,
+    /// Use the last address of `X-Forwarded-For` as the client address, the one appended by the
+    /// reverse proxy. The ones before it are sent by the client and can't be trusted. Only safe
+    /// behind a single reverse proxy that sets the header.
+    trust_forwarded_for: bool,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Allows bursts of up to `per_minute` requests. Doesn't limit anything if `per_minute` is
+    /// not set.
+    pub fn new(per_minute: Option<u64>, trust_forwarded_for: bool) -> Self {
+        Self {
+            per_minute: AtomicU64::new(per_minute.unwrap_or(0)),
+            trust_forwarded_for,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes the limit of the running limiter. The buckets are kept while it stays enabled:
+    /// lowering the limit caps them at the new burst size, raising it speeds up their refill.
+    pub fn set_per_minute(&self, per_minute: Option<u64>) {
+        self.per_minute
+            .store(per_minute.unwrap_or(0), Ordering::Relaxed);
+        if per_minute.is_none() {
+            self.buckets.lock().unwrap().clear();
+        }
+    }
+
+    /// Takes a token from the client's bucket. Returns the time until the next token is
+    /// available if the bucket is empty.
+    pub fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let capacity = match self.per_minute.load(Ordering::Relaxed) {
+            0 => return Ok(()),
+            per_minute => per_minute as f64,
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now, capacity);
+                bucket.tokens < capacity
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.refill(now, capacity);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) * 60.0 / capacity,
+            ))
+        }
+    }
+
+    fn client_addr<B>(&self, req: &Request<B>) -> Option<IpAddr> {
+        if self.trust_forwarded_for {
+            let forwarded = req
+                .headers()
+                .get(FORWARDED_FOR_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|addr| addr.trim().parse().ok());
+
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    }
+}
+
+/// Requests without a known client address (e.g. over a unix socket without `X-Forwarded-For`)
+/// are not limited.
+pub async fn middleware<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(client) = limiter.client_addr(&req) else {
+        return next.run(req).await;
+    };
+
+    match limiter.check(client, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            tracing::warn!("Rate limit exceeded for {client}");
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())],
+                Json(json!({
+                    "error": "Too many requests",
+                    "code": "RATE_LIMITED",
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::post, Router};
+
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(Some(2), false);
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check(client, now).is_ok());
+        assert!(limiter.check(client, now).is_ok());
+        assert_eq!(limiter.check(client, now), Err(Duration::from_secs(30)));
+
+        // Buckets are per client
+        assert!(limiter.check(other, now).is_ok());
+
+        // One token every 30 seconds
+        let later = now + Duration::from_secs(15);
+        assert_eq!(limiter.check(client, later), Err(Duration::from_secs(15)));
+        let later = now + Duration::from_secs(30);
+        assert!(limiter.check(client, later).is_ok());
+        assert!(limiter.check(client, later).is_err());
+
+        // The bucket is never filled above the burst size
+        let later = now + Duration::from_secs(3600);
+        assert!(limiter.check(client, later).is_ok());
+        assert!(limiter.check(client, later).is_ok());
+        assert!(limiter.check(client, later).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_middleware() {
+        let limiter = Arc::new(RateLimiter::new(Some(2), true));
+        let router = Router::new()
+            .route("/transactions", post(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                limiter.clone(),
+                super::middleware,
+            ));
+
+        let addr = test_utils::serve(router);
+
+        let client = reqwest::Client::new();
+        let send = |forwarded_for: Option<&'static str>| {
+            let mut req = client.post(format!("http://{addr}/transactions"));
+            if let Some(forwarded_for) = forwarded_for {
+                req = req.header(FORWARDED_FOR_HEADER, forwarded_for);
+            }
+            req.send()
+        };
+
+        assert_eq!(send(None).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send(None).await.unwrap().status(), StatusCode::OK);
+
+        let res = send(None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[RETRY_AFTER], "30");
+
+        // A different client behind the proxy, identified by the address the proxy appended
+        let res = send(Some("203.0.113.7, 10.0.0.1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = send(Some("10.0.0.1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Addresses sent by the client itself are ignored
+        let res = send(Some("198.51.100.1, 10.0.0.1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // The limit applies to the running server as soon as it's changed
+        limiter.set_per_minute(None);
+        for _ in 0..5 {
+            assert_eq!(send(None).await.unwrap().status(), StatusCode::OK);
+        }
+        limiter.set_per_minute(Some(1));
+        assert_eq!(send(None).await.unwrap().status(), StatusCode::OK);
+        let res = send(None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[RETRY_AFTER], "60");
+    }
+
+    #[test]
+    fn test_rate_limit_change() {
+        let limiter = RateLimiter::new(None, false);
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert!(limiter.check(client, now).is_ok());
+        }
+
+        limiter.set_per_minute(Some(4));
+        for _ in 0..4 {
+            assert!(limiter.check(client, now).is_ok());
+        }
+        assert!(limiter.check(client, now).is_err());
+
+        // Lowering the limit caps the bucket, raising it refills faster
+        let later = now + Duration::from_secs(60);
+        limiter.set_per_minute(Some(1));
+        assert!(limiter.check(client, later).is_ok());
+        assert!(limiter.check(client, later).is_err());
+        limiter.set_per_minute(Some(60));
+        assert!(limiter
+            .check(client, later + Duration::from_secs(1))
+            .is_ok());
+    }
+}
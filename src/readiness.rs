@@ -0,0 +1,115 @@
+//! Serves `/health/ready` and answers all other requests with 503 until the app state is
+//! initialized. This way the listeners are bound while the state is synced with the chain, and
+//! orchestrators can tell a starting relayer from a dead one.
+
+use std::sync::{atomic::Ordering, Arc};
+
+use axum::{
+    extract::State,
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use hyper::{service::Service, Body};
+use serde_json::json;
+use tokio::sync::OnceCell;
+
+use crate::state::SyncProgress;
+
+pub struct Readiness {
+    /// Requests are forwarded to these routes once they're set.
+    routes: OnceCell<Router>,
+    progress: Arc<SyncProgress>,
+}
+
+impl Readiness {
+    pub fn new(progress: Arc<SyncProgress>) -> Arc<Self> {
+        Arc::new(Self {
+            routes: OnceCell::new(),
+            progress,
+        })
+    }
+
+    /// Marks the relayer as ready. Only the first call has an effect.
+    pub fn set_routes(&self, routes: Router) {
+        let _ = self.routes.set(routes);
+    }
+
+    pub fn router(self: &Arc<Self>) -> Router {
+        Router::new()
+            .route("/health/ready", get(ready))
+            .fallback(forward)
+            .with_state(self.clone())
+    }
+
+    fn not_ready(&self) -> Response {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "error": "The relayer is starting",
+                "code": "NOT_READY",
+                "synced": self.progress.synced.load(Ordering::SeqCst),
+                "total": self.progress.total.load(Ordering::SeqCst),
+            })),
+        )
+            .into_response()
+    }
+}
+
+async fn ready(State(readiness): State<Arc<Readiness>>) -> Response {
+    if readiness.routes.initialized() {
+        StatusCode::OK.into_response()
+    } else {
+        readiness.not_ready()
+    }
+}
+
+async fn forward(State(readiness): State<Arc<Readiness>>, req: Request<Body>) -> Response {
+    match readiness.routes.get() {
+        Some(routes) => match routes.clone().call(req).await {
+            Ok(res) => res,
+            Err(never) => match never {},
+        },
+        None => readiness.not_ready(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let progress = Arc::new(SyncProgress::default());
+        let readiness = Readiness::new(progress.clone());
+
+        let addr = test_utils::serve(readiness.router());
+
+        let request = |path: &'static str| async move {
+            let res = reqwest::get(format!("http://{addr}{path}")).await.unwrap();
+            let status = res.status();
+            (status, res.text().await.unwrap())
+        };
+
+        progress.synced.store(200, Ordering::SeqCst);
+        progress.total.store(300, Ordering::SeqCst);
+
+        let (status, body) = request("/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["code"], "NOT_READY");
+        assert_eq!(body["synced"], 200);
+        assert_eq!(body["total"], 300);
+
+        let (status, _) = request("/info").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        readiness.set_routes(Router::new().route("/info", get(|| async { "info" })));
+
+        assert_eq!(request("/health/ready").await.0, StatusCode::OK);
+        assert_eq!(request("/info").await, (StatusCode::OK, "info".to_owned()));
+        assert_eq!(request("/unknown").await.0, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,228 @@
+//! Detection of chain reorganizations that revert already mined pool transactions.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use libzeropool_rs::libzeropool::fawkes_crypto::engines::U256;
+
+use crate::{
+    audit::AuditEvent,
+    backend::BlockchainBackend,
+    monitoring,
+    state::AppState,
+    tx_worker::{cancel_jobs_from, TX_SIZE},
+};
+
+/// Remembers the last observed on-chain pool state.
+pub struct ReorgDetector {
+    pool_index: u64,
+    root: U256,
+}
+
+impl ReorgDetector {
+    pub fn new(pool_index: u64, root: U256) -> Self {
+        Self { pool_index, root }
+    }
+
+    /// Polls the on-chain pool state and returns it. The flag is set if the pool index went
+    /// backwards or the root at the previously observed index has changed.
+    pub async fn check(&mut self, backend: &dyn BlockchainBackend) -> Result<(u64, U256, bool)> {
+        let pool_index = backend.get_pool_index().await?;
+        let root = backend
+            .get_merkle_root(pool_index)
+            .await?
+            .ok_or_else(|| anyhow!("Pool root is not available for index {pool_index}"))?;
+
+        let reorged = if pool_index < self.pool_index {
+            true
+        } else if pool_index == self.pool_index {
+            root != self.root
+        } else {
+            backend.get_merkle_root(self.pool_index).await? != Some(self.root)
+        };
+
+        self.pool_index = pool_index;
+        self.root = root;
+
+        Ok((pool_index, root, reorged))
+    }
+}
+
+/// Periodically reconciles the cached pool state with the chain and rolls back the local state
+/// after a reorg.
+pub async fn run(ctx: Arc<AppState>) {
+    let period = Duration::from_secs(ctx.config.reorg_check_interval_secs);
+    let mut interval = tokio::time::interval(period);
+
+    let mut detector =
+        ReorgDetector::new(*ctx.pool_index.read().await, *ctx.pool_root.read().await);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = reconcile(&ctx, &mut detector).await {
+            tracing::error!("Reorg check failed: {err}");
+        }
+    }
+}
+
+async fn reconcile(ctx: &AppState, detector: &mut ReorgDetector) -> Result<()> {
+    let (pool_index, root, reorged) = detector.check(ctx.backend.as_ref()).await?;
+
+    if !reorged {
+        let mut cached_index = ctx.pool_index.write().await;
+        if pool_index > *cached_index {
+            *cached_index = pool_index;
+            *ctx.pool_root.write().await = root;
+        }
+
+        return Ok(());
+    }
+
+    // Holding the tree lock prevents new jobs from being created during the rollback.
+    let tree = ctx.tree.lock("reorg").await;
+    let num_leaves = tree.num_leaves();
+    let commit_index = pool_index / TX_SIZE;
+
+    tracing::warn!(
+        "Chain reorg detected, rolling back from {} to pool index {pool_index}",
+        num_leaves * TX_SIZE
+    );
+
+    cancel_jobs_from(ctx, commit_index, num_leaves).await?;
+
+    if commit_index < num_leaves {
+        let removed = ctx.transactions.rollback(pool_index)?;
+        tree.rollback(commit_index)?;
+        tracing::warn!("Removed {removed} transactions after reorg");
+        ctx.audit.record(AuditEvent::Rollback {
+            job_id: None,
+            to: pool_index,
+            removed,
+            cause: "Chain reorg".to_owned(),
+        });
+    }
+
+    let mut cached_index = ctx.pool_index.write().await;
+    let depth = cached_index.saturating_sub(pool_index) / TX_SIZE;
+    *cached_index = pool_index;
+    *ctx.pool_root.write().await = root;
+
+    monitoring::record_reorg(depth);
+    tracing::warn!(
+        "Rolled back {depth} mined transactions, new root: {}",
+        tree.root()?
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        backend::mock::{mock_config, MockBackend},
+        job_queue::JobStatus,
+        test_utils,
+        tx_worker::prepare_job,
+    };
+
+    fn mock_backend() -> MockBackend {
+        MockBackend::new(mock_config(None, vec![]))
+    }
+
+    #[tokio::test]
+    async fn test_reorg_detection() {
+        let backend = mock_backend();
+        let pool_index = backend.get_pool_index().await.unwrap();
+        let root = backend.get_merkle_root(pool_index).await.unwrap().unwrap();
+        let mut detector = ReorgDetector::new(pool_index, root);
+
+        assert!(!detector.check(&backend).await.unwrap().2);
+
+        for i in 1..=3 {
+            backend.mine(U256::from(i)).await;
+        }
+        let (pool_index, root, reorged) = detector.check(&backend).await.unwrap();
+        assert_eq!(
+            (pool_index, root, reorged),
+            (3 * TX_SIZE, U256::from(3), false)
+        );
+
+        // Pool index goes backwards
+        backend.reorg(TX_SIZE).await;
+        let (pool_index, root, reorged) = detector.check(&backend).await.unwrap();
+        assert_eq!((pool_index, root, reorged), (TX_SIZE, U256::from(1), true));
+        assert!(!detector.check(&backend).await.unwrap().2);
+
+        // Same pool index, but a different history
+        backend.mine(U256::from(2)).await;
+        detector.check(&backend).await.unwrap();
+        backend.reorg(TX_SIZE).await;
+        backend.mine(U256::from(20)).await;
+        let (pool_index, root, reorged) = detector.check(&backend).await.unwrap();
+        assert_eq!(
+            (pool_index, root, reorged),
+            (2 * TX_SIZE, U256::from(20), true)
+        );
+
+        // Pool index moves forward on top of a different history
+        backend.reorg(TX_SIZE).await;
+        backend.mine(U256::from(200)).await;
+        backend.mine(U256::from(300)).await;
+        let (pool_index, _, reorged) = detector.check(&backend).await.unwrap();
+        assert_eq!((pool_index, reorged), (3 * TX_SIZE, true));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(mock_backend());
+        let ctx = test_utils::app_state(dir.path(), backend.clone(), |_| {}).await;
+        let mut detector =
+            ReorgDetector::new(*ctx.pool_index.read().await, *ctx.pool_root.read().await);
+
+        // Three transactions, the last two are still queued when the first one is mined.
+        let mut job_ids = Vec::new();
+        for seed in 1..=3 {
+            let payload = prepare_job(test_utils::deposit(seed), Uuid::new_v4(), None, ctx.clone())
+                .await
+                .unwrap();
+            if seed > 1 {
+                job_ids.push(ctx.job_queue.push(payload).await.unwrap());
+            }
+        }
+        backend.mine(U256::from(1)).await;
+
+        // The cached pool state follows the chain.
+        reconcile(&ctx, &mut detector).await.unwrap();
+        assert_eq!(*ctx.pool_index.read().await, TX_SIZE);
+        assert_eq!(*ctx.pool_root.read().await, U256::from(1));
+        assert_eq!(ctx.tree.lock("test").await.num_leaves(), 3);
+
+        // The first transaction is reverted, everything on top of it is removed.
+        backend.reorg(0).await;
+        reconcile(&ctx, &mut detector).await.unwrap();
+        assert_eq!(*ctx.pool_index.read().await, 0);
+        assert_eq!(
+            *ctx.pool_root.read().await,
+            backend.get_merkle_root(0).await.unwrap().unwrap()
+        );
+        assert_eq!(ctx.tree.lock("test").await.num_leaves(), 0);
+        assert_eq!(ctx.transactions.next_index().unwrap(), 0);
+        for job_id in job_ids {
+            assert_eq!(
+                ctx.job_queue.job_status(job_id).await.unwrap(),
+                Some(JobStatus::Cancelled)
+            );
+            assert!(ctx.failed_jobs.get(job_id).unwrap().is_some());
+        }
+        assert_eq!(ctx.job_queue.queue_len().await.unwrap(), 0);
+
+        // Nothing changes without another reorg.
+        reconcile(&ctx, &mut detector).await.unwrap();
+        assert_eq!(*ctx.pool_index.read().await, 0);
+    }
+}
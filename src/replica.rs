@@ -0,0 +1,385 @@
+//! Read-only replica mode (`--read-replica`). The replica keeps its own copy of the transaction
+//! storage, synced from the primary relayer over HTTP, and serves `/transactions` and `/info`
+//! from it. Transaction submissions are refused.
+//!
+//! Persy locks its files, so the replica can't simply open the primary's storage.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{sync::RwLock, task::JoinSet};
+use tower_http::trace::TraceLayer;
+use zeropool_relayer_client::{InfoResponse, RelayerClient};
+
+use crate::{
+    config::{parse_listen_addrs, CorsOrigins},
+    json_api::{
+        cors_layer, read_transactions_legacy, stream_transactions_json, AppError, AppResult,
+        LegacyTxQuery, TxPaginationQuery,
+    },
+    server,
+    tx_storage::TxStorage,
+    tx_worker::TX_SIZE,
+};
+
+/// Number of transactions fetched from the primary per request.
+const SYNC_PAGE_SIZE: u64 = 100;
+
+/// Loaded from `REPLICA_*` environment variables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Base URL of the primary relayer.
+    pub primary_url: String,
+    /// Comma-separated listen addresses, same format as `LISTEN`.
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    #[serde(default = "default_sync_interval_ms")]
+    pub sync_interval_ms: u64,
+    #[serde(default = "default_tx_storage_path")]
+    pub tx_storage_path: String,
+    #[serde(default = "default_cors_allowed_origins")]
+    pub cors_allowed_origins: String,
+}
+
+fn default_listen() -> String {
+    "0.0.0.0:3000".to_owned()
+}
+
+fn default_sync_interval_ms() -> u64 {
+    1000
+}
+
+fn default_tx_storage_path() -> String {
+    "replica_transactions.persy".to_owned()
+}
+
+fn default_cors_allowed_origins() -> String {
+    "*".to_owned()
+}
+
+pub struct ReplicaState {
+    transactions: TxStorage,
+    primary: RelayerClient,
+    primary_url: String,
+    /// Last `/info` of the primary, `None` until the first successful sync.
+    info: RwLock<Option<InfoResponse>>,
+}
+
+impl ReplicaState {
+    pub(crate) fn new(transactions: TxStorage, primary_url: &str) -> Self {
+        Self {
+            transactions,
+            primary: RelayerClient::new(primary_url),
+            primary_url: primary_url.to_owned(),
+            info: RwLock::new(None),
+        }
+    }
+}
+
+pub async fn run() -> Result<()> {
+    let config: Config = envy::prefixed("REPLICA_").from_env()?;
+    tracing::info!("{config:#?}");
+
+    let listen = parse_listen_addrs(&config.listen)?;
+    let cors_allowed_origins = config.cors_allowed_origins.parse()?;
+    let state = Arc::new(ReplicaState::new(
+        TxStorage::open(&config.tx_storage_path)?,
+        &config.primary_url,
+    ));
+
+    tokio::spawn(sync_loop(
+        state.clone(),
+        Duration::from_millis(config.sync_interval_ms),
+    ));
+
+    let router = routes(state, &cors_allowed_origins);
+    let mut servers = JoinSet::new();
+    for addr in listen {
+        servers.spawn(server::serve(addr, router.clone()));
+    }
+
+    if let Some(res) = servers.join_next().await {
+        res??;
+    }
+
+    Ok(())
+}
+
+pub fn routes(state: Arc<ReplicaState>, cors_allowed_origins: &CorsOrigins) -> Router {
+    let router = Router::new()
+        .route("/transactions", get(get_transactions).post(read_only))
+        .route("/transactions/v2", get(get_transactions_legacy))
+        .route("/sendTransactions", post(read_only))
+        .route("/info", get(info))
+        .layer(TraceLayer::new_for_http());
+
+    let router = match cors_layer(cors_allowed_origins) {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+
+    router.with_state(state)
+}
+
+async fn sync_loop(state: Arc<ReplicaState>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = sync(&state).await {
+            tracing::warn!("Failed to sync with the primary: {err:#}");
+        }
+    }
+}
+
+/// Copies new transactions from the primary. Optimistic transactions can still be rolled back on
+/// the primary, so they are compared again on every sync and replaced if they've changed.
+pub async fn sync(state: &ReplicaState) -> Result<()> {
+    let info = state.primary.info().await?;
+    let pool_index: u64 = info.pool_index.parse()?;
+    let next_index = state.transactions.next_index()?;
+
+    let mut index = next_index.min(pool_index);
+    let mut diverged = false;
+    loop {
+        let page = state.primary.transactions(index, SYNC_PAGE_SIZE).await?;
+        let page_len = page.len() as u64;
+
+        for data in page {
+            if !diverged {
+                if state.transactions.get(index)?.as_ref() == Some(&data) {
+                    index += TX_SIZE;
+                    continue;
+                }
+
+                let removed = state.transactions.rollback(index)?;
+                if removed > 0 {
+                    tracing::info!("Replaced {removed} transactions starting at {index}");
+                }
+                diverged = true;
+            }
+
+            state.transactions.set_raw(index, &data)?;
+            index += TX_SIZE;
+        }
+
+        if page_len < SYNC_PAGE_SIZE {
+            break;
+        }
+    }
+
+    // The primary has rolled back transactions without replacing them.
+    if !diverged && index < state.transactions.next_index()? {
+        let removed = state.transactions.rollback(index)?;
+        tracing::info!("Removed {removed} transactions starting at {index}");
+    }
+
+    *state.info.write().await = Some(info);
+
+    Ok(())
+}
+
+async fn get_transactions(
+    State(state): State<Arc<ReplicaState>>,
+    Query(pagination): Query<TxPaginationQuery>,
+) -> Response {
+    stream_transactions_json(state, |state| &state.transactions, pagination.range())
+}
+
+async fn get_transactions_legacy(
+    State(state): State<Arc<ReplicaState>>,
+    Query(query): Query<LegacyTxQuery>,
+) -> AppResult<Json<Vec<String>>> {
+    let pool_index = match &*state.info.read().await {
+        Some(info) => info.pool_index.parse()?,
+        None => 0,
+    };
+    let txs = read_transactions_legacy(&state.transactions, pool_index, &query)?;
+
+    Ok(Json(txs))
+}
+
+async fn info(State(state): State<Arc<ReplicaState>>) -> AppResult<Json<InfoResponse>> {
+    state
+        .info
+        .read()
+        .await
+        .clone()
+        .map(Json)
+        .ok_or(AppError::NotFound)
+}
+
+async fn read_only(State(state): State<Arc<ReplicaState>>) -> Response {
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        Json(json!({
+            "error": "Transactions can only be submitted to the primary relayer",
+            "code": "READ_ONLY_REPLICA",
+            "primary": state.primary_url,
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+    use scopeguard::defer;
+    use zeropool_relayer_client::Hex;
+
+    use super::*;
+    use crate::test_utils;
+
+    struct Primary {
+        transactions: TxStorage,
+        pool_index: AtomicU64,
+    }
+
+    async fn primary_info(State(primary): State<Arc<Primary>>) -> Json<InfoResponse> {
+        let pool_index = primary.pool_index.load(Ordering::SeqCst);
+        let optimistic_index = primary.transactions.next_index().unwrap();
+
+        Json(InfoResponse {
+            backend: "mock".to_owned(),
+            chain_id: "mock".to_owned(),
+            pool_id: 0,
+            api_version: "3".to_owned(),
+            root: "0".to_owned(),
+            optimistic_root: "0".to_owned(),
+            pool_index: pool_index.to_string(),
+            optimistic_index: optimistic_index.to_string(),
+            paused: false,
+            sending_paused: false,
+            pending_jobs: None,
+            in_progress_jobs: None,
+            failed_jobs_last24h: None,
+            mined_transactions: pool_index / TX_SIZE,
+            optimistic_transactions: None,
+            replicas: vec![],
+            pool_address: None,
+            build: None,
+            supersede_counter: 0,
+        })
+    }
+
+    async fn primary_transactions(
+        State(primary): State<Arc<Primary>>,
+        Query(pagination): Query<TxPaginationQuery>,
+    ) -> Response {
+        stream_transactions_json(primary, |p| &p.transactions, pagination.range())
+    }
+
+    fn records(storage: &TxStorage) -> Vec<Vec<u8>> {
+        storage.iter().unwrap().map(|res| res.unwrap().1).collect()
+    }
+
+    #[tokio::test]
+    async fn test_replica_sync() {
+        const PRIMARY_FILE: &str = "replica_test_sync_primary.persy";
+        const REPLICA_FILE: &str = "replica_test_sync_replica.persy";
+        defer! {
+            std::fs::remove_file(PRIMARY_FILE).unwrap();
+            std::fs::remove_file(REPLICA_FILE).unwrap();
+        }
+
+        let primary = Arc::new(Primary {
+            transactions: TxStorage::open(PRIMARY_FILE).unwrap(),
+            pool_index: AtomicU64::new(0),
+        });
+        let primary_addr = test_utils::serve(
+            Router::new()
+                .route("/info", get(primary_info))
+                .route("/transactions", get(primary_transactions))
+                .with_state(primary.clone()),
+        );
+        let primary_url = format!("http://{primary_addr}");
+
+        let state = Arc::new(ReplicaState::new(
+            TxStorage::open(REPLICA_FILE).unwrap(),
+            &primary_url,
+        ));
+
+        // One mined and one optimistic transaction
+        for i in 0..2 {
+            primary
+                .transactions
+                .push(i * TX_SIZE, Num::from(i), &[i as u8], &[0; 8])
+                .unwrap();
+        }
+        primary.pool_index.store(TX_SIZE, Ordering::SeqCst);
+
+        sync(&state).await.unwrap();
+        assert_eq!(records(&state.transactions), records(&primary.transactions));
+
+        // New transactions on the primary
+        for i in 2..5 {
+            primary
+                .transactions
+                .push(i * TX_SIZE, Num::from(i), &[i as u8], &[0; 8])
+                .unwrap();
+        }
+
+        sync(&state).await.unwrap();
+        assert_eq!(state.transactions.count().unwrap(), 5);
+        assert_eq!(records(&state.transactions), records(&primary.transactions));
+
+        // The primary rolls back optimistic transactions and replaces one of them
+        primary.transactions.rollback(2 * TX_SIZE).unwrap();
+        primary
+            .transactions
+            .push(2 * TX_SIZE, Num::from(20), &[20], &[0; 8])
+            .unwrap();
+
+        sync(&state).await.unwrap();
+        assert_eq!(state.transactions.count().unwrap(), 3);
+        assert_eq!(records(&state.transactions), records(&primary.transactions));
+
+        // The replica serves the synced data and refuses submissions
+        let replica_url = format!(
+            "http://{}",
+            test_utils::serve(routes(state, &CorsOrigins::Any))
+        );
+        let client = reqwest::Client::new();
+
+        let txs = client
+            .get(format!("{replica_url}/transactions?offset=0&limit=10"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let txs: Vec<Hex> = serde_json::from_str(&txs).unwrap();
+        assert_eq!(txs.len(), 3);
+
+        let info = client
+            .get(format!("{replica_url}/info"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let info: InfoResponse = serde_json::from_str(&info).unwrap();
+        assert_eq!(info.pool_index, TX_SIZE.to_string());
+
+        let res = client
+            .post(format!("{replica_url}/transactions"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,165 @@
+//! Detection of local historic roots that disagree with the roots of the mined transactions.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use libzeropool_rs::libzeropool::{
+    constants,
+    fawkes_crypto::{engines::U256, ff_uint::PrimeField},
+};
+
+use crate::{
+    backend::BlockchainBackend, merkle_tree::MerkleTree, monitoring, state::AppState,
+    timed_mutex::TimedMutex, tx_worker::TX_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootCheck {
+    Match,
+    Mismatch {
+        pool_index: u64,
+        chain: U256,
+        local: U256,
+    },
+    /// The local tree has no root for the mined index, e.g. it's not synced yet or the root is
+    /// pruned.
+    Unknown,
+}
+
+/// Compares the root at the current mined pool index with the local historic root.
+/// The tree is only locked after the chain is queried.
+pub async fn check(
+    backend: &dyn BlockchainBackend,
+    tree: &TimedMutex<MerkleTree>,
+) -> Result<RootCheck> {
+    let pool_index = backend.get_pool_index().await?;
+    let chain = backend
+        .get_merkle_root(pool_index)
+        .await?
+        .ok_or_else(|| anyhow!("Pool root is not available for index {pool_index}"))?;
+
+    let Some(local) = tree
+        .lock("root_check")
+        .await
+        .historic_root(pool_index / TX_SIZE)?
+    else {
+        return Ok(RootCheck::Unknown);
+    };
+    let local = local.to_uint().0;
+
+    if local == chain {
+        Ok(RootCheck::Match)
+    } else {
+        Ok(RootCheck::Mismatch {
+            pool_index,
+            chain,
+            local,
+        })
+    }
+}
+
+/// The first root of the pool is the root of the empty tree, so it only matches the local empty
+/// root if the pool has the tree height the relayer is compiled with. Fails otherwise.
+pub async fn check_tree_height(backend: &dyn BlockchainBackend, tree: &MerkleTree) -> Result<()> {
+    let Some(first_root) = backend.get_merkle_root(0).await? else {
+        tracing::warn!("First pool root is not available, skipping the tree height check");
+        return Ok(());
+    };
+
+    let empty_root = tree.empty_root().to_uint().0;
+    if first_root != empty_root {
+        bail!(
+            "Pool tree height doesn't match the compiled height {}: first pool root is \
+             {first_root}, expected {empty_root}",
+            constants::HEIGHT
+        );
+    }
+
+    Ok(())
+}
+
+/// Periodically compares the roots. A mismatch is only reported, since the reorg detector
+/// handles the reorgs, and anything else needs a closer look.
+pub async fn run(ctx: Arc<AppState>, interval_secs: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+
+    loop {
+        interval.tick().await;
+
+        match check(ctx.backend.as_ref(), &ctx.tree).await {
+            Ok(RootCheck::Mismatch {
+                pool_index,
+                chain,
+                local,
+            }) => {
+                monitoring::record_root_mismatch();
+                tracing::error!(
+                    "Root mismatch at pool index {pool_index}: chain root is {chain}, local \
+                     root is {local}"
+                );
+            }
+            Ok(RootCheck::Match) => {}
+            Ok(RootCheck::Unknown) => tracing::debug!("No local root to compare with"),
+            Err(err) => tracing::error!("Root check failed: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+    use scopeguard::defer;
+
+    use super::*;
+    use crate::backend::mock::{mock_config, MockBackend};
+
+    fn mock_backend() -> MockBackend {
+        MockBackend::new(mock_config(None, vec![]))
+    }
+
+    #[tokio::test]
+    async fn test_check_tree_height() {
+        const FILE_NAME: &str = "root_check_test_height.persy";
+        let tree = MerkleTree::open(FILE_NAME).unwrap();
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        let backend = mock_backend();
+        assert!(check_tree_height(&backend, &tree).await.is_ok());
+
+        backend.set_root(0, U256::from(1)).await;
+        assert!(check_tree_height(&backend, &tree).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_root_check() {
+        const FILE_NAME: &str = "root_check_test.persy";
+        let tree = TimedMutex::new(MerkleTree::open(FILE_NAME).unwrap());
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        let backend = mock_backend();
+        let root = |tree: &MerkleTree| tree.root().unwrap().to_uint().0;
+
+        tree.lock("test").await.add_leaf(Num::from(1)).unwrap();
+        backend.mine(root(&*tree.lock("test").await)).await;
+        assert_eq!(check(&backend, &tree).await.unwrap(), RootCheck::Match);
+
+        // The tree is behind the chain
+        backend.mine(U256::from(2)).await;
+        assert_eq!(check(&backend, &tree).await.unwrap(), RootCheck::Unknown);
+
+        tree.lock("test").await.add_leaf(Num::from(2)).unwrap();
+        let local = root(&*tree.lock("test").await);
+        assert_eq!(
+            check(&backend, &tree).await.unwrap(),
+            RootCheck::Mismatch {
+                pool_index: 2 * TX_SIZE,
+                chain: U256::from(2),
+                local,
+            }
+        );
+    }
+}
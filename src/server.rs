@@ -0,0 +1,143 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::Result;
+use axum::Router;
+use hyper::server::accept::Accept;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config::ListenAddr;
+
+/// Permissions of the unix socket file: read/write for the owner and the group.
+const SOCKET_MODE: u32 = 0o660;
+
+/// A listener bound by [`Listener::bind`], so that the address is known before serving.
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn bind(addr: &ListenAddr) -> Result<Self> {
+        let listener = match addr {
+            ListenAddr::Tcp(addr) => {
+                let listener = std::net::TcpListener::bind(addr)?;
+                tracing::info!("Starting server on {}", listener.local_addr()?);
+                Listener::Tcp(listener)
+            }
+            ListenAddr::Unix(path) => {
+                let listener = bind_unix(path)?;
+                tracing::info!("Starting server on {addr}");
+                Listener::Unix(listener)
+            }
+        };
+
+        Ok(listener)
+    }
+
+    /// Bound address of a TCP listener, with the actual port if it was bound to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            Listener::Unix(_) => None,
+        }
+    }
+}
+
+/// Serves the router on the given address until an error occurs.
+pub async fn serve(addr: ListenAddr, router: Router) -> Result<()> {
+    serve_listener(Listener::bind(&addr)?, router).await
+}
+
+/// Serves the router on an already bound listener until an error occurs.
+pub async fn serve_listener(listener: Listener, router: Router) -> Result<()> {
+    match listener {
+        Listener::Tcp(listener) => {
+            axum::Server::from_tcp(listener)?
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        Listener::Unix(listener) => {
+            axum::Server::builder(UnixAccept(listener))
+                .serve(router.into_make_service())
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Binds a unix socket, removing a stale socket file left over from a previous run.
+fn bind_unix(path: &Path) -> Result<UnixListener> {
+    match fs::remove_file(path) {
+        Ok(()) => tracing::debug!("Removed stale socket file {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(SOCKET_MODE))?;
+
+    Ok(listener)
+}
+
+struct UnixAccept(UnixListener);
+
+impl Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let (stream, _) = std::task::ready!(self.0.poll_accept(cx))?;
+        Poll::Ready(Some(Ok(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relayer.sock");
+
+        // A stale file must not prevent binding.
+        fs::write(&path, b"").unwrap();
+
+        let router = Router::new().route("/info", get(|| async { "ok" }));
+        tokio::spawn(serve(ListenAddr::Unix(path.clone()), router));
+
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+
+        stream
+            .write_all(b"GET /info HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("ok"));
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, SOCKET_MODE);
+    }
+}
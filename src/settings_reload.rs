@@ -0,0 +1,41 @@
+//! Reloads `RuntimeSettings` on SIGHUP, re-reading `.env` first so that the settings can be
+//! changed by editing it. Values in `.env` override the environment on reload, unlike at startup.
+//! The environment itself is left as is, it's not safe to modify while other threads read it.
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{config::RuntimeSettings, state::AppState};
+
+pub async fn run(ctx: Arc<AppState>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            tracing::error!("Failed to listen for SIGHUP, settings can't be reloaded: {err}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        tracing::info!("SIGHUP received, reloading runtime settings");
+        if let Err(err) = reload(&ctx) {
+            // The previous settings stay in effect.
+            tracing::error!("Failed to reload runtime settings: {err:#}");
+        }
+    }
+}
+
+fn reload(ctx: &AppState) -> Result<()> {
+    let dotenv = match dotenv::dotenv_iter() {
+        Ok(vars) => vars.collect::<Result<HashMap<_, _>, _>>()?,
+        Err(err) if err.not_found() => HashMap::new(),
+        Err(err) => return Err(err.into()),
+    };
+
+    let settings = RuntimeSettings::from_vars(|key| {
+        dotenv.get(key).cloned().or_else(|| std::env::var(key).ok())
+    })?;
+    ctx.update_settings(settings, "sighup")
+}
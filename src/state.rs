@@ -1,4 +1,10 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
 use anyhow::Result;
 #[cfg(feature = "plonk")]
@@ -6,7 +12,7 @@ use libzeropool_rs::libzeropool::fawkes_crypto::backend::plonk::{
     setup::{setup, ProvingKey},
     Parameters as PlonkParameters,
 };
-use libzeropool_rs::libzeropool::fawkes_crypto::{circuit::cs::CS, engines::U256};
+use libzeropool_rs::libzeropool::fawkes_crypto::{circuit::cs::CS, engines::U256, ff_uint::Num};
 #[cfg(feature = "plonk")]
 use libzeropool_rs::libzeropool::{
     circuit::{
@@ -15,19 +21,39 @@ use libzeropool_rs::libzeropool::{
     },
     POOL_PARAMS,
 };
-use tokio::sync::{Mutex, RwLock};
+#[cfg(feature = "metrics")]
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::sync::{broadcast, watch, RwLock, Semaphore};
 
 use crate::{
+    audit::{AuditEvent, AuditLog},
     backend::BlockchainBackend,
-    config::{BackendKind, Config},
+    config::{BackendKind, Config, RuntimeSettings},
+    failed_jobs::FailedJobStorage,
+    instance_lock::InstanceLock,
     job_queue::JobQueue,
-    merkle_tree::MerkleTree,
+    merkle_tree::{self, MerkleTree},
+    monitoring,
+    peer_sync::OptimisticLog,
+    rate_limit::RateLimiter,
+    root_check, state_archive,
+    timed_mutex::TimedMutex,
+    tx::TxEvent,
     tx_storage::TxStorage,
-    tx_worker::{Payload, WorkerJobQueue},
+    tx_worker::{cancel_jobs_from, Payload, WorkerJobQueue},
     Engine, Fr, VK,
 };
 
+pub const TX_STORAGE_PATH: &str = "transactions.persy";
+pub const TREE_PATH: &str = "tree.persy";
+pub const FAILED_JOBS_PATH: &str = "failed_jobs.persy";
+
 const TX_INDEX_STRIDE: usize = libzeropool_rs::libzeropool::constants::OUT + 1;
+/// Subscribers lagging behind by more than this many events are disconnected.
+const TX_EVENTS_CAPACITY: usize = 1024;
+/// Number of transactions written to the tree at once while syncing with the chain. Progress is
+/// persisted after every batch.
+const SYNC_BATCH_SIZE: usize = 100;
 
 #[cfg(feature = "groth16")]
 pub struct Groth16Params {
@@ -45,107 +71,183 @@ pub struct PlonkParams {
 
 pub struct AppState {
     pub config: Config,
-    pub transactions: TxStorage,
-    pub tree: Mutex<MerkleTree>,
+    pub(crate) transactions: TxStorage,
+    pub(crate) failed_jobs: FailedJobStorage<Payload>,
+    pub audit: AuditLog,
+    pub(crate) tree: TimedMutex<MerkleTree>,
+    /// Progress of the current sync with the chain, reported by `/health/ready` at startup.
+    pub sync_progress: Arc<SyncProgress>,
+    /// Optimistic root, number of leaves and supersede counter, kept up to date by the tree itself
+    /// so that readers don't need to lock the tree.
+    pub optimistic_tree_state: watch::Receiver<(Num<Fr>, u64, u64)>,
     pub job_queue: JobQueue<Payload, AppState>,
+    /// Held by the jobs in progress, see `JobQueue::start`.
+    pub job_permits: Arc<Semaphore>,
     pub backend: Arc<dyn BlockchainBackend>,
+    /// Cached `BlockchainBackend::chain_id`.
+    pub chain_id: String,
     pub pool_root: RwLock<U256>,
     pub pool_index: RwLock<u64>,
-    pub fee: u64,
+    /// Current fee, rate limit and queue limit, see `update_settings`.
+    settings: watch::Sender<RuntimeSettings>,
+    /// Limits transaction submissions, shared by the routers of all listeners.
+    pub(crate) rate_limiter: Arc<RateLimiter>,
+    /// New transactions are rejected while `false`.
+    pub accepting: AtomicBool,
+    /// The worker doesn't send transactions while `false`.
+    pub sending: AtomicBool,
+    /// Following a primary relayer until promoted. Not accepting or sending meanwhile.
+    pub standby: AtomicBool,
+    /// Held for as long as the relayer runs, `None` with the in-memory job queue.
+    pub instance_lock: Option<Arc<InstanceLock>>,
+    /// Payloads of the optimistic transactions, served to or inherited from a peer relayer.
+    pub(crate) optimistic_log: OptimisticLog,
+    pub tx_events: broadcast::Sender<TxEvent>,
+    #[cfg(feature = "metrics")]
+    pub metrics: PrometheusHandle,
+    /// Not loaded with `mock_prover`.
     #[cfg(feature = "groth16")]
-    pub groth16_params: Groth16Params,
+    pub groth16_params: Option<Groth16Params>,
+    /// Not loaded with `mock_prover`.
     #[cfg(feature = "plonk")]
-    pub plonk_params: PlonkParams,
+    pub plonk_params: Option<PlonkParams>,
+}
+
+/// Progress of the current sync with the chain, in transactions.
+#[derive(Debug, Default)]
+pub struct SyncProgress {
+    pub synced: AtomicU64,
+    pub total: AtomicU64,
 }
 
 impl AppState {
-    pub async fn init(config: Config) -> Result<Self> {
+    pub async fn init(
+        config: Config,
+        sync_progress: Arc<SyncProgress>,
+        instance_lock: Option<Arc<InstanceLock>>,
+    ) -> Result<Self> {
+        let backend = Self::connect_backend(&config).await?;
+        Self::init_with_backend(config, backend, sync_progress, instance_lock).await
+    }
+
+    /// The backend configured with `Config::backend`.
+    async fn connect_backend(config: &Config) -> Result<Arc<dyn BlockchainBackend>> {
         let backend: Arc<dyn BlockchainBackend> = match config.backend.clone() {
-            BackendKind::Mock => Arc::new(crate::backend::mock::MockBackend::new()),
+            BackendKind::Mock(config) => Arc::new(crate::backend::mock::MockBackend::new(config)),
             #[cfg(feature = "evm_backend")]
-            BackendKind::Evm(config) => Arc::new(crate::backend::evm::EvmBackend::new(config)?),
+            BackendKind::Evm(backend_config) => Arc::new(crate::backend::evm::EvmBackend::new(
+                backend_config,
+                config.rpc_retry.clone(),
+                config.dry_run,
+            )?),
             #[cfg(feature = "near_backend")]
-            BackendKind::Near(config) => Arc::new(crate::backend::near::NearBackend::new(config)?),
+            BackendKind::Near(backend_config) => Arc::new(crate::backend::near::NearBackend::new(
+                backend_config,
+                config.rpc_retry.clone(),
+                config.dry_run,
+            )?),
             #[cfg(feature = "waves_backend")]
-            BackendKind::Waves(config) => {
-                Arc::new(crate::backend::waves::WavesBackend::new(config).await?)
-            }
+            BackendKind::Waves(backend_config) => Arc::new(
+                crate::backend::waves::WavesBackend::new(
+                    backend_config,
+                    config.rpc_retry.clone(),
+                    config.dry_run,
+                )
+                .await?,
+            ),
         };
 
-        let job_queue = WorkerJobQueue::new(&config.redis_url)?;
-        let mut transactions = TxStorage::open("transactions.persy")?;
-        let mut tree = MerkleTree::open("tree.persy")?;
+        Ok(backend)
+    }
+
+    /// Same as [`AppState::init`], with a backend created by the caller instead of the configured
+    /// one, e.g. a mock backend shared by several relayers.
+    pub async fn init_with_backend(
+        config: Config,
+        backend: Arc<dyn BlockchainBackend>,
+        sync_progress: Arc<SyncProgress>,
+        instance_lock: Option<Arc<InstanceLock>>,
+    ) -> Result<Self> {
+        let chain_id = backend.chain_id().await?;
+        tracing::info!("Backend: {}, chain id: {chain_id}", backend.name());
+
+        match backend.get_pool_id().await {
+            Ok(Some(pool_id)) if pool_id != config.pool_id => tracing::warn!(
+                "Configured pool id {} differs from the pool id {pool_id} of the contract",
+                config.pool_id
+            ),
+            Ok(_) => {}
+            Err(err) => tracing::warn!("Failed to fetch the pool id: {err}"),
+        }
+
+        let job_queue = WorkerJobQueue::new(&config.job_queue, config.job_retention.clone())?;
+        let failed_jobs = FailedJobStorage::open(&config.storage_path(FAILED_JOBS_PATH))?;
+        let audit = AuditLog::open(&config.audit)?;
+        let tree_path = config.storage_path(TREE_PATH);
+        let tx_storage_path = config.storage_path(TX_STORAGE_PATH);
+        if state_archive::finish_import(&tree_path, &tx_storage_path)? {
+            tracing::info!("Finished an interrupted state import");
+        }
+        let transactions = TxStorage::open(&tx_storage_path)?;
+        let indexed = transactions.index_ciphertexts()?;
+        if indexed > 0 {
+            tracing::info!("Indexed the ciphertexts of {indexed} stored transactions");
+        }
+        let mut tree = match MerkleTree::open(&tree_path) {
+            Ok(tree) => tree,
+            Err(err) if merkle_tree::is_corrupted(&err) => {
+                tracing::error!("{err:#}. Rebuilding the tree from the chain...");
+                // The transactions are fetched again along with the tree.
+                transactions.rollback(0)?;
+                MerkleTree::clear_and_open(&tree_path)?
+            }
+            Err(err) => return Err(err),
+        };
+        root_check::check_tree_height(backend.as_ref(), &tree).await?;
         let pool_index = backend.get_pool_index().await?;
         let pool_root = backend.get_merkle_root(pool_index).await?.ok_or_else(|| {
             anyhow::anyhow!("Pool root is not available for index {}", pool_index)
         })?;
-        let mut relayer_index = tree.num_leaves() * TX_INDEX_STRIDE as u64;
-        let fee = config.fee;
+        let relayer_index = tree.num_leaves() * TX_INDEX_STRIDE as u64;
 
         tracing::info!("Pool index: {}", pool_index);
         tracing::info!("Relayer index: {}", relayer_index);
         tracing::info!("Pool root: {}", pool_root);
         tracing::info!("Relayer root: {}", tree.root()?);
 
-        // TODO: Attempt rollback first and check the roots. Only reinitialize if the roots don't match.
-        if relayer_index > pool_index {
-            tracing::error!("Relayer state is corrupted. Reinitializing...");
-
-            transactions = TxStorage::clear_and_open("transactions.persy")?;
-            tree = MerkleTree::clear_and_open("tree.persy")?;
-            relayer_index = 0;
-        } else if relayer_index < pool_index {
-            tracing::info!("Fetching transactions...");
-            let all_txs = backend.fetch_latest_transactions().await?;
-            tracing::info!(
-                "Fetched {} transactions, initializing state...",
-                all_txs.len()
-            );
-
-            for (i, tx) in all_txs.into_iter().enumerate() {
-                let tx_index = i * TX_INDEX_STRIDE;
-                if tx_index < relayer_index as usize {
-                    tracing::info!("Skipping tx {}", tx_index);
-                    continue;
-                }
-
-                let tx_data = backend.parse_calldata(tx.calldata)?;
-                let tx_hash = tx.hash;
-
-                tree.add_leaf(tx_data.out_commit)?;
-                transactions.set(
-                    tx_index as u64,
-                    tx_data.out_commit,
-                    &tx_hash,
-                    backend.extract_ciphertext_from_memo(&tx_data.memo, tx_data.tx_type),
-                )?;
-            }
-
-            relayer_index = tree.num_leaves() * TX_INDEX_STRIDE as u64;
-
-            tracing::info!("New relayer index: {}", relayer_index);
-            tracing::info!("New relayer root: {}", tree.root()?);
-        }
-
         #[cfg(feature = "groth16")]
-        let groth16_params = {
-            let transfer_vk = std::fs::read_to_string("params/transfer_verification_key.json")?;
-            let transfer_vk: VK = serde_json::from_str(&transfer_vk)?;
-            let tree_vk = std::fs::read_to_string("params/tree_verification_key.json")?;
-            let tree_vk: VK = serde_json::from_str(&tree_vk)?;
-            let tree_params_data = std::fs::read("params/tree_params.bin")?;
+        let groth16_params = if config.mock_prover {
+            None
+        } else {
+            let params = &config.params;
+            let transfer_vk = params
+                .load(&params.transfer_vk, params.transfer_vk_hash.as_deref())
+                .await?;
+            let transfer_vk: VK = serde_json::from_slice(&transfer_vk)?;
+            let tree_vk = params
+                .load(&params.tree_vk, params.tree_vk_hash.as_deref())
+                .await?;
+            let tree_vk: VK = serde_json::from_slice(&tree_vk)?;
+            let tree_params_data = params
+                .load(&params.tree_params, params.tree_params_hash.as_deref())
+                .await?;
             let tree_params = Parameters::read(&mut tree_params_data.as_slice(), true, true)?;
 
-            Groth16Params {
+            Some(Groth16Params {
                 tree_params,
                 tree_vk,
                 transfer_vk,
-            }
+            })
         };
 
         #[cfg(feature = "plonk")]
-        let plonk_params = {
-            let plonk_params_data = std::fs::read("params/plonk_params.bin")?;
+        let plonk_params = if config.mock_prover {
+            None
+        } else {
+            let params = &config.params;
+            let plonk_params_data = params
+                .load(&params.plonk_params, params.plonk_params_hash.as_deref())
+                .await?;
             let params = PlonkParameters::read(&mut plonk_params_data.as_slice())?;
 
             fn tree_circuit<C: CS<Fr = Fr>>(public: CTreePub<C>, secret: CTreeSec<C>) {
@@ -160,26 +262,467 @@ impl AppState {
             let (_, tree_pk) = setup(&params, tree_circuit);
             let (transfer_vk, _) = setup(&params, tx_circuit);
 
-            PlonkParams {
+            Some(PlonkParams {
                 tree_pk,
                 params,
                 transfer_vk,
-            }
+            })
         };
 
-        Ok(Self {
+        let (tx_events, _) = broadcast::channel(TX_EVENTS_CAPACITY);
+
+        let (tree_state_sender, optimistic_tree_state) =
+            watch::channel((tree.root()?, tree.num_leaves(), tree.supersede_counter()?));
+        tree.set_on_root_change(move |root, num_leaves, supersede_counter| {
+            // Fails only if there are no receivers left.
+            let _ = tree_state_sender.send((root, num_leaves, supersede_counter));
+        });
+
+        #[cfg(feature = "metrics")]
+        let metrics = crate::monitoring::install()?;
+
+        let standby = config.standby_primary_url.is_some();
+        let job_permits = config
+            .max_concurrent_jobs
+            .unwrap_or(Semaphore::MAX_PERMITS)
+            .min(Semaphore::MAX_PERMITS);
+        let job_permits = Arc::new(Semaphore::new(job_permits));
+
+        let settings = config.settings;
+        monitoring::set_runtime_settings(&settings);
+        let rate_limiter = Arc::new(RateLimiter::new(
+            settings.rate_limit_per_minute,
+            config.rate_limit_trust_forwarded_for,
+        ));
+
+        let state = Self {
             config,
             transactions,
+            failed_jobs,
+            audit,
             job_queue,
+            job_permits,
             backend,
-            tree: Mutex::new(tree),
+            chain_id,
+            tree: TimedMutex::new(tree),
+            sync_progress,
+            optimistic_tree_state,
             pool_index: RwLock::new(pool_index),
             pool_root: RwLock::new(pool_root),
-            fee,
+            settings: watch::channel(settings).0,
+            rate_limiter,
+            accepting: AtomicBool::new(!standby),
+            sending: AtomicBool::new(!standby),
+            standby: AtomicBool::new(standby),
+            instance_lock,
+            optimistic_log: OptimisticLog::default(),
+            tx_events,
+            #[cfg(feature = "metrics")]
+            metrics,
             #[cfg(feature = "groth16")]
             groth16_params,
             #[cfg(feature = "plonk")]
             plonk_params,
-        })
+        };
+
+        // TODO: Attempt rollback first and check the roots. Only reinitialize if the roots don't match.
+        if relayer_index != pool_index {
+            let tree = state.tree.lock("init").await;
+            if relayer_index > pool_index {
+                tracing::error!("Relayer state is corrupted. Reinitializing...");
+                state.audit.record(AuditEvent::Reinitialized {
+                    relayer_index,
+                    pool_index,
+                });
+
+                state.rebuild_from_chain(&tree).await?;
+            } else {
+                state.sync_tree_from_chain(&tree).await?;
+            }
+
+            tracing::info!(
+                "New relayer index: {}",
+                tree.num_leaves() * TX_INDEX_STRIDE as u64
+            );
+            tracing::info!("New relayer root: {}", tree.root()?);
+        }
+
+        Ok(state)
+    }
+
+    /// Discards the local tree and transaction storage and rebuilds them from the mined
+    /// transactions, like `init` does for a corrupted state. All jobs are cancelled first, and no
+    /// transactions are accepted or sent until done. Returns the new pool index and root.
+    pub async fn resync(&self) -> Result<(u64, Num<Fr>)> {
+        let accepting = self.accepting.swap(false, Ordering::SeqCst);
+        let sending = self.sending.swap(false, Ordering::SeqCst);
+
+        let result = self.rebuild().await;
+
+        self.accepting.store(accepting, Ordering::SeqCst);
+        self.sending.store(sending, Ordering::SeqCst);
+
+        result
+    }
+
+    async fn rebuild(&self) -> Result<(u64, Num<Fr>)> {
+        // Holding the tree lock prevents new jobs from being created.
+        let tree = self.tree.lock("resync").await;
+        let relayer_index = tree.num_leaves() * TX_INDEX_STRIDE as u64;
+        let pool_index = *self.pool_index.read().await;
+
+        tracing::warn!("Resyncing from relayer index {relayer_index}, pool index {pool_index}");
+
+        cancel_jobs_from(self, pool_index / TX_INDEX_STRIDE as u64, tree.num_leaves()).await?;
+        self.optimistic_log.truncate(0);
+
+        self.rebuild_from_chain(&tree).await?;
+
+        let pool_index = tree.num_leaves() * TX_INDEX_STRIDE as u64;
+        let root = tree.root()?;
+        *self.pool_index.write().await = pool_index;
+        *self.pool_root.write().await = root.0.into();
+
+        tracing::info!("Resynced to pool index {pool_index}, root {root}");
+        self.audit.record(AuditEvent::Reinitialized {
+            relayer_index,
+            pool_index,
+        });
+
+        Ok((pool_index, root))
+    }
+
+    pub fn settings(&self) -> RuntimeSettings {
+        *self.settings.borrow()
+    }
+
+    /// Applies new runtime settings to the running relayer. Requests that already passed the
+    /// rate limiter or the fee check are not affected. `source` is recorded in the audit log.
+    pub fn update_settings(&self, settings: RuntimeSettings, source: &str) -> Result<()> {
+        settings.validate()?;
+
+        // Compared and applied at once, so that concurrent updates can't both pass as changes or
+        // leave the rate limiter at the older one.
+        let changed = self.settings.send_if_modified(|current| {
+            if *current == settings {
+                return false;
+            }
+
+            self.rate_limiter
+                .set_per_minute(settings.rate_limit_per_minute);
+            monitoring::set_runtime_settings(&settings);
+            *current = settings;
+            true
+        });
+        if !changed {
+            return Ok(());
+        }
+
+        tracing::info!("Runtime settings changed ({source}): {settings:?}");
+        self.audit.record(AuditEvent::SettingsChanged {
+            settings,
+            source: source.to_owned(),
+        });
+
+        Ok(())
+    }
+
+    /// Fails with [`crate::instance_lock::LockLost`] if another instance might have taken over
+    /// the state. Checked by the worker before it changes the state.
+    pub async fn ensure_instance_lock(&self) -> Result<()> {
+        match &self.instance_lock {
+            Some(lock) => lock.ensure_held().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Appends the transactions mined after the last leaf of the tree to the tree and tx storage,
+    /// and advances the cached pool index and root to the synced state.
+    pub async fn sync_from_chain(&self) -> Result<()> {
+        let tree = self.tree.lock("sync_from_chain").await;
+        self.sync_tree_from_chain(&tree).await
+    }
+
+    /// Same as [`AppState::sync_from_chain`], for callers that already hold the tree lock.
+    pub(crate) async fn sync_tree_from_chain(&self, tree: &MerkleTree) -> Result<()> {
+        sync_from_chain(
+            self.backend.as_ref(),
+            tree,
+            &self.transactions,
+            &self.sync_progress,
+        )
+        .await?;
+
+        let synced_index = tree.num_leaves() * TX_INDEX_STRIDE as u64;
+        let mut pool_index = self.pool_index.write().await;
+        if *pool_index < synced_index {
+            *pool_index = synced_index;
+            *self.pool_root.write().await = tree.root()?.0.into();
+        }
+
+        Ok(())
+    }
+
+    /// Clears the tree and tx storage and fills them with all mined transactions.
+    pub(crate) async fn rebuild_from_chain(&self, tree: &MerkleTree) -> Result<()> {
+        self.transactions.rollback(0)?;
+        tree.rollback(0)?;
+
+        self.sync_tree_from_chain(tree).await
+    }
+}
+
+/// Appends the transactions mined after the last leaf of the tree to the tree and tx storage.
+/// The transactions are fetched and written in batches, so an interrupted sync continues from the
+/// last complete batch.
+pub(crate) async fn sync_from_chain(
+    backend: &dyn BlockchainBackend,
+    tree: &MerkleTree,
+    transactions: &TxStorage,
+    progress: &SyncProgress,
+) -> Result<()> {
+    let start = tree.num_leaves();
+    // Only an estimate for the progress, the chain can advance during the sync.
+    let total = (backend.get_pool_index().await? / TX_INDEX_STRIDE as u64).saturating_sub(start);
+    tracing::info!("Syncing about {total} transactions starting from {start}...");
+
+    progress.synced.store(0, Ordering::SeqCst);
+    progress.total.store(total, Ordering::SeqCst);
+
+    let started = Instant::now();
+    let mut synced = 0;
+    loop {
+        let next_leaf = tree.num_leaves();
+        let batch = backend
+            .fetch_latest_transactions(next_leaf, SYNC_BATCH_SIZE as u64)
+            .await?;
+        if batch.is_empty() {
+            break;
+        }
+
+        let mut commitments = Vec::with_capacity(batch.len());
+        for (i, tx) in batch.iter().enumerate() {
+            let tx_index = (next_leaf + i as u64) * TX_INDEX_STRIDE as u64;
+            let tx_data = backend.parse_calldata(tx.calldata.clone())?;
+
+            commitments.push(tx_data.out_commit);
+            transactions.set(
+                tx_index,
+                tx_data.out_commit,
+                &tx.hash,
+                backend.extract_ciphertext_from_memo(&tx_data.memo, tx_data.tx_type),
+            )?;
+        }
+
+        // The tree is the source of truth for the resync point: transactions are written first,
+        // so that they're already in place once the leaves are.
+        tree.add_leaves_at(next_leaf, commitments)?;
+
+        synced += batch.len() as u64;
+        progress.synced.store(synced, Ordering::SeqCst);
+        progress.total.fetch_max(synced, Ordering::SeqCst);
+
+        let elapsed = started.elapsed();
+        let eta = elapsed.mul_f64(total.saturating_sub(synced) as f64 / synced as f64);
+        tracing::info!(
+            "Synced {synced}/{total} transactions, ETA {}s",
+            eta.as_secs()
+        );
+
+        if batch.len() < SYNC_BATCH_SIZE {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::{PrimeField, Uint};
+    use scopeguard::defer;
+    use zeropool_tx::{TxData, TxType};
+
+    use super::*;
+    use crate::{
+        backend::{
+            mock::{mock_config, MockBackend},
+            TxConfirmation,
+        },
+        tx_worker::mock_proof,
+    };
+
+    fn mock_backend() -> MockBackend {
+        MockBackend::new(mock_config(None, vec![]))
+    }
+
+    /// Sends transactions to the pool as someone else would, updating `reference` accordingly.
+    async fn send_deposits(backend: &MockBackend, reference: &MerkleTree, count: u64) {
+        for _ in 0..count {
+            let i = reference.num_leaves() + 1;
+            let out_commit = Num::from(i);
+            reference.add_leaf(out_commit).unwrap();
+            let tx = TxData {
+                tx_type: TxType::Deposit,
+                delta: Num::ZERO,
+                token_id: String::new(),
+                out_commit,
+                nullifier: Num::from(i),
+                proof: mock_proof(),
+                root_after: reference.root().unwrap(),
+                tree_proof: mock_proof(),
+                memo: vec![0; 16],
+                extra_data: vec![],
+            };
+            let hash = backend.send_tx(tx).await.unwrap().hash;
+            // Mined in the background, the next one is only sent after it to keep the order.
+            while backend.get_tx_status(&hash).await.unwrap() == TxConfirmation::Pending {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+
+    async fn assert_synced(backend: &MockBackend, tree: &MerkleTree, transactions: &TxStorage) {
+        let pool_index = backend.get_pool_index().await.unwrap();
+        assert_eq!(tree.num_leaves() * TX_INDEX_STRIDE as u64, pool_index);
+        assert_eq!(
+            Some(tree.root().unwrap().to_uint().0),
+            backend.get_merkle_root(pool_index).await.unwrap()
+        );
+        assert_eq!(transactions.count().unwrap(), tree.num_leaves());
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_chain() {
+        const TREE_FILE: &str = "state_test_sync_from_chain_tree.persy";
+        const REFERENCE_TREE_FILE: &str = "state_test_sync_from_chain_reference.persy";
+        const TX_FILE: &str = "state_test_sync_from_chain_txs.persy";
+        defer! {
+            std::fs::remove_file(TREE_FILE).unwrap();
+            std::fs::remove_file(REFERENCE_TREE_FILE).unwrap();
+            std::fs::remove_file(TX_FILE).unwrap();
+        }
+
+        let backend = mock_backend();
+        let tree = MerkleTree::open(TREE_FILE).unwrap();
+        let reference = MerkleTree::open(REFERENCE_TREE_FILE).unwrap();
+        let transactions = TxStorage::open(TX_FILE).unwrap();
+        let progress = SyncProgress::default();
+
+        send_deposits(&backend, &reference, 1).await;
+        sync_from_chain(&backend, &tree, &transactions, &progress)
+            .await
+            .unwrap();
+        assert_eq!(tree.num_leaves(), 1);
+
+        send_deposits(&backend, &reference, 2).await;
+        sync_from_chain(&backend, &tree, &transactions, &progress)
+            .await
+            .unwrap();
+
+        assert_synced(&backend, &tree, &transactions).await;
+        for i in 0..3 {
+            assert!(transactions
+                .get(i * TX_INDEX_STRIDE as u64)
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_cleared_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+
+        let backend = mock_backend();
+        let tree = MerkleTree::open(&path("tree.persy")).unwrap();
+        let reference = MerkleTree::open(&path("reference.persy")).unwrap();
+        let transactions = TxStorage::open(&path("transactions.persy")).unwrap();
+        let progress = SyncProgress::default();
+
+        send_deposits(&backend, &reference, 3).await;
+        sync_from_chain(&backend, &tree, &transactions, &progress)
+            .await
+            .unwrap();
+
+        // Drifted optimistic state
+        for i in 3..5 {
+            tree.add_leaf(Num::from(100 + i)).unwrap();
+            transactions
+                .push(
+                    i * TX_INDEX_STRIDE as u64,
+                    Num::from(100 + i),
+                    &[0; 32],
+                    &[],
+                )
+                .unwrap();
+        }
+        transactions
+            .set(TX_INDEX_STRIDE as u64, Num::ZERO, &[0xff], &[])
+            .unwrap();
+
+        transactions.rollback(0).unwrap();
+        tree.rollback(0).unwrap();
+        sync_from_chain(&backend, &tree, &transactions, &progress)
+            .await
+            .unwrap();
+
+        assert_synced(&backend, &tree, &transactions).await;
+        assert_eq!(tree.root().unwrap(), reference.root().unwrap());
+        assert_eq!(
+            transactions.next_index().unwrap(),
+            3 * TX_INDEX_STRIDE as u64
+        );
+        assert_eq!(progress.total.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_chain_batches() {
+        const TREE_FILE: &str = "state_test_sync_batches_tree.persy";
+        const REFERENCE_TREE_FILE: &str = "state_test_sync_batches_reference.persy";
+        const TX_FILE: &str = "state_test_sync_batches_txs.persy";
+        defer! {
+            std::fs::remove_file(TREE_FILE).unwrap();
+            std::fs::remove_file(REFERENCE_TREE_FILE).unwrap();
+            std::fs::remove_file(TX_FILE).unwrap();
+        }
+
+        let backend = mock_backend();
+        let tree = MerkleTree::open(TREE_FILE).unwrap();
+        let reference = MerkleTree::open(REFERENCE_TREE_FILE).unwrap();
+        let transactions = TxStorage::open(TX_FILE).unwrap();
+        let progress = SyncProgress::default();
+
+        send_deposits(&backend, &reference, 250).await;
+        sync_from_chain(&backend, &tree, &transactions, &progress)
+            .await
+            .unwrap();
+
+        assert_synced(&backend, &tree, &transactions).await;
+        assert_eq!(progress.synced.load(Ordering::SeqCst), 250);
+        assert_eq!(progress.total.load(Ordering::SeqCst), 250);
+
+        // A restart continues from the last leaf of the tree instead of starting over. Leaves
+        // that are only in tx storage, e.g. from an interrupted batch, are overwritten.
+        send_deposits(&backend, &reference, 120).await;
+        transactions
+            .set(250 * TX_INDEX_STRIDE as u64, Num::ZERO, &[0xff], &[])
+            .unwrap();
+
+        sync_from_chain(&backend, &tree, &transactions, &progress)
+            .await
+            .unwrap();
+
+        assert_synced(&backend, &tree, &transactions).await;
+        assert_eq!(progress.synced.load(Ordering::SeqCst), 120);
+        assert_eq!(progress.total.load(Ordering::SeqCst), 120);
+        let record = transactions
+            .get(250 * TX_INDEX_STRIDE as u64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            record[..32],
+            Num::<Fr>::from(251).0.to_uint().to_big_endian()
+        );
     }
 }
@@ -0,0 +1,335 @@
+//! Portable snapshot of the local state, for moving a relayer to another host.
+//!
+//! The archive holds the tree leaves and the tx storage records, the internal nodes of the tree
+//! are recomputed on import. Layout: magic, version (u32 LE), sha256 of the body, bincode body.
+//!
+//! An import writes the new files next to the existing ones and creates a marker file once both
+//! are complete. The files are only replaced after that, and [`finish_import`] completes the
+//! replacement if the import is interrupted halfway through it.
+
+use std::{fs::File, path::Path};
+
+use anyhow::{bail, Result};
+use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{merkle_tree::MerkleTree, tx_storage::TxStorage, tx_worker::TX_SIZE, Fr};
+
+const MAGIC: &[u8; 8] = b"ZPRSTATE";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 4 + 32;
+/// Number of leaves added to the tree at once.
+const IMPORT_BATCH_SIZE: usize = 100;
+/// Size of the out commitment at the start of a tx storage record, followed by the tx hash.
+const OUT_COMMIT_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateArchive {
+    /// `BACKEND` of the exporting relayer, if it was set.
+    pub backend: Option<String>,
+    /// Pool index after the last transaction.
+    pub pool_index: u64,
+    pub root: Num<Fr>,
+    pub leaves: Vec<Num<Fr>>,
+    /// Raw tx storage records by pool index.
+    pub transactions: Vec<(u64, Vec<u8>)>,
+}
+
+impl StateArchive {
+    /// Reads the local state. Transactions that are not sent yet would be restored as if they
+    /// were, so this fails if there are any, unless `force` is set. `hash_size` is the size of
+    /// the backend's tx hashes, see [`HashEncoding::size`](crate::backend::HashEncoding::size).
+    pub fn export(
+        tree: &MerkleTree,
+        transactions: &TxStorage,
+        hash_size: usize,
+        force: bool,
+    ) -> Result<Self> {
+        let num_leaves = tree.num_leaves();
+        let pool_index = num_leaves * TX_SIZE;
+        if transactions.next_index()? != pool_index {
+            bail!(
+                "The tree has {num_leaves} leaves, but the next tx storage index is {}",
+                transactions.next_index()?
+            );
+        }
+
+        let leaves = (0..num_leaves)
+            .map(|index| tree.leaf(index))
+            .collect::<Result<Vec<_>>>()?;
+        let transactions = transactions.iter()?.collect::<Result<Vec<_>>>()?;
+
+        let pending = transactions
+            .iter()
+            .filter(|(_, record)| is_pending(record, hash_size))
+            .count();
+        if pending > 0 && !force {
+            bail!("{pending} transactions are not sent yet, pass --force to export anyway");
+        }
+
+        Ok(Self {
+            backend: std::env::var("BACKEND").ok(),
+            pool_index,
+            root: tree.root()?,
+            leaves,
+            transactions,
+        })
+    }
+
+    /// Rebuilds the tree and the tx storage. Both are written next to the given paths first and
+    /// only replace the existing files once the root matches the recorded one.
+    pub fn import(&self, tree_path: &str, tx_storage_path: &str) -> Result<()> {
+        finish_import(tree_path, tx_storage_path)?;
+
+        if self.leaves.len() as u64 * TX_SIZE != self.pool_index {
+            bail!(
+                "The archive has {} leaves, expected {}",
+                self.leaves.len(),
+                self.pool_index / TX_SIZE
+            );
+        }
+
+        let new_tree_path = format!("{tree_path}.import");
+        let new_tx_storage_path = format!("{tx_storage_path}.import");
+        for path in [&new_tree_path, &new_tx_storage_path] {
+            if Path::new(path).exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        let root = {
+            let tree = MerkleTree::open(&new_tree_path)?;
+            for (i, batch) in self.leaves.chunks(IMPORT_BATCH_SIZE).enumerate() {
+                let index = (i * IMPORT_BATCH_SIZE) as u64;
+                tree.add_leaves_at(index, batch.iter().copied())?;
+            }
+
+            let transactions = TxStorage::open(&new_tx_storage_path)?;
+            for (index, record) in &self.transactions {
+                transactions.set_raw(*index, record)?;
+            }
+
+            tree.root()?
+        };
+
+        if root != self.root {
+            std::fs::remove_file(&new_tree_path)?;
+            std::fs::remove_file(&new_tx_storage_path)?;
+            bail!(
+                "Rebuilt root {root} doesn't match the recorded root {}",
+                self.root
+            );
+        }
+
+        let marker = File::create(marker_path(tree_path))?;
+        marker.sync_all()?;
+        drop(marker);
+        finish_import(tree_path, tx_storage_path)?;
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let body = bincode::serialize(self)?;
+
+        let mut data = Vec::with_capacity(HEADER_SIZE + body.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&Sha256::digest(&body));
+        data.extend_from_slice(&body);
+        std::fs::write(path, data)?;
+
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        if data.len() < HEADER_SIZE || !data.starts_with(MAGIC) {
+            bail!("{path} is not a relayer state archive");
+        }
+
+        let (header, body) = data.split_at(HEADER_SIZE);
+        let version = u32::from_le_bytes(header[MAGIC.len()..MAGIC.len() + 4].try_into()?);
+        if version != VERSION {
+            bail!("Unsupported archive version {version}, expected {VERSION}");
+        }
+        if Sha256::digest(body).as_slice() != &header[MAGIC.len() + 4..] {
+            bail!("Checksum mismatch, {path} is corrupted");
+        }
+
+        Ok(bincode::deserialize(body)?)
+    }
+}
+
+/// Replaces the tree and the tx storage with the imported ones if an import got as far as
+/// creating its marker, a no-op otherwise. Returns whether there was an import to finish. Must
+/// run before either file is opened.
+pub fn finish_import(tree_path: &str, tx_storage_path: &str) -> Result<bool> {
+    let marker_path = marker_path(tree_path);
+    if !Path::new(&marker_path).exists() {
+        return Ok(false);
+    }
+
+    // Either rename may have happened before an interruption, the other is still to do.
+    for path in [tree_path, tx_storage_path] {
+        let new_path = format!("{path}.import");
+        if Path::new(&new_path).exists() {
+            std::fs::rename(&new_path, path)?;
+        }
+    }
+    std::fs::remove_file(&marker_path)?;
+
+    Ok(true)
+}
+
+fn marker_path(tree_path: &str) -> String {
+    format!("{tree_path}.import-complete")
+}
+
+/// Records of queued jobs have a zero tx hash until the transaction is sent.
+fn is_pending(record: &[u8], hash_size: usize) -> bool {
+    record
+        .get(OUT_COMMIT_SIZE..OUT_COMMIT_SIZE + hash_size)
+        .map_or(false, |hash| hash.iter().all(|&byte| byte == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tx hash size of the mock backend.
+    const HASH_SIZE: usize = 8;
+
+    fn path(dir: &tempfile::TempDir, name: &str) -> String {
+        dir.path().join(name).to_str().unwrap().to_owned()
+    }
+
+    fn fill(tree: &MerkleTree, transactions: &TxStorage, count: u64, pending: bool) {
+        for i in 0..count {
+            let index = tree.num_leaves();
+            let out_commit = Num::from(index + 1);
+            let tx_hash = if pending {
+                [0; HASH_SIZE]
+            } else {
+                [i as u8 + 1; HASH_SIZE]
+            };
+            tree.add_leaf(out_commit).unwrap();
+            transactions
+                .push(index * TX_SIZE, out_commit, &tx_hash, &[i as u8; 10])
+                .unwrap();
+        }
+    }
+
+    fn records(transactions: &TxStorage) -> Vec<(u64, Vec<u8>)> {
+        transactions.iter().unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_state_archive_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = MerkleTree::open(&path(&dir, "tree.persy")).unwrap();
+        let transactions = TxStorage::open(&path(&dir, "transactions.persy")).unwrap();
+        fill(&tree, &transactions, 250, false);
+
+        let archive_path = path(&dir, "state.bin");
+        StateArchive::export(&tree, &transactions, HASH_SIZE, false)
+            .unwrap()
+            .save(&archive_path)
+            .unwrap();
+
+        let new_tree_path = path(&dir, "new_tree.persy");
+        let new_tx_storage_path = path(&dir, "new_transactions.persy");
+        let archive = StateArchive::load(&archive_path).unwrap();
+        assert_eq!(archive.pool_index, 250 * TX_SIZE);
+        archive
+            .import(&new_tree_path, &new_tx_storage_path)
+            .unwrap();
+
+        let new_tree = MerkleTree::open(&new_tree_path).unwrap();
+        let new_transactions = TxStorage::open(&new_tx_storage_path).unwrap();
+        assert_eq!(new_tree.root().unwrap(), tree.root().unwrap());
+        assert_eq!(new_tree.num_leaves(), tree.num_leaves());
+        for num_leaves in 0..=250 {
+            assert_eq!(
+                new_tree.historic_root(num_leaves).unwrap(),
+                tree.historic_root(num_leaves).unwrap(),
+                "{num_leaves}"
+            );
+        }
+        assert_eq!(records(&new_transactions), records(&transactions));
+        assert_eq!(
+            new_transactions.next_index().unwrap(),
+            transactions.next_index().unwrap()
+        );
+        assert!(!Path::new(&format!("{new_tree_path}.import")).exists());
+        assert!(!Path::new(&marker_path(&new_tree_path)).exists());
+    }
+
+    #[test]
+    fn test_state_archive_interrupted_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree_path = path(&dir, "tree.persy");
+        let tx_storage_path = path(&dir, "transactions.persy");
+        for path in [&tree_path, &tx_storage_path] {
+            std::fs::write(path, b"old").unwrap();
+            std::fs::write(format!("{path}.import"), b"new").unwrap();
+        }
+
+        // Without the marker the imported files may be incomplete.
+        assert!(!finish_import(&tree_path, &tx_storage_path).unwrap());
+        assert_eq!(std::fs::read(&tree_path).unwrap(), b"old");
+
+        // Interrupted between the two renames
+        std::fs::write(marker_path(&tree_path), b"").unwrap();
+        std::fs::rename(format!("{tree_path}.import"), &tree_path).unwrap();
+
+        assert!(finish_import(&tree_path, &tx_storage_path).unwrap());
+        assert_eq!(std::fs::read(&tree_path).unwrap(), b"new");
+        assert_eq!(std::fs::read(&tx_storage_path).unwrap(), b"new");
+        assert!(!Path::new(&marker_path(&tree_path)).exists());
+        assert!(!finish_import(&tree_path, &tx_storage_path).unwrap());
+    }
+
+    #[test]
+    fn test_state_archive_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = MerkleTree::open(&path(&dir, "tree.persy")).unwrap();
+        let transactions = TxStorage::open(&path(&dir, "transactions.persy")).unwrap();
+        fill(&tree, &transactions, 3, false);
+        fill(&tree, &transactions, 1, true);
+
+        assert!(StateArchive::export(&tree, &transactions, HASH_SIZE, false).is_err());
+        let archive = StateArchive::export(&tree, &transactions, HASH_SIZE, true).unwrap();
+        assert_eq!(archive.leaves.len(), 4);
+    }
+
+    #[test]
+    fn test_state_archive_corrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = MerkleTree::open(&path(&dir, "tree.persy")).unwrap();
+        let transactions = TxStorage::open(&path(&dir, "transactions.persy")).unwrap();
+        fill(&tree, &transactions, 3, false);
+        let archive = StateArchive::export(&tree, &transactions, HASH_SIZE, false).unwrap();
+
+        let archive_path = path(&dir, "state.bin");
+        archive.save(&archive_path).unwrap();
+        let mut data = std::fs::read(&archive_path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        std::fs::write(&archive_path, data).unwrap();
+        assert!(StateArchive::load(&archive_path).is_err());
+
+        // A root mismatch leaves the existing files untouched.
+        let new_tree_path = path(&dir, "new_tree.persy");
+        let new_tx_storage_path = path(&dir, "new_transactions.persy");
+        let wrong_root = StateArchive {
+            root: Num::from(1),
+            ..archive
+        };
+        assert!(wrong_root
+            .import(&new_tree_path, &new_tx_storage_path)
+            .is_err());
+        assert!(!Path::new(&new_tree_path).exists());
+        assert!(!Path::new(&format!("{new_tree_path}.import")).exists());
+    }
+}
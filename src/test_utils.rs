@@ -0,0 +1,56 @@
+//! Helpers shared by the unit tests.
+
+use std::{net::SocketAddr, path::Path, sync::Arc};
+
+use axum::Router;
+use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+use zeropool_tx::TxType;
+
+use crate::{
+    backend::mock::MockBackend, config::Config, state::AppState, tx::ParsedTxData,
+    tx_worker::mock_proof,
+};
+
+/// Serves the router on a random local port in the background, returns the bound address.
+pub fn serve(router: Router) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>()),
+    );
+
+    addr
+}
+
+/// State of a relayer with the mock backend and the in-memory job queue, storing its files in
+/// `dir`. Neither the worker nor the background tasks are started.
+pub async fn app_state(
+    dir: &Path,
+    backend: Arc<MockBackend>,
+    configure: impl FnOnce(&mut Config),
+) -> Arc<AppState> {
+    let mut config = Config::mock(dir);
+    configure(&mut config);
+
+    let state = AppState::init_with_backend(config, backend, Default::default(), None)
+        .await
+        .unwrap();
+    Arc::new(state)
+}
+
+/// Zero fee deposit, valid with the mock prover. `seed` makes the nullifier and the out
+/// commitment unique.
+pub fn deposit(seed: u64) -> ParsedTxData {
+    ParsedTxData {
+        tx_type: TxType::Deposit,
+        proof: mock_proof(),
+        inputs: vec![],
+        delta: Num::ZERO,
+        out_commit: Num::from(1_000_000 + seed),
+        nullifier: Num::from(seed),
+        memo: vec![0; 8],
+        extra_data: vec![],
+    }
+}
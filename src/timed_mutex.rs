@@ -0,0 +1,205 @@
+//! Mutex that reports how long it's waited for and held, to see how much the tree lock serializes
+//! the relayer. Durations are recorded as metrics labelled by call site, and added up per request
+//! for the slow request log, see [`measure`].
+
+use std::{
+    cell::Cell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::monitoring;
+
+tokio::task_local! {
+    static LOCK_TIMES: Cell<LockTimes>;
+}
+
+/// Total time spent waiting for and holding locks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LockTimes {
+    pub wait: Duration,
+    pub hold: Duration,
+}
+
+pub struct TimedMutex<T> {
+    inner: Mutex<T>,
+    acquisitions: AtomicU64,
+}
+
+impl<T> TimedMutex<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            acquisitions: AtomicU64::new(0),
+        }
+    }
+
+    /// `site` labels the durations, e.g. the name of the calling function.
+    pub async fn lock(&self, site: &'static str) -> TimedMutexGuard<'_, T> {
+        let started = Instant::now();
+        let guard = self.inner.lock().await;
+        let acquired = Instant::now();
+
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        let wait = acquired - started;
+        monitoring::record_tree_lock_wait(site, wait);
+        add_lock_times(LockTimes {
+            wait,
+            hold: Duration::ZERO,
+        });
+
+        TimedMutexGuard {
+            guard,
+            site,
+            acquired,
+        }
+    }
+
+    /// Number of times the lock has been acquired.
+    pub fn acquisitions(&self) -> u64 {
+        self.acquisitions.load(Ordering::Relaxed)
+    }
+}
+
+pub struct TimedMutexGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    site: &'static str,
+    acquired: Instant,
+}
+
+impl<T> Deref for TimedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for TimedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for TimedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let hold = self.acquired.elapsed();
+        monitoring::record_tree_lock_hold(self.site, hold);
+        add_lock_times(LockTimes {
+            wait: Duration::ZERO,
+            hold,
+        });
+    }
+}
+
+fn add_lock_times(times: LockTimes) {
+    // Outside of `measure`, only the metrics are recorded.
+    let _ = LOCK_TIMES.try_with(|total| {
+        let mut sum = total.get();
+        sum.wait += times.wait;
+        sum.hold += times.hold;
+        total.set(sum);
+    });
+}
+
+/// Runs the future and adds up the time it has spent on the locks of all `TimedMutex`es.
+pub async fn measure<F: Future>(f: F) -> (F::Output, LockTimes) {
+    LOCK_TIMES
+        .scope(Cell::new(LockTimes::default()), async {
+            let output = f.await;
+            (output, LOCK_TIMES.with(Cell::get))
+        })
+        .await
+}
+
+/// Logs a warning for requests that take longer than the threshold (`SLOW_REQUEST_THRESHOLD_MS`),
+/// with the time they have spent on the tree lock.
+pub async fn slow_request_middleware<B>(
+    State(threshold): State<Duration>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let method = req.method().clone();
+    let route = match req.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_owned(),
+        None => req.uri().path().to_owned(),
+    };
+
+    let started = Instant::now();
+    let (response, lock_times) = measure(next.run(req)).await;
+    let elapsed = started.elapsed();
+
+    if elapsed >= threshold {
+        tracing::warn!(
+            "Slow request: {method} {route} took {elapsed:?}, tree lock wait {:?}, hold {:?}",
+            lock_times.wait,
+            lock_times.hold
+        );
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timed_mutex() {
+        let mutex = Arc::new(TimedMutex::new(0));
+
+        let ((), times) = measure(async {
+            *mutex.lock("test").await += 1;
+        })
+        .await;
+        assert_eq!(mutex.acquisitions(), 1);
+        assert_eq!(*mutex.lock("test").await, 1);
+        assert_eq!(mutex.acquisitions(), 2);
+        assert!(times.hold < Duration::from_secs(1));
+
+        // Waiting for another holder
+        let guard = mutex.lock("test").await;
+        let waiter = tokio::spawn({
+            let mutex = mutex.clone();
+            measure(async move {
+                let started = Instant::now();
+                drop(mutex.lock("test").await);
+                started.elapsed()
+            })
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(guard);
+
+        let (elapsed, times) = waiter.await.unwrap();
+        assert!(times.wait >= Duration::from_millis(50));
+        assert!(times.wait <= elapsed);
+        assert_eq!(mutex.acquisitions(), 4);
+
+        // Not measured outside of `measure`
+        assert_eq!(LOCK_TIMES.try_with(Cell::get).ok(), None);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_timed_mutex_metrics() {
+        let handle = monitoring::install().unwrap();
+        let mutex = TimedMutex::new(());
+        drop(mutex.lock("prepare_job").await);
+
+        let metrics = handle.render();
+        assert!(metrics.contains("relayer_tree_lock_wait_seconds_bucket{site=\"prepare_job\""));
+        assert!(metrics.contains("relayer_tree_lock_hold_seconds_bucket{site=\"prepare_job\""));
+    }
+}
@@ -1,17 +1,39 @@
+use std::{fmt, str::FromStr};
+
 #[cfg(feature = "groth16")]
 use libzeropool_rs::libzeropool::fawkes_crypto::backend::bellman_groth16::group::{
     G1Point, G2Point,
 };
 use libzeropool_rs::libzeropool::fawkes_crypto::ff_uint::Num;
 use serde::{Deserialize, Serialize};
-use zeropool_tx::{proof::Proof as _, TxType};
+use zeropool_tx::{proof::Proof as _, TxData, TxType};
 
 use crate::{Fr, Proof};
 
 #[derive(Serialize, Deserialize)]
 pub struct ProofWithInputs {
     pub proof: Proof,
-    pub inputs: Vec<Num<Fr>>,
+    pub inputs: Vec<ProofInput>,
+}
+
+/// Public input as sent by the client, a field element in decimal. Kept unparsed until
+/// validation, so that non-canonical encodings are rejected rather than reduced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProofInput(pub String);
+
+impl ProofInput {
+    /// Returns `None` unless the input is the canonical encoding of a field element.
+    pub fn parse(&self) -> Option<Num<Fr>> {
+        let num = Num::<Fr>::from_str(&self.0).ok()?;
+        (num.to_string() == self.0).then_some(num)
+    }
+}
+
+impl From<Num<Fr>> for ProofInput {
+    fn from(num: Num<Fr>) -> Self {
+        Self(num.to_string())
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, thiserror::Error)]
@@ -29,6 +51,55 @@ pub enum TxValidationError {
     InvalidValues,
     #[error("Invalid tx index")]
     InvalidTxIndex,
+    #[error("Expired tx index")]
+    ExpiredTxIndex,
+    #[error("Root mismatch")]
+    RootMismatch,
+    #[error("Invalid withdraw address")]
+    InvalidWithdrawAddress,
+    #[error("Invalid deposit signature")]
+    InvalidSignature,
+    #[error("Wrong pool id: expected {expected}, got {got}")]
+    WrongPoolId { expected: u64, got: u64 },
+    #[error("Malformed proof inputs: {0}")]
+    MalformedProofInputs(MalformedInputs),
+    #[error("Memo is longer than {max} bytes")]
+    MemoTooLong { max: usize },
+    #[error("Extra data is longer than {max} bytes")]
+    ExtraDataTooLong { max: usize },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, thiserror::Error)]
+#[serde(rename_all = "snake_case")]
+pub enum MalformedInputs {
+    #[error("expected {expected} public inputs, got {got}")]
+    WrongCount { expected: usize, got: usize },
+    #[error("input {index} is not a canonical field element")]
+    NonCanonical { index: usize },
+}
+
+impl TxValidationError {
+    /// Machine-readable code, the snake_case name of the variant.
+    pub fn code(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(code)) => code,
+            Ok(serde_json::Value::Object(fields)) => {
+                fields.into_iter().next().unwrap_or_default().0
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+/// A committed transaction, broadcast to `/ws/transactions` subscribers.
+///
+/// `data` uses the same encoding as the `/transactions` endpoint: out commitment, tx hash and
+/// ciphertext.
+#[derive(Debug, Clone, Serialize)]
+pub struct TxEvent {
+    pub index: u64,
+    #[serde(with = "hex")]
+    pub data: Vec<u8>,
 }
 
 /// Intermediate transaction data ready to be sent to the worker.
@@ -36,6 +107,8 @@ pub enum TxValidationError {
 pub struct ParsedTxData {
     pub tx_type: TxType,
     pub proof: Proof,
+    /// Public inputs of the transfer proof, kept for re-validation of archived jobs.
+    pub inputs: Vec<Num<Fr>>,
     pub delta: Num<Fr>,
     pub out_commit: Num<Fr>,
     pub nullifier: Num<Fr>,
@@ -48,6 +121,7 @@ impl Clone for ParsedTxData {
         Self {
             tx_type: self.tx_type,
             proof: self.proof.my_clone(),
+            inputs: self.inputs.clone(),
             delta: self.delta.clone(),
             out_commit: self.out_commit.clone(),
             nullifier: self.nullifier.clone(),
@@ -56,3 +130,55 @@ impl Clone for ParsedTxData {
         }
     }
 }
+
+/// Human-readable [`TxData`] for logs, one field per line. The proofs are omitted.
+pub struct DisplayTx<'a>(pub &'a TxData<Fr, Proof>);
+
+impl fmt::Display for DisplayTx<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tx = self.0;
+
+        writeln!(f, "    tx_type: {:?}", tx.tx_type)?;
+        writeln!(f, "    delta: {}", tx.delta)?;
+        writeln!(f, "    token_id: {}", tx.token_id)?;
+        writeln!(f, "    out_commit: {}", tx.out_commit)?;
+        writeln!(f, "    nullifier: {}", tx.nullifier)?;
+        writeln!(f, "    root_after: {}", tx.root_after)?;
+        writeln!(f, "    memo: {}", hex::encode(&tx.memo))?;
+        write!(f, "    extra_data: {}", hex::encode(&tx.extra_data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx_worker::mock_proof;
+
+    #[test]
+    fn test_display_tx() {
+        let tx = TxData {
+            tx_type: TxType::Withdraw,
+            delta: Num::from(1),
+            token_id: "token".to_owned(),
+            out_commit: Num::from(2),
+            nullifier: Num::from(3),
+            proof: mock_proof(),
+            root_after: Num::from(4),
+            tree_proof: mock_proof(),
+            memo: vec![0xab, 0xcd],
+            extra_data: vec![],
+        };
+
+        assert_eq!(
+            DisplayTx(&tx).to_string(),
+            "    tx_type: Withdraw
+    delta: 1
+    token_id: token
+    out_commit: 2
+    nullifier: 3
+    root_after: 4
+    memo: abcd
+    extra_data: "
+        );
+    }
+}
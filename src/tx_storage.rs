@@ -1,41 +1,243 @@
-use std::ops::RangeBounds;
+use std::{
+    ops::{Range, RangeBounds},
+    sync::{RwLock, RwLockReadGuard},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use libzeropool_rs::libzeropool::{
     constants,
     fawkes_crypto::ff_uint::{Num, PrimeField, Uint},
 };
-use persy::{Persy, PersyId, ValueMode};
+use persy::{ByteVec, Persy, PersyId, Transaction, ValueMode};
+use serde::Serialize;
 
-use crate::Fr;
+use crate::{ciphertext, Fr};
 
 pub type Index = u64;
 
 const STRIDE: u64 = constants::OUT as u64 + 1;
+pub const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Fee collected by a sent transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FeeRecord {
+    pub index: Index,
+    /// Unix timestamp in seconds of when the transaction was stored as sent.
+    pub timestamp: u64,
+    pub fee: u64,
+}
+
+impl FeeRecord {
+    fn encode(&self) -> ByteVec {
+        [self.timestamp.to_be_bytes(), self.fee.to_be_bytes()]
+            .concat()
+            .into()
+    }
+
+    fn decode(index: Index, data: &[u8]) -> Result<Self> {
+        if data.len() != 16 {
+            anyhow::bail!("Invalid fee record at index {index}");
+        }
+
+        Ok(Self {
+            index,
+            timestamp: u64::from_be_bytes(data[..8].try_into()?),
+            fee: u64::from_be_bytes(data[8..].try_into()?),
+        })
+    }
+
+    /// Key in the `fee_times` index, which orders the records by timestamp, then by index.
+    fn time_key(&self) -> u128 {
+        ((self.timestamp as u128) << 64) | self.index as u128
+    }
+
+    /// Start of the UTC day of the timestamp.
+    fn day(&self) -> u64 {
+        self.timestamp - self.timestamp % SECONDS_PER_DAY
+    }
+}
 
 pub struct TxStorage {
-    db: Persy,
+    /// Replaced with a fresh file on compaction.
+    db: RwLock<Persy>,
+    path: String,
 }
 
 impl TxStorage {
     pub fn open(path: &str) -> Result<Self> {
+        Ok(Self {
+            db: RwLock::new(Self::open_db(path)?),
+            path: path.to_owned(),
+        })
+    }
+
+    fn open_db(path: &str) -> Result<Persy> {
         let db = Persy::open_or_create_with(path, Default::default(), |db| {
             let mut tx = db.begin()?;
             tx.create_segment("data")?;
             tx.create_index::<Index, PersyId>("keys", ValueMode::Replace)?;
             tx.create_index::<String, u64>("meta", ValueMode::Replace)?;
             tx.put("meta", "next_index".to_owned(), 0u64)?;
+            tx.put("meta", "ciphertexts_indexed".to_owned(), 1u64)?;
             tx.prepare()?.commit()?;
 
             Ok(())
         })?;
 
-        Ok(Self { db })
+        // Added later, filled by `index_ciphertexts`
+        if !db.exists_index("ciphertexts")? {
+            let mut tx = db.begin()?;
+            tx.create_index::<Index, ByteVec>("ciphertexts", ValueMode::Replace)?;
+            tx.prepare()?.commit()?;
+        }
+
+        // Added later, fees are only recorded from then on
+        if !db.exists_index("fees")? {
+            let mut tx = db.begin()?;
+            tx.create_index::<Index, ByteVec>("fees", ValueMode::Replace)?;
+            tx.create_index::<u64, u64>("fee_days", ValueMode::Replace)?;
+            tx.put("meta", "fees_total".to_owned(), 0u64)?;
+            tx.prepare()?.commit()?;
+        }
+
+        // Added later, filled from the fees index
+        if !db.exists_index("fee_times")? {
+            let mut tx = db.begin()?;
+            tx.create_index::<u128, u64>("fee_times", ValueMode::Replace)?;
+            for record in Self::fee_records(&db, ..)? {
+                let record = record?;
+                tx.put::<u128, u64>("fee_times", record.time_key(), record.fee)?;
+            }
+            tx.prepare()?.commit()?;
+        }
+
+        Ok(db)
+    }
+
+    /// Adds the fee to the totals of its day and the lifetime total, or subtracts it if
+    /// `remove` is set.
+    fn update_fee_totals(tx: &mut Transaction, record: &FeeRecord, remove: bool) -> Result<()> {
+        let apply = |total: u64| {
+            if remove {
+                total.checked_sub(record.fee)
+            } else {
+                total.checked_add(record.fee)
+            }
+            .ok_or_else(|| anyhow!("Fee total out of range at index {}", record.index))
+        };
+
+        let day = record.day();
+        let day_total = apply(tx.one::<u64, u64>("fee_days", &day)?.unwrap_or(0))?;
+        if day_total == 0 {
+            tx.remove::<u64, u64>("fee_days", day, None)?;
+        } else {
+            tx.put::<u64, u64>("fee_days", day, day_total)?;
+        }
+
+        let total = tx
+            .one::<String, u64>("meta", &"fees_total".to_owned())?
+            .unwrap_or(0);
+        tx.put("meta", "fees_total".to_owned(), apply(total)?)?;
+
+        Ok(())
+    }
+
+    /// Removes the fee recorded for `index`, if any, and subtracts it from the totals.
+    fn remove_fee(tx: &mut Transaction, index: Index) -> Result<()> {
+        if let Some(data) = tx.one::<Index, ByteVec>("fees", &index)? {
+            let record = FeeRecord::decode(index, &data)?;
+            tx.remove::<Index, ByteVec>("fees", index, None)?;
+            tx.remove::<u128, u64>("fee_times", record.time_key(), None)?;
+            Self::update_fee_totals(tx, &record, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the fee recorded for `record.index`, so that storing a transaction twice doesn't
+    /// count its fee twice.
+    fn put_fee(tx: &mut Transaction, record: &FeeRecord) -> Result<()> {
+        Self::remove_fee(tx, record.index)?;
+        tx.put::<Index, ByteVec>("fees", record.index, record.encode())?;
+        tx.put::<u128, u64>("fee_times", record.time_key(), record.fee)?;
+        Self::update_fee_totals(tx, record, false)
+    }
+
+    /// Indexes the output ciphertext prefixes of a transaction, see [`ciphertext::output_prefixes`].
+    /// Transactions with an unknown ciphertext layout are left out.
+    fn put_ciphertexts(tx: &mut Transaction, index: Index, ciphertext: &[u8]) -> Result<()> {
+        let Some(prefixes) = ciphertext::output_prefixes(ciphertext) else {
+            tracing::debug!("Unexpected ciphertext layout at index {index}, not indexing it");
+            tx.remove::<Index, ByteVec>("ciphertexts", index, None)?;
+            return Ok(());
+        };
+
+        tx.put::<Index, ByteVec>("ciphertexts", index, prefixes.concat().into())?;
+
+        Ok(())
+    }
+
+    fn put_record_ciphertexts(tx: &mut Transaction, index: Index, record: &[u8]) -> Result<()> {
+        let ciphertext = ciphertext::find_in_record(record).unwrap_or_default();
+        Self::put_ciphertexts(tx, index, ciphertext)
+    }
+
+    /// Stores the record at `index`, deleting the one it replaces.
+    fn put_data(tx: &mut Transaction, index: Index, data: &[u8]) -> Result<()> {
+        if let Some(old_id) = tx.one::<Index, PersyId>("keys", &index)? {
+            tx.delete("data", &old_id)?;
+        }
+
+        let id = tx.insert("data", data)?;
+        tx.put::<Index, PersyId>("keys", index, id)?;
+
+        Ok(())
+    }
+
+    fn db(&self) -> Persy {
+        self.db.read().unwrap().clone()
+    }
+
+    /// Held by writes until they are committed, so that a concurrent compaction can't copy the
+    /// file without them.
+    fn db_for_write(&self) -> RwLockReadGuard<'_, Persy> {
+        self.db.read().unwrap()
+    }
+
+    fn read_next_index(db: &Persy) -> Result<Index> {
+        Ok(db
+            .one::<String, Index>("meta", &"next_index".to_string())?
+            .unwrap_or(0))
     }
 
-    pub fn clear_and_open(path: &str) -> Result<Self> {
-        std::fs::remove_file(&path)?;
-        Self::open(path)
+    /// Records in `range`, read from `db` rather than the current file.
+    fn records<R>(db: Persy, range: R) -> Result<impl Iterator<Item = Result<(Index, Vec<u8>)>>>
+    where
+        R: RangeBounds<Index>,
+    {
+        let indices = db.range::<Index, PersyId, _>("keys", range)?;
+
+        let iter = indices.map(move |(index, mut id)| {
+            let id = id.next().unwrap();
+            let data = db
+                .read("data", &id)?
+                .ok_or_else(|| anyhow!("Missing record at index {index}"))?;
+
+            Ok((index, data))
+        });
+
+        Ok(iter)
+    }
+
+    fn fee_records<R>(db: &Persy, range: R) -> Result<impl Iterator<Item = Result<FeeRecord>>>
+    where
+        R: RangeBounds<Index>,
+    {
+        let records = db.range::<Index, ByteVec, _>("fees", range)?;
+        let iter =
+            records.filter_map(|(index, mut data)| Some(FeeRecord::decode(index, &data.next()?)));
+
+        Ok(iter)
     }
 
     pub fn set(
@@ -44,12 +246,32 @@ impl TxStorage {
         out_commit: Num<Fr>,
         tx_hash: &[u8],
         memo: &[u8],
+    ) -> Result<()> {
+        self.set_with_fee(index, out_commit, tx_hash, memo, None)
+    }
+
+    /// Same as [`TxStorage::set`], also accounting the fee of a transaction sent by this relayer
+    /// in the same storage transaction.
+    pub fn set_with_fee(
+        &self,
+        index: Index,
+        out_commit: Num<Fr>,
+        tx_hash: &[u8],
+        memo: &[u8],
+        fee: Option<FeeRecord>,
     ) -> Result<()> {
         if index % STRIDE != 0 {
             anyhow::bail!("Index must be in steps of {STRIDE}")
         }
 
-        let mut tx = self.db.begin()?;
+        let db = self.db_for_write();
+        let mut tx = db.begin()?;
+        if let Some(fee) = &fee {
+            if fee.index != index {
+                anyhow::bail!("Fee record for {} stored at {index}", fee.index);
+            }
+            Self::put_fee(&mut tx, fee)?;
+        }
 
         let mut buf =
             Vec::with_capacity(std::mem::size_of_val(&out_commit) + tx_hash.len() + memo.len());
@@ -57,8 +279,8 @@ impl TxStorage {
         buf.extend_from_slice(tx_hash);
         buf.extend_from_slice(memo);
 
-        let id = tx.insert("data", &buf)?;
-        tx.put::<Index, PersyId>("keys", index, id)?;
+        Self::put_data(&mut tx, index, &buf)?;
+        Self::put_ciphertexts(&mut tx, index, memo)?;
 
         tx.put("meta", "next_index".to_owned(), index + STRIDE)?;
 
@@ -67,6 +289,27 @@ impl TxStorage {
         Ok(())
     }
 
+    /// Stores a record in the format returned by [`TxStorage::get`], e.g. one copied from
+    /// another relayer. Replacing a record doesn't remove the ones after it.
+    pub fn set_raw(&self, index: Index, data: &[u8]) -> Result<()> {
+        if index % STRIDE != 0 {
+            anyhow::bail!("Index must be in steps of {STRIDE}")
+        }
+
+        let db = self.db_for_write();
+        let next_index = Self::read_next_index(&db)?.max(index + STRIDE);
+        let mut tx = db.begin()?;
+
+        Self::put_data(&mut tx, index, data)?;
+        Self::put_record_ciphertexts(&mut tx, index, data)?;
+
+        tx.put("meta", "next_index".to_owned(), next_index)?;
+
+        tx.prepare()?.commit()?;
+
+        Ok(())
+    }
+
     pub fn push(
         &self,
         index: Index,
@@ -74,7 +317,8 @@ impl TxStorage {
         tx_hash: &[u8],
         memo: &[u8],
     ) -> Result<()> {
-        let next_index = self.next_index()?;
+        let db = self.db_for_write();
+        let next_index = Self::read_next_index(&db)?;
 
         if index > next_index {
             return Err(anyhow::anyhow!(
@@ -84,7 +328,7 @@ impl TxStorage {
             ));
         }
 
-        let mut tx = self.db.begin()?;
+        let mut tx = db.begin()?;
 
         let mut buf =
             Vec::with_capacity(std::mem::size_of_val(&out_commit) + tx_hash.len() + memo.len());
@@ -92,8 +336,8 @@ impl TxStorage {
         buf.extend_from_slice(tx_hash);
         buf.extend_from_slice(memo);
 
-        let id = tx.insert("data", &buf)?;
-        tx.put::<Index, PersyId>("keys", index, id)?;
+        Self::put_data(&mut tx, index, &buf)?;
+        Self::put_ciphertexts(&mut tx, index, memo)?;
 
         tx.put("meta", "next_index".to_owned(), index + STRIDE)?;
 
@@ -103,47 +347,183 @@ impl TxStorage {
     }
 
     pub fn get(&self, index: Index) -> Result<Option<Vec<u8>>> {
-        let Some(id) = self.db.one("keys", &index)? else {
+        let db = self.db();
+        let Some(id) = db.one("keys", &index)? else {
             return Ok(None);
         };
 
-        Ok(self.db.read("data", &id)?)
+        Ok(db.read("data", &id)?)
     }
 
-    /// Remove all transactions with indices >= `index`.
-    pub fn rollback(&self, index: Index) -> Result<()> {
+    /// Remove all transactions with indices >= `index`. Returns the number of removed records.
+    ///
+    /// The new `next_index` is derived from the remaining records rather than from `index`.
+    pub fn rollback(&self, index: Index) -> Result<u64> {
         if index % STRIDE != 0 {
             anyhow::bail!("Index must be in steps of {STRIDE}")
         }
 
-        let indices = self.db.range::<Index, PersyId, _>("keys", index..)?;
+        let db = self.db_for_write();
+        let next_index = Self::read_next_index(&db)?;
+        if index > next_index {
+            anyhow::bail!("Cannot rollback to {index}, next index is {next_index}");
+        }
 
-        let mut tx = self.db.begin()?;
+        let indices = db.range::<Index, PersyId, _>("keys", index..)?;
 
+        let mut tx = db.begin()?;
+
+        let mut removed = 0;
         for (index, mut id) in indices {
             let id = id.next().unwrap();
             tx.remove::<Index, PersyId>("keys", index, None)?;
+            tx.remove::<Index, ByteVec>("ciphertexts", index, None)?;
+            Self::remove_fee(&mut tx, index)?;
             tx.delete("data", &id)?;
+            removed += 1;
         }
 
-        tx.put("meta", "next_index".to_owned(), index)?;
+        let new_next_index = db
+            .range::<Index, PersyId, _>("keys", ..index)?
+            .next_back()
+            .map(|(last_index, _)| last_index + STRIDE)
+            .unwrap_or(0);
+
+        tx.put("meta", "next_index".to_owned(), new_next_index)?;
 
         tx.prepare()?.commit()?;
 
-        Ok(())
+        Ok(removed)
     }
 
     pub fn next_index(&self) -> Result<Index> {
-        Ok(self
-            .db
-            .one::<String, Index>("meta", &"next_index".to_string())
-            .map(|val| val.unwrap_or(0))?)
+        Self::read_next_index(&self.db())
     }
 
     pub fn len(&self) -> Result<Index> {
         self.next_index()
     }
 
+    /// Number of stored transactions.
+    pub fn count(&self) -> Result<u64> {
+        Ok(self.next_index()? / STRIDE)
+    }
+
+    pub fn file_size(&self) -> Result<u64> {
+        Ok(std::fs::metadata(&self.path)?.len())
+    }
+
+    /// Copies all live records into a fresh file and atomically replaces the current file with it.
+    /// Persy never shrinks its files, so this is the only way to reclaim space after rollbacks.
+    /// Writes wait until it's done.
+    pub fn compact(&self) -> Result<()> {
+        let mut db = self.db.write().unwrap();
+
+        let tmp_path = format!("{}.compact", self.path);
+        if std::path::Path::new(&tmp_path).exists() {
+            std::fs::remove_file(&tmp_path)?;
+        }
+
+        let compacted = Self::open_db(&tmp_path)?;
+        let mut tx = compacted.begin()?;
+
+        for res in Self::records(db.clone(), ..)? {
+            let (index, data) = res?;
+            Self::put_data(&mut tx, index, &data)?;
+            Self::put_record_ciphertexts(&mut tx, index, &data)?;
+        }
+
+        for record in Self::fee_records(&db, ..)? {
+            Self::put_fee(&mut tx, &record?)?;
+        }
+
+        tx.put("meta", "next_index".to_owned(), Self::read_next_index(&db)?)?;
+        tx.prepare()?.commit()?;
+        drop(compacted);
+
+        std::fs::rename(&tmp_path, &self.path)?;
+        *db = Self::open_db(&self.path)?;
+
+        Ok(())
+    }
+
+    /// Indexes the ciphertexts of the records stored before the index existed. Does nothing once
+    /// done. Returns the number of indexed records.
+    pub fn index_ciphertexts(&self) -> Result<u64> {
+        let db = self.db_for_write();
+        let indexed = db.one::<String, u64>("meta", &"ciphertexts_indexed".to_owned())?;
+        if indexed == Some(1) {
+            return Ok(0);
+        }
+
+        let mut tx = db.begin()?;
+        let mut count = 0;
+        for res in Self::records(db.clone(), ..)? {
+            let (index, data) = res?;
+            Self::put_record_ciphertexts(&mut tx, index, &data)?;
+            count += 1;
+        }
+
+        tx.put("meta", "ciphertexts_indexed".to_owned(), 1u64)?;
+        tx.prepare()?.commit()?;
+
+        Ok(count)
+    }
+
+    /// Output ciphertext prefixes with their commitment indices, for the transactions in `range`.
+    pub fn ciphertexts_range<R>(&self, range: R) -> Result<impl Iterator<Item = (Index, Vec<u8>)>>
+    where
+        R: RangeBounds<Index>,
+    {
+        let prefixes = self.db().range::<Index, ByteVec, _>("ciphertexts", range)?;
+
+        let iter = prefixes.flat_map(|(index, mut prefixes)| {
+            prefixes
+                .next()
+                .unwrap()
+                .chunks(ciphertext::PREFIX_SIZE)
+                .enumerate()
+                .map(|(i, prefix)| (index + i as Index, prefix.to_vec()))
+                .collect::<Vec<_>>()
+        });
+
+        Ok(iter)
+    }
+
+    /// Fee records with a timestamp in `times`, ordered by timestamp.
+    pub fn fees_between(&self, times: Range<u64>) -> Result<Vec<FeeRecord>> {
+        let range = ((times.start as u128) << 64)..((times.end as u128) << 64);
+        Ok(self
+            .db()
+            .range::<u128, u64, _>("fee_times", range)?
+            .filter_map(|(key, mut fee)| {
+                Some(FeeRecord {
+                    index: key as u64,
+                    timestamp: (key >> 64) as u64,
+                    fee: fee.next()?,
+                })
+            })
+            .collect())
+    }
+
+    /// Total fees per UTC day for the days starting in `days`, as `(start of day, total)`. Days
+    /// without fees are left out.
+    pub fn fee_days(&self, days: Range<u64>) -> Result<Vec<(u64, u64)>> {
+        Ok(self
+            .db()
+            .range::<u64, u64, _>("fee_days", days)?
+            .filter_map(|(day, mut total)| Some((day, total.next()?)))
+            .collect())
+    }
+
+    /// Fees of all transactions currently in the storage.
+    pub fn fees_total(&self) -> Result<u64> {
+        Ok(self
+            .db()
+            .one::<String, u64>("meta", &"fees_total".to_owned())?
+            .unwrap_or(0))
+    }
+
     pub fn iter<'a>(&'a self) -> Result<impl Iterator<Item = Result<(u64, Vec<u8>)>> + 'a> {
         self.iter_range(..)
     }
@@ -153,18 +533,9 @@ impl TxStorage {
         range: R,
     ) -> Result<impl Iterator<Item = Result<(Index, Vec<u8>)>> + 'a>
     where
-        R: RangeBounds<Index>,
+        R: RangeBounds<Index> + 'a,
     {
-        let indices = self.db.range::<Index, PersyId, _>("keys", range)?;
-
-        let iter = indices.map(|(index, mut id)| {
-            let id = id.next().unwrap();
-            let data = self.db.read("data", &id)?.unwrap();
-
-            Ok((index, data))
-        });
-
-        Ok(iter)
+        Self::records(self.db(), range)
     }
 }
 
@@ -173,6 +544,7 @@ mod tests {
     use scopeguard::defer;
 
     use super::*;
+    use crate::ciphertext::{test_ciphertext, PREFIX_SIZE};
 
     #[test]
     fn test_tx_storage_push() {
@@ -197,4 +569,273 @@ mod tests {
         let res = storage.set(STRIDE * 2, Num::ZERO, &[0, 1, 2], &[3, 4, 5]);
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn test_tx_storage_set_raw() {
+        const FILE_NAME: &str = "tx_storage_test_set_raw.persy";
+        let storage = TxStorage::open(FILE_NAME).unwrap();
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        storage.push(0, Num::ZERO, &[0, 1, 2], &[3, 4, 5]).unwrap();
+        let data = storage.get(0).unwrap().unwrap();
+
+        assert!(storage.set_raw(1, &data).is_err());
+        storage.set_raw(STRIDE, &data).unwrap();
+        assert_eq!(storage.get(STRIDE).unwrap(), Some(data));
+        assert_eq!(storage.next_index().unwrap(), STRIDE * 2);
+
+        // The replaced records are deleted
+        storage.set_raw(STRIDE, &data).unwrap();
+        storage.set(0, Num::ZERO, &[6, 7, 8], &[3, 4, 5]).unwrap();
+        assert_eq!(storage.db().scan("data").unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_tx_storage_locked() {
+        // A second relayer started against the same files
+        const FILE_NAME: &str = "tx_storage_test_locked.persy";
+        let storage = TxStorage::open(FILE_NAME).unwrap();
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        assert!(TxStorage::open(FILE_NAME).is_err());
+
+        drop(storage);
+        assert!(TxStorage::open(FILE_NAME).is_ok());
+    }
+
+    #[test]
+    fn test_tx_storage_rollback_to_future() {
+        const FILE_NAME: &str = "tx_storage_test_rollback_future.persy";
+        let storage = TxStorage::open(FILE_NAME).unwrap();
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        storage.push(0, Num::ZERO, &[0, 1, 2], &[3, 4, 5]).unwrap();
+
+        let res = storage.rollback(STRIDE * 2);
+        assert!(res.is_err());
+        assert_eq!(storage.next_index().unwrap(), STRIDE);
+    }
+
+    #[test]
+    fn test_tx_storage_rollback_to_unaligned() {
+        const FILE_NAME: &str = "tx_storage_test_rollback_unaligned.persy";
+        let storage = TxStorage::open(FILE_NAME).unwrap();
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        storage.push(0, Num::ZERO, &[0, 1, 2], &[3, 4, 5]).unwrap();
+        storage
+            .push(STRIDE, Num::ZERO, &[0, 1, 2], &[3, 4, 5])
+            .unwrap();
+
+        let res = storage.rollback(STRIDE + 1);
+        assert!(res.is_err());
+        assert_eq!(storage.next_index().unwrap(), STRIDE * 2);
+
+        let res = storage.set(STRIDE * 2, Num::ZERO, &[0, 1, 2], &[3, 4, 5]);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_tx_storage_rollback_to_zero() {
+        const FILE_NAME: &str = "tx_storage_test_rollback_zero.persy";
+        let storage = TxStorage::open(FILE_NAME).unwrap();
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        storage.push(0, Num::ZERO, &[0, 1, 2], &[3, 4, 5]).unwrap();
+        storage
+            .push(STRIDE, Num::ZERO, &[0, 1, 2], &[3, 4, 5])
+            .unwrap();
+
+        assert_eq!(storage.rollback(0).unwrap(), 2);
+        assert_eq!(storage.next_index().unwrap(), 0);
+        assert!(storage.get(0).unwrap().is_none());
+
+        let res = storage.push(0, Num::ZERO, &[0, 1, 2], &[3, 4, 5]);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_tx_storage_compact() {
+        const FILE_NAME: &str = "tx_storage_test_compact.persy";
+        let storage = TxStorage::open(FILE_NAME).unwrap();
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        let memo = [7u8; 256];
+        for i in 0..1000 {
+            storage
+                .push(i * STRIDE, Num::from(i), &[1; 32], &memo)
+                .unwrap();
+        }
+
+        storage.rollback(10 * STRIDE).unwrap();
+        let records = storage.iter().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        let size_before = storage.file_size().unwrap();
+
+        storage.compact().unwrap();
+
+        assert!(storage.file_size().unwrap() < size_before);
+        assert_eq!(storage.next_index().unwrap(), 10 * STRIDE);
+        assert_eq!(
+            storage.iter().unwrap().collect::<Result<Vec<_>>>().unwrap(),
+            records
+        );
+
+        let res = storage.push(10 * STRIDE, Num::ZERO, &[0, 1, 2], &[3, 4, 5]);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_tx_storage_fees() {
+        const FILE_NAME: &str = "tx_storage_test_fees.persy";
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        let day = 19_000 * SECONDS_PER_DAY;
+        let fee = |index: u64, timestamp: u64, fee: u64| FeeRecord {
+            index: index * STRIDE,
+            timestamp,
+            fee,
+        };
+        let records = [
+            fee(0, day + 10, 100),
+            fee(1, day + SECONDS_PER_DAY - 1, 20),
+            fee(2, day + SECONDS_PER_DAY, 3),
+            fee(3, day + 3 * SECONDS_PER_DAY, 4000),
+        ];
+        let resent = fee(3, day + 2 * SECONDS_PER_DAY, 4000);
+
+        {
+            let storage = TxStorage::open(FILE_NAME).unwrap();
+            for record in &records {
+                storage
+                    .push(record.index, Num::ZERO, &[0; 32], &[3, 4, 5])
+                    .unwrap();
+                storage
+                    .set_with_fee(record.index, Num::ZERO, &[1; 32], &[3, 4, 5], Some(*record))
+                    .unwrap();
+            }
+            // Storing the same transaction again replaces its fee
+            storage
+                .set_with_fee(resent.index, Num::ZERO, &[2; 32], &[3, 4, 5], Some(resent))
+                .unwrap();
+            assert!(storage
+                .set_with_fee(0, Num::ZERO, &[2; 32], &[3, 4, 5], Some(records[1]))
+                .is_err());
+        }
+
+        // Survives a restart
+        let storage = TxStorage::open(FILE_NAME).unwrap();
+        assert_eq!(storage.fees_total().unwrap(), 4123);
+        assert_eq!(
+            storage.fee_days(0..u64::MAX).unwrap(),
+            vec![
+                (day, 100),
+                (day + SECONDS_PER_DAY, 23),
+                (day + 2 * SECONDS_PER_DAY, 4000)
+            ]
+        );
+        assert_eq!(
+            storage
+                .fee_days(day + SECONDS_PER_DAY..day + 2 * SECONDS_PER_DAY)
+                .unwrap(),
+            vec![(day + SECONDS_PER_DAY, 23)]
+        );
+        assert_eq!(
+            storage
+                .fees_between(day + 10..day + SECONDS_PER_DAY + 1)
+                .unwrap(),
+            vec![records[0], records[1], records[2]]
+        );
+        assert_eq!(
+            storage.fees_between(day + 11..u64::MAX).unwrap(),
+            vec![records[1], records[2], resent]
+        );
+
+        storage.rollback(STRIDE * 2).unwrap();
+        assert_eq!(storage.fees_total().unwrap(), 120);
+        assert_eq!(
+            storage.fee_days(0..u64::MAX).unwrap(),
+            vec![(day, 100), (day + SECONDS_PER_DAY, 20)]
+        );
+
+        storage.compact().unwrap();
+        assert_eq!(storage.fees_total().unwrap(), 120);
+        assert_eq!(storage.fees_between(0..u64::MAX).unwrap().len(), 2);
+
+        storage.rollback(0).unwrap();
+        assert_eq!(storage.fees_total().unwrap(), 0);
+        assert!(storage.fee_days(0..u64::MAX).unwrap().is_empty());
+        assert!(storage.fees_between(0..u64::MAX).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tx_storage_ciphertexts() {
+        const FILE_NAME: &str = "tx_storage_test_ciphertexts.persy";
+        let storage = TxStorage::open(FILE_NAME).unwrap();
+        defer! {
+            std::fs::remove_file(FILE_NAME).unwrap();
+        }
+
+        let ciphertexts =
+            |storage: &TxStorage| storage.ciphertexts_range(..).unwrap().collect::<Vec<_>>();
+        let prefix = |i: u8| vec![i; PREFIX_SIZE];
+
+        storage
+            .push(0, Num::ZERO, &[1; 32], &test_ciphertext(1))
+            .unwrap();
+        storage
+            .push(STRIDE, Num::ZERO, &[2; 8], &[3, 4, 5])
+            .unwrap();
+        let data = storage.get(0).unwrap().unwrap();
+        storage.set_raw(2 * STRIDE, &data).unwrap();
+
+        let expected = vec![
+            (0, prefix(0)),
+            (1, prefix(1)),
+            (2 * STRIDE, prefix(0)),
+            (2 * STRIDE + 1, prefix(1)),
+        ];
+        assert_eq!(ciphertexts(&storage), expected);
+        assert_eq!(
+            storage
+                .ciphertexts_range(STRIDE..)
+                .unwrap()
+                .collect::<Vec<_>>(),
+            expected[2..]
+        );
+
+        // Records stored before the index existed
+        let mut tx = storage.db().begin().unwrap();
+        for index in [0, 2 * STRIDE] {
+            tx.remove::<Index, ByteVec>("ciphertexts", index, None)
+                .unwrap();
+        }
+        tx.put("meta", "ciphertexts_indexed".to_owned(), 0u64)
+            .unwrap();
+        tx.prepare().unwrap().commit().unwrap();
+
+        assert!(ciphertexts(&storage).is_empty());
+        assert_eq!(storage.index_ciphertexts().unwrap(), 3);
+        assert_eq!(ciphertexts(&storage), expected);
+        assert_eq!(storage.index_ciphertexts().unwrap(), 0);
+
+        storage.compact().unwrap();
+        assert_eq!(ciphertexts(&storage), expected);
+
+        storage.rollback(STRIDE).unwrap();
+        assert_eq!(ciphertexts(&storage), expected[..2]);
+    }
 }
@@ -1,16 +1,20 @@
-use std::sync::Arc;
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 #[cfg(feature = "groth16")]
-use libzeropool_rs::libzeropool::fawkes_crypto::backend::bellman_groth16::{
-    group::{G1Point, G2Point},
-    prover::Proof,
+use libzeropool_rs::libzeropool::fawkes_crypto::backend::bellman_groth16::group::{
+    G1Point, G2Point,
 };
-#[cfg(feature = "plonk")]
-use libzeropool_rs::libzeropool::fawkes_crypto::backend::plonk::prover::Proof;
 use libzeropool_rs::libzeropool::{
     constants,
-    native::tree::{TreePub, TreeSec},
+    fawkes_crypto::ff_uint::Num,
+    native::{
+        tree::{TreePub, TreeSec},
+        tx::parse_delta,
+    },
     POOL_PARAMS,
 };
 #[cfg(feature = "groth16")]
@@ -18,16 +22,28 @@ use libzeropool_rs::proof_groth16::prove_tree;
 #[cfg(feature = "plonk")]
 use libzeropool_rs::proof_plonk::prove_tree;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 use zeropool_tx::TxData;
 
 use crate::{
-    job_queue::{Job, JobQueue},
+    audit::AuditEvent,
+    backend::{SendError, TxConfirmation, TxHash},
+    failed_jobs::FailedJob,
+    job_queue::{unix_timestamp, Aborted, Expired, Job, JobId, JobQueue, Retryable},
+    json_api::check_transfer_index,
+    monitoring,
     state::AppState,
-    tx::ParsedTxData,
-    Fr,
+    tx::{DisplayTx, ParsedTxData, TxEvent, TxValidationError},
+    tx_storage::FeeRecord,
+    Fr, Proof,
 };
 
-const TX_SIZE: u64 = constants::OUT as u64 + 1;
+pub const TX_SIZE: u64 = constants::OUT as u64 + 1;
+/// Job queue mapping namespace for commit index -> job id.
+pub const INDEX_MAPPING: &str = "job_mapping";
+/// Job queue mapping namespace for idempotency key -> job id.
+pub const IDEMPOTENCY_MAPPING: &str = "idem";
+const CONFIRMATION_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Payload {
@@ -36,14 +52,39 @@ pub struct Payload {
     tree_sec: TreeSec<Fr>,
     next_commit_index: u64,
     prev_commit_index: u64,
+    /// Id of the request that created the job, used to correlate logs.
+    request_id: Uuid,
+    /// Key of the submission that created the job, released if the job fails or is cancelled so
+    /// that the transaction can be submitted again.
+    idempotency_key: Option<String>,
+}
+
+impl Payload {
+    pub fn tx(&self) -> &ParsedTxData {
+        &self.tx
+    }
+
+    pub fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+
+    /// Pool index the transaction is going to be sent at.
+    pub fn pool_index(&self) -> u64 {
+        self.next_commit_index * TX_SIZE
+    }
 }
 
 pub type WorkerJobQueue = JobQueue<Payload, AppState>;
 
 /// Does as much as possible before creating a job in order to guarantee that the optimistic state
 /// is updated by the time a user receives a response.
-pub async fn prepare_job(tx: ParsedTxData, ctx: Arc<AppState>) -> Result<Payload> {
-    let tree = ctx.tree.lock().await;
+pub async fn prepare_job(
+    tx: ParsedTxData,
+    request_id: Uuid,
+    idempotency_key: Option<String>,
+    ctx: Arc<AppState>,
+) -> Result<Payload> {
+    let tree = ctx.tree.lock("prepare_job").await;
     let root_before = tree.root()?;
     let next_commit_index = tree.num_leaves();
     let prev_commit_index = next_commit_index.saturating_sub(1);
@@ -81,100 +122,192 @@ pub async fn prepare_job(tx: ParsedTxData, ctx: Arc<AppState>) -> Result<Payload
         tree_sec,
         next_commit_index,
         prev_commit_index,
+        request_id,
+        idempotency_key,
     })
 }
 
-#[tracing::instrument(skip_all, fields(job_id = %job.id))]
-pub async fn process_failure(job: Job<Payload>, ctx: Arc<AppState>) -> Result<()> {
-    let prev_commit_index = job.data.prev_commit_index;
+/// Cancels the jobs of all transactions with commit indices >= `commit_index`, both queued and
+/// in progress.
+pub async fn cancel_jobs_from(ctx: &AppState, commit_index: u64, num_leaves: u64) -> Result<()> {
+    for index in commit_index..num_leaves {
+        if let Some(job_id) = ctx.job_queue.get_job_mapping(INDEX_MAPPING, index).await? {
+            ctx.job_queue.cancel_job(job_id).await?;
+        }
+    }
 
-    let rollback_to = if prev_commit_index > 0 {
-        // The rollback index is inclusive
-        prev_commit_index + 1
-    } else {
-        0
-    };
+    // Queued jobs always come after the ones in progress.
+    cancel_queued_jobs_after(
+        ctx,
+        0,
+        "Cancelled: the preceding transactions were rolled back",
+    )
+    .await
+}
 
-    tracing::info!("Rolling back tx storage to {prev_commit_index}");
-    ctx.transactions.rollback(rollback_to * TX_SIZE)?;
-    ctx.tree.lock().await.rollback(rollback_to)?;
-    ctx.job_queue.cancel_jobs_after(job.id).await?;
-    tracing::info!("Rollback complete");
+/// Cancels the queued jobs with ids greater than `job_id` and archives them with `reason`, so
+/// that they can be retried. The ones in progress are archived and release their idempotency
+/// keys in [`process_failure`].
+async fn cancel_queued_jobs_after(ctx: &AppState, job_id: JobId, reason: &str) -> Result<()> {
+    for job in ctx.job_queue.cancel_jobs_after(job_id).await? {
+        release_idempotency_key(ctx, &job.data).await;
+
+        let failed_job = FailedJob {
+            job,
+            error: reason.to_owned(),
+            failed_at: unix_timestamp(),
+        };
+        if let Err(err) = ctx.failed_jobs.add(&failed_job) {
+            tracing::error!("Failed to archive job: {err}");
+        }
+    }
 
     Ok(())
 }
 
-#[tracing::instrument(skip_all, fields(job_id = %job.id))]
-pub async fn process_job(job: Job<Payload>, ctx: Arc<AppState>) -> Result<()> {
-    let Payload {
-        tx,
-        tree_pub,
-        tree_sec,
-        next_commit_index,
-        ..
-    } = job.data;
+async fn release_idempotency_key(ctx: &AppState, payload: &Payload) {
+    let Some(key) = &payload.idempotency_key else {
+        return;
+    };
 
-    ctx.job_queue
-        .add_job_mapping(job.id, next_commit_index)
-        .await?;
+    if let Err(err) = ctx
+        .job_queue
+        .remove_job_mapping(IDEMPOTENCY_MAPPING, key)
+        .await
+    {
+        tracing::warn!("Failed to remove idempotency key: {err}");
+    }
+}
 
-    let root_after = tree_pub.root_after;
+#[tracing::instrument(skip_all, fields(job_id = %job.id, request_id = %job.data.request_id))]
+pub async fn process_failure(job: Job<Payload>, error: String, ctx: Arc<AppState>) -> Result<()> {
+    let next_commit_index = job.data.next_commit_index;
+    let out_commit = job.data.tx.out_commit;
+    let job_id = job.id;
+    release_idempotency_key(&ctx, &job.data).await;
+
+    // Archive the job before the rollback, so that it's not lost if the rollback fails.
+    let failed_job = FailedJob {
+        job,
+        error: error.clone(),
+        failed_at: unix_timestamp(),
+    };
+    if let Err(err) = ctx.failed_jobs.add(&failed_job) {
+        tracing::error!("Failed to archive job: {err}");
+    }
 
-    let tree_proof = if ctx.config.mock_prover {
-        tracing::debug!("Mocking tree proof");
+    // The new lock holder rolls back on its own, if needed.
+    ctx.ensure_instance_lock().await?;
 
-        #[cfg(feature = "groth16")]
-        {
-            Proof {
-                a: G1Point(Num::ZERO, Num::ZERO),
-                b: G2Point((Num::ZERO, Num::ZERO), (Num::ZERO, Num::ZERO)),
-                c: G1Point(Num::ZERO, Num::ZERO),
-            }
+    let tree = ctx.tree.lock("process_failure").await;
+
+    // The leaf might have moved after a resync, or might be already removed after a reorg or a
+    // failure of a preceding job.
+    let pool_commit_index = *ctx.pool_index.read().await / TX_SIZE;
+    let mut rollback_to = None;
+    for index in pool_commit_index.min(next_commit_index)..tree.num_leaves() {
+        if tree.leaf(index)? == out_commit {
+            rollback_to = Some(index);
+            break;
         }
+    }
 
-        #[cfg(feature = "plonk")]
-        {
-            Proof(vec![])
+    if let Some(rollback_to) = rollback_to {
+        // The later leaves are removed along with this one. Their jobs in progress stop before
+        // sending, the queued ones are cancelled below. Mappings of older jobs can be stale.
+        for index in rollback_to + 1..tree.num_leaves() {
+            match ctx.job_queue.get_job_mapping(INDEX_MAPPING, index).await? {
+                Some(later_job_id) if later_job_id > job_id => {
+                    ctx.job_queue.cancel_job(later_job_id).await?
+                }
+                _ => {}
+            }
         }
+
+        tracing::info!("Rolling back tx storage to {rollback_to}");
+        let removed = ctx.transactions.rollback(rollback_to * TX_SIZE)?;
+        tracing::info!("Removed {removed} transactions from tx storage");
+        tree.rollback(rollback_to)?;
+        ctx.audit.record(AuditEvent::Rollback {
+            job_id: Some(job_id),
+            to: rollback_to * TX_SIZE,
+            removed,
+            cause: error,
+        });
     } else {
-        tracing::debug!("Proving tree");
-
-        #[cfg(feature = "groth16")]
-        {
-            let ctx = ctx.clone();
-            let proof = tokio::task::spawn_blocking(move || {
-                prove_tree(
-                    &ctx.groth16_params.tree_params,
-                    &*POOL_PARAMS,
-                    tree_pub,
-                    tree_sec,
-                )
-                .1
-            })
-            .await?;
-            tracing::info!("Tree proof complete");
-            proof
-        }
+        tracing::info!("Transaction is already rolled back");
+    }
+    drop(tree);
+
+    cancel_queued_jobs_after(
+        &ctx,
+        job_id,
+        &format!("Cancelled after job {job_id} failed"),
+    )
+    .await?;
+    tracing::info!("Rollback complete");
 
-        #[cfg(feature = "plonk")]
-        {
-            let ctx = ctx.clone();
-            let proof = tokio::task::spawn_blocking(move || {
-                prove_tree(
-                    &ctx.plonk_params.params,
-                    &ctx.plonk_params.tree_pk,
-                    &*POOL_PARAMS,
-                    tree_pub,
-                    tree_sec,
-                )
-                .1
-            })
+    Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(job_id = %job.id, request_id = %job.data.request_id))]
+pub async fn process_job(job: Job<Payload>, ctx: Arc<AppState>) -> Result<()> {
+    let pool_index = *ctx.pool_index.read().await;
+    let expiry = check_expiry(
+        job.data.tx.delta,
+        job.data.next_commit_index,
+        pool_index,
+        ctx.config.max_tx_index_lag,
+    );
+    if let Some(err) = expiry {
+        return expire_job(job, err, ctx).await;
+    }
+
+    let mut payload = job.data;
+
+    let (payload, tree_proof) = loop {
+        ctx.job_queue
+            .add_job_mapping(INDEX_MAPPING, job.id, payload.next_commit_index)
+            .await?;
+        ctx.job_queue
+            .set_job_commit_index(job.id, payload.next_commit_index)
             .await?;
-            tracing::info!("Tree proof complete");
-            proof
+
+        let tree_proof =
+            prove_tree_update(&ctx, payload.tree_pub.clone(), payload.tree_sec.clone()).await?;
+
+        wait_for_turn(&ctx, job.id, payload.next_commit_index).await?;
+
+        if matches_chain_state(&ctx, &payload).await? {
+            break (payload, tree_proof);
         }
+
+        payload = match resync(&ctx, payload).await? {
+            Resynced::Prepared(payload) => payload,
+            Resynced::Mined(commit_index) => {
+                tracing::info!(
+                    "Transaction is already mined at {}, not sending it again",
+                    commit_index * TX_SIZE
+                );
+                ctx.job_queue
+                    .add_job_mapping(INDEX_MAPPING, job.id, commit_index)
+                    .await?;
+                ctx.job_queue
+                    .set_job_commit_index(job.id, commit_index)
+                    .await?;
+                return Ok(());
+            }
+        };
     };
 
+    let Payload {
+        tx,
+        tree_pub,
+        next_commit_index,
+        ..
+    } = payload;
+    let root_after = tree_pub.root_after;
+
     let full_tx = TxData {
         tx_type: tx.tx_type,
         delta: tx.delta,
@@ -188,77 +321,451 @@ pub async fn process_job(job: Job<Payload>, ctx: Arc<AppState>) -> Result<()> {
         extra_data: tx.extra_data,
     };
 
-    // TODO: Proper human-readable Debug implementation for TxData
-    tracing::debug!(
-        r#"Transaction prepared:
-    tx_type: {:?}
-    delta: {}
-    token_id: {}
-    out_commit: {}
-    nullifier: {}
-    proof: ...
-    root_after: {}
-    tree_proof: ...
-    memo: {:?}
-    extra_data: {:?}
-"#,
-        full_tx.tx_type,
-        full_tx.delta,
-        full_tx.token_id,
-        full_tx.out_commit,
-        full_tx.nullifier,
-        full_tx.root_after,
-        hex::encode(&full_tx.memo),
-        hex::encode(&full_tx.extra_data)
-    );
+    tracing::debug!("Transaction prepared:\n{}", DisplayTx(&full_tx));
 
-    // TODO: Use a separate ordered queue for sending transactions?
-    loop {
-        if ctx.job_queue.is_job_cancelled(job.id).await? {
-            tracing::info!("Job cancelled, skipping tx");
-            return Err(anyhow!("Job cancelled"));
-        }
-
-        // Wait until the preceding transactions are executed.
-        let pool_index = *ctx.pool_index.read().await;
-        if pool_index == next_commit_index * TX_SIZE {
-            break;
-        } else {
-            tracing::debug!(
-                "Waiting for tx {} to be executed, current pool index is {}",
-                next_commit_index * TX_SIZE,
-                pool_index
-            );
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-        }
-    }
+    ctx.ensure_instance_lock().await.context(Aborted)?;
 
     tracing::info!("Sending tx");
 
-    let tx_hash = match ctx.backend.send_tx(full_tx).await {
-        Ok(tx_hash) => tx_hash,
-        Err(e) => {
+    let send_started = Instant::now();
+    let send_result = ctx.backend.send_tx(full_tx).await;
+    monitoring::record_send_tx_duration(ctx.backend.name(), send_started.elapsed());
+
+    let sent = match send_result {
+        Ok(sent) => sent,
+        Err(SendError::Retryable(e)) => {
+            tracing::warn!("Failed to send tx: {:#?}", e);
+            return Err(e.context(Retryable));
+        }
+        Err(SendError::Fatal(e)) => {
             tracing::error!("Failed to send tx: {:#?}", e);
             return Err(e);
         }
     };
 
-    tracing::info!(
-        "Transaction successfully sent ({}). Updating permanent state...",
-        ctx.backend.format_hash(&tx_hash)
-    );
+    let tx_hash = sent.hash;
+    let formatted_hash = ctx.backend.format_hash(&tx_hash);
+    tracing::info!("Transaction successfully sent ({formatted_hash})");
+    ctx.audit.record(AuditEvent::TransactionSent {
+        job_id: job.id,
+        index: next_commit_index * TX_SIZE,
+        tx_hash: formatted_hash.clone(),
+    });
+
+    // Informational only, the transaction is already sent.
+    if let Some(sender) = &sent.sender {
+        if let Err(err) = ctx.job_queue.set_job_sender(job.id, sender).await {
+            tracing::warn!("Failed to record the sender of job {}: {err}", job.id);
+        }
+    }
+    if let Err(err) = ctx.job_queue.set_job_tx_hash(job.id, &formatted_hash).await {
+        tracing::warn!(
+            "Failed to record the transaction hash of job {}: {err}",
+            job.id
+        );
+    }
+
+    // Dry run transactions never get mined.
+    if ctx.config.wait_for_confirmation && !ctx.config.dry_run {
+        wait_for_confirmation(&ctx, &tx_hash).await?;
+    }
+
+    // The transaction is sent, the new lock holder picks it up from the chain.
+    ctx.ensure_instance_lock().await.context(Aborted)?;
+
+    tracing::info!("Updating permanent state...");
+
+    // Dry run transactions collect no fees.
+    let fee = (!ctx.config.dry_run).then(|| FeeRecord {
+        index: next_commit_index * TX_SIZE,
+        timestamp: unix_timestamp(),
+        fee: memo_fee(&tx.memo),
+    });
 
     // Update transaction with hash
-    ctx.transactions.set(
+    ctx.transactions.set_with_fee(
         next_commit_index * TX_SIZE,
         tx.out_commit,
         &tx_hash,
         ctx.backend
             .extract_ciphertext_from_memo(&tx.memo, tx.tx_type),
+        fee,
     )?;
 
-    *ctx.pool_index.write().await += TX_SIZE;
-    *ctx.pool_root.write().await = root_after.0.into();
+    // The reorg detector might have already observed the transaction on chain.
+    {
+        let mut pool_index = ctx.pool_index.write().await;
+        if *pool_index < (next_commit_index + 1) * TX_SIZE {
+            *pool_index = (next_commit_index + 1) * TX_SIZE;
+            *ctx.pool_root.write().await = root_after.0.into();
+        }
+    }
+
+    // Notify subscribers only after the pool index is updated, so that a subscriber doing a
+    // backfill up to the pool index cannot miss this transaction.
+    // The transaction is already sent at this point, so errors must not fail the job.
+    match ctx.transactions.get(next_commit_index * TX_SIZE) {
+        Ok(Some(data)) => {
+            // An error here only means that there are no subscribers.
+            let _ = ctx.tx_events.send(TxEvent {
+                index: next_commit_index * TX_SIZE,
+                data,
+            });
+        }
+        Ok(None) => tracing::warn!("Sent transaction is missing from tx storage"),
+        Err(err) => tracing::warn!("Failed to read sent transaction: {err}"),
+    }
 
     Ok(())
 }
+
+/// The memo starts with the fee, checked to be there when the transaction is submitted.
+fn memo_fee(memo: &[u8]) -> u64 {
+    memo.get(..8)
+        .map_or(0, |fee| u64::from_be_bytes(fee.try_into().unwrap()))
+}
+
+/// Re-validates the transfer index of a queued job. The transaction is going to be sent at the
+/// pool index of its leaf, or later if someone else has sent transactions to the pool.
+fn check_expiry(
+    delta: Num<Fr>,
+    next_commit_index: u64,
+    pool_index: u64,
+    max_lag: Option<u64>,
+) -> Option<TxValidationError> {
+    let (_, _, transfer_index, _) = parse_delta(delta);
+    let send_index = pool_index.max(next_commit_index * TX_SIZE);
+
+    check_transfer_index(transfer_index, pool_index, send_index, max_lag)
+}
+
+/// Removes the leaf of an expired job without sending its transaction. If later transactions are
+/// already built on top of the leaf, they are rolled back as after a failure.
+#[tracing::instrument(skip_all, fields(job_id = %job.id, request_id = %job.data.request_id))]
+async fn expire_job(job: Job<Payload>, error: TxValidationError, ctx: Arc<AppState>) -> Result<()> {
+    let commit_index = job.data.next_commit_index;
+    tracing::warn!("Transaction at {commit_index} expired: {error}");
+    ctx.audit.record(AuditEvent::JobExpired {
+        job_id: job.id,
+        index: commit_index * TX_SIZE,
+        cause: error.to_string(),
+    });
+
+    ctx.ensure_instance_lock().await.context(Aborted)?;
+
+    let tree = ctx.tree.lock("expire_job").await;
+    let is_last_leaf =
+        tree.num_leaves() == commit_index + 1 && tree.leaf(commit_index)? == job.data.tx.out_commit;
+    if is_last_leaf {
+        ctx.transactions.rollback(commit_index * TX_SIZE)?;
+        tree.rollback(commit_index)?;
+        tracing::info!("Removed the expired transaction from the local state");
+    } else {
+        drop(tree);
+        process_failure(job, error.to_string(), ctx).await?;
+    }
+
+    Err(anyhow::Error::from(error).context(Expired))
+}
+
+pub fn mock_proof() -> Proof {
+    #[cfg(feature = "groth16")]
+    {
+        Proof {
+            a: G1Point(Num::ZERO, Num::ZERO),
+            b: G2Point((Num::ZERO, Num::ZERO), (Num::ZERO, Num::ZERO)),
+            c: G1Point(Num::ZERO, Num::ZERO),
+        }
+    }
+
+    #[cfg(feature = "plonk")]
+    {
+        Proof(vec![])
+    }
+}
+
+async fn prove_tree_update(
+    ctx: &Arc<AppState>,
+    tree_pub: TreePub<Fr>,
+    tree_sec: TreeSec<Fr>,
+) -> Result<Proof> {
+    if ctx.config.mock_prover {
+        tracing::debug!("Mocking tree proof");
+        return Ok(mock_proof());
+    }
+
+    tracing::debug!("Proving tree");
+
+    #[cfg(feature = "groth16")]
+    let proof = {
+        let ctx = ctx.clone();
+        tokio::task::spawn_blocking(move || {
+            let params = ctx
+                .groth16_params
+                .as_ref()
+                .expect("Loaded without mock prover");
+            prove_tree(&params.tree_params, &*POOL_PARAMS, tree_pub, tree_sec).1
+        })
+        .await?
+    };
+
+    #[cfg(feature = "plonk")]
+    let proof = {
+        let ctx = ctx.clone();
+        tokio::task::spawn_blocking(move || {
+            let params = ctx
+                .plonk_params
+                .as_ref()
+                .expect("Loaded without mock prover");
+            prove_tree(
+                &params.params,
+                &params.tree_pk,
+                &*POOL_PARAMS,
+                tree_pub,
+                tree_sec,
+            )
+            .1
+        })
+        .await?
+    };
+
+    tracing::info!("Tree proof complete");
+
+    Ok(proof)
+}
+
+/// Waits until the preceding transactions are executed and sending is not paused.
+// TODO: Use a separate ordered queue for sending transactions?
+async fn wait_for_turn(ctx: &AppState, job_id: JobId, next_commit_index: u64) -> Result<()> {
+    loop {
+        if ctx.job_queue.is_job_cancelled(job_id).await? {
+            tracing::info!("Job cancelled, skipping tx");
+            return Err(anyhow!("Job cancelled"));
+        }
+
+        // The pool index can be ahead if someone else has sent transactions to the pool.
+        let pool_index = *ctx.pool_index.read().await;
+        if pool_index >= next_commit_index * TX_SIZE && ctx.sending.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        tracing::debug!(
+            "Waiting for tx {} to be executed, current pool index is {}",
+            next_commit_index * TX_SIZE,
+            pool_index
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+/// Polls the status of a sent transaction until it's mined. Fails if the transaction is reverted
+/// or not mined within `confirmation_timeout_secs`. If it's mined later anyway, the next job
+/// resyncs with the chain.
+async fn wait_for_confirmation(ctx: &AppState, tx_hash: &TxHash) -> Result<()> {
+    let timeout = Duration::from_secs(ctx.config.confirmation_timeout_secs);
+    let started = Instant::now();
+    let hash = ctx.backend.format_hash(tx_hash);
+
+    loop {
+        match ctx.backend.get_tx_status(tx_hash).await {
+            Ok(TxConfirmation::Confirmed) => {
+                tracing::info!("Transaction {hash} confirmed");
+                return Ok(());
+            }
+            Ok(TxConfirmation::Failed) => return Err(anyhow!("Transaction {hash} reverted")),
+            Ok(TxConfirmation::Pending) => {}
+            // The transaction is already sent, so keep polling until the timeout.
+            Err(err) => tracing::warn!("Failed to fetch the status of tx {hash}: {err:#}"),
+        }
+
+        if started.elapsed() >= timeout {
+            return Err(anyhow!(
+                "Transaction {hash} is not confirmed after {timeout:?}"
+            ));
+        }
+
+        tokio::time::sleep(CONFIRMATION_POLL_INTERVAL).await;
+    }
+}
+
+/// Checks that the job was prepared on top of the current on-chain state. If the chain is
+/// behind, the preceding transactions are not mined yet and there is nothing to compare.
+async fn matches_chain_state(ctx: &AppState, payload: &Payload) -> Result<bool> {
+    let expected_index = payload.next_commit_index * TX_SIZE;
+    let pool_index = ctx.backend.get_pool_index().await?;
+
+    if pool_index < expected_index {
+        return Ok(true);
+    }
+
+    if pool_index > expected_index {
+        tracing::warn!("Pool index is {pool_index}, expected {expected_index}");
+        return Ok(false);
+    }
+
+    let root = ctx.backend.get_merkle_root(pool_index).await?;
+    let root_before = payload.tree_pub.root_before.to_uint().0;
+    if root != Some(root_before) {
+        tracing::warn!("Pool root is {root:?}, expected {root_before}");
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+enum Resynced {
+    /// The job is prepared again on top of the mined transactions.
+    Prepared(Payload),
+    /// The job's own transaction is among the mined ones, at the given commit index.
+    Mined(u64),
+}
+
+/// Replaces the local state starting at the job's commit index with the transactions mined on
+/// chain and prepares the job again on top of it. Later jobs are cancelled, since their leaves
+/// are removed. If the job's transaction is already mined, e.g. the response to an earlier send
+/// was lost, it must not be sent again: its nullifier is spent.
+async fn resync(ctx: &Arc<AppState>, payload: Payload) -> Result<Resynced> {
+    let commit_index = payload.next_commit_index;
+
+    {
+        let tree = ctx.tree.lock("resync").await;
+        ctx.ensure_instance_lock().await.context(Aborted)?;
+        tracing::warn!("Local state diverged from the chain at {commit_index}, resyncing");
+
+        cancel_jobs_from(ctx, commit_index + 1, tree.num_leaves()).await?;
+        let removed = ctx.transactions.rollback(commit_index * TX_SIZE)?;
+        tree.rollback(commit_index)?;
+        ctx.audit.record(AuditEvent::Rollback {
+            job_id: None,
+            to: commit_index * TX_SIZE,
+            removed,
+            cause: "Local state diverged from the chain".to_owned(),
+        });
+        ctx.sync_tree_from_chain(&tree).await?;
+
+        tracing::info!("Resynced to pool index {}", tree.num_leaves() * TX_SIZE);
+
+        for index in commit_index..tree.num_leaves() {
+            if tree.leaf(index)? == payload.tx.out_commit {
+                return Ok(Resynced::Mined(index));
+            }
+        }
+    }
+
+    prepare_job(
+        payload.tx,
+        payload.request_id,
+        payload.idempotency_key,
+        ctx.clone(),
+    )
+    .await
+    .map(Resynced::Prepared)
+}
+
+#[cfg(test)]
+mod tests {
+    use libzeropool_rs::libzeropool::{fawkes_crypto::engines::U256, native::tx::make_delta};
+
+    use super::*;
+    use crate::{
+        backend::{
+            mock::{mock_config, MockBackend},
+            BlockchainBackend,
+        },
+        job_queue::JobStatus,
+        test_utils,
+    };
+
+    #[tokio::test]
+    async fn test_check_expiry() {
+        let backend = MockBackend::new(mock_config(None, vec![]));
+        let max_lag = Some(2 * TX_SIZE);
+
+        // Proven at pool index 0 and queued as the third transaction
+        let delta = make_delta::<Fr>(Num::ZERO, Num::ZERO, Num::ZERO, Num::ZERO);
+        let pool_index = backend.get_pool_index().await.unwrap();
+        assert!(check_expiry(delta, 2, pool_index, max_lag).is_none());
+        assert!(check_expiry(delta, 3, pool_index, None).is_none());
+        assert!(matches!(
+            check_expiry(delta, 3, pool_index, max_lag),
+            Some(TxValidationError::ExpiredTxIndex)
+        ));
+
+        // Transactions sent by someone else push the job further away from its transfer index
+        for _ in 0..3 {
+            backend.mine(U256::ZERO).await;
+        }
+        let pool_index = backend.get_pool_index().await.unwrap();
+        assert!(matches!(
+            check_expiry(delta, 2, pool_index, max_lag),
+            Some(TxValidationError::ExpiredTxIndex)
+        ));
+
+        // A transfer index ahead of the mined pool index is invalid regardless of the lag
+        let delta = make_delta::<Fr>(Num::ZERO, Num::ZERO, Num::from(4 * TX_SIZE), Num::ZERO);
+        assert!(matches!(
+            check_expiry(delta, 4, pool_index, None),
+            Some(TxValidationError::InvalidTxIndex)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expire_last_job() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(MockBackend::new(mock_config(None, vec![])));
+        let ctx = test_utils::app_state(dir.path(), backend, |_| {}).await;
+
+        let payload = prepare_job(test_utils::deposit(1), Uuid::new_v4(), None, ctx.clone())
+            .await
+            .unwrap();
+        let job_id = ctx.job_queue.push(payload.clone()).await.unwrap();
+        let job = Job {
+            id: job_id,
+            data: payload,
+        };
+
+        let err = expire_job(job, TxValidationError::ExpiredTxIndex, ctx.clone())
+            .await
+            .unwrap_err();
+        assert!(err.is::<Expired>());
+
+        // Only the leaf is removed, there is nothing to fail.
+        assert_eq!(ctx.tree.lock("test").await.num_leaves(), 0);
+        assert_eq!(ctx.transactions.next_index().unwrap(), 0);
+        assert!(ctx.failed_jobs.get(job_id).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expire_job_with_later_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(MockBackend::new(mock_config(None, vec![])));
+        let ctx = test_utils::app_state(dir.path(), backend, |_| {}).await;
+
+        let mut jobs = Vec::new();
+        for seed in 1..=2 {
+            let payload = prepare_job(test_utils::deposit(seed), Uuid::new_v4(), None, ctx.clone())
+                .await
+                .unwrap();
+            let id = ctx.job_queue.push(payload.clone()).await.unwrap();
+            jobs.push(Job { id, data: payload });
+        }
+        let later_id = jobs[1].id;
+        let job = jobs.remove(0);
+        let job_id = job.id;
+
+        let err = expire_job(job, TxValidationError::ExpiredTxIndex, ctx.clone())
+            .await
+            .unwrap_err();
+        assert!(err.is::<Expired>());
+
+        // The later job is built on top of the expired leaf, so both are rolled back.
+        assert_eq!(ctx.tree.lock("test").await.num_leaves(), 0);
+        assert_eq!(ctx.transactions.next_index().unwrap(), 0);
+        assert_eq!(
+            ctx.job_queue.job_status(later_id).await.unwrap(),
+            Some(JobStatus::Cancelled)
+        );
+        assert!(ctx.failed_jobs.get(job_id).unwrap().is_some());
+        assert!(ctx.failed_jobs.get(later_id).unwrap().is_some());
+    }
+}
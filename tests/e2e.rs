@@ -0,0 +1,954 @@
+//! Drives a relayer with the mock backend and the in-memory job queue through its HTTP API:
+//! submission, validation, the job queue, the worker and the mock backend, then `/job`, `/info`
+//! and `/transactions`.
+
+use std::{
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use libzeropool_rs::libzeropool::{
+    fawkes_crypto::ff_uint::{Num, PrimeField, Uint},
+    native::tx::make_delta,
+};
+use tempfile::TempDir;
+use zeropool_relayer::{
+    audit::AuditEvent,
+    backend::{
+        mock::{self, MockBackend},
+        BlockchainBackend,
+    },
+    config::{BackendKind, Config, FeeModel, RuntimeSettings, Secret},
+    json_api::TxDataRequest,
+    tx::{ProofInput, ProofWithInputs},
+    tx_worker::{mock_proof, TX_SIZE},
+    Fr, RelayerHandle,
+};
+use zeropool_relayer_client::{Error, JobId, JobStatus, RelayerClient};
+use zeropool_tx::TxType;
+
+const JOB_TIMEOUT: Duration = Duration::from_secs(10);
+const ADMIN_TOKEN: &str = "admin";
+
+struct Relayer {
+    handle: RelayerHandle,
+    client: RelayerClient,
+    config: Config,
+    backend: Option<Arc<MockBackend>>,
+    dir: TempDir,
+}
+
+impl Relayer {
+    async fn start(configure: impl FnOnce(&mut Config)) -> Self {
+        Self::start_with_backend(None, configure).await
+    }
+
+    /// Several relayers started with the same backend share the pool.
+    async fn start_with_backend(
+        backend: Option<Arc<MockBackend>>,
+        configure: impl FnOnce(&mut Config),
+    ) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::mock(dir.path());
+        configure(&mut config);
+
+        Self::launch(config, backend, dir).await
+    }
+
+    async fn launch(config: Config, backend: Option<Arc<MockBackend>>, dir: TempDir) -> Self {
+        let handle = match backend.clone() {
+            Some(backend) => zeropool_relayer::run_with_backend(config.clone(), backend).await,
+            None => zeropool_relayer::run(config.clone()).await,
+        }
+        .unwrap();
+        let client = RelayerClient::new(&format!("http://{}", handle.local_addrs()[0]));
+
+        Self {
+            handle,
+            client,
+            config,
+            backend,
+            dir,
+        }
+    }
+
+    /// Stops the relayer and starts it again on the same storage. Without a shared backend the
+    /// chain starts over as well.
+    async fn restart(self) -> Self {
+        let Self {
+            handle,
+            client,
+            config,
+            backend,
+            dir,
+        } = self;
+        let state = handle.state().clone();
+        drop(client);
+        handle.shutdown_trigger().trigger();
+        handle.wait().await.unwrap();
+
+        // The storage files stay locked until the connections of the old client are closed.
+        let started = Instant::now();
+        while Arc::strong_count(&state) > 1 {
+            assert!(started.elapsed() < JOB_TIMEOUT, "The state is still in use");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(state);
+
+        Self::launch(config, backend, dir).await
+    }
+
+    fn url(&self) -> String {
+        format!("http://{}", self.handle.local_addrs()[0])
+    }
+
+    /// Request to the admin API, see `ADMIN_TOKEN`.
+    fn admin(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        reqwest::Client::new()
+            .request(method, format!("{}{path}", self.url()))
+            .bearer_auth(ADMIN_TOKEN)
+    }
+
+    async fn stop(self) {
+        self.handle.shutdown_trigger().trigger();
+        self.handle.wait().await.unwrap();
+    }
+
+    async fn submit(&self, tx_type: TxType, seed: u64) -> JobId {
+        let tx = self.tx(tx_type, seed).await;
+        self.client.submit_transaction(&tx).await.unwrap()
+    }
+
+    /// Waits until `/info` reports the given optimistic and mined pool indices.
+    async fn wait_for_index(&self, optimistic_index: u64, pool_index: u64) {
+        let started = Instant::now();
+        loop {
+            let info = self.client.info().await.unwrap();
+            if info.optimistic_index == optimistic_index.to_string()
+                && info.pool_index == pool_index.to_string()
+            {
+                return;
+            }
+
+            assert!(started.elapsed() < JOB_TIMEOUT, "Stuck at {info:?}");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// A transaction made against the current pool root, valid with the mock prover. `seed`
+    /// makes the nullifier and the out commitment unique.
+    async fn tx(&self, tx_type: TxType, seed: u64) -> TxDataRequest {
+        let info = self.client.info().await.unwrap();
+        let root = Num::<Fr>::from_str(&info.root).unwrap();
+        let pool_index = info.pool_index.parse::<u64>().unwrap();
+
+        let amount = match tx_type {
+            TxType::Deposit => Num::from(1000u64),
+            _ => Num::ZERO,
+        };
+        let delta = make_delta::<Fr>(amount, Num::ZERO, Num::from(pool_index), Num::ZERO);
+        let inputs = [root, Num::from(seed), out_commit(seed), delta, Num::ZERO];
+
+        TxDataRequest {
+            tx_type,
+            proof: ProofWithInputs {
+                proof: mock_proof(),
+                inputs: inputs.into_iter().map(ProofInput::from).collect(),
+            },
+            // Zero fee, as configured by `Config::mock`.
+            memo: vec![0; 8],
+            extra_data: vec![],
+        }
+    }
+}
+
+fn out_commit(seed: u64) -> Num<Fr> {
+    Num::from(1_000_000 + seed)
+}
+
+/// Records start with the big-endian out commitment.
+fn has_out_commit(record: &[u8], seed: u64) -> bool {
+    record[..32] == out_commit(seed).0.to_uint().to_big_endian()[..]
+}
+
+#[tokio::test]
+async fn test_deposit_and_transfer() {
+    let relayer = Relayer::start(|_| {}).await;
+    let client = &relayer.client;
+    let empty_root = client.info().await.unwrap().root;
+
+    let deposit = relayer.tx(TxType::Deposit, 1).await;
+    let job_id = client.submit_transaction(&deposit).await.unwrap();
+    client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+
+    let info = client.info().await.unwrap();
+    assert_eq!(info.pool_index, TX_SIZE.to_string());
+    assert_eq!(info.optimistic_index, info.pool_index);
+    assert_ne!(info.root, empty_root);
+    assert_eq!(info.root, info.optimistic_root);
+
+    let transfer = relayer.tx(TxType::Transfer, 2).await;
+    assert_eq!(
+        client.validate_transaction(&transfer).await.unwrap(),
+        TX_SIZE
+    );
+    let job_id = client.submit_transaction(&transfer).await.unwrap();
+    client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+    assert_eq!(
+        client.job_status(job_id).await.unwrap(),
+        JobStatus::Completed
+    );
+    assert_eq!(
+        client.job_status_by_index(TX_SIZE).await.unwrap(),
+        JobStatus::Completed
+    );
+
+    let info = client.info().await.unwrap();
+    assert_eq!(info.pool_index, (2 * TX_SIZE).to_string());
+    assert_eq!(info.mined_transactions, 2);
+    assert_eq!(info.root, info.optimistic_root);
+
+    let records = client.transactions(0, 10).await.unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(has_out_commit(&records[0], 1));
+    assert!(has_out_commit(&records[1], 2));
+
+    relayer.stop().await;
+}
+
+/// Every method of the client against the real API.
+#[tokio::test]
+async fn test_client() {
+    let relayer = Relayer::start(|config| {
+        config.admin_token = Some(Secret(ADMIN_TOKEN.to_owned()));
+    })
+    .await;
+    let client = &relayer.client;
+
+    let version = client.version().await.unwrap();
+    assert_eq!(version.backend, "mock");
+
+    let tx = relayer.tx(TxType::Deposit, 1).await;
+    assert_eq!(client.validate_transaction(&tx).await.unwrap(), 0);
+    let job_id = client.submit_transaction(&tx).await.unwrap();
+    client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+    assert_eq!(
+        client.job_status(job_id).await.unwrap(),
+        JobStatus::Completed
+    );
+    assert_eq!(
+        client.job_status_by_index(0).await.unwrap(),
+        JobStatus::Completed
+    );
+
+    let info = client.info().await.unwrap();
+    assert_eq!(info.pool_index, TX_SIZE.to_string());
+
+    let records = client.transactions(0, 10).await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert!(has_out_commit(&records[0], 1));
+    client.ciphertexts(0, 10).await.unwrap();
+
+    let roots = client.roots(TX_SIZE).await.unwrap();
+    assert_eq!(roots.index, TX_SIZE);
+    assert_eq!(roots.roots.last().unwrap().root, info.root);
+
+    assert!(matches!(
+        client.jobs(None, None, 10).await,
+        Err(Error::Unauthorized)
+    ));
+    let jobs = RelayerClient::new(&relayer.url())
+        .with_token(ADMIN_TOKEN)
+        .jobs(Some(JobStatus::Completed), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].id, job_id);
+
+    // Error responses map to the client errors.
+    assert!(matches!(
+        client.job_status(job_id + 1000).await,
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(client.roots(1).await, Err(Error::BadRequest(_))));
+    let mut invalid = relayer.tx(TxType::Deposit, 2).await;
+    invalid.memo.clear();
+    match client.validate_transaction(&invalid).await {
+        Err(Error::Validation(errors)) => {
+            assert!(
+                errors.iter().any(|err| err.code == "empty_memo"),
+                "{errors:?}"
+            )
+        }
+        res => panic!("Invalid transaction is accepted: {res:?}"),
+    }
+
+    relayer.stop().await;
+}
+
+#[tokio::test]
+async fn test_failed_job_is_rolled_back() {
+    // The transaction at commit index 1 fails to send.
+    let relayer = Relayer::start(|config| {
+        if let BackendKind::Mock(mock) = &mut config.backend {
+            mock.fail_indices = vec![1];
+        }
+    })
+    .await;
+    let client = &relayer.client;
+
+    let job_id = client
+        .submit_transaction(&relayer.tx(TxType::Deposit, 1).await)
+        .await
+        .unwrap();
+    client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+    let mined = client.info().await.unwrap();
+
+    let job_id = client
+        .submit_transaction(&relayer.tx(TxType::Deposit, 2).await)
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.wait_for_job(job_id, JOB_TIMEOUT).await,
+        Err(Error::JobFailed)
+    ));
+
+    // The optimistic state is back at the mined one.
+    let info = client.info().await.unwrap();
+    assert_eq!(info.pool_index, mined.pool_index);
+    assert_eq!(info.root, mined.root);
+    assert_eq!(info.optimistic_index, mined.pool_index);
+    assert_eq!(info.optimistic_root, mined.root);
+
+    let records = client.transactions(0, 10).await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert!(has_out_commit(&records[0], 1));
+
+    // The rolled back transaction can be submitted again with the same idempotency key, and
+    // fails again.
+    let mut job_ids = Vec::new();
+    for _ in 0..2 {
+        let tx = relayer.tx(TxType::Deposit, 2).await;
+        let res = reqwest::Client::new()
+            .post(format!("{}/transactions", relayer.url()))
+            .header("Idempotency-Key", "deposit-2")
+            .json(&tx)
+            .send()
+            .await
+            .unwrap();
+        let body: serde_json::Value = res.json().await.unwrap();
+        let job_id = body["jobId"].as_u64().unwrap();
+        assert!(matches!(
+            client.wait_for_job(job_id, JOB_TIMEOUT).await,
+            Err(Error::JobFailed)
+        ));
+        job_ids.push(job_id);
+    }
+    assert_ne!(job_ids[0], job_ids[1]);
+
+    relayer.stop().await;
+}
+
+#[tokio::test]
+async fn test_later_jobs_are_cancelled_after_failure() {
+    // The transaction at commit index 1 fails to send.
+    let relayer = Relayer::start(|config| {
+        if let BackendKind::Mock(mock) = &mut config.backend {
+            mock.fail_indices = vec![1];
+        }
+    })
+    .await;
+    let client = &relayer.client;
+
+    // All four are queued on top of each other before the first one is sent.
+    let sending = &relayer.handle.state().sending;
+    sending.store(false, Ordering::SeqCst);
+    let mut job_ids = Vec::new();
+    for seed in 1..=4 {
+        job_ids.push(relayer.submit(TxType::Deposit, seed).await);
+    }
+    relayer.wait_for_index(4 * TX_SIZE, 0).await;
+    sending.store(true, Ordering::SeqCst);
+
+    client.wait_for_job(job_ids[0], JOB_TIMEOUT).await.unwrap();
+    assert!(matches!(
+        client.wait_for_job(job_ids[1], JOB_TIMEOUT).await,
+        Err(Error::JobFailed)
+    ));
+    for &job_id in &job_ids[2..] {
+        assert!(matches!(
+            client.wait_for_job(job_id, JOB_TIMEOUT).await,
+            Err(Error::JobCancelled)
+        ));
+    }
+
+    // The tree and the tx storage are back at the first transaction.
+    relayer.wait_for_index(TX_SIZE, TX_SIZE).await;
+    let info = client.info().await.unwrap();
+    assert_eq!(info.optimistic_root, info.root);
+    let records = client.transactions(0, 10).await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert!(has_out_commit(&records[0], 1));
+
+    relayer.stop().await;
+}
+
+#[tokio::test]
+async fn test_failed_jobs_admin() {
+    // The second send fails. One job at a time, so that the third one is still queued then.
+    let relayer = Relayer::start(|config| {
+        config.admin_token = Some(Secret(ADMIN_TOKEN.to_owned()));
+        config.max_concurrent_jobs = Some(1);
+        if let BackendKind::Mock(mock) = &mut config.backend {
+            mock.fail_every_n = Some(2);
+        }
+    })
+    .await;
+    let client = &relayer.client;
+
+    let job_id = relayer.submit(TxType::Deposit, 1).await;
+    client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+
+    let sending = &relayer.handle.state().sending;
+    sending.store(false, Ordering::SeqCst);
+    let failed_id = relayer.submit(TxType::Deposit, 2).await;
+    let cancelled_id = relayer.submit(TxType::Deposit, 3).await;
+    relayer.wait_for_index(3 * TX_SIZE, TX_SIZE).await;
+    sending.store(true, Ordering::SeqCst);
+    assert!(matches!(
+        client.wait_for_job(failed_id, JOB_TIMEOUT).await,
+        Err(Error::JobFailed)
+    ));
+    assert!(matches!(
+        client.wait_for_job(cancelled_id, JOB_TIMEOUT).await,
+        Err(Error::JobCancelled)
+    ));
+
+    // Both are archived, newest first.
+    let failed_jobs: serde_json::Value = relayer
+        .admin(reqwest::Method::GET, "/admin/failed-jobs")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let ids = failed_jobs
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|failed_job| failed_job["job"]["id"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![cancelled_id, failed_id]);
+
+    let res = relayer
+        .admin(reqwest::Method::GET, "/admin/failed-jobs?limit=1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.json::<Vec<serde_json::Value>>().await.unwrap().len(), 1);
+
+    let failed_job: serde_json::Value = relayer
+        .admin(
+            reqwest::Method::GET,
+            &format!("/admin/failed-jobs/{failed_id}"),
+        )
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(failed_job["job"]["id"], failed_id);
+    assert!(failed_job["error"]
+        .as_str()
+        .unwrap()
+        .contains("Simulated failure"));
+
+    let res = relayer
+        .admin(reqwest::Method::GET, "/admin/failed-jobs/1000")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let res = reqwest::Client::new()
+        .get(format!("{}/admin/failed-jobs", relayer.url()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    // The cancelled transaction is still valid and goes through the third send.
+    let res: serde_json::Value = relayer
+        .admin(
+            reqwest::Method::POST,
+            &format!("/admin/failed-jobs/{cancelled_id}/retry"),
+        )
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let job_id = res["jobId"].as_u64().unwrap();
+    client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+
+    let res = relayer
+        .admin(
+            reqwest::Method::GET,
+            &format!("/admin/failed-jobs/{cancelled_id}"),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let records = client.transactions(0, 10).await.unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(has_out_commit(&records[0], 1));
+    assert!(has_out_commit(&records[1], 3));
+
+    relayer.stop().await;
+}
+
+#[tokio::test]
+async fn test_standby_promotion() {
+    const PEER_TOKEN: &str = "peer";
+
+    let backend = Arc::new(MockBackend::new(mock::Config {
+        send_latency_ms: 0,
+        mining_delay_ms: 0,
+        fail_every_n: None,
+        fail_indices: vec![],
+        transient_failures: 0,
+    }));
+    let primary = Relayer::start_with_backend(Some(backend.clone()), |config| {
+        config.peer_token = Some(Secret(PEER_TOKEN.to_owned()));
+    })
+    .await;
+    // The reorg detector would only update the cached pool index of the standby after a minute,
+    // so the promotion has to look at the chain itself.
+    let standby = Relayer::start_with_backend(Some(backend.clone()), |config| {
+        config.peer_token = Some(Secret(PEER_TOKEN.to_owned()));
+        config.admin_token = Some(Secret(ADMIN_TOKEN.to_owned()));
+        config.standby_primary_url = Some(primary.url());
+        config.standby_sync_interval_ms = 10;
+        config.reorg_check_interval_secs = 60;
+    })
+    .await;
+
+    // Two transactions are mined by the primary, the standby only follows them.
+    let sending = &primary.handle.state().sending;
+    sending.store(false, Ordering::SeqCst);
+    primary.submit(TxType::Deposit, 1).await;
+    primary.submit(TxType::Deposit, 2).await;
+    standby.wait_for_index(2 * TX_SIZE, 0).await;
+    sending.store(true, Ordering::SeqCst);
+    primary.wait_for_index(2 * TX_SIZE, 2 * TX_SIZE).await;
+
+    // The third one is only accepted when the primary goes down.
+    sending.store(false, Ordering::SeqCst);
+    primary.submit(TxType::Deposit, 3).await;
+    standby.wait_for_index(3 * TX_SIZE, 0).await;
+    primary.stop().await;
+
+    let promotion: serde_json::Value = standby
+        .admin(reqwest::Method::POST, "/admin/promote")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(promotion["resubmitted"], 1);
+    assert_eq!(promotion["dropped"], 0);
+
+    // Only the inherited transaction is sent again.
+    standby.wait_for_index(3 * TX_SIZE, 3 * TX_SIZE).await;
+    assert_eq!(backend.get_pool_index().await.unwrap(), 3 * TX_SIZE);
+
+    let records = standby.client.transactions(0, 10).await.unwrap();
+    assert_eq!(records.len(), 3);
+    for (record, seed) in records.iter().zip(1..) {
+        assert!(has_out_commit(record, seed));
+    }
+
+    standby.stop().await;
+}
+
+/// Two relayers sharing the pool, one of them paused while the other one sends.
+async fn start_pair() -> (Arc<MockBackend>, Relayer, Relayer) {
+    let backend = Arc::new(MockBackend::new(mock::Config {
+        send_latency_ms: 0,
+        mining_delay_ms: 0,
+        fail_every_n: None,
+        fail_indices: vec![],
+        transient_failures: 0,
+    }));
+    // Only the worker may notice the transactions of the other relayer.
+    let paused = Relayer::start_with_backend(Some(backend.clone()), |config| {
+        config.reorg_check_interval_secs = 60;
+    })
+    .await;
+    let other = Relayer::start_with_backend(Some(backend.clone()), |_| {}).await;
+
+    (backend, paused, other)
+}
+
+#[tokio::test]
+async fn test_externally_advanced_pool() {
+    let (backend, relayer, other) = start_pair().await;
+
+    let sending = &relayer.handle.state().sending;
+    sending.store(false, Ordering::SeqCst);
+    let job_id = relayer.submit(TxType::Deposit, 1).await;
+    relayer.wait_for_index(TX_SIZE, 0).await;
+
+    let other_job_id = other.submit(TxType::Deposit, 2).await;
+    other
+        .client
+        .wait_for_job(other_job_id, JOB_TIMEOUT)
+        .await
+        .unwrap();
+
+    // The job is prepared again on top of the other transaction.
+    sending.store(true, Ordering::SeqCst);
+    relayer
+        .client
+        .wait_for_job(job_id, JOB_TIMEOUT)
+        .await
+        .unwrap();
+    relayer.wait_for_index(2 * TX_SIZE, 2 * TX_SIZE).await;
+    assert_eq!(backend.get_pool_index().await.unwrap(), 2 * TX_SIZE);
+
+    let records = relayer.client.transactions(0, 10).await.unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(has_out_commit(&records[0], 2));
+    assert!(has_out_commit(&records[1], 1));
+
+    relayer.stop().await;
+    other.stop().await;
+}
+
+#[tokio::test]
+async fn test_already_mined_tx_is_not_resent() {
+    let (backend, relayer, other) = start_pair().await;
+
+    let sending = &relayer.handle.state().sending;
+    sending.store(false, Ordering::SeqCst);
+    let job_id = relayer.submit(TxType::Deposit, 1).await;
+    relayer.wait_for_index(TX_SIZE, 0).await;
+
+    // The same transaction gets mined through the other relayer, as if the response to the
+    // first send was lost.
+    let other_job_id = other.submit(TxType::Deposit, 1).await;
+    other
+        .client
+        .wait_for_job(other_job_id, JOB_TIMEOUT)
+        .await
+        .unwrap();
+
+    sending.store(true, Ordering::SeqCst);
+    relayer
+        .client
+        .wait_for_job(job_id, JOB_TIMEOUT)
+        .await
+        .unwrap();
+    relayer.wait_for_index(TX_SIZE, TX_SIZE).await;
+    assert_eq!(backend.get_pool_index().await.unwrap(), TX_SIZE);
+
+    let records = relayer.client.transactions(0, 10).await.unwrap();
+    assert_eq!(records.len(), 1);
+    assert!(has_out_commit(&records[0], 1));
+
+    relayer.stop().await;
+    other.stop().await;
+}
+
+#[tokio::test]
+async fn test_compaction_after_rollback() {
+    // Every second transaction fails to send.
+    let backend = Arc::new(MockBackend::new(mock::Config {
+        send_latency_ms: 0,
+        mining_delay_ms: 0,
+        fail_every_n: Some(2),
+        fail_indices: vec![],
+        transient_failures: 0,
+    }));
+    let relayer = Relayer::start_with_backend(Some(backend), |config| {
+        config.admin_token = Some(Secret(ADMIN_TOKEN.to_owned()));
+    })
+    .await;
+
+    let job_id = relayer.submit(TxType::Deposit, 1).await;
+    relayer
+        .client
+        .wait_for_job(job_id, JOB_TIMEOUT)
+        .await
+        .unwrap();
+    let job_id = relayer.submit(TxType::Deposit, 2).await;
+    assert!(matches!(
+        relayer.client.wait_for_job(job_id, JOB_TIMEOUT).await,
+        Err(Error::JobFailed)
+    ));
+    let mined = relayer.client.info().await.unwrap();
+
+    let res = relayer
+        .admin(reqwest::Method::POST, "/admin/compact")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+
+    // The compacted files are opened again, without rebuilding from the chain.
+    let relayer = relayer.restart().await;
+    let info = relayer.client.info().await.unwrap();
+    assert_eq!(info.pool_index, mined.pool_index);
+    assert_eq!(info.root, mined.root);
+    assert_eq!(info.optimistic_index, mined.pool_index);
+    assert_eq!(info.optimistic_root, mined.root);
+
+    let job_id = relayer.submit(TxType::Deposit, 3).await;
+    relayer
+        .client
+        .wait_for_job(job_id, JOB_TIMEOUT)
+        .await
+        .unwrap();
+
+    let records = relayer.client.transactions(0, 10).await.unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(has_out_commit(&records[0], 1));
+    assert!(has_out_commit(&records[1], 3));
+
+    relayer.stop().await;
+}
+
+/// Body of a `PUT /admin/settings` request.
+fn settings(fee: u64, max_pending_jobs: Option<u64>) -> serde_json::Value {
+    serde_json::json!({
+        "fee": { "flat": fee },
+        "maxPendingJobs": max_pending_jobs,
+    })
+}
+
+#[tokio::test]
+async fn test_settings_update() {
+    let relayer = Relayer::start(|config| {
+        config.admin_token = Some(Secret(ADMIN_TOKEN.to_owned()));
+    })
+    .await;
+    let client = &relayer.client;
+
+    let job_id = relayer.submit(TxType::Deposit, 1).await;
+    client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+
+    let res = relayer
+        .admin(reqwest::Method::PUT, "/admin/settings")
+        .json(&settings(10, None))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+    // The same transaction, with the zero fee that was enough before.
+    let tx = relayer.tx(TxType::Deposit, 2).await;
+    match client.submit_transaction(&tx).await {
+        Err(Error::Validation(errors)) => {
+            let codes = errors
+                .iter()
+                .map(|err| err.code.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(codes, ["fee_too_low"]);
+        }
+        res => panic!("Expected a validation error, got {res:?}"),
+    }
+
+    let mut tx = tx;
+    tx.memo = 10u64.to_be_bytes().to_vec();
+    let job_id = client.submit_transaction(&tx).await.unwrap();
+    client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+
+    // Applied once, and recorded in the audit log.
+    let state = relayer.handle.state();
+    state.audit.flush().await;
+    let changes = state
+        .audit
+        .read(0, 100)
+        .unwrap()
+        .into_iter()
+        .filter(|entry| matches!(entry.event, AuditEvent::SettingsChanged { .. }))
+        .collect::<Vec<_>>();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(
+        changes[0].event,
+        AuditEvent::SettingsChanged {
+            settings: RuntimeSettings {
+                fee: FeeModel::Flat(10),
+                rate_limit_per_minute: None,
+                max_pending_jobs: None,
+            },
+            source: "api".to_owned(),
+        }
+    );
+
+    relayer.stop().await;
+}
+
+#[tokio::test]
+async fn test_pause_and_resume() {
+    let relayer = Relayer::start(|config| {
+        config.admin_token = Some(Secret(ADMIN_TOKEN.to_owned()));
+    })
+    .await;
+    let client = &relayer.client;
+    let post = |path: &str| relayer.admin(reqwest::Method::POST, path).send();
+    let no_content = |res: reqwest::Result<reqwest::Response>| {
+        assert_eq!(res.unwrap().status(), reqwest::StatusCode::NO_CONTENT)
+    };
+
+    // Paused in the middle of the queue.
+    let state = relayer.handle.state();
+    state.sending.store(false, Ordering::SeqCst);
+    let queued = [
+        relayer.submit(TxType::Deposit, 1).await,
+        relayer.submit(TxType::Deposit, 2).await,
+    ];
+    no_content(post("/admin/pause").await);
+
+    let info = client.info().await.unwrap();
+    assert!(info.paused);
+    let tx = relayer.tx(TxType::Deposit, 3).await;
+    assert!(matches!(
+        client.submit_transaction(&tx).await,
+        Err(Error::Paused)
+    ));
+
+    // Reads keep working and the queue is drained.
+    state.sending.store(true, Ordering::SeqCst);
+    for job_id in queued {
+        client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+    }
+    relayer.wait_for_index(2 * TX_SIZE, 2 * TX_SIZE).await;
+    assert_eq!(client.transactions(0, 10).await.unwrap().len(), 2);
+    assert!(client.info().await.unwrap().paused);
+
+    // Sending can be paused along with accepting, resuming enables both.
+    no_content(post("/admin/pause?sending=true").await);
+    let info = client.info().await.unwrap();
+    assert!(info.paused);
+    assert!(info.sending_paused);
+
+    no_content(post("/admin/resume").await);
+    let info = client.info().await.unwrap();
+    assert!(!info.paused);
+    assert!(!info.sending_paused);
+
+    let tx = relayer.tx(TxType::Deposit, 3).await;
+    let job_id = client.submit_transaction(&tx).await.unwrap();
+    client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+
+    let res = reqwest::Client::new()
+        .post(format!("{}/admin/pause", relayer.url()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+    assert!(!client.info().await.unwrap().paused);
+
+    relayer.stop().await;
+}
+
+#[tokio::test]
+async fn test_queue_full() {
+    // Jobs stay in the queue.
+    let relayer = Relayer::start(|config| {
+        config.admin_token = Some(Secret(ADMIN_TOKEN.to_owned()));
+        config.max_concurrent_jobs = Some(0);
+        config.settings.max_pending_jobs = Some(1);
+    })
+    .await;
+
+    relayer.submit(TxType::Deposit, 1).await;
+
+    let tx = relayer.tx(TxType::Deposit, 2).await;
+    let res = reqwest::Client::new()
+        .post(format!("{}/transactions", relayer.url()))
+        .json(&tx)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "QUEUE_FULL");
+
+    // Raising the limit takes effect immediately
+    let res = relayer
+        .admin(reqwest::Method::PUT, "/admin/settings")
+        .json(&settings(0, Some(2)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::OK);
+    relayer.client.submit_transaction(&tx).await.unwrap();
+
+    relayer.stop().await;
+}
+
+#[tokio::test]
+async fn test_jobs_access() {
+    let relayer = Relayer::start(|config| {
+        config.admin_token = Some(Secret(ADMIN_TOKEN.to_owned()));
+        config.api_keys = vec![Secret("key".to_owned())];
+    })
+    .await;
+    let job_id = relayer
+        .client
+        .clone()
+        .with_token("key")
+        .submit_transaction(&relayer.tx(TxType::Deposit, 1).await)
+        .await
+        .unwrap();
+
+    // Submissions need a key, reads don't, except for the list of jobs.
+    assert!(matches!(
+        relayer.client.jobs(None, None, 10).await,
+        Err(Error::Unauthorized)
+    ));
+    assert!(matches!(
+        relayer
+            .client
+            .clone()
+            .with_token("wrong")
+            .jobs(None, None, 10)
+            .await,
+        Err(Error::Unauthorized)
+    ));
+
+    for token in ["key", ADMIN_TOKEN] {
+        let client = relayer.client.clone().with_token(token);
+        client.wait_for_job(job_id, JOB_TIMEOUT).await.unwrap();
+        let jobs = client
+            .jobs(Some(JobStatus::Completed), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id, job_id);
+        assert!(client
+            .jobs(Some(JobStatus::Pending), None, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    relayer.stop().await;
+}